@@ -1,24 +1,43 @@
-#![allow(dead_code)]
+//! Control state shared between the threads, and the actions they send the
+//! main loop
 
-use std::i16;
+use std::fmt;
 use std::sync::mpsc::Sender;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use crossterm::event::KeyEvent;
 
+use skelebot_protocol::Telemetry;
+
+use crate::channel::ChannelStatus;
+use crate::config::DriveConfig;
+use crate::diversity::RadioLink;
+use crate::fleet::FleetStatus;
+use crate::latency::LatencyStats;
+use crate::link::LinkStats;
+use crate::sim::SimPose;
+use crate::transport::RadioStatus;
+
+/// How often threads log how many times their loop ran
 pub const RECORD_TICKS_INTERVAL: Duration = Duration::from_secs(2);
+/// Camera pan and tilt limits, on the sticks' scale
 pub const PAN_TILT_MAX: f64 = i16::MAX as f64;
+/// See [`PAN_TILT_MAX`]
 pub const PAN_TILT_MIN: f64 = (i16::MIN + 1) as f64;
-// 180° in 300ms, so 0.6 °/ms
-const CAMERA_DEG_MS: f64 = 0.6;
+/// Approximate no-load wheel speed at 100% drive
+pub const WHEEL_RPM_MAX: u16 = 180;
 
+/// Drive speed range: slow caps the wheels lower, for fine control
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ControlSpeed {
+    /// Capped for fine control
     Slow,
+    /// Full range
     Fast,
 }
 
 impl ControlSpeed {
+    /// The other speed range
     pub fn toggle(self) -> Self {
         match self {
             Self::Fast => Self::Slow,
@@ -27,26 +46,43 @@ impl ControlSpeed {
     }
 }
 
-impl ToString for ControlSpeed {
-    fn to_string(&self) -> String {
+impl fmt::Display for ControlSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Fast => String::from("Fast"),
-            Self::Slow => String::from("Slow"),
+            Self::Fast => write!(f, "Fast"),
+            Self::Slow => write!(f, "Slow"),
         }
     }
 }
 
+/// What the operator is asking the robot to do, updated from the sticks and
+/// read by the radio thread each tick
+///
+/// Throttle, steering, pan and tilt are on the sticks' scale, ±32767.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ControlState {
+    /// Forwards positive
     pub throttle: i16,
+    /// Right positive
     pub steering: i16,
+    /// Camera pan, accumulated from the view stick
     pub pan: f32,
+    /// Camera tilt, accumulated from the view stick
     pub tilt: f32,
+    /// Speed range the throttle and steering are scaled into
     pub move_speed: ControlSpeed,
+    /// When pan and tilt were last moved, for rate-based camera control
     pub last_update: Instant,
 }
 
+impl Default for ControlState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ControlState {
+    /// Stopped, with the camera centred and the slow speed range
     pub fn new() -> Self {
         Self {
             throttle: 0,
@@ -58,6 +94,7 @@ impl ControlState {
         }
     }
 
+    /// Keeps every value within ±32767, so negating it can't overflow
     pub fn trim(mut self) -> Self {
         if self.throttle == i16::MIN {
             self.throttle += 1;
@@ -70,11 +107,12 @@ impl ControlState {
         self
     }
 
-    // Convert throttle and steering values to left/right tank-drive values,
-    // as expressed in +/- %
-    // Constant curvature drive logic from https://ewpratten.com/blog/joystick-to-voltage
-    // except straight tank drive when no throttle component
-    pub fn as_tank_drive(&self) -> (i8, i8) {
+    /// Converts throttle and steering values to left/right tank-drive values,
+    /// as expressed in +/- % within the configured speed limits
+    ///
+    /// Constant curvature drive logic from <https://ewpratten.com/blog/joystick-to-voltage>
+    /// except straight tank drive when no throttle component
+    pub fn as_tank_drive(&self, drive: &DriveConfig) -> (i8, i8) {
         let t = (self.throttle as f64) / (i16::MAX as f64);
         let s = (self.steering as f64) / (i16::MAX as f64);
 
@@ -97,8 +135,8 @@ impl ControlState {
         };
         let max = f64::max(left.abs(), right.abs()).max(1.0);
         let factor = match self.move_speed {
-            ControlSpeed::Slow => 50.0,
-            ControlSpeed::Fast => 100.0,
+            ControlSpeed::Slow => f64::from(drive.slow_speed),
+            ControlSpeed::Fast => f64::from(drive.fast_speed),
         };
 
         let left = (factor * left / max).clamp(-factor, factor) as i8;
@@ -107,7 +145,7 @@ impl ControlState {
         (left, right)
     }
 
-    // Convert pan and tilt values to angular values in +/- degrees (max 90°)
+    /// Converts pan and tilt values to angular values in +/- degrees (max 90°)
     pub fn as_camera_angles(&self) -> (i8, i8) {
         let pan = (self.pan as f64) / PAN_TILT_MAX;
         let tilt = (self.tilt as f64) / PAN_TILT_MAX;
@@ -118,8 +156,15 @@ impl ControlState {
         (pan, tilt)
     }
 
-    // Get new pan and tilt values given view x/y positions and last update time
-    pub fn get_rotated_camera(&self, view_x: i16, view_y: i16, curr_time: Instant) -> (f32, f32) {
+    /// Gets new pan and tilt values given view x/y positions and last update
+    /// time, at the configured camera rate
+    pub fn get_rotated_camera(
+        &self,
+        drive: &DriveConfig,
+        view_x: i16,
+        view_y: i16,
+        curr_time: Instant,
+    ) -> (f32, f32) {
         let ms_since = curr_time
             .checked_duration_since(self.last_update)
             .unwrap()
@@ -129,7 +174,7 @@ impl ControlState {
         // X/Y movement as a fraction of the max movement rate
         // Since it's already on the interval (-32767, 32767), we just need to
         // get the proportional delta
-        let delta_frac = (ms_since as f64) * CAMERA_DEG_MS / 90.0;
+        let delta_frac = (ms_since as f64) * drive.camera_deg_per_ms / 90.0;
         let x_delta = (view_x as f64) * delta_frac;
         let y_delta = (view_y as f64) * delta_frac;
 
@@ -140,51 +185,96 @@ impl ControlState {
     }
 }
 
+/// A message for the log pane, from the named thread
 #[derive(Debug)]
 pub struct ThreadMsg {
+    /// Thread the message is from, shown as its prefix
     pub name: String,
+    /// The message itself
     pub message: String,
 }
 
+/// One stick's axes, ±32767, and whether its button is pressed
 #[derive(Clone, Debug)]
 pub struct StickPosition {
+    /// Right positive
     pub x: i16,
+    /// Up positive
     pub y: i16,
+    /// Whether the stick is pressed in
     pub button: bool,
 }
 
+/// The move stick, then the view stick
 #[derive(Clone, Debug)]
 pub struct StickValues(pub StickPosition, pub StickPosition);
 
+/// Battery voltage as the robot reports it, in 1/1023 V
 #[derive(Debug)]
 pub struct BatteryVoltage(pub u16);
 
 impl BatteryVoltage {
+    /// In volts
     pub fn as_float(&self) -> f32 {
         f32::from(self.0) / 1023.0
     }
 }
 
+/// Battery current as the robot reports it, in 1/1023 A
 #[derive(Debug)]
 pub struct BatteryCurrent(pub u16);
 
 impl BatteryCurrent {
+    /// In amps
     pub fn as_float(&self) -> f32 {
         f32::from(self.0) / 1023.0
     }
 }
 
+/// A wheel's speed as the robot reports it
+#[derive(Debug)]
+pub struct WheelRpm(pub u16);
+
+impl WheelRpm {
+    /// As a percentage of max wheel speed, for comparison with drive values
+    pub fn as_percent(&self) -> f32 {
+        100.0 * f32::from(self.0) / f32::from(WHEEL_RPM_MAX)
+    }
+}
+
+/// Everything the input, radio and simulator threads tell the main loop
 #[derive(Debug)]
 pub enum Action {
+    /// Something to log
     Message(ThreadMsg),
+    /// Something that went wrong, logged and highlighted
     Error(ThreadMsg),
+    /// The sending thread can't carry on, so the session ends
     Fatal(ThreadMsg),
+    /// Key pressed in the terminal
     KeyPress(KeyEvent),
-    StickUpdate(StickValues),
-    BatteryVoltageUpdate(BatteryVoltage),
-    BatteryCurrentUpdate(BatteryCurrent),
+    /// Stick positions, with the kernel timestamp of the first new event if any arrived
+    StickUpdate(StickValues, Option<SystemTime>),
+    /// Telemetry item from an ack payload, with the index of the robot it came
+    /// from and when it arrived
+    Telemetry(usize, Telemetry, Instant),
+    /// Packet counts and ack rate over the last window
+    LinkStatsUpdate(LinkStats),
+    /// Link quality of each radio, when sending on more than one
+    RadioLinksUpdate(Vec<RadioLink>),
+    /// Round trip times from pings
+    LatencyUpdate(LatencyStats),
+    /// Radio settings in use, and any move under way
+    ChannelUpdate(ChannelStatus),
+    /// Which robot is being driven, and any handover under way
+    FleetUpdate(FleetStatus),
+    /// Whether the radio is connected
+    RadioUpdate(RadioStatus),
+    /// Simulated robot's pose, when driving the simulator instead of a radio
+    SimUpdate(SimPose),
 }
 
+/// Logs how many times a thread's loop ran since the last report
 pub fn record_ticks_for_period(
     tx: &Sender<Action>,
     name: &str,
@@ -205,6 +295,7 @@ pub fn record_ticks_for_period(
     tx.send(Action::Message(msg)).unwrap();
 }
 
+/// Logs a message from the named thread
 pub fn send_message(tx: &Sender<Action>, name: &str, msg: &str) {
     let msg = ThreadMsg {
         name: name.to_owned(),
@@ -213,6 +304,7 @@ pub fn send_message(tx: &Sender<Action>, name: &str, msg: &str) {
     tx.send(Action::Message(msg)).unwrap();
 }
 
+/// Logs an error from the named thread
 pub fn send_error_message(tx: &Sender<Action>, name: &str, msg: &str) {
     let msg = ThreadMsg {
         name: name.to_owned(),
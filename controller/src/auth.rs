@@ -0,0 +1,42 @@
+//! Key files for authenticated commands
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use skelebot_protocol::auth::{parse_key, AuthKey, KEY_LEN};
+
+/// Reads a key file, holding the key as 32 hex digits
+pub fn load_key(path: &Path) -> io::Result<AuthKey> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let bytes = parse_key(text.trim()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: key must be 32 hex digits", path.display()),
+        )
+    })?;
+    Ok(AuthKey::new(bytes))
+}
+
+/// Generates a new key and writes it to `path`, refusing to replace an existing one
+pub fn generate_key_file(path: &Path) -> io::Result<[u8; KEY_LEN]> {
+    let mut key = [0_u8; KEY_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", format_key(&key))?;
+    Ok(key)
+}
+
+/// Formats a key as the 32 hex digits the key file and firmware build take
+pub fn format_key(key: &[u8; KEY_LEN]) -> String {
+    key.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
@@ -0,0 +1,59 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// ATmega328's EEPROM size
+const EEPROM_LEN: usize = 1024;
+
+/// The AVR's EEPROM, optionally kept in a file so it survives restarts
+///
+/// Erased bytes read as 0xFF and values are little-endian, as on the AVR,
+/// so the firmware's layout carries over unchanged.
+pub struct Eeprom {
+    bytes: Vec<u8>,
+    path: Option<PathBuf>,
+}
+
+impl Eeprom {
+    /// Loads the file if there is one, otherwise starts erased
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut bytes = vec![0xFF; EEPROM_LEN];
+        if let Some(ref path) = path {
+            match fs::read(path) {
+                Ok(stored) => {
+                    let len = stored.len().min(EEPROM_LEN);
+                    bytes[..len].copy_from_slice(&stored[..len]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Self { bytes, path })
+    }
+
+    pub fn get_u16(&self, addr: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[addr], self.bytes[addr + 1]])
+    }
+
+    pub fn get_u32(&self, addr: usize) -> u32 {
+        u32::from_le_bytes(self.bytes[addr..addr + 4].try_into().unwrap())
+    }
+
+    pub fn put_u16(&mut self, addr: usize, value: u16) -> io::Result<()> {
+        self.put(addr, &value.to_le_bytes())
+    }
+
+    pub fn put_u32(&mut self, addr: usize, value: u32) -> io::Result<()> {
+        self.put(addr, &value.to_le_bytes())
+    }
+
+    fn put(&mut self, addr: usize, bytes: &[u8]) -> io::Result<()> {
+        self.bytes[addr..addr + bytes.len()].copy_from_slice(bytes);
+        match self.path {
+            Some(ref path) => fs::write(path, &self.bytes),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use std::time::Duration;
+
+use skelebot_protocol::auth::{self, AuthError, AuthKey};
+use skelebot_protocol::{
+    DataRate, Param, ResetCause, Telemetry, CMD_CENTER_CAMERA, CMD_CHANGE_CHANNEL, CMD_DRIVE,
+    CMD_LOOK, CMD_NOOP, CMD_PARAM_COMMIT, CMD_PARAM_GET, CMD_PARAM_SET, CMD_PING, CMD_SET_RADIO,
+    CMD_SET_VTX, CMD_STOP, MAX_CHANNEL, MAX_PA_LEVEL, MAX_TELEMETRY_LEN,
+};
+
+use crate::eeprom::Eeprom;
+use crate::nrf24::Nrf24;
+
+/// Firmware version reported in Identity, matching herakles.ino
+pub const FIRMWARE_VERSION: (u8, u8) = (1, 1);
+/// How long setup takes before the radio listens, mostly the servo wake-up stretch
+pub const BOOT_TIME: Duration = Duration::from_millis(4_400);
+
+const RADIO_REVERT_MS: u64 = 1_000;
+const HEALTH_ACK_EVERY: u8 = 10;
+// Command buffer slots, and bytes per slot with and without authentication
+const COMMAND_SLOTS: usize = 4;
+const COMMAND_LEN: usize = 4;
+const AUTH_COMMAND_LEN: usize = auth::MAX_FRAME_LEN;
+
+const PARAM_DEFAULTS: [u16; Param::ALL.len()] = [65, 306, 548, 190, 300, 394, 200, 50];
+const PARAMS_EEPROM_ADDR: usize = 0;
+const PARAMS_EEPROM_MAGIC: u16 = 0x5B01;
+const AUTH_RESERVE_STEP: u32 = 65_536;
+const AUTH_EEPROM_ADDR: usize = 32;
+const AUTH_EEPROM_MAGIC: u16 = 0xA7E4;
+
+// Battery draw with the motors stopped and the VTX off, plus what each adds,
+// all in amps
+const IDLE_CURRENT: f32 = 0.25;
+const MOTOR_CURRENT_PER_PERCENT: f32 = 0.012;
+const VTX_CURRENT: f32 = 0.3;
+
+/// Drive and camera state, as the firmware last set it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Outputs {
+    /// Signed motor speeds in %, forwards positive
+    pub left: i8,
+    pub right: i8,
+    /// Servo angles as commanded (0-180), and the PWM counts they map to
+    pub pan_angle: u8,
+    pub tilt_angle: u8,
+    pub pan_pwm: u16,
+    pub tilt_pwm: u16,
+    pub vtx_on: bool,
+}
+
+/// herakles.ino's `loop()` and the state it keeps, one call to `step` per loop
+pub struct Hercules {
+    commands: [[u8; AUTH_COMMAND_LEN]; COMMAND_SLOTS],
+    command_lens: [usize; COMMAND_SLOTS],
+    command_head: usize,
+    command_tail: usize,
+    rate: DataRate,
+    pa_level: u8,
+    prev: (u8, DataRate, u8),
+    radio_pending: bool,
+    radio_changed: u64,
+    last_cmd: u64,
+    last_ack: u64,
+    acks_since_health: u8,
+    // Telemetry staged for the next ack, behind any receipt
+    ack_items: Vec<Telemetry>,
+    reset_cause: ResetCause,
+    params: [u16; Param::ALL.len()],
+    outputs: Outputs,
+    battery_voltage: f32,
+    auth_key: Option<AuthKey>,
+    auth_last_counter: u32,
+    auth_counter_reserve: u32,
+    // Whether a command has been accepted since boot, so there's a receipt to give
+    auth_accepted: bool,
+    eeprom: Eeprom,
+    events: Vec<String>,
+}
+
+impl Hercules {
+    /// Runs the equivalent of `setup()`, leaving the radio listening
+    pub fn boot(
+        radio: &mut Nrf24,
+        eeprom: Eeprom,
+        auth_key: Option<AuthKey>,
+        battery_voltage: f32,
+    ) -> Self {
+        let mut hercules = Self {
+            commands: [[0; AUTH_COMMAND_LEN]; COMMAND_SLOTS],
+            command_lens: [0; COMMAND_SLOTS],
+            command_head: 0,
+            command_tail: 0,
+            rate: radio.rate,
+            pa_level: MAX_PA_LEVEL,
+            prev: (radio.channel, radio.rate, MAX_PA_LEVEL),
+            radio_pending: false,
+            radio_changed: 0,
+            last_cmd: 0,
+            last_ack: 0,
+            acks_since_health: 0,
+            ack_items: Vec::new(),
+            reset_cause: ResetCause(ResetCause::POWER_ON),
+            params: PARAM_DEFAULTS,
+            outputs: Outputs {
+                left: 0,
+                right: 0,
+                pan_angle: 90,
+                tilt_angle: 90,
+                pan_pwm: 0,
+                tilt_pwm: 0,
+                vtx_on: false,
+            },
+            battery_voltage,
+            auth_key,
+            auth_last_counter: 0,
+            auth_counter_reserve: 0,
+            auth_accepted: false,
+            eeprom,
+            events: Vec::new(),
+        };
+        hercules.load_params();
+        if hercules.auth_key.is_some() {
+            hercules.load_auth();
+        }
+        hercules.set_pan_angle(90);
+        hercules.set_tilt_angle(90);
+        radio.start_listening();
+        // Under 6V and we can assume we're on external power
+        if battery_voltage > 6.0 {
+            hercules.outputs.vtx_on = true;
+        }
+        hercules
+    }
+
+    pub fn outputs(&self) -> Outputs {
+        self.outputs
+    }
+
+    /// Things worth telling whoever's watching, since last called
+    pub fn take_events(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// One pass of `loop()`, at `now` milliseconds since power-on
+    pub fn step(&mut self, radio: &mut Nrf24, now: u64) {
+        let mut received_cmd = false;
+
+        // Only one payload received per loop
+        if radio.available() {
+            let next_head = (self.command_head + 1) % COMMAND_SLOTS;
+            // Payloads stay in the radio if the ring buffer's full
+            if next_head != self.command_tail {
+                let payload = radio.read().unwrap_or_default();
+                let len = payload.len().min(self.command_buf_len());
+                self.commands[self.command_head][..len].copy_from_slice(&payload[..len]);
+                self.command_lens[self.command_head] = len;
+                self.command_head = next_head;
+                // Its ack took whatever was staged
+                self.ack_items.clear();
+            }
+        }
+
+        while self.command_tail != self.command_head {
+            let slot = self.command_tail;
+            self.command_tail = (self.command_tail + 1) % COMMAND_SLOTS;
+            let frame = self.commands[slot];
+            let len = self.command_lens[slot];
+            let command = if self.auth_key.is_some() {
+                match self.authenticate(radio, &frame[..len]) {
+                    Some(command) => command,
+                    None => continue,
+                }
+            } else {
+                let mut command = [0; COMMAND_LEN];
+                command.copy_from_slice(&frame[..COMMAND_LEN]);
+                command
+            };
+            received_cmd = true;
+            self.handle_command(radio, command, now);
+            // Restaged so the next ack carries this command's receipt
+            if self.auth_key.is_some() {
+                self.stage_ack(radio);
+            }
+        }
+
+        // If no command received in the conn loss period, assume connection lost and stop
+        if received_cmd {
+            self.last_cmd = now;
+        }
+        let conn_loss_ms = u64::from(self.params[Param::ConnLossMs.to_u8() as usize]);
+        if now - self.last_cmd >= conn_loss_ms {
+            if self.outputs.left != 0 || self.outputs.right != 0 {
+                self.events
+                    .push(format!("no command for {}ms, stopping", conn_loss_ms));
+            }
+            self.outputs.left = 0;
+            self.outputs.right = 0;
+            self.last_cmd = now;
+            radio.flush_tx();
+            self.ack_items.clear();
+        }
+
+        // If the controller never showed up on the new settings, go back to the old ones
+        if self.radio_pending && now - self.radio_changed >= RADIO_REVERT_MS {
+            let (channel, rate, pa_level) = self.prev;
+            self.apply_radio(radio, channel, rate, pa_level);
+            self.radio_pending = false;
+            self.events.push(format!(
+                "controller didn't follow, reverted to channel {} {}",
+                channel, rate
+            ));
+        }
+
+        let ack_payload_ms = u64::from(self.params[Param::AckPayloadMs.to_u8() as usize]);
+        // Replies still waiting to go out take priority
+        if now - self.last_ack >= ack_payload_ms && self.ack_items.is_empty() {
+            while now - self.last_ack >= ack_payload_ms {
+                self.last_ack += ack_payload_ms;
+            }
+            self.ack_items = self.health_or_battery_items(now);
+            self.stage_ack(radio);
+        }
+    }
+
+    fn command_buf_len(&self) -> usize {
+        if self.auth_key.is_some() {
+            AUTH_COMMAND_LEN
+        } else {
+            COMMAND_LEN
+        }
+    }
+
+    fn handle_command(&mut self, radio: &mut Nrf24, command: [u8; COMMAND_LEN], now: u64) {
+        // Any other command after a radio change confirms the controller followed
+        if command[0] != CMD_CHANGE_CHANNEL && command[0] != CMD_SET_RADIO {
+            if self.radio_pending {
+                self.events.push(format!(
+                    "controller followed to channel {} {}",
+                    radio.channel, radio.rate
+                ));
+            }
+            self.radio_pending = false;
+        }
+        match command[0] {
+            CMD_NOOP => {}
+            CMD_CHANGE_CHANNEL if command[1] <= MAX_CHANNEL && command[1] != radio.channel => {
+                self.change_radio(radio, command[1], self.rate, self.pa_level, now);
+            }
+            CMD_CHANGE_CHANNEL => {}
+            CMD_PING => {
+                self.ack_items = vec![Telemetry::Pong(command[1])];
+                self.stage_ack(radio);
+            }
+            CMD_STOP => {
+                self.outputs.left = 0;
+                self.outputs.right = 0;
+            }
+            CMD_DRIVE => {
+                self.outputs.left = motor_speed(command[1]);
+                self.outputs.right = motor_speed(command[2]);
+            }
+            CMD_CENTER_CAMERA => {
+                self.set_pan_angle(90);
+                self.set_tilt_angle(90);
+            }
+            CMD_LOOK => {
+                self.set_pan_angle(command[1]);
+                self.set_tilt_angle(command[2]);
+            }
+            CMD_PARAM_GET => {
+                if let Some(param) = Param::from_u8(command[1]) {
+                    self.stage_param_value(radio, param);
+                }
+            }
+            CMD_PARAM_SET => {
+                if let Some(param) = Param::from_u8(command[1]) {
+                    let value = u16::from_be_bytes([command[2], command[3]]);
+                    if self.set_param(param, value) {
+                        self.events.push(format!("set {} to {}", param, value));
+                    } else {
+                        self.events
+                            .push(format!("rejected {} = {}, out of range", param, value));
+                    }
+                    self.stage_param_value(radio, param);
+                }
+            }
+            CMD_PARAM_COMMIT => {
+                self.commit_params();
+            }
+            CMD_SET_VTX => {
+                self.outputs.vtx_on = command[1] != 0;
+            }
+            CMD_SET_RADIO => {
+                let rate = DataRate::from_u8(command[2])
+                    .filter(|_| command[1] <= MAX_CHANNEL && command[3] <= MAX_PA_LEVEL);
+                if let Some(rate) = rate {
+                    self.change_radio(radio, command[1], rate, command[3], now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Switches to new radio settings, pending confirmation by the controller
+    fn change_radio(
+        &mut self,
+        radio: &mut Nrf24,
+        channel: u8,
+        rate: DataRate,
+        pa_level: u8,
+        now: u64,
+    ) {
+        // Keep the last confirmed settings if several changes arrive unconfirmed
+        if !self.radio_pending {
+            self.prev = (radio.channel, self.rate, self.pa_level);
+        }
+        self.apply_radio(radio, channel, rate, pa_level);
+        self.radio_pending = true;
+        self.radio_changed = now;
+        self.events
+            .push(format!("moved to channel {} {}, pending", channel, rate));
+    }
+
+    fn apply_radio(&mut self, radio: &mut Nrf24, channel: u8, rate: DataRate, pa_level: u8) {
+        radio.channel = channel;
+        radio.rate = rate;
+        self.rate = rate;
+        self.pa_level = pa_level;
+    }
+
+    // Identity and uptime every tenth payload, otherwise battery and VTX state
+    fn health_or_battery_items(&mut self, now: u64) -> Vec<Telemetry> {
+        if self.acks_since_health + 1 >= HEALTH_ACK_EVERY {
+            self.acks_since_health = 0;
+            let (major, minor) = FIRMWARE_VERSION;
+            [
+                Telemetry::Identity {
+                    major,
+                    minor,
+                    reset_cause: self.reset_cause,
+                },
+                Telemetry::Uptime(((now / 100) & 0xFF_FFFF) as u32),
+            ]
+            .to_vec()
+        } else {
+            self.acks_since_health += 1;
+            [
+                Telemetry::BatteryVoltage(to_raw(self.battery_voltage)),
+                Telemetry::BatteryCurrent(to_raw(self.battery_current())),
+                Telemetry::VtxState(self.outputs.vtx_on),
+            ]
+            .to_vec()
+        }
+    }
+
+    // Replaces whatever's staged with the receipt for the last command
+    // accepted, if authenticating, followed by the staged telemetry
+    fn stage_ack(&self, radio: &mut Nrf24) {
+        let receipt = self
+            .auth_key
+            .as_ref()
+            .filter(|_| self.auth_accepted)
+            .map(|key| auth::receipt(key, &radio.address, self.auth_last_counter));
+        let mut ack = [0; MAX_TELEMETRY_LEN];
+        let mut len = 0;
+        for item in receipt.iter().chain(&self.ack_items) {
+            len += item.encode(&mut ack[len..]).unwrap();
+        }
+        radio.flush_tx();
+        radio.write_ack_payload(&ack[..len]);
+    }
+
+    fn battery_current(&self) -> f32 {
+        let motors = f32::from(self.outputs.left.unsigned_abs())
+            + f32::from(self.outputs.right.unsigned_abs());
+        let vtx = if self.outputs.vtx_on {
+            VTX_CURRENT
+        } else {
+            0.0
+        };
+        IDLE_CURRENT + motors * MOTOR_CURRENT_PER_PERCENT + vtx
+    }
+
+    fn set_pan_angle(&mut self, angle: u8) {
+        self.outputs.pan_angle = angle;
+        let [min, mid, max] = self.servo_limits(Param::ServoPanMin);
+        self.outputs.pan_pwm = servo_pwm(angle, min, mid, max);
+    }
+
+    fn set_tilt_angle(&mut self, angle: u8) {
+        self.outputs.tilt_angle = angle;
+        let [min, mid, max] = self.servo_limits(Param::ServoTiltMin);
+        self.outputs.tilt_pwm = servo_pwm(angle, min, mid, max);
+    }
+
+    fn servo_limits(&self, first: Param) -> [u16; 3] {
+        let id = first.to_u8() as usize;
+        [self.params[id], self.params[id + 1], self.params[id + 2]]
+    }
+
+    // Changes one param if the result is valid, reapplying servo limits straight away
+    fn set_param(&mut self, param: Param, value: u16) -> bool {
+        let mut updated = self.params;
+        updated[param.to_u8() as usize] = value;
+        if !params_valid(&updated) {
+            return false;
+        }
+        self.params = updated;
+        match param {
+            Param::ServoPanMin | Param::ServoPanMid | Param::ServoPanMax => {
+                self.set_pan_angle(self.outputs.pan_angle);
+            }
+            Param::ServoTiltMin | Param::ServoTiltMid | Param::ServoTiltMax => {
+                self.set_tilt_angle(self.outputs.tilt_angle);
+            }
+            Param::ConnLossMs | Param::AckPayloadMs => {}
+        }
+        true
+    }
+
+    fn stage_param_value(&mut self, radio: &mut Nrf24, param: Param) {
+        let value = self.params[param.to_u8() as usize];
+        self.ack_items = vec![Telemetry::ParamValue { param, value }];
+        self.stage_ack(radio);
+    }
+
+    fn load_params(&mut self) {
+        if self.eeprom.get_u16(PARAMS_EEPROM_ADDR) != PARAMS_EEPROM_MAGIC {
+            return;
+        }
+        let mut stored = [0; Param::ALL.len()];
+        for (id, value) in stored.iter_mut().enumerate() {
+            *value = self.eeprom.get_u16(PARAMS_EEPROM_ADDR + 2 + id * 2);
+        }
+        // Anything odd, such as limits from an older build, and the defaults stay
+        if params_valid(&stored) {
+            self.params = stored;
+        }
+    }
+
+    fn commit_params(&mut self) {
+        let mut result = self.eeprom.put_u16(PARAMS_EEPROM_ADDR, PARAMS_EEPROM_MAGIC);
+        for (id, &value) in self.params.iter().enumerate() {
+            result = result.and(self.eeprom.put_u16(PARAMS_EEPROM_ADDR + 2 + id * 2, value));
+        }
+        match result {
+            Ok(()) => self.events.push(String::from("committed params to EEPROM")),
+            Err(e) => self.events.push(format!("couldn't save EEPROM: {}", e)),
+        }
+    }
+
+    fn load_auth(&mut self) {
+        if self.eeprom.get_u16(AUTH_EEPROM_ADDR) == AUTH_EEPROM_MAGIC {
+            self.auth_last_counter = self.eeprom.get_u32(AUTH_EEPROM_ADDR + 2);
+        }
+        // Forces a fresh reservation on the first command accepted
+        self.auth_counter_reserve = self.auth_last_counter;
+    }
+
+    // Unwraps an authenticated frame, returning the command inside if it's
+    // genuine and fresh
+    fn authenticate(&mut self, radio: &mut Nrf24, frame: &[u8]) -> Option<[u8; COMMAND_LEN]> {
+        let key = self.auth_key.as_ref()?;
+        match auth::open(key, &radio.address, frame, self.auth_last_counter) {
+            Ok((counter, packet)) => {
+                self.auth_last_counter = counter;
+                self.auth_accepted = true;
+                if counter >= self.auth_counter_reserve {
+                    self.auth_counter_reserve = counter.saturating_add(AUTH_RESERVE_STEP);
+                    let result = self
+                        .eeprom
+                        .put_u16(AUTH_EEPROM_ADDR, AUTH_EEPROM_MAGIC)
+                        .and(
+                            self.eeprom
+                                .put_u32(AUTH_EEPROM_ADDR + 2, self.auth_counter_reserve),
+                        );
+                    if let Err(e) = result {
+                        self.events.push(format!("couldn't save EEPROM: {}", e));
+                    }
+                }
+                let mut command = [0; COMMAND_LEN];
+                command[..packet.len()].copy_from_slice(packet);
+                Some(command)
+            }
+            // The same frame again, as sent on each of the controller's radios
+            Err(AuthError::Stale { counter }) if counter == self.auth_last_counter => None,
+            Err(AuthError::Stale { counter }) => {
+                self.events.push(format!(
+                    "stale counter {}, last accepted {}",
+                    counter, self.auth_last_counter
+                ));
+                self.ack_items = vec![Telemetry::AuthStale(self.auth_last_counter >> 8)];
+                self.stage_ack(radio);
+                None
+            }
+            Err(e) => {
+                self.events.push(format!("dropped command: {}", e));
+                None
+            }
+        }
+    }
+}
+
+// Checks a full set of params, including that servo limits stay in order
+fn params_valid(values: &[u16; Param::ALL.len()]) -> bool {
+    let in_range = Param::ALL.iter().zip(values).all(|(param, &value)| {
+        let (min, max) = param.range();
+        (min..=max).contains(&value)
+    });
+    in_range
+        && values[0] < values[1]
+        && values[1] < values[2]
+        && values[3] < values[4]
+        && values[4] < values[5]
+}
+
+// Drive values 0-200 as signed %, as `setLeftMotorSpeed()` decodes them
+fn motor_speed(value: u8) -> i8 {
+    if value >= 100 {
+        (value - 100).min(100) as i8
+    } else {
+        -((100 - value) as i8)
+    }
+}
+
+// As `setCameraAngle()`, including inverting, as both servos turn backwards
+fn servo_pwm(angle: u8, min: u16, mid: u16, max: u16) -> u16 {
+    let angle = 180 - u16::from(angle.min(180));
+    let map = |angle: u16, in_min: u16, in_max: u16, out_min: u16, out_max: u16| {
+        (angle - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+    };
+    match angle {
+        0 => min,
+        1..90 => map(angle, 0, 90, min, mid),
+        90 => mid,
+        91..180 => map(angle, 90, 180, mid, max),
+        _ => max,
+    }
+}
+
+// Volts or amps in the protocol's units of 1/1023
+fn to_raw(value: f32) -> u16 {
+    (value * 1023.0).round().clamp(0.0, f32::from(u16::MAX)) as u16
+}
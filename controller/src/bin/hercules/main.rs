@@ -0,0 +1,270 @@
+//! Virtual Hercules: the robot firmware's behaviour on the host, for driving
+//! with `controller --udp` without the hardware
+
+mod eeprom;
+mod firmware;
+mod nrf24;
+
+use std::env;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use skelebot_controller::auth;
+use skelebot_protocol::DataRate;
+
+use eeprom::Eeprom;
+use firmware::{Hercules, Outputs, BOOT_TIME};
+use nrf24::Nrf24;
+
+const USAGE: &str = "\
+Usage: hercules [OPTIONS]
+
+Emulates the robot firmware (herakles.ino) on a UDP socket, for the controller's
+--udp option, printing what the robot does.
+
+Options:
+  --listen <ADDR>     Address to listen on (default 127.0.0.1:9000)
+  --address <HEX>     Robot address as 10 hex digits (default E7E7E7E7E7)
+  --auth-key <FILE>   Only take commands authenticated with the key in FILE, as
+                      when the firmware's built with AUTH_KEY
+  --eeprom <FILE>     Keep committed params and the auth counter in FILE, so they
+                      survive restarts as on the robot
+  --battery <V>       Battery voltage to report (default 11.1); at 6V or under,
+                      the VTX starts off as if on external power
+  --fast-boot         Listen straight away, skipping the servo wake-up stretch
+  -h, --help          Show this help";
+
+// Sequence number, channel, data rate, then address, as sent by the controller
+const UDP_HEADER_LEN: usize = 8;
+// Longest datagram: the header and an nRF24 payload
+const MAX_DATAGRAM: usize = UDP_HEADER_LEN + 32;
+// How long to wait for a packet before running the firmware loop again
+const LOOP_WAIT: Duration = Duration::from_millis(1);
+// Output changes are printed at most this often
+const STATUS_INTERVAL: Duration = Duration::from_millis(250);
+
+struct Options {
+    listen: SocketAddr,
+    address: [u8; 5],
+    auth_key: Option<PathBuf>,
+    eeprom: Option<PathBuf>,
+    battery: f32,
+    fast_boot: bool,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let auth_key = options
+        .auth_key
+        .as_deref()
+        .map(auth::load_key)
+        .transpose()?;
+    let eeprom = Eeprom::open(options.eeprom.clone())?;
+    let socket = UdpSocket::bind(options.listen)?;
+    socket.set_read_timeout(Some(LOOP_WAIT))?;
+
+    let power_on = Instant::now();
+    let boot_time = if options.fast_boot {
+        Duration::ZERO
+    } else {
+        BOOT_TIME
+    };
+    let mut radio = Nrf24::new(options.address);
+    let mut eeprom = Some(eeprom);
+    let mut auth_key = auth_key;
+    let mut hercules: Option<Hercules> = None;
+    let mut shown: Option<(Outputs, u8, DataRate)> = None;
+    let mut next_status = power_on;
+    let mut stdout = io::stdout();
+    log(
+        &mut stdout,
+        power_on,
+        &format!(
+            "listening on {}, address {}{}",
+            socket.local_addr()?,
+            format_address(&options.address),
+            if auth_key.is_some() {
+                ", authenticated commands only"
+            } else {
+                ""
+            }
+        ),
+    )?;
+
+    let mut datagram = [0_u8; MAX_DATAGRAM];
+    loop {
+        match socket.recv_from(&mut datagram) {
+            Ok((len, from)) if len >= UDP_HEADER_LEN => {
+                if let Some(reply) = receive(&mut radio, &datagram[..len]) {
+                    socket.send_to(&reply, from)?;
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+
+        let now = Instant::now();
+        if hercules.is_none() && now.duration_since(power_on) >= boot_time {
+            let eeprom = eeprom.take().expect("only boots once");
+            let battery = options.battery;
+            hercules = Some(Hercules::boot(&mut radio, eeprom, auth_key.take(), battery));
+            log(&mut stdout, power_on, "booted, radio listening")?;
+        }
+        let Some(ref mut hercules) = hercules else {
+            continue;
+        };
+        let millis = now.duration_since(power_on).as_millis() as u64;
+        hercules.step(&mut radio, millis);
+        for event in hercules.take_events() {
+            log(&mut stdout, power_on, &event)?;
+        }
+        let state = (hercules.outputs(), radio.channel, radio.rate);
+        if shown != Some(state) && now >= next_status {
+            log(&mut stdout, power_on, &describe_outputs(state.0))?;
+            shown = Some(state);
+            next_status = now + STATUS_INTERVAL;
+        }
+    }
+}
+
+// Hands a datagram to the radio as if received on air, returning the reply
+// carrying its ack payload if it was acked
+fn receive(radio: &mut Nrf24, datagram: &[u8]) -> Option<Vec<u8>> {
+    let (header, payload) = datagram.split_at(UDP_HEADER_LEN);
+    let rate = DataRate::from_u8(header[2])?;
+    let address: [u8; 5] = header[3..8].try_into().unwrap();
+    let ack = radio.receive(header[1], rate, &address, payload)?;
+    let mut reply = vec![header[0]];
+    reply.extend_from_slice(&ack);
+    Some(reply)
+}
+
+fn describe_outputs(outputs: Outputs) -> String {
+    format!(
+        "left {:+}% right {:+}%, pan {}° ({}), tilt {}° ({}), VTX {}",
+        outputs.left,
+        outputs.right,
+        outputs.pan_angle,
+        outputs.pan_pwm,
+        outputs.tilt_angle,
+        outputs.tilt_pwm,
+        if outputs.vtx_on { "on" } else { "off" }
+    )
+}
+
+// Prints with the time since power-on, as the firmware's millis() would give
+fn log(stdout: &mut io::Stdout, power_on: Instant, message: &str) -> io::Result<()> {
+    let elapsed = power_on.elapsed().as_secs_f32();
+    writeln!(stdout, "[{:8.3}] {}", elapsed, message)
+}
+
+fn format_address(address: &[u8; 5]) -> String {
+    address.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        listen: SocketAddr::from(([127, 0, 0, 1], 9000)),
+        address: [0xE7; 5],
+        auth_key: None,
+        eeprom: None,
+        battery: 11.1,
+        fast_boot: false,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires an address", arg))?;
+                options.listen = value
+                    .parse()
+                    .map_err(|e| format!("invalid address \"{}\" for {}: {}", value, arg, e))?;
+            }
+            "--address" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires an address", arg))?;
+                options.address = parse_address(&value)
+                    .ok_or_else(|| format!("invalid address \"{}\" for {}", value, arg))?;
+            }
+            "--auth-key" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a file", arg))?;
+                options.auth_key = Some(PathBuf::from(path));
+            }
+            "--eeprom" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a file", arg))?;
+                options.eeprom = Some(PathBuf::from(path));
+            }
+            "--battery" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a voltage", arg))?;
+                options.battery = match value.parse::<f32>() {
+                    Ok(volts) if (0.0..=60.0).contains(&volts) => volts,
+                    _ => {
+                        return Err(format!(
+                            "invalid voltage \"{}\" for {}: must be 0-60",
+                            value, arg
+                        ));
+                    }
+                };
+            }
+            "--fast-boot" => {
+                options.fast_boot = true;
+            }
+            "-h" | "--help" => {
+                return Ok(None);
+            }
+            _ => {
+                return Err(format!("unknown argument \"{}\"\n\n{}", arg, USAGE));
+            }
+        }
+    }
+    Ok(Some(options))
+}
+
+fn parse_address(value: &str) -> Option<[u8; 5]> {
+    if value.len() != 10 || !value.is_ascii() {
+        return None;
+    }
+    let mut address = [0_u8; 5];
+    for (i, byte) in address.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(address)
+}
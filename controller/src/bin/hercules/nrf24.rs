@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+
+use skelebot_protocol::{DataRate, MAX_CHANNEL};
+
+// The nRF24's RX and TX FIFOs each hold this many payloads
+const FIFO_DEPTH: usize = 3;
+
+/// The robot's nRF24 as the firmware sees it, in receive mode with ack payloads
+///
+/// Packets only arrive on the current channel, rate and address. The chip
+/// acks by itself as they arrive, taking the oldest staged ack payload with
+/// it, so anything the firmware stages in answer to a packet goes out with
+/// the ack for a later one. A full RX FIFO means no ack at all.
+pub struct Nrf24 {
+    pub channel: u8,
+    pub rate: DataRate,
+    pub address: [u8; 5],
+    listening: bool,
+    rx_fifo: VecDeque<Vec<u8>>,
+    ack_fifo: VecDeque<Vec<u8>>,
+}
+
+impl Nrf24 {
+    pub fn new(address: [u8; 5]) -> Self {
+        Self {
+            channel: 76,
+            rate: DataRate::Kbps250,
+            address,
+            listening: false,
+            rx_fifo: VecDeque::new(),
+            ack_fifo: VecDeque::new(),
+        }
+    }
+
+    /// Starts receiving, as `startRadio()` does at the end of setup
+    pub fn start_listening(&mut self) {
+        self.listening = true;
+    }
+
+    /// A packet on air, returning the ack payload if the chip acked it
+    pub fn receive(
+        &mut self,
+        channel: u8,
+        rate: DataRate,
+        address: &[u8; 5],
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        if !self.listening
+            || channel != self.channel
+            || rate != self.rate
+            || *address != self.address
+            || channel > MAX_CHANNEL
+        {
+            return None;
+        }
+        if self.rx_fifo.len() >= FIFO_DEPTH {
+            return None;
+        }
+        self.rx_fifo.push_back(payload.to_vec());
+        Some(self.ack_fifo.pop_front().unwrap_or_default())
+    }
+
+    pub fn available(&self) -> bool {
+        !self.rx_fifo.is_empty()
+    }
+
+    pub fn read(&mut self) -> Option<Vec<u8>> {
+        self.rx_fifo.pop_front()
+    }
+
+    /// Stages an ack payload, returning false if the TX FIFO is full
+    pub fn write_ack_payload(&mut self, payload: &[u8]) -> bool {
+        if self.ack_fifo.len() >= FIFO_DEPTH {
+            return false;
+        }
+        self.ack_fifo.push_back(payload.to_vec());
+        true
+    }
+
+    pub fn flush_tx(&mut self) {
+        self.ack_fifo.clear();
+    }
+}
@@ -0,0 +1,430 @@
+//! Channel selection: the quiet channels, scanning them for the least busy,
+//! and migrating the robot between them
+
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::Command;
+
+use crate::link::LinkStats;
+use crate::rf::Tuning;
+
+/// Channel the robot firmware listens on at boot
+pub const ROBOT_BOOT_CHANNEL: u8 = 76;
+
+/// Channels clear of the common 2.4GHz WiFi channels (1, 6, 11), as per radio.md
+pub const QUIET_CHANNELS: [u8; 28] = [
+    0, 1, // 2400-2402MHz
+    21, 22, 23, 24, 25, 26, 27, // 2421-2427MHz
+    45, 46, 47, 48, 49, 50, 51, 52, 53, // 2445-2453MHz
+    71, 72, 73, 74, 75, 76, 77, 78, 79, 80, // 2471-2480MHz
+];
+
+/// The quiet channels split by WiFi gap, so migration can move well away from interference
+pub(crate) const QUIET_GROUPS: [&[u8]; 4] = [
+    &[0, 1],
+    &[21, 22, 23, 24, 25, 26, 27],
+    &[45, 46, 47, 48, 49, 50, 51, 52, 53],
+    &[71, 72, 73, 74, 75, 76, 77, 78, 79, 80],
+];
+
+// Attempts at sending the change channel command before retuning regardless
+const ANNOUNCE_ATTEMPTS: u32 = 5;
+// Time to wait for an ack on the new channel before rolling back
+const CONFIRM_TIMEOUT: Duration = Duration::from_millis(500);
+// Time to wait for an ack back on the old channel before giving up; longer
+// than the robot's own revert timeout, so it has a chance to come back too
+const ROLLBACK_TIMEOUT: Duration = Duration::from_millis(2_000);
+
+// Sustained loss over this percentage triggers automatic migration
+const MIGRATE_LOSS_PERCENT: f32 = 30.0;
+// How long loss must stay over the threshold before migrating
+const MIGRATE_AFTER: Duration = Duration::from_secs(2);
+// Minimum time between migrations, so stats can settle on the new channel
+const MIGRATE_HOLDOFF: Duration = Duration::from_secs(5);
+// How long to avoid a channel after migrating away from it
+const BAD_CHANNEL_EXPIRY: Duration = Duration::from_secs(60);
+// Too few packets in the window to judge loss, eg while the radio is missing;
+// an idle link only carries keepalives, around 10 per second
+const MIGRATE_MIN_PACKETS: u32 = 8;
+
+/// Current and (if switching) target radio settings, as reported to the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelStatus {
+    /// Settings the robot is on
+    pub tuning: Tuning,
+    /// Settings it's being moved to
+    pub target: Option<Tuning>,
+    /// Whether it moves to a quieter channel when the link degrades
+    pub auto_migrate: bool,
+    /// Whether it steps the data rate and power with the link
+    pub auto_rate: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SwitchPhase {
+    // Telling the robot to switch, on the old settings
+    Announce,
+    // Retuned, waiting for any ack on the new settings
+    Confirm,
+    // Gave up on the new settings, waiting for any ack on the old ones
+    Rollback,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SwitchOutcome {
+    InProgress,
+    /// Robot acked on the new settings
+    Switched(Tuning),
+    /// Robot never showed up on the new settings, but did on the old ones
+    RolledBack(Tuning),
+    /// Robot showed up on neither; left tuned to the old settings
+    Lost(Tuning),
+}
+
+/// State machine for moving both the controller and robot to a new channel,
+/// data rate or PA level
+///
+/// Each radio loop tick, the caller retunes to `tuning()`, sends
+/// `next_command()` if any (otherwise the normal state update), then feeds
+/// the ack result to `record()`.
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelSwitch {
+    from: Tuning,
+    to: Tuning,
+    phase: SwitchPhase,
+    phase_started: Instant,
+    announce_attempts: u32,
+}
+
+impl ChannelSwitch {
+    pub fn new(from: Tuning, to: Tuning, now: Instant) -> Self {
+        Self {
+            from,
+            to,
+            phase: SwitchPhase::Announce,
+            phase_started: now,
+            announce_attempts: 0,
+        }
+    }
+
+    pub fn from(&self) -> Tuning {
+        self.from
+    }
+
+    pub fn to(&self) -> Tuning {
+        self.to
+    }
+
+    /// Settings the transport should be tuned to for this phase
+    pub fn tuning(&self) -> Tuning {
+        match self.phase {
+            SwitchPhase::Announce | SwitchPhase::Rollback => self.from,
+            SwitchPhase::Confirm => self.to,
+        }
+    }
+
+    /// Command to send instead of the usual state update, if any
+    pub fn next_command(&self) -> Option<Command> {
+        match self.phase {
+            // The plain channel change is understood by older firmware too
+            SwitchPhase::Announce if self.to == self.from.with_channel(self.to.channel) => {
+                Some(Command::ChangeChannel(self.to.channel))
+            }
+            SwitchPhase::Announce => Some(Command::SetRadio {
+                channel: self.to.channel,
+                rate: self.to.rate,
+                power: self.to.power,
+            }),
+            SwitchPhase::Confirm | SwitchPhase::Rollback => None,
+        }
+    }
+
+    /// Records whether this tick's packet was acked, advancing phase as required
+    pub fn record(&mut self, acked: bool, now: Instant) -> SwitchOutcome {
+        let elapsed = now.saturating_duration_since(self.phase_started);
+        match self.phase {
+            SwitchPhase::Announce => {
+                self.announce_attempts += 1;
+                // Even if the command was never acked, the robot may have
+                // received it and the ack got lost, so check the new settings
+                if acked || self.announce_attempts >= ANNOUNCE_ATTEMPTS {
+                    self.set_phase(SwitchPhase::Confirm, now);
+                }
+                SwitchOutcome::InProgress
+            }
+            SwitchPhase::Confirm => {
+                if acked {
+                    SwitchOutcome::Switched(self.to)
+                } else {
+                    if elapsed >= CONFIRM_TIMEOUT {
+                        self.set_phase(SwitchPhase::Rollback, now);
+                    }
+                    SwitchOutcome::InProgress
+                }
+            }
+            SwitchPhase::Rollback => {
+                if acked {
+                    SwitchOutcome::RolledBack(self.from)
+                } else if elapsed >= ROLLBACK_TIMEOUT {
+                    SwitchOutcome::Lost(self.from)
+                } else {
+                    SwitchOutcome::InProgress
+                }
+            }
+        }
+    }
+
+    fn set_phase(&mut self, phase: SwitchPhase, now: Instant) {
+        self.phase = phase;
+        self.phase_started = now;
+    }
+}
+
+/// Next quiet channel after (or before, if `forward` is false) the given one
+pub fn step_quiet_channel(channel: u8, forward: bool) -> u8 {
+    let len = QUIET_CHANNELS.len();
+    let idx = match QUIET_CHANNELS.binary_search(&channel) {
+        Ok(idx) => {
+            if forward {
+                (idx + 1) % len
+            } else {
+                (idx + len - 1) % len
+            }
+        }
+        // Not a quiet channel, so snap to the nearest in the given direction
+        Err(idx) => {
+            if forward {
+                idx % len
+            } else {
+                (idx + len - 1) % len
+            }
+        }
+    };
+    QUIET_CHANNELS[idx]
+}
+
+/// Decides when sustained packet loss warrants moving to another channel, and where to
+pub(crate) struct AutoMigrate {
+    pub enabled: bool,
+    degraded_since: Option<Instant>,
+    last_migration: Option<Instant>,
+    bad_channels: Vec<(u8, Instant)>,
+}
+
+impl AutoMigrate {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            degraded_since: None,
+            last_migration: None,
+            bad_channels: Vec::new(),
+        }
+    }
+
+    /// Checks the latest link stats, returning a channel to migrate to if the
+    /// current one has been bad for long enough
+    pub fn check(&mut self, stats: &LinkStats, channel: u8, now: Instant) -> Option<u8> {
+        self.bad_channels
+            .retain(|&(_, marked)| now.saturating_duration_since(marked) < BAD_CHANNEL_EXPIRY);
+
+        // Only migrate away from interference: with total loss the robot can't
+        // hear the change command, or it isn't there at all
+        let degraded = stats.packets >= MIGRATE_MIN_PACKETS
+            && stats.loss_percent >= MIGRATE_LOSS_PERCENT
+            && stats.loss_percent < 100.0;
+        if !self.enabled || !degraded {
+            self.degraded_since = None;
+            return None;
+        }
+        let degraded_since = *self.degraded_since.get_or_insert(now);
+        if now.saturating_duration_since(degraded_since) < MIGRATE_AFTER {
+            return None;
+        }
+        if let Some(last) = self.last_migration {
+            if now.saturating_duration_since(last) < MIGRATE_HOLDOFF {
+                return None;
+            }
+        }
+
+        let target = self.pick_channel(channel)?;
+        self.bad_channels.push((channel, now));
+        self.degraded_since = None;
+        self.last_migration = Some(now);
+        Some(target)
+    }
+
+    // Prefers the middle of the next quiet group along, skipping recently bad channels
+    fn pick_channel(&self, channel: u8) -> Option<u8> {
+        let current_group = QUIET_GROUPS
+            .iter()
+            .position(|group| group.contains(&channel))
+            .unwrap_or(QUIET_GROUPS.len() - 1);
+        let is_bad = |ch: &u8| *ch == channel || self.bad_channels.iter().any(|(bad, _)| bad == ch);
+
+        for offset in 1..=QUIET_GROUPS.len() {
+            let group = QUIET_GROUPS[(current_group + offset) % QUIET_GROUPS.len()];
+            let mid = group.len() / 2;
+            let mut candidates: Vec<u8> = group.to_vec();
+            candidates.sort_by_key(|&ch| (ch as i16 - group[mid] as i16).abs());
+            if let Some(ch) = candidates.into_iter().find(|ch| !is_bad(ch)) {
+                return Some(ch);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skelebot_protocol::DataRate;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn channel_only_changes_announce_then_confirm_on_the_new_channel() {
+        let start = Instant::now();
+        let from = Tuning::boot();
+        let to = from.with_channel(24);
+        let mut switch = ChannelSwitch::new(from, to, start);
+        assert_eq!(switch.tuning(), from);
+        assert_eq!(switch.next_command(), Some(Command::ChangeChannel(24)));
+
+        assert_eq!(switch.record(true, start), SwitchOutcome::InProgress);
+        assert_eq!(switch.tuning(), to);
+        assert_eq!(switch.next_command(), None);
+        assert_eq!(
+            switch.record(true, start + TICK),
+            SwitchOutcome::Switched(to)
+        );
+    }
+
+    #[test]
+    fn rate_changes_announce_the_full_settings() {
+        let from = Tuning::boot();
+        let to = Tuning {
+            rate: DataRate::Mbps2,
+            ..from
+        };
+        let switch = ChannelSwitch::new(from, to, Instant::now());
+        assert_eq!(
+            switch.next_command(),
+            Some(Command::SetRadio {
+                channel: to.channel,
+                rate: to.rate,
+                power: to.power,
+            })
+        );
+    }
+
+    #[test]
+    fn unacked_announce_retunes_after_its_attempts() {
+        let start = Instant::now();
+        let from = Tuning::boot();
+        let mut switch = ChannelSwitch::new(from, from.with_channel(24), start);
+        for _ in 1..ANNOUNCE_ATTEMPTS {
+            switch.record(false, start);
+            assert_eq!(switch.tuning(), from);
+        }
+        switch.record(false, start);
+        assert_eq!(switch.tuning(), from.with_channel(24));
+    }
+
+    #[test]
+    fn silence_on_the_new_channel_rolls_back_or_gives_up() {
+        let start = Instant::now();
+        let from = Tuning::boot();
+        let mut switch = ChannelSwitch::new(from, from.with_channel(24), start);
+        switch.record(true, start);
+        assert_eq!(
+            switch.record(false, start + TICK),
+            SwitchOutcome::InProgress
+        );
+        let rollback = start + CONFIRM_TIMEOUT;
+        switch.record(false, rollback);
+        assert_eq!(switch.tuning(), from);
+        assert_eq!(switch.next_command(), None);
+        let mut lost = switch.clone();
+        assert_eq!(
+            switch.record(true, rollback + TICK),
+            SwitchOutcome::RolledBack(from)
+        );
+        assert_eq!(
+            lost.record(false, rollback + ROLLBACK_TIMEOUT),
+            SwitchOutcome::Lost(from)
+        );
+    }
+
+    fn lossy(loss_percent: f32) -> LinkStats {
+        LinkStats {
+            packets: 20,
+            loss_percent,
+            ..LinkStats::default()
+        }
+    }
+
+    #[test]
+    fn migrates_after_sustained_partial_loss() {
+        let start = Instant::now();
+        let mut migrate = AutoMigrate::new(true);
+        assert_eq!(migrate.check(&lossy(50.0), 76, start), None);
+        let later = start + MIGRATE_AFTER;
+        // The middle of the next group along, wrapping around
+        assert_eq!(migrate.check(&lossy(50.0), 76, later), Some(1));
+        assert_eq!(migrate.check(&lossy(50.0), 1, later + MIGRATE_AFTER), None);
+    }
+
+    #[test]
+    fn ignores_light_total_or_unmeasured_loss() {
+        let start = Instant::now();
+        let later = start + MIGRATE_AFTER;
+        let too_few = LinkStats {
+            packets: MIGRATE_MIN_PACKETS - 1,
+            ..lossy(50.0)
+        };
+        for stats in [lossy(10.0), lossy(100.0), too_few] {
+            let mut migrate = AutoMigrate::new(true);
+            migrate.check(&stats, 76, start);
+            assert_eq!(migrate.check(&stats, 76, later), None, "{:?}", stats);
+        }
+        let mut disabled = AutoMigrate::new(false);
+        disabled.check(&lossy(50.0), 76, start);
+        assert_eq!(disabled.check(&lossy(50.0), 76, later), None);
+    }
+
+    #[test]
+    fn recovery_restarts_the_wait() {
+        let start = Instant::now();
+        let mut migrate = AutoMigrate::new(true);
+        migrate.check(&lossy(50.0), 76, start);
+        migrate.check(&lossy(0.0), 76, start + TICK);
+        assert_eq!(migrate.check(&lossy(50.0), 76, start + MIGRATE_AFTER), None);
+    }
+
+    #[test]
+    fn avoids_channels_it_left_until_they_expire() {
+        let start = Instant::now();
+        let mut migrate = AutoMigrate::new(true);
+        let mut migrate_from = |channel, since: Instant| {
+            migrate.check(&lossy(50.0), channel, since);
+            migrate.check(&lossy(50.0), channel, since + MIGRATE_AFTER)
+        };
+        assert_eq!(migrate_from(49, start), Some(76));
+
+        // Back in the next group down, its middle channel is skipped for the
+        // nearest one, until it's no longer marked bad
+        let later = start + MIGRATE_HOLDOFF * 2;
+        assert_eq!(migrate_from(24, later), Some(48));
+        let expired = start + BAD_CHANNEL_EXPIRY;
+        assert_eq!(migrate_from(24, expired), Some(49));
+    }
+
+    #[test]
+    fn stepping_wraps_and_snaps_to_quiet_channels() {
+        assert_eq!(step_quiet_channel(76, true), 77);
+        assert_eq!(step_quiet_channel(80, true), 0);
+        assert_eq!(step_quiet_channel(0, false), 80);
+        assert_eq!(step_quiet_channel(30, true), 45);
+        assert_eq!(step_quiet_channel(30, false), 27);
+    }
+}
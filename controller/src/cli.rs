@@ -0,0 +1,459 @@
+//! Command-line parsing for the controller
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use skelebot_controller::config::RadioConfig;
+use skelebot_controller::radio::RadioOptions;
+use skelebot_controller::rf::{self, AutoRate, ROBOT_BOOT_POWER, ROBOT_BOOT_RATE};
+use skelebot_controller::transport::{RetrySettings, TransportKind, DEFAULT_ADDRESS};
+use skelebot_protocol::{DataRate, PaLevel, MAX_CHANNEL};
+
+const USAGE: &str = "\
+Usage: controller [MODE] [OPTIONS]
+
+Modes:
+  drive               Drive the robot with the joystick (default)
+  survey              Sweep all channels for 2.4GHz activity and recommend quiet ones
+  discover            Scan all channels for robots, printing any that respond
+  pair                Generate a new shared key into the --auth-key file, and
+                      print it for building into the robot firmware
+
+Options:
+  --channel <N>       Move the robot to channel N (0-125) once connected
+  --no-auto-channel   Don't migrate to another channel on sustained packet loss
+  --datarate <R>      Air data rate: 250k (default), 1m or 2m
+  --power <P>         Transmit power: min, low, high or max (default)
+  --auto-rate         Step down to a slower rate or higher power on sustained
+                      packet loss, and back up once the link recovers; needs
+                      a rate or power that leaves room below the defaults
+  --ard <US>          Crazyradio retransmit delay, 250-4000us in steps of 250
+                      (default: just long enough for the ack payload)
+  --arc <N>           Crazyradio retransmits before giving up, 0-15 (default 1)
+  --address <HEX>     Robot address as 10 hex digits (default E7E7E7E7E7); repeat
+                      for a fleet, selected with keys 1-9 when driving, or to
+                      scan for several when discovering
+  --auth-key <FILE>   Authenticate every command with the shared key in FILE,
+                      for robots built with it (see `pair`)
+  --radio-serial <S>  Use the Crazyradio with serial S, rather than the first found;
+                      repeat to send every packet on each of several radios
+  --udp <ADDR>        Drive a software robot at ADDR over UDP instead of the
+                      Crazyradio; repeat to send on several sockets, as above
+  --udp-bind <ADDR>   Local address to bind for --udp (default 127.0.0.1:0)
+  --sim               Drive a simulated robot around an on-screen arena instead,
+                      with no radio, eg to practise or to tune the drive mixing
+  --config <FILE>     Read settings from FILE rather than
+                      $XDG_CONFIG_HOME/skelebot/controller.toml; the radio
+                      options above win over its [radio] settings
+  -h, --help          Show this help";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Drive,
+    Survey,
+    Discover,
+    Pair,
+}
+
+pub struct Options {
+    pub mode: Mode,
+    /// Software robots to drive over UDP instead of the Crazyradio
+    pub udp_remotes: Vec<SocketAddr>,
+    /// Local address for the UDP sockets
+    pub udp_local: SocketAddr,
+    /// Crazyradios to use by serial, or the first found if none
+    pub radio_serials: Vec<String>,
+    pub channel: Option<u8>,
+    pub auto_channel: bool,
+    /// Radio settings, each None unless given on the command line or (when
+    /// driving) in the settings file
+    pub rate: Option<DataRate>,
+    pub power: Option<PaLevel>,
+    pub ard: Option<Duration>,
+    pub arc: Option<u8>,
+    pub auto_rate: bool,
+    pub addresses: Vec<[u8; 5]>,
+    pub auth_key: Option<PathBuf>,
+    /// Drive the simulator rather than a robot over the radio
+    pub sim: bool,
+    /// Settings file given on the command line, rather than the default
+    pub config: Option<PathBuf>,
+}
+
+impl Options {
+    /// Radios to use, always at least one; only driving uses more than the first
+    pub fn transports(&self) -> Vec<TransportKind> {
+        if !self.udp_remotes.is_empty() {
+            return self
+                .udp_remotes
+                .iter()
+                .map(|&remote| TransportKind::Udp {
+                    local: self.udp_local,
+                    remote,
+                })
+                .collect();
+        }
+        let retry = RetrySettings {
+            ard: self.ard,
+            arc: self.arc.unwrap_or(RetrySettings::default().arc),
+        };
+        if self.radio_serials.is_empty() {
+            return vec![TransportKind::Crazyradio {
+                serial: None,
+                retry,
+            }];
+        }
+        self.radio_serials
+            .iter()
+            .map(|serial| TransportKind::Crazyradio {
+                serial: Some(serial.clone()),
+                retry,
+            })
+            .collect()
+    }
+
+    /// Data rate to use, the robot's boot rate unless given
+    pub fn rate(&self) -> DataRate {
+        self.rate.unwrap_or(ROBOT_BOOT_RATE)
+    }
+
+    /// PA level to use, the robot's boot level unless given
+    pub fn power(&self) -> PaLevel {
+        self.power.unwrap_or(ROBOT_BOOT_POWER)
+    }
+
+    /// Fills in radio settings from `[radio]` where the command line didn't
+    /// give them, then checks they make sense together
+    pub fn apply_config(&mut self, radio: &RadioConfig) -> Result<(), String> {
+        if self.radio_serials.is_empty() {
+            self.radio_serials = radio.serials.clone();
+        }
+        self.channel = self.channel.or(radio.channel);
+        self.rate = self.rate.or(radio.data_rate());
+        self.power = self.power.or(radio.pa_level());
+        self.ard = self.ard.or(radio.ard());
+        self.arc = self.arc.or(radio.arc);
+        self.auto_rate |= radio.auto_rate;
+        if self.auto_rate && !AutoRate::new(true, self.rate(), self.power()).has_steps() {
+            return Err(format!(
+                "auto-rate has nothing to step to from {} at {} power; start from a \
+                 faster data rate or lower power",
+                self.rate(),
+                self.power()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The parts of the options the radio thread needs
+    pub fn radio(&self) -> RadioOptions {
+        RadioOptions {
+            transports: self.transports(),
+            addresses: self.addresses.clone(),
+            auto_channel: self.auto_channel,
+            auto_rate: self.auto_rate,
+            rate: self.rate(),
+            power: self.power(),
+        }
+    }
+}
+
+/// Result of parsing args: either options to run with, or text to print and exit
+pub enum ParsedArgs {
+    Run(Options),
+    Help(String),
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<ParsedArgs, String> {
+    let mut udp_remotes: Vec<SocketAddr> = Vec::new();
+    let mut udp_local: SocketAddr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut channel: Option<u8> = None;
+    let mut auto_channel = true;
+    let mut rate: Option<DataRate> = None;
+    let mut power: Option<PaLevel> = None;
+    let mut auto_rate = false;
+    let mut ard: Option<Duration> = None;
+    let mut arc: Option<u8> = None;
+    let mut mode = Mode::Drive;
+    let mut addresses: Vec<[u8; 5]> = Vec::new();
+    let mut radio_serials: Vec<String> = Vec::new();
+    let mut auth_key: Option<PathBuf> = None;
+    let mut sim = false;
+    let mut config: Option<PathBuf> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "drive" => {
+                mode = Mode::Drive;
+            }
+            "survey" => {
+                mode = Mode::Survey;
+            }
+            "discover" => {
+                mode = Mode::Discover;
+            }
+            "pair" => {
+                mode = Mode::Pair;
+            }
+            "--channel" => {
+                channel = Some(parse_channel(&arg, args.next())?);
+            }
+            "--no-auto-channel" => {
+                auto_channel = false;
+            }
+            "--datarate" => {
+                rate = Some(parse_rate(&arg, args.next())?);
+            }
+            "--power" => {
+                power = Some(parse_power(&arg, args.next())?);
+            }
+            "--auto-rate" => {
+                auto_rate = true;
+            }
+            "--ard" => {
+                ard = Some(parse_ard(&arg, args.next())?);
+            }
+            "--arc" => {
+                arc = Some(parse_arc(&arg, args.next())?);
+            }
+            "--address" => {
+                addresses.push(parse_address(&arg, args.next())?);
+            }
+            "--auth-key" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a file", arg))?;
+                auth_key = Some(PathBuf::from(path));
+            }
+            "--radio-serial" => {
+                let serial = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a serial", arg))?;
+                radio_serials.push(serial);
+            }
+            "--udp" => {
+                udp_remotes.push(parse_addr(&arg, args.next())?);
+            }
+            "--udp-bind" => {
+                udp_local = parse_addr(&arg, args.next())?;
+            }
+            "--sim" => {
+                sim = true;
+            }
+            "--config" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a file", arg))?;
+                config = Some(PathBuf::from(path));
+            }
+            "-h" | "--help" => {
+                return Ok(ParsedArgs::Help(USAGE.to_owned()));
+            }
+            _ => {
+                return Err(format!("unknown argument \"{}\"\n\n{}", arg, USAGE));
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        addresses.push(DEFAULT_ADDRESS);
+    }
+    if mode == Mode::Pair && auth_key.is_none() {
+        return Err(format!("pair requires --auth-key <FILE>\n\n{}", USAGE));
+    }
+
+    Ok(ParsedArgs::Run(Options {
+        mode,
+        udp_remotes,
+        udp_local,
+        radio_serials,
+        channel,
+        auto_channel,
+        rate,
+        power,
+        ard,
+        arc,
+        auto_rate,
+        addresses,
+        auth_key,
+        sim,
+        config,
+    }))
+}
+
+fn parse_channel(arg: &str, value: Option<String>) -> Result<u8, String> {
+    let value = value.ok_or_else(|| format!("{} requires a channel number", arg))?;
+    match value.parse::<u8>() {
+        Ok(channel) if channel <= MAX_CHANNEL => Ok(channel),
+        _ => Err(format!(
+            "invalid channel \"{}\" for {}: must be 0-{}",
+            value, arg, MAX_CHANNEL
+        )),
+    }
+}
+
+fn parse_rate(arg: &str, value: Option<String>) -> Result<DataRate, String> {
+    let value = value.ok_or_else(|| format!("{} requires a data rate", arg))?;
+    rf::parse_rate(&value).ok_or_else(|| {
+        format!(
+            "invalid data rate \"{}\" for {}: must be 250k, 1m or 2m",
+            value, arg
+        )
+    })
+}
+
+fn parse_power(arg: &str, value: Option<String>) -> Result<PaLevel, String> {
+    let value = value.ok_or_else(|| format!("{} requires a power level", arg))?;
+    rf::parse_power(&value).ok_or_else(|| {
+        format!(
+            "invalid power level \"{}\" for {}: must be min, low, high or max",
+            value, arg
+        )
+    })
+}
+
+fn parse_ard(arg: &str, value: Option<String>) -> Result<Duration, String> {
+    let value = value.ok_or_else(|| format!("{} requires a delay", arg))?;
+    match value.parse::<u64>() {
+        Ok(us) => RetrySettings::ard_from_micros(us),
+        Err(_) => None,
+    }
+    .ok_or_else(|| {
+        format!(
+            "invalid delay \"{}\" for {}: must be 250-4000 in steps of 250",
+            value, arg
+        )
+    })
+}
+
+fn parse_arc(arg: &str, value: Option<String>) -> Result<u8, String> {
+    let value = value.ok_or_else(|| format!("{} requires a count", arg))?;
+    match value.parse::<u8>() {
+        Ok(arc) if arc <= RetrySettings::MAX_ARC => Ok(arc),
+        _ => Err(format!(
+            "invalid count \"{}\" for {}: must be 0-{}",
+            value,
+            arg,
+            RetrySettings::MAX_ARC
+        )),
+    }
+}
+
+fn parse_addr(arg: &str, value: Option<String>) -> Result<SocketAddr, String> {
+    let value = value.ok_or_else(|| format!("{} requires an address", arg))?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid address \"{}\" for {}: {}", value, arg, e))
+}
+
+fn parse_address(arg: &str, value: Option<String>) -> Result<[u8; 5], String> {
+    let value = value.ok_or_else(|| format!("{} requires an address", arg))?;
+    let invalid = || {
+        format!(
+            "invalid address \"{}\" for {}: must be 10 hex digits",
+            value, arg
+        )
+    };
+    if value.len() != 10 || !value.is_ascii() {
+        return Err(invalid());
+    }
+    let mut address = [0_u8; 5];
+    for (i, byte) in address.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        match parse_args(args.iter().map(|&arg| arg.to_owned())) {
+            Ok(ParsedArgs::Run(options)) => options,
+            Ok(ParsedArgs::Help(_)) => panic!("help for {:?}", args),
+            Err(e) => panic!("{:?}: {}", args, e),
+        }
+    }
+
+    fn radio_config() -> RadioConfig {
+        RadioConfig {
+            channel: Some(24),
+            rate: Some("1m".to_owned()),
+            power: Some("low".to_owned()),
+            ard_us: Some(1000),
+            arc: Some(5),
+            auto_rate: true,
+            serials: vec!["A".to_owned(), "B".to_owned()],
+        }
+    }
+
+    fn retry(options: &Options) -> RetrySettings {
+        match options.transports()[0] {
+            TransportKind::Crazyradio { retry, .. } => retry,
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn settings_fill_in_what_the_command_line_leaves_out() {
+        let mut options = parse(&[]);
+        options.apply_config(&radio_config()).unwrap();
+        assert_eq!(options.channel, Some(24));
+        assert_eq!(options.rate(), DataRate::Mbps1);
+        assert_eq!(options.power(), PaLevel::Low);
+        assert_eq!(retry(&options).ard, Some(Duration::from_micros(1000)));
+        assert_eq!(retry(&options).arc, 5);
+        assert!(options.auto_rate);
+        assert_eq!(options.transports().len(), 2);
+
+        let mut options = parse(&[]);
+        options.apply_config(&RadioConfig::default()).unwrap();
+        assert_eq!(options.rate(), ROBOT_BOOT_RATE);
+        assert_eq!(options.power(), ROBOT_BOOT_POWER);
+        assert_eq!(retry(&options).ard, None);
+        assert_eq!(retry(&options).arc, RetrySettings::default().arc);
+        assert!(!options.auto_rate);
+    }
+
+    #[test]
+    fn command_line_wins_over_settings() {
+        let mut options = parse(&[
+            "--channel",
+            "50",
+            "--datarate",
+            "2m",
+            "--power",
+            "high",
+            "--ard",
+            "250",
+            "--arc",
+            "0",
+            "--radio-serial",
+            "C",
+        ]);
+        options.apply_config(&radio_config()).unwrap();
+        assert_eq!(options.channel, Some(50));
+        assert_eq!(options.rate(), DataRate::Mbps2);
+        assert_eq!(options.power(), PaLevel::High);
+        assert_eq!(retry(&options).ard, Some(Duration::from_micros(250)));
+        assert_eq!(retry(&options).arc, 0);
+        assert_eq!(options.radio_serials, ["C"]);
+    }
+
+    #[test]
+    fn auto_rate_needs_room_to_step_down_from_the_merged_settings() {
+        let mut options = parse(&["--auto-rate"]);
+        assert!(options.apply_config(&RadioConfig::default()).is_err());
+
+        // Rate from the settings file leaves room
+        let mut options = parse(&["--auto-rate"]);
+        let radio = RadioConfig {
+            rate: Some("2m".to_owned()),
+            ..RadioConfig::default()
+        };
+        options.apply_config(&radio).unwrap();
+        assert!(options.auto_rate);
+    }
+}
@@ -0,0 +1,443 @@
+//! Settings operators and robots differ on, from a TOML file
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use skelebot_protocol::{DataRate, PaLevel, MAX_CHANNEL};
+
+use crate::rf;
+use crate::transport::RetrySettings;
+
+// Under the XDG config dir, or ~/.config if that isn't set
+const CONFIG_FILE: &str = "skelebot/controller.toml";
+
+/// Controller settings, as loaded from `controller.toml`
+///
+/// Anything left out of the file keeps its default, so an empty file is the
+/// same as none.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `[radio]`
+    pub radio: RadioConfig,
+    /// `[drive]`
+    pub drive: DriveConfig,
+    /// `[input]`
+    pub input: InputConfig,
+    /// `[ui]`
+    pub ui: UiConfig,
+}
+
+/// Radio settings applied when driving; any given on the command line win
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RadioConfig {
+    /// Channel to move the robot to once connected, unless `--channel` is given
+    pub channel: Option<u8>,
+    /// Air data rate to move the robot to: 250k, 1m or 2m
+    pub rate: Option<String>,
+    /// Transmit power to move the robot to: min, low, high or max
+    pub power: Option<String>,
+    /// Crazyradio retransmit delay, in µs
+    pub ard_us: Option<u64>,
+    /// Crazyradio retransmits before giving up
+    pub arc: Option<u8>,
+    /// Step down to a slower rate or higher power on sustained packet loss
+    pub auto_rate: bool,
+    /// Crazyradios to send on by serial, unless `--radio-serial` is given;
+    /// empty for the first found
+    pub serials: Vec<String>,
+}
+
+impl RadioConfig {
+    /// `rate` as a data rate, if set
+    pub fn data_rate(&self) -> Option<DataRate> {
+        self.rate.as_deref().and_then(rf::parse_rate)
+    }
+
+    /// `power` as a PA level, if set
+    pub fn pa_level(&self) -> Option<PaLevel> {
+        self.power.as_deref().and_then(rf::parse_power)
+    }
+
+    /// `ard_us` as a duration, if set
+    pub fn ard(&self) -> Option<Duration> {
+        self.ard_us.and_then(RetrySettings::ard_from_micros)
+    }
+}
+
+/// How the control state turns into drive and camera commands
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriveConfig {
+    /// Top wheel speed in slow mode, in %
+    pub slow_speed: u8,
+    /// Top wheel speed in fast mode, in %
+    pub fast_speed: u8,
+    /// Camera rate with the view stick all the way over, in °/ms
+    pub camera_deg_per_ms: f64,
+}
+
+impl Default for DriveConfig {
+    fn default() -> Self {
+        Self {
+            slow_speed: 50,
+            fast_speed: 100,
+            // 180° in 300ms
+            camera_deg_per_ms: 0.6,
+        }
+    }
+}
+
+/// Where the joystick is, and how its axes are read
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    /// Glob for the joystick's evdev device; the first with X and Y axes is used
+    pub joystick: String,
+    /// Stick travel either side of centre read as centre, in raw axis units
+    pub dead_zone: u16,
+    /// Longest wait for joystick events before checking for exit, in ms
+    pub poll_wait_ms: u64,
+    /// Pushing the view stick forward tilts the camera down, as in a flight sim
+    pub invert_view_y: bool,
+}
+
+impl InputConfig {
+    /// `poll_wait_ms` as a duration
+    pub fn poll_wait(&self) -> Duration {
+        Duration::from_millis(self.poll_wait_ms)
+    }
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            joystick: "/dev/input/by-id/*-event-joystick".to_owned(),
+            dead_zone: (i16::MAX / 32) as u16,
+            poll_wait_ms: 10,
+            invert_view_y: true,
+        }
+    }
+}
+
+/// How the terminal UI is laid out
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// Log lines shown under the gauges
+    pub message_lines: u16,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self { message_lines: 5 }
+    }
+}
+
+/// Why the settings file couldn't be loaded, with its path
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// The file isn't valid TOML, or has unknown or mistyped settings
+    Parse(PathBuf, toml::de::Error),
+    /// A setting is out of range
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "couldn't read config {}: {}", path.display(), e),
+            Self::Parse(path, e) => {
+                let e = e.to_string();
+                write!(f, "invalid config {}: {}", path.display(), e.trim_end())
+            }
+            Self::Invalid(path, msg) => write!(f, "invalid config {}: {}", path.display(), msg),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    /// Loads the given file, or the one in the XDG config dir if there is one,
+    /// otherwise the defaults
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        Self::load_or_default(path, default_path())
+    }
+
+    // As `load`, with the default file given, which needn't exist
+    fn load_or_default(path: Option<&Path>, default: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(ConfigError::Io(path, e)),
+        };
+        let config: Self =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+        config
+            .validate()
+            .map_err(|msg| ConfigError::Invalid(path, msg))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let radio = &self.radio;
+        if let Some(channel) = radio.channel {
+            if channel > MAX_CHANNEL {
+                return Err(format!(
+                    "radio.channel is {}, must be 0-{}",
+                    channel, MAX_CHANNEL
+                ));
+            }
+        }
+        if let Some(ref rate) = radio.rate {
+            if radio.data_rate().is_none() {
+                return Err(format!(
+                    "radio.rate is \"{}\", must be 250k, 1m or 2m",
+                    rate
+                ));
+            }
+        }
+        if let Some(ref power) = radio.power {
+            if radio.pa_level().is_none() {
+                return Err(format!(
+                    "radio.power is \"{}\", must be min, low, high or max",
+                    power
+                ));
+            }
+        }
+        if let Some(ard_us) = radio.ard_us {
+            if radio.ard().is_none() {
+                return Err(format!(
+                    "radio.ard_us is {}, must be 250-4000 in steps of 250",
+                    ard_us
+                ));
+            }
+        }
+        for (i, serial) in radio.serials.iter().enumerate() {
+            if serial.is_empty() {
+                return Err("radio.serials has an empty serial".to_owned());
+            }
+            if radio.serials[..i].contains(serial) {
+                return Err(format!("radio.serials has {} more than once", serial));
+            }
+        }
+        if let Some(arc) = radio.arc {
+            if arc > RetrySettings::MAX_ARC {
+                return Err(format!(
+                    "radio.arc is {}, must be 0-{}",
+                    arc,
+                    RetrySettings::MAX_ARC
+                ));
+            }
+        }
+        let drive = &self.drive;
+        if !(1..=100).contains(&drive.slow_speed) {
+            return Err(format!(
+                "drive.slow_speed is {}, must be 1-100",
+                drive.slow_speed
+            ));
+        }
+        if !(drive.slow_speed..=100).contains(&drive.fast_speed) {
+            return Err(format!(
+                "drive.fast_speed is {}, must be from slow_speed ({}) to 100",
+                drive.fast_speed, drive.slow_speed
+            ));
+        }
+        if !(drive.camera_deg_per_ms > 0.0 && drive.camera_deg_per_ms <= 10.0) {
+            return Err(format!(
+                "drive.camera_deg_per_ms is {}, must be over 0 and at most 10",
+                drive.camera_deg_per_ms
+            ));
+        }
+        let input = &self.input;
+        if let Err(e) = glob::Pattern::new(&input.joystick) {
+            return Err(format!(
+                "input.joystick \"{}\" isn't a valid glob: {}",
+                input.joystick, e
+            ));
+        }
+        // Any more and the stick would only ever read as centre or full travel
+        if input.dead_zone >= i16::MAX as u16 / 2 {
+            return Err(format!(
+                "input.dead_zone is {}, must be under {}",
+                input.dead_zone,
+                i16::MAX / 2
+            ));
+        }
+        if !(1..=1000).contains(&input.poll_wait_ms) {
+            return Err(format!(
+                "input.poll_wait_ms is {}, must be 1-1000",
+                input.poll_wait_ms
+            ));
+        }
+        if !(1..=50).contains(&self.ui.message_lines) {
+            return Err(format!(
+                "ui.message_lines is {}, must be 1-50",
+                self.ui.message_lines
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join(CONFIG_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // A file of its own under the temp dir, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(text: &str) -> Self {
+            // Tests run in parallel, so each needs its own name
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "skelebot-{}-{}.toml",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = env::temp_dir().join(name);
+            fs::write(&path, text).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        let file = TempFile::new(text);
+        Config::load(Some(&file.0))
+    }
+
+    #[test]
+    fn example_file_is_the_defaults() {
+        let example = include_str!("../controller.example.toml");
+        assert_eq!(parse(example).unwrap(), Config::default());
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn settings_override_the_defaults() {
+        let config = parse("[drive]\nslow_speed = 30\n[radio]\nchannel = 24\n").unwrap();
+        assert_eq!(config.drive.slow_speed, 30);
+        assert_eq!(config.drive.fast_speed, DriveConfig::default().fast_speed);
+        assert_eq!(config.radio.channel, Some(24));
+        assert_eq!(config.radio.data_rate(), None);
+
+        let config = parse(
+            "[radio]\nrate = \"1M\"\npower = \"low\"\nard_us = 500\narc = 3\nauto_rate = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.radio.data_rate(), Some(DataRate::Mbps1));
+        assert_eq!(config.radio.pa_level(), Some(PaLevel::Low));
+        assert_eq!(config.radio.ard(), Some(Duration::from_micros(500)));
+        assert_eq!(config.radio.arc, Some(3));
+        assert!(config.radio.auto_rate);
+
+        let config = parse("[radio]\nserials = [\"E7E7E7E701\", \"E7E7E7E702\"]\n").unwrap();
+        assert_eq!(config.radio.serials, ["E7E7E7E701", "E7E7E7E702"]);
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        for text in [
+            "[drive]\nslow_sped = 30\n",
+            "[camera]\n",
+            "[ui]\nmessage_lines = \"5\"\n",
+        ] {
+            assert!(
+                matches!(parse(text), Err(ConfigError::Parse(..))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        for text in [
+            "[radio]\nchannel = 126\n",
+            "[radio]\nrate = \"500k\"\n",
+            "[radio]\npower = \"full\"\n",
+            "[radio]\nard_us = 300\n",
+            "[radio]\nard_us = 4250\n",
+            "[radio]\narc = 16\n",
+            "[radio]\nserials = [\"A\", \"B\", \"A\"]\n",
+            "[radio]\nserials = [\"\"]\n",
+            "[drive]\nslow_speed = 0\n",
+            "[drive]\nslow_speed = 101\n",
+            "[drive]\nslow_speed = 60\nfast_speed = 50\n",
+            "[drive]\ncamera_deg_per_ms = 0.0\n",
+            "[input]\ndead_zone = 16383\n",
+            "[input]\njoystick = \"[\"\n",
+            "[input]\npoll_wait_ms = 0\n",
+            "[ui]\nmessage_lines = 51\n",
+        ] {
+            assert!(
+                matches!(parse(text), Err(ConfigError::Invalid(..))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn only_a_given_file_has_to_exist() {
+        let missing = env::temp_dir().join(format!("skelebot-{}-missing.toml", std::process::id()));
+        assert!(matches!(
+            Config::load_or_default(Some(&missing), None),
+            Err(ConfigError::Io(..))
+        ));
+        assert_eq!(
+            Config::load_or_default(None, Some(missing)).unwrap(),
+            Config::default()
+        );
+        assert_eq!(
+            Config::load_or_default(None, None).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn given_file_wins_over_default() {
+        let given = TempFile::new("[ui]\nmessage_lines = 3\n");
+        let default = TempFile::new("[ui]\nmessage_lines = 8\n");
+        let config = Config::load_or_default(None, Some(default.0.clone())).unwrap();
+        assert_eq!(config.ui.message_lines, 8);
+        let config = Config::load_or_default(Some(&given.0), Some(default.0.clone())).unwrap();
+        assert_eq!(config.ui.message_lines, 3);
+    }
+}
@@ -0,0 +1,203 @@
+//! Sweeping the channels and rates for robots, for `controller discover`
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use skelebot_controller::actions::{BatteryCurrent, BatteryVoltage};
+use skelebot_controller::rf::Tuning;
+use skelebot_controller::telemetry::format_uptime;
+use skelebot_controller::transport::{
+    format_address, open_transport, Transport, TransportError, TransportKind,
+};
+use skelebot_protocol::{
+    Command, DataRate, PaLevel, Telemetry, TelemetryItems, MAX_CHANNEL, MAX_ITEM_LEN,
+    MAX_TELEMETRY_LEN, UPTIME_TICK,
+};
+
+// No-ops sent per channel and address before moving on
+const PROBES_PER_TARGET: u32 = 3;
+// Time spent on a channel and address after an ack, to collect telemetry; the
+// firmware stages one telemetry item every 50ms, alternating between types
+const LISTEN_TIME: Duration = Duration::from_millis(250);
+
+/// A robot which acked a no-op on the given channel and address
+#[derive(Clone, Debug)]
+pub struct Discovered {
+    pub channel: u8,
+    pub address: [u8; 5],
+    /// Acked packets out of those sent, while listening
+    pub acks: u32,
+    pub sent: u32,
+    /// Latest telemetry of each type received while listening
+    pub telemetry: Vec<Telemetry>,
+}
+
+impl Discovered {
+    fn record_telemetry(&mut self, telemetry: Telemetry) {
+        if matches!(
+            telemetry,
+            Telemetry::Noop | Telemetry::Pong(_) | Telemetry::ParamValue { .. }
+        ) {
+            return;
+        }
+        let type_byte = telemetry.type_byte();
+        match self
+            .telemetry
+            .iter_mut()
+            .find(|t| t.type_byte() == type_byte)
+        {
+            Some(existing) => *existing = telemetry,
+            None => self.telemetry.push(telemetry),
+        }
+    }
+}
+
+/// Sends no-ops on every channel to each address in turn, calling `on_found`
+/// as robots ack, and returns all of them once the scan is done
+pub fn scan(
+    transport: &mut dyn Transport,
+    addresses: &[[u8; 5]],
+    mut on_found: impl FnMut(&Discovered),
+) -> Result<Vec<Discovered>, TransportError> {
+    let mut found = Vec::new();
+    for address in addresses {
+        transport.set_address(address)?;
+        for channel in 0..=MAX_CHANNEL {
+            transport.set_channel(channel)?;
+            if let Some(robot) = probe(transport, channel, address)? {
+                on_found(&robot);
+                found.push(robot);
+            }
+        }
+    }
+    Ok(found)
+}
+
+// Probes the currently tuned channel and address, listening for telemetry if anything acks
+fn probe(
+    transport: &mut dyn Transport,
+    channel: u8,
+    address: &[u8; 5],
+) -> Result<Option<Discovered>, TransportError> {
+    let mut packet = [0_u8; MAX_ITEM_LEN];
+    let packet_len = Command::Noop.encode(&mut packet).unwrap();
+    let mut ack_data = [0_u8; MAX_TELEMETRY_LEN];
+
+    let mut acked = false;
+    for _ in 0..PROBES_PER_TARGET {
+        if transport
+            .send_packet(&packet[..packet_len], &mut ack_data)?
+            .received
+        {
+            acked = true;
+            break;
+        }
+    }
+    if !acked {
+        return Ok(None);
+    }
+
+    let mut robot = Discovered {
+        channel,
+        address: *address,
+        acks: 0,
+        sent: 0,
+        telemetry: Vec::new(),
+    };
+    let listen_start = Instant::now();
+    while listen_start.elapsed() < LISTEN_TIME {
+        let ack = transport.send_packet(&packet[..packet_len], &mut ack_data)?;
+        robot.sent += 1;
+        if !ack.received {
+            continue;
+        }
+        robot.acks += 1;
+        // Anything undecodable is just skipped, we only want a rough picture here
+        for telemetry in TelemetryItems::new(&ack_data[..ack.length]).map_while(Result::ok) {
+            robot.record_telemetry(telemetry);
+        }
+    }
+    Ok(Some(robot))
+}
+
+/// Runs a discovery scan as a standalone mode, printing robots as they're found
+pub fn run_discovery(
+    transport_kind: &TransportKind,
+    addresses: &[[u8; 5]],
+    rate: DataRate,
+    power: PaLevel,
+) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let tuning = Tuning {
+        channel: 0,
+        rate,
+        power,
+    };
+    let mut transport = match open_transport(transport_kind, tuning) {
+        Ok(transport) => transport,
+        Err(e) => {
+            return Err(io::Error::other(format!(
+                "couldn't open radio device: {}",
+                e
+            )));
+        }
+    };
+    writeln!(
+        stdout,
+        "Scanning channels 0-{} at {} for {} address(es) using {}...",
+        MAX_CHANNEL,
+        rate,
+        addresses.len(),
+        transport.describe()
+    )?;
+
+    let found = scan(transport.as_mut(), addresses, |robot| {
+        // Nothing useful to do if stdout has gone away, the scan result still stands
+        let _ = writeln!(io::stdout(), "{}", describe_robot(robot));
+    })
+    .map_err(|e| io::Error::other(format!("scan failed: {}", e)))?;
+
+    if found.is_empty() {
+        writeln!(stdout, "No robots found")?;
+    } else {
+        writeln!(stdout, "Found {} robot(s)", found.len())?;
+    }
+    Ok(())
+}
+
+fn describe_robot(robot: &Discovered) -> String {
+    let mut desc = format!(
+        "Channel {:3}  address {}  acks {}/{}",
+        robot.channel,
+        format_address(&robot.address),
+        robot.acks,
+        robot.sent
+    );
+    for telemetry in robot.telemetry.iter() {
+        let item = match telemetry {
+            Telemetry::Noop
+            | Telemetry::Pong(_)
+            | Telemetry::ParamValue { .. }
+            | Telemetry::AuthStale(_)
+            | Telemetry::AuthReceipt { .. } => continue,
+            Telemetry::Identity {
+                major,
+                minor,
+                reset_cause,
+            } => format!("fw {}.{} (reset: {})", major, minor, reset_cause),
+            Telemetry::VtxState(on) => format!("VTX {}", if *on { "on" } else { "off" }),
+            Telemetry::Uptime(ticks) => format!("up {}", format_uptime(UPTIME_TICK * *ticks)),
+            Telemetry::BatteryVoltage(value) => {
+                format!("{:.2}V", BatteryVoltage(*value).as_float())
+            }
+            Telemetry::BatteryCurrent(value) => {
+                format!("{:.2}A", BatteryCurrent(*value).as_float())
+            }
+            Telemetry::LeftRpm(value) => format!("L {}rpm", value),
+            Telemetry::RightRpm(value) => format!("R {}rpm", value),
+        };
+        desc.push_str("  ");
+        desc.push_str(&item);
+    }
+    desc
+}
@@ -0,0 +1,301 @@
+//! Sending on several radios at once, picking whichever link is doing best
+
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::MAX_TELEMETRY_LEN;
+
+use crate::actions::{send_error_message, send_message, Action};
+use crate::link::{LinkMonitor, LinkStats};
+use crate::rf::Tuning;
+use crate::transport::{
+    format_address, open_transport, AckInfo, RadioStatus, Transport, TransportError, TransportKind,
+};
+
+/// Retry opening a radio this often, as well as straight away on hot-plug
+/// events, in case one's missed (eg an open failing while udev's still at it)
+pub(crate) const RADIO_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Link quality of one radio in the set, as reported to the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RadioLink {
+    /// Whether it's connected, once known
+    pub status: Option<RadioStatus>,
+    /// Its own packet counts over the last window
+    pub stats: LinkStats,
+}
+
+/// One packet's acks from every radio that sent it
+pub(crate) struct SetAck {
+    /// Acked if any radio's copy was, with the fewest retries any needed
+    pub merged: AckInfo,
+    /// Each radio's ack along with its payload, as the robot only stages a
+    /// payload for whichever copy it acks first
+    pub acks: Vec<(AckInfo, [u8; MAX_TELEMETRY_LEN])>,
+}
+
+// One radio, opened and retuned independently of the others
+struct RadioSlot {
+    kind: TransportKind,
+    transport: Option<Box<dyn Transport>>,
+    status: Option<RadioStatus>,
+    tuned: Tuning,
+    tuned_address: Option<[u8; 5]>,
+    link: LinkMonitor,
+    open_now: bool,
+    next_open_retry: Instant,
+}
+
+/// Every radio packets go out on, sending each packet on all of them
+///
+/// All radios stay on the robot's channel, as it only has the one receiver,
+/// so diversity comes from their antennas being apart or pointing differently.
+/// The robot acts on whichever copy arrives first; commands set state, so
+/// acting on both is harmless.
+pub(crate) struct RadioSet {
+    slots: Vec<RadioSlot>,
+}
+
+impl RadioSet {
+    pub fn new(kinds: &[TransportKind], tuning: Tuning) -> Self {
+        let now = Instant::now();
+        let slots = kinds
+            .iter()
+            .map(|kind| RadioSlot {
+                kind: kind.clone(),
+                transport: None,
+                status: None,
+                tuned: tuning,
+                tuned_address: None,
+                link: LinkMonitor::new(),
+                open_now: true,
+                next_open_retry: now,
+            })
+            .collect();
+        Self { slots }
+    }
+
+    /// Whether there's more than one radio, so per-radio details are worth showing
+    pub fn is_diverse(&self) -> bool {
+        self.slots.len() > 1
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.slots.iter().any(|slot| slot.transport.is_some())
+    }
+
+    /// Overall status, connected while any radio is
+    pub fn status(&self) -> Option<RadioStatus> {
+        if self.is_connected() {
+            Some(RadioStatus::Connected)
+        } else {
+            self.slots.iter().find_map(|slot| slot.status)
+        }
+    }
+
+    /// Tries opening any radios which aren't open, when there's a chance it
+    /// will work, returning whether the set has gone from none to some open
+    pub fn open_missing(
+        &mut self,
+        tx: &Sender<Action>,
+        wanted: Tuning,
+        hotplugged: bool,
+        now: Instant,
+    ) -> bool {
+        let was_connected = self.is_connected();
+        for idx in 0..self.slots.len() {
+            let name = self.name(idx);
+            let slot = &mut self.slots[idx];
+            if slot.transport.is_some() {
+                continue;
+            }
+            let retry_due = now >= slot.next_open_retry;
+            if !(slot.open_now || hotplugged || retry_due) {
+                continue;
+            }
+            slot.open_now = false;
+            slot.next_open_retry = now + RADIO_RETRY_INTERVAL;
+            match open_transport(&slot.kind, wanted) {
+                Ok(transport) => {
+                    let msg = if slot.status == Some(RadioStatus::Disconnected) {
+                        format!("{} reconnected, {}", name, transport.describe())
+                    } else {
+                        format!("initialized {}, {}", name, transport.describe())
+                    };
+                    send_message(tx, "Radio", &msg);
+                    slot.transport = Some(transport);
+                    slot.tuned = wanted;
+                    slot.tuned_address = None;
+                    slot.link.reset();
+                    slot.status = Some(RadioStatus::Connected);
+                }
+                Err(e) => {
+                    // Only report the first failure, until it's back
+                    if slot.status != Some(RadioStatus::Disconnected) {
+                        let msg = format!("no {}: {}, waiting for it to be plugged in", name, e);
+                        send_error_message(tx, "Radio", &msg);
+                        slot.status = Some(RadioStatus::Disconnected);
+                    }
+                }
+            }
+        }
+        !was_connected && self.is_connected()
+    }
+
+    /// Retunes every open radio to the given settings and robot address
+    pub fn tune(&mut self, tx: &Sender<Action>, wanted: Tuning, address: &[u8; 5]) {
+        for idx in 0..self.slots.len() {
+            let on = self.on(idx);
+            let slot = &mut self.slots[idx];
+            let Some(ref mut transport) = slot.transport else {
+                continue;
+            };
+            if slot.tuned.channel != wanted.channel {
+                match transport.set_channel(wanted.channel) {
+                    Ok(()) => {
+                        slot.tuned.channel = wanted.channel;
+                    }
+                    Err(e) => {
+                        let msg = format!("couldn't set channel {}{}: {}", wanted.channel, on, e);
+                        send_error_message(tx, "Radio", &msg);
+                    }
+                }
+            }
+            if (slot.tuned.rate, slot.tuned.power) != (wanted.rate, wanted.power) {
+                match transport.set_rate(wanted.rate, wanted.power) {
+                    Ok(()) => {
+                        slot.tuned.rate = wanted.rate;
+                        slot.tuned.power = wanted.power;
+                    }
+                    Err(e) => {
+                        let msg = format!(
+                            "couldn't set {} {} power{}: {}",
+                            wanted.rate, wanted.power, on, e
+                        );
+                        send_error_message(tx, "Radio", &msg);
+                    }
+                }
+            }
+            if slot.tuned_address != Some(*address) {
+                match transport.set_address(address) {
+                    Ok(()) => {
+                        slot.tuned_address = Some(*address);
+                    }
+                    Err(e) => {
+                        let msg = format!(
+                            "couldn't set address {}{}: {}",
+                            format_address(address),
+                            on,
+                            e
+                        );
+                        send_error_message(tx, "Radio", &msg);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a packet on every open radio, dropping any that fail until
+    /// they're reopened; only an error if every radio failed
+    pub fn send_packet(
+        &mut self,
+        tx: &Sender<Action>,
+        data: &[u8],
+    ) -> Result<SetAck, TransportError> {
+        let mut merged: Option<AckInfo> = None;
+        let mut acks = Vec::new();
+        let mut last_error = None;
+        for idx in 0..self.slots.len() {
+            let name = self.name(idx);
+            let slot = &mut self.slots[idx];
+            let Some(ref mut transport) = slot.transport else {
+                continue;
+            };
+            let mut ack_data = [0_u8; MAX_TELEMETRY_LEN];
+            match transport.send_packet(data, &mut ack_data) {
+                Ok(ack) => {
+                    slot.link.record_ack(&ack, Instant::now());
+                    merged = Some(match merged {
+                        Some(merged) => merge_acks(merged, ack),
+                        None => ack,
+                    });
+                    acks.push((ack, ack_data));
+                }
+                Err(e) => {
+                    slot.link.record_failure(Instant::now());
+                    let msg = format!("{} disconnected: {}", name, e);
+                    send_error_message(tx, "Radio", &msg);
+                    // Try reopening once straight away, in case it was a
+                    // transient error, otherwise wait for it to be plugged back in
+                    slot.transport = None;
+                    slot.open_now = true;
+                    slot.status = Some(RadioStatus::Disconnected);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (merged, last_error) {
+            (Some(merged), _) => Ok(SetAck { merged, acks }),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(TransportError::NotOpen),
+        }
+    }
+
+    /// Records a slot where nothing could be sent, as for the robot that's a
+    /// lost packet on every radio
+    pub fn record_missed(&mut self, now: Instant) {
+        for slot in self.slots.iter_mut() {
+            slot.link.record_failure(now);
+        }
+    }
+
+    /// Clears every radio's link statistics, eg after changing channel
+    pub fn reset_links(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.link.reset();
+        }
+    }
+
+    pub fn links(&self, now: Instant) -> Vec<RadioLink> {
+        self.slots
+            .iter()
+            .map(|slot| RadioLink {
+                status: slot.status,
+                stats: slot.link.stats(now),
+            })
+            .collect()
+    }
+
+    // Radios are numbered from 1 in messages, once there's more than one
+    fn name(&self, idx: usize) -> String {
+        if self.is_diverse() {
+            format!("radio {}", idx + 1)
+        } else {
+            String::from("radio")
+        }
+    }
+
+    fn on(&self, idx: usize) -> String {
+        if self.is_diverse() {
+            format!(" on radio {}", idx + 1)
+        } else {
+            String::new()
+        }
+    }
+}
+
+// Combines two radios' acks for the same packet, as if from one better radio
+fn merge_acks(a: AckInfo, b: AckInfo) -> AckInfo {
+    let retry = match (a.received, b.received) {
+        (true, true) => a.retry.min(b.retry),
+        (true, false) => a.retry,
+        (false, true) => b.retry,
+        (false, false) => a.retry.max(b.retry),
+    };
+    AckInfo {
+        received: a.received || b.received,
+        power_detector: a.power_detector || b.power_detector,
+        retry,
+        length: a.length.max(b.length),
+    }
+}
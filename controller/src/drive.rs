@@ -0,0 +1,740 @@
+//! A driving session: the joystick, terminal, radio (or simulator) and UI
+//! threads, and the loop between them turning input into control state and
+//! requests for the radio thread
+
+#![allow(clippy::explicit_write)]
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::terminal;
+use dbus::blocking::Connection;
+use skelebot_controller::actions::{Action, ControlState, StickValues, ThreadMsg};
+use skelebot_controller::channel::{self, ROBOT_BOOT_CHANNEL};
+use skelebot_controller::config::{Config, DriveConfig};
+use skelebot_controller::params::ParamEditor;
+use skelebot_controller::radio::{self, RadioRequest, RadioSender};
+use skelebot_controller::rf::Tuning;
+use skelebot_controller::{joystick, sim};
+use skelebot_protocol::auth::AuthKey;
+use skelebot_protocol::{Command, Telemetry};
+
+use crate::cli::Options;
+use crate::term;
+use crate::ui::{self, UIUpdate};
+
+// Holding the view stick's button this long toggles the video transmitter
+const VTX_HOLD: Duration = Duration::from_secs(1);
+// A VTX switch not reported back by then has likely been given up on, so
+// toggles go by the reported state again
+const VTX_CONFIRM: Duration = Duration::from_secs(1);
+
+struct ToggleButtons {
+    r#move: bool,
+    view: bool,
+    // When the view button went down, until it's been held long enough to act
+    view_held_since: Option<Instant>,
+    // The current press has toggled the VTX, so releasing it mustn't recenter
+    view_held: bool,
+}
+
+/// Video transmitter state, as reported by the robot and as last asked for
+#[derive(Default)]
+struct VtxToggle {
+    reported: Option<bool>,
+    requested: Option<(bool, Instant)>,
+}
+
+impl VtxToggle {
+    fn record(&mut self, on: bool) {
+        self.reported = Some(on);
+        if self.requested.is_some_and(|(requested, _)| requested == on) {
+            self.requested = None;
+        }
+    }
+
+    /// State to switch to, the opposite of any recent request not yet
+    /// reported back, otherwise of the reported state; None if neither is known
+    fn toggle(&mut self, now: Instant) -> Option<bool> {
+        let recent = self
+            .requested
+            .filter(|(_, at)| now.saturating_duration_since(*at) < VTX_CONFIRM)
+            .map(|(requested, _)| requested);
+        let on = !recent.or(self.reported)?;
+        self.requested = Some((on, now));
+        Some(on)
+    }
+}
+
+struct ChannelSelect {
+    current: u8,
+    selected: Option<u8>,
+}
+
+/// Drives the robot (or the simulator) from the joystick and keyboard with
+/// the TUI up, until the operator quits; puts the terminal in raw mode for
+/// the duration
+pub fn run_drive(options: &Options, config: &Config, auth_key: Option<AuthKey>) -> io::Result<()> {
+    // Set up before entering raw mode, as it's the last thing that can fail outright
+    let (radio_tx, radio_rx) = radio::radio_channel()?;
+
+    terminal::enable_raw_mode()?;
+    write!(io::stdout(), "Starting up...\r\n")?;
+
+    // Prevent screen blanking and locking via d-bus call
+    let mut dbus_conn: Option<Connection> = None;
+    let mut dbus_cookie: Option<u32> = None;
+    match Connection::new_session() {
+        Ok(conn) => {
+            dbus_conn = Some(conn);
+        }
+        Err(e) => {
+            write!(io::stderr(), "Error creating D-Bus connection: {}\r\n", e)?;
+        }
+    }
+    if let Some(ref conn) = dbus_conn {
+        let proxy = conn.with_proxy(
+            "org.freedesktop.ScreenSaver",
+            "/org/freedesktop/ScreenSaver",
+            Duration::from_millis(5_000),
+        );
+        let result: Result<(u32,), dbus::Error> = proxy.method_call(
+            "org.freedesktop.ScreenSaver",
+            "Inhibit",
+            ("controller.rs", "wake lock enabled"),
+        );
+        match result {
+            Ok((cookie,)) => {
+                dbus_cookie = Some(cookie);
+                write!(io::stdout(), "Screensaver inhibited\r\n")?;
+            }
+            Err(e) => {
+                write!(io::stderr(), "Error inhibiting screensaver: {}\r\n", e)?;
+            }
+        }
+    }
+
+    let control_state_mutex = Arc::new(Mutex::new(ControlState::new()));
+    let exit_flag = AtomicBool::new(false);
+    let mut err_msg: Option<String> = None;
+
+    let (tx, rx) = mpsc::channel::<Action>();
+    let j_tx = tx.clone();
+    let r_tx = tx.clone();
+    let t_tx = tx.clone();
+    let u_tx = tx.clone();
+    drop(tx);
+
+    let (ui_tx, ui_rx) = mpsc::channel::<UIUpdate>();
+
+    // Robot always boots with the same settings, so move it once connected
+    let tuning = Tuning {
+        channel: options.channel.unwrap_or(ROBOT_BOOT_CHANNEL),
+        rate: options.rate(),
+        power: options.power(),
+    };
+    if tuning != Tuning::boot() {
+        radio_tx.send(RadioRequest::Retune(tuning)).unwrap();
+    }
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            joystick::collect_joystick_events(j_tx, &config.input, &exit_flag);
+        });
+        s.spawn(|| {
+            if options.sim {
+                sim::simulate(
+                    r_tx,
+                    radio_rx,
+                    Arc::clone(&control_state_mutex),
+                    &exit_flag,
+                    &config.drive,
+                );
+            } else {
+                radio::radio_comms(
+                    r_tx,
+                    radio_rx,
+                    Arc::clone(&control_state_mutex),
+                    &exit_flag,
+                    &options.radio(),
+                    &config.drive,
+                    auth_key,
+                );
+            }
+        });
+        s.spawn(|| {
+            term::collect_terminal_events(t_tx, &exit_flag);
+        });
+        s.spawn(|| {
+            ui::draw_ui(ui_rx, u_tx, &config.ui, &config.drive, &exit_flag);
+        });
+
+        // Loop over channel rx and process events
+        // Set error message and exit flag on any error, then allow threads to end
+        if let Err(e) = handle_actions(
+            rx,
+            ui_tx,
+            radio_tx,
+            &exit_flag,
+            Arc::clone(&control_state_mutex),
+            &config.drive,
+        ) {
+            err_msg = Some(format!("{}", e));
+            exit_flag.store(true, Ordering::Relaxed);
+        }
+    });
+
+    if let Some(msg) = err_msg {
+        write!(io::stderr(), "{0}\r\n", msg)?;
+    }
+
+    if let Some(ref conn) = dbus_conn {
+        if let Some(cookie) = dbus_cookie {
+            let proxy = conn.with_proxy(
+                "org.freedesktop.ScreenSaver",
+                "/org/freedesktop/ScreenSaver",
+                Duration::from_millis(5_000),
+            );
+            let result: Result<(), dbus::Error> =
+                proxy.method_call("org.freedesktop.ScreenSaver", "UnInhibit", (cookie,));
+            if let Err(e) = result {
+                write!(io::stderr(), "Error uninhibiting screensaver: {}\r\n", e)?;
+            }
+        } else {
+            write!(
+                io::stderr(),
+                "DBus connection present but no cookie for uninhibit call\r\n"
+            )?;
+        }
+    }
+
+    write!(io::stdout(), "Shutting down...\r\n")?;
+    terminal::disable_raw_mode()
+}
+
+fn handle_actions(
+    rx: Receiver<Action>,
+    ui_tx: Sender<UIUpdate>,
+    radio_tx: RadioSender,
+    exit_flag: &AtomicBool,
+    control_state_mutex: Arc<Mutex<ControlState>>,
+    drive: &DriveConfig,
+) -> Result<(), Box<dyn Error>> {
+    let max_wait = Duration::from_millis(20);
+    let mut buttons = ToggleButtons {
+        r#move: false,
+        view: false,
+        view_held_since: None,
+        view_held: false,
+    };
+    let mut channel_select = ChannelSelect {
+        current: ROBOT_BOOT_CHANNEL,
+        selected: None,
+    };
+    let mut param_editor = ParamEditor::new();
+    let mut active_robot = 0;
+    let mut vtx = VtxToggle::default();
+
+    'listener: loop {
+        match rx.recv_timeout(max_wait) {
+            Ok(action) => {
+                // The UI thread may already have gone once exiting, so stop forwarding
+                if exit_flag.load(Ordering::Relaxed) {
+                    break 'listener;
+                }
+                match action {
+                    Action::Message(msg) => {
+                        ui_tx.send(UIUpdate::Message(msg))?;
+                        // write!(io::stdout(), "{0}: {1}\r\n", msg.name, msg.message)?;
+                    }
+                    Action::Error(err) => {
+                        ui_tx.send(UIUpdate::Error(err))?;
+                        // write!(
+                        //     io::stderr(),
+                        //     "Error from {0}: {1}\r\n",
+                        //     err.name,
+                        //     err.message
+                        // )?;
+                    }
+                    Action::Fatal(err) => {
+                        return Err(
+                            format!("Fatal error from {0}: {1}", err.name, err.message).into()
+                        );
+                    }
+                    Action::KeyPress(key_event) => {
+                        // 'd' swaps the stick chart for latency diagnostics
+                        if key_event.code == KeyCode::Char('d') {
+                            ui_tx.send(UIUpdate::ToggleDiagnostics)?;
+                            continue 'listener;
+                        }
+                        if let Some(commands) = handle_param_keypress(&mut param_editor, key_event)
+                        {
+                            for command in commands {
+                                radio_tx.send(RadioRequest::Param(command))?;
+                            }
+                            ui_tx.send(UIUpdate::Params(param_editor))?;
+                            continue 'listener;
+                        }
+                        // 'v' switches the video transmitter on or off
+                        if key_event.code == KeyCode::Char('v') {
+                            toggle_vtx(&mut vtx, &radio_tx, &ui_tx)?;
+                            continue 'listener;
+                        }
+                        if let Some(request) = handle_fleet_keypress(key_event) {
+                            radio_tx.send(request)?;
+                            continue 'listener;
+                        }
+                        if let Some(request) =
+                            handle_channel_keypress(&mut channel_select, key_event)
+                        {
+                            if let Some(request) = request {
+                                radio_tx.send(request)?;
+                            }
+                            ui_tx.send(UIUpdate::ChannelSelection(channel_select.selected))?;
+                            continue 'listener;
+                        }
+                        let prev_state = {
+                            let prev_state = control_state_mutex.lock().unwrap();
+                            *prev_state
+                        };
+                        match handle_keypress_event(&prev_state, key_event) {
+                            Some(control_state) => {
+                                if control_state != prev_state {
+                                    {
+                                        let mut stored_state = control_state_mutex.lock().unwrap();
+                                        *stored_state = control_state;
+                                    }
+                                    // Wake the radio thread, so changes go out right away
+                                    radio_tx.send(RadioRequest::ControlChanged(None))?;
+                                }
+                                ui_tx.send(UIUpdate::Control(control_state))?;
+                                // write!(io::stdout(), "Control state: {:?}\r\n", control_state)?;
+                            }
+                            None => {
+                                exit_flag.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    Action::StickUpdate(stick_pos, input_time) => {
+                        if check_vtx_hold(&mut buttons, stick_pos.1.button, Instant::now()) {
+                            toggle_vtx(&mut vtx, &radio_tx, &ui_tx)?;
+                        }
+                        let prev_state = {
+                            let prev_state = control_state_mutex.lock().unwrap();
+                            *prev_state
+                        };
+                        let control_state =
+                            handle_stick_positions(&prev_state, drive, &mut buttons, stick_pos);
+                        if control_state != prev_state {
+                            {
+                                let mut stored_state = control_state_mutex.lock().unwrap();
+                                *stored_state = control_state;
+                            }
+                            // Wake the radio thread, so changes go out right away
+                            radio_tx.send(RadioRequest::ControlChanged(input_time))?;
+                        }
+                        ui_tx.send(UIUpdate::Control(control_state))?;
+                        // write!(io::stdout(), "Control state: {:?}\r\n", control_state)?;
+                    }
+                    Action::Telemetry(robot, telemetry, received) => {
+                        // Stops during handover get acks from the other robots
+                        // too, which mustn't be taken for the driven robot's
+                        match telemetry {
+                            Telemetry::VtxState(on) if robot == active_robot => {
+                                vtx.record(on);
+                            }
+                            Telemetry::ParamValue { param, value } if robot == active_robot => {
+                                if let Some(rejected) = param_editor.record(param, value) {
+                                    let msg = format!(
+                                        "robot rejected {} = {}, keeping {}",
+                                        rejected.param, rejected.requested, rejected.value
+                                    );
+                                    ui_tx.send(UIUpdate::Error(ThreadMsg {
+                                        name: String::from("Params"),
+                                        message: msg,
+                                    }))?;
+                                }
+                                ui_tx.send(UIUpdate::Params(param_editor))?;
+                            }
+                            _ => {}
+                        }
+                        ui_tx.send(UIUpdate::Telemetry(robot, telemetry, received))?;
+                    }
+                    Action::LinkStatsUpdate(stats) => {
+                        ui_tx.send(UIUpdate::LinkStats(stats))?;
+                    }
+                    Action::RadioLinksUpdate(links) => {
+                        ui_tx.send(UIUpdate::RadioLinks(links))?;
+                    }
+                    Action::LatencyUpdate(stats) => {
+                        ui_tx.send(UIUpdate::Latency(stats))?;
+                    }
+                    Action::ChannelUpdate(status) => {
+                        channel_select.current = status.tuning.channel;
+                        ui_tx.send(UIUpdate::Channel(status))?;
+                    }
+                    Action::FleetUpdate(status) => {
+                        // Parameters shown are for whichever robot is being driven
+                        if status.active != active_robot {
+                            active_robot = status.active;
+                            vtx = VtxToggle::default();
+                            if param_editor.open {
+                                for command in param_editor.reload() {
+                                    radio_tx.send(RadioRequest::Param(command))?;
+                                }
+                                ui_tx.send(UIUpdate::Params(param_editor))?;
+                            }
+                        }
+                        ui_tx.send(UIUpdate::Fleet(status))?;
+                    }
+                    Action::RadioUpdate(status) => {
+                        ui_tx.send(UIUpdate::Radio(status))?;
+                    }
+                    Action::SimUpdate(pose) => {
+                        ui_tx.send(UIUpdate::Sim(pose))?;
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // If we've timed out after signalling exit, just break
+                if exit_flag.load(Ordering::Relaxed) {
+                    break 'listener;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // Disconnected implies all senders dropped
+                break 'listener;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a modified control state if arrow keys are pressed, or None if the quit
+/// key ('q' at present) is pressed
+fn handle_keypress_event(prev_state: &ControlState, key_event: KeyEvent) -> Option<ControlState> {
+    let mut control_state = *prev_state;
+    match key_event.code {
+        // Quit on 'q'
+        KeyCode::Char('q') => {
+            return None;
+        }
+        // Manipulate control state on arrow keys
+        KeyCode::Up => {
+            let mut step = 8_192;
+            if control_state.throttle <= (i16::MIN + 1) {
+                step = 8_191;
+            }
+            control_state.throttle = control_state.throttle.saturating_add(step);
+        }
+        KeyCode::Down => {
+            let mut step = 8_192;
+            if control_state.throttle == i16::MAX {
+                step = 8_191;
+            }
+            control_state.throttle = control_state.throttle.saturating_sub(step);
+        }
+        KeyCode::Right => {
+            let mut step = 8_192;
+            if control_state.steering <= (i16::MIN + 1) {
+                step = 8_191;
+            }
+            control_state.steering = control_state.steering.saturating_add(step);
+        }
+        KeyCode::Left => {
+            let mut step = 8_192;
+            if control_state.steering == i16::MAX {
+                step = 8_191;
+            }
+            control_state.steering = control_state.steering.saturating_sub(step);
+        }
+        // Reset control state to center on spacebar
+        KeyCode::Char(' ') => {
+            control_state.throttle = 0;
+            control_state.steering = 0;
+        }
+        // Cycle through speed modes
+        KeyCode::Char('m') => {
+            control_state.move_speed = control_state.move_speed.toggle();
+        }
+        // Ignore others
+        _ => {}
+    }
+    Some(control_state.trim())
+}
+
+/// Handles channel selection keys: '[' and ']' step through the quiet channels,
+/// Enter requests the change, Esc cancels, 'a' toggles automatic migration and
+/// 'r' toggles automatic data rate and power. Returns None if not a channel
+/// key, otherwise any resulting radio request
+fn handle_channel_keypress(
+    channel_select: &mut ChannelSelect,
+    key_event: KeyEvent,
+) -> Option<Option<RadioRequest>> {
+    match key_event.code {
+        KeyCode::Char('[') => {
+            let from = channel_select.selected.unwrap_or(channel_select.current);
+            channel_select.selected = Some(channel::step_quiet_channel(from, false));
+            Some(None)
+        }
+        KeyCode::Char(']') => {
+            let from = channel_select.selected.unwrap_or(channel_select.current);
+            channel_select.selected = Some(channel::step_quiet_channel(from, true));
+            Some(None)
+        }
+        KeyCode::Enter => {
+            let request = channel_select
+                .selected
+                .take()
+                .map(RadioRequest::ChangeChannel);
+            Some(request)
+        }
+        KeyCode::Esc => {
+            channel_select.selected = None;
+            Some(None)
+        }
+        KeyCode::Char('a') => Some(Some(RadioRequest::ToggleAutoMigrate)),
+        KeyCode::Char('r') => Some(Some(RadioRequest::ToggleAutoRate)),
+        _ => None,
+    }
+}
+
+/// Handles parameter editor keys: 'p' opens and closes the editor, then while
+/// it's open 'k' and 'j' select a parameter, '-' and '=' adjust it (PgDn and
+/// PgUp in larger steps), 'g' reads everything again and 'w' saves the values
+/// on the robot. None of these drive, so the arrow keys keep driving and Esc
+/// keeps cancelling a channel selection. Returns None if not a parameter key,
+/// otherwise any commands to send
+fn handle_param_keypress(editor: &mut ParamEditor, key_event: KeyEvent) -> Option<Vec<Command>> {
+    if key_event.code == KeyCode::Char('p') {
+        return Some(editor.toggle());
+    }
+    if !editor.open {
+        return None;
+    }
+    let commands = match key_event.code {
+        KeyCode::Char('k') => {
+            editor.select(false);
+            Vec::new()
+        }
+        KeyCode::Char('j') => {
+            editor.select(true);
+            Vec::new()
+        }
+        KeyCode::Char('-') => editor.nudge(-1, false).into_iter().collect(),
+        KeyCode::Char('=') => editor.nudge(1, false).into_iter().collect(),
+        KeyCode::PageDown => editor.nudge(-1, true).into_iter().collect(),
+        KeyCode::PageUp => editor.nudge(1, true).into_iter().collect(),
+        KeyCode::Char('g') => editor.reload(),
+        KeyCode::Char('w') => vec![editor.commit()],
+        _ => return None,
+    };
+    Some(commands)
+}
+
+/// Handles robot selection keys: '1' to '9' drive that robot from the fleet.
+/// Returns None if not a selection key
+fn handle_fleet_keypress(key_event: KeyEvent) -> Option<RadioRequest> {
+    match key_event.code {
+        KeyCode::Char(c @ '1'..='9') => {
+            let idx = c.to_digit(10)? as usize - 1;
+            Some(RadioRequest::SelectRobot(idx))
+        }
+        _ => None,
+    }
+}
+
+/// Asks the robot to switch its video transmitter to the opposite of what was
+/// last asked for, or of what it last reported
+fn toggle_vtx(
+    vtx: &mut VtxToggle,
+    radio_tx: &RadioSender,
+    ui_tx: &Sender<UIUpdate>,
+) -> Result<(), Box<dyn Error>> {
+    match vtx.toggle(Instant::now()) {
+        Some(on) => {
+            radio_tx.send(RadioRequest::SetVtx(on))?;
+            let msg = format!("switching VTX {}", if on { "on" } else { "off" });
+            ui_tx.send(UIUpdate::Message(ThreadMsg {
+                name: String::from("Main"),
+                message: msg,
+            }))?;
+        }
+        None => {
+            ui_tx.send(UIUpdate::Error(ThreadMsg {
+                name: String::from("Main"),
+                message: String::from("VTX state not reported by the robot yet"),
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true once each time the view button has been held for `VTX_HOLD`
+fn check_vtx_hold(buttons: &mut ToggleButtons, pressed: bool, now: Instant) -> bool {
+    if !pressed {
+        buttons.view_held_since = None;
+        return false;
+    }
+    // Only set on the press itself, so a long hold doesn't toggle repeatedly
+    if !buttons.view {
+        buttons.view_held_since = Some(now);
+        buttons.view_held = false;
+    }
+    let held = buttons
+        .view_held_since
+        .is_some_and(|since| now.saturating_duration_since(since) >= VTX_HOLD);
+    if held {
+        buttons.view_held_since = None;
+        buttons.view_held = true;
+    }
+    held
+}
+
+/// Converts a joystick position to a new control state
+fn handle_stick_positions(
+    prev_state: &ControlState,
+    drive: &DriveConfig,
+    buttons: &mut ToggleButtons,
+    stick_pos: StickValues,
+) -> ControlState {
+    let curr_time = Instant::now();
+
+    // Convert stick position to control state
+    // At present this is a simple mapping of Y axis to throttle
+    // and X axis to steering, except the movement speed toggle
+    let StickValues(move_pos, view_pos) = stick_pos;
+    let mut move_speed = prev_state.move_speed;
+
+    if move_pos.button && !buttons.r#move {
+        move_speed = move_speed.toggle();
+    }
+    buttons.r#move = move_pos.button;
+
+    // Reset pan/tilt when the button's released, unless it was held down long
+    // enough to toggle the VTX instead
+    // TODO: figure out the &mut required to still apply the stick postion after
+    // recentering, in the same frame (instead of waiting until the next)
+    let (new_pan, new_tilt) = if !view_pos.button && buttons.view && !buttons.view_held {
+        (0.0, 0.0)
+    } else {
+        prev_state.get_rotated_camera(drive, view_pos.x, view_pos.y, curr_time)
+    };
+    buttons.view = view_pos.button;
+
+    // TODO: make all this an impl fn on ControlState instead?
+    let control_state = ControlState {
+        throttle: move_pos.y,
+        steering: move_pos.x,
+        pan: new_pan,
+        tilt: new_tilt,
+        move_speed,
+        last_update: curr_time,
+    };
+    control_state.trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skelebot_controller::actions::StickPosition;
+
+    fn view_button(pressed: bool) -> StickValues {
+        let centre = StickPosition {
+            x: 0,
+            y: 0,
+            button: false,
+        };
+        let view = StickPosition {
+            button: pressed,
+            ..centre
+        };
+        StickValues(centre, view)
+    }
+
+    // Feeds the view button's state through as the main loop does, returning
+    // whether the VTX toggled and the camera pan afterwards
+    fn press(
+        state: &mut ControlState,
+        buttons: &mut ToggleButtons,
+        pressed: bool,
+        now: Instant,
+    ) -> (bool, f32) {
+        let toggled = check_vtx_hold(buttons, pressed, now);
+        let drive = DriveConfig::default();
+        *state = handle_stick_positions(state, &drive, buttons, view_button(pressed));
+        (toggled, state.pan)
+    }
+
+    fn setup() -> (ControlState, ToggleButtons) {
+        let state = ControlState {
+            pan: 45.0,
+            ..ControlState::new()
+        };
+        let buttons = ToggleButtons {
+            r#move: false,
+            view: false,
+            view_held_since: None,
+            view_held: false,
+        };
+        (state, buttons)
+    }
+
+    #[test]
+    fn short_press_recenters_on_release() {
+        let (mut state, mut buttons) = setup();
+        let start = Instant::now();
+        assert_eq!(press(&mut state, &mut buttons, true, start), (false, 45.0));
+        let release = start + Duration::from_millis(200);
+        assert_eq!(
+            press(&mut state, &mut buttons, false, release),
+            (false, 0.0)
+        );
+    }
+
+    #[test]
+    fn long_press_toggles_vtx_without_recentering() {
+        let (mut state, mut buttons) = setup();
+        let start = Instant::now();
+        press(&mut state, &mut buttons, true, start);
+        assert_eq!(
+            press(&mut state, &mut buttons, true, start + VTX_HOLD),
+            (true, 45.0)
+        );
+        // Only once per hold
+        let later = start + VTX_HOLD * 3;
+        assert_eq!(press(&mut state, &mut buttons, true, later), (false, 45.0));
+        assert_eq!(press(&mut state, &mut buttons, false, later), (false, 45.0));
+    }
+
+    #[test]
+    fn vtx_toggles_from_the_requested_state() {
+        let start = Instant::now();
+        let mut vtx = VtxToggle::default();
+        assert_eq!(vtx.toggle(start), None);
+        vtx.record(true);
+        assert_eq!(vtx.toggle(start), Some(false));
+        // The robot hasn't caught up yet, so a second toggle undoes the first
+        vtx.record(true);
+        assert_eq!(vtx.toggle(start), Some(true));
+        vtx.record(true);
+        assert_eq!(vtx.toggle(start), Some(false));
+    }
+
+    #[test]
+    fn unconfirmed_vtx_request_expires() {
+        let start = Instant::now();
+        let mut vtx = VtxToggle::default();
+        vtx.record(false);
+        assert_eq!(vtx.toggle(start), Some(true));
+        vtx.record(false);
+        assert_eq!(vtx.toggle(start + VTX_CONFIRM), Some(true));
+    }
+}
@@ -0,0 +1,131 @@
+//! Several robots on one channel, and handing control between them
+
+use crate::rf::Tuning;
+
+// Attempts at stopping each robot before handing over control regardless
+const STOP_ATTEMPTS: u32 = 10;
+
+/// A robot the controller can drive, identified by its radio address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Robot {
+    pub address: [u8; 5],
+    /// Radio settings the robot was last known to be using
+    pub tuning: Tuning,
+}
+
+impl Robot {
+    pub fn new(address: [u8; 5]) -> Self {
+        Self {
+            address,
+            tuning: Tuning::boot(),
+        }
+    }
+}
+
+/// Which robot the sticks drive and (if handing over) which will be next, as
+/// reported to the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FleetStatus {
+    /// Size of the fleet
+    pub robots: usize,
+    /// Index of the robot being driven
+    pub active: usize,
+    /// Index of the robot being handed over to
+    pub target: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StopOutcome {
+    InProgress,
+    /// Robot acked the stop command
+    Stopped(usize),
+    /// Robot never acked, it may be off or out of range
+    Unanswered(usize),
+}
+
+/// Moving control from one robot to another, stopping all the others first
+///
+/// Each radio loop tick, the caller tunes to the robot given by `stopping()`
+/// and sends it a stop command, then feeds the ack result to `record()`. Once
+/// `stopping()` returns None, control can move to `to()`.
+#[derive(Clone, Debug)]
+pub(crate) struct Handover {
+    to: usize,
+    // Robots still to stop, in order
+    pending: Vec<usize>,
+    attempts: u32,
+}
+
+impl Handover {
+    pub fn new(robots: usize, from: usize, to: usize) -> Self {
+        // The robot being driven is the important one, so stop it first
+        let mut pending = vec![from];
+        pending.extend((0..robots).filter(|&idx| idx != from && idx != to));
+        Self {
+            to,
+            pending,
+            attempts: 0,
+        }
+    }
+
+    pub fn to(&self) -> usize {
+        self.to
+    }
+
+    /// Robot to send a stop command to this tick, if any are left
+    pub fn stopping(&self) -> Option<usize> {
+        self.pending.first().copied()
+    }
+
+    /// Records whether this tick's stop command was acked, moving on to the
+    /// next robot as required
+    pub fn record(&mut self, acked: bool) -> StopOutcome {
+        let Some(idx) = self.stopping() else {
+            return StopOutcome::InProgress;
+        };
+        self.attempts += 1;
+        if acked || self.attempts >= STOP_ATTEMPTS {
+            self.pending.remove(0);
+            self.attempts = 0;
+            if acked {
+                StopOutcome::Stopped(idx)
+            } else {
+                StopOutcome::Unanswered(idx)
+            }
+        } else {
+            StopOutcome::InProgress
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_the_driven_robot_first_and_skips_the_next() {
+        let mut handover = Handover::new(4, 2, 0);
+        let mut stopped = Vec::new();
+        while let Some(idx) = handover.stopping() {
+            assert_eq!(handover.record(true), StopOutcome::Stopped(idx));
+            stopped.push(idx);
+        }
+        assert_eq!(stopped, [2, 1, 3]);
+        assert_eq!(handover.to(), 0);
+        assert_eq!(handover.record(true), StopOutcome::InProgress);
+    }
+
+    #[test]
+    fn moves_on_from_a_robot_that_never_answers() {
+        let mut handover = Handover::new(3, 0, 1);
+        for _ in 1..STOP_ATTEMPTS {
+            assert_eq!(handover.record(false), StopOutcome::InProgress);
+        }
+        assert_eq!(handover.record(false), StopOutcome::Unanswered(0));
+        // Attempts start over for the next robot
+        assert_eq!(handover.stopping(), Some(2));
+        assert_eq!(handover.record(false), StopOutcome::InProgress);
+        assert_eq!(handover.record(true), StopOutcome::Stopped(2));
+        assert_eq!(handover.stopping(), None);
+    }
+}
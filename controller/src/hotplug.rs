@@ -0,0 +1,60 @@
+//! Noticing radios being plugged in
+
+use std::fs;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+
+// usbfs device nodes, one directory per bus
+const USB_DEVICE_DIR: &str = "/dev/bus/usb";
+
+/// Watches usbfs for devices being plugged in, so opening the radio is
+/// retried as soon as there's a chance it will work
+///
+/// Device nodes are created before udev sets their permissions, so attribute
+/// changes count as well as creation.
+pub struct UsbHotplug {
+    inotify: Inotify,
+    root: WatchDescriptor,
+}
+
+impl UsbHotplug {
+    pub fn new() -> Result<Self, Errno> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let root = inotify.add_watch(USB_DEVICE_DIR, AddWatchFlags::IN_CREATE)?;
+        let hotplug = Self { inotify, root };
+        if let Ok(entries) = fs::read_dir(USB_DEVICE_DIR) {
+            for entry in entries.flatten() {
+                hotplug.watch_bus(&entry.path())?;
+            }
+        }
+        Ok(hotplug)
+    }
+
+    /// Whether any USB devices have appeared (or become accessible) since last called
+    pub fn devices_added(&mut self) -> bool {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            // EAGAIN just means nothing happened; anything else we can't do much about
+            Err(_) => {
+                return false;
+            }
+        };
+        for event in events.iter() {
+            // New bus, so watch it for devices too
+            if event.wd == self.root && event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                if let Some(ref name) = event.name {
+                    _ = self.watch_bus(&Path::new(USB_DEVICE_DIR).join(name));
+                }
+            }
+        }
+        !events.is_empty()
+    }
+
+    fn watch_bus(&self, path: &Path) -> Result<(), Errno> {
+        let flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB;
+        self.inotify.add_watch(path, flags)?;
+        Ok(())
+    }
+}
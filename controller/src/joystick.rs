@@ -1,8 +1,10 @@
+//! Reading the sticks from an evdev gamepad, reopening it when unplugged
+
 use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use evdev::{AbsoluteAxisCode, Device, EventSummary, InputEvent, KeyCode};
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
@@ -11,12 +13,13 @@ use crate::actions::{
     record_ticks_for_period, send_error_message, send_message, Action, StickPosition, StickValues,
     RECORD_TICKS_INTERVAL,
 };
+use crate::config::InputConfig;
 
 const FIND_WAIT: Duration = Duration::from_millis(100);
-const POLL_WAIT: Duration = Duration::from_millis(10);
-const DEAD_ZONE: i32 = (i16::MAX as i32) / 32;
 
-pub fn collect_joystick_events(tx: Sender<Action>, exit_flag: &AtomicBool) {
+/// Sends stick updates and button presses as actions until the exit flag is
+/// set, waiting for a joystick to appear if there isn't one
+pub fn collect_joystick_events(tx: Sender<Action>, config: &InputConfig, exit_flag: &AtomicBool) {
     let mut prev_marker = Instant::now();
     let mut next_marker = prev_marker + RECORD_TICKS_INTERVAL;
     let mut ticks = 0_u32;
@@ -42,7 +45,7 @@ pub fn collect_joystick_events(tx: Sender<Action>, exit_flag: &AtomicBool) {
     'outer: loop {
         // Try to find an appropriate joystick device
         if device.is_none() {
-            match StickDevice::find() {
+            match StickDevice::find(config) {
                 Ok(maybe_device) => {
                     device = maybe_device;
                     if let Some(ref dev) = device {
@@ -60,8 +63,8 @@ pub fn collect_joystick_events(tx: Sender<Action>, exit_flag: &AtomicBool) {
         match device {
             Some(ref mut dev) => {
                 match dev.update_position() {
-                    Ok(pos) => {
-                        if let Err(_) = tx.send(Action::StickUpdate(pos)) {
+                    Ok((pos, input_time)) => {
+                        if tx.send(Action::StickUpdate(pos, input_time)).is_err() {
                             // Can happen during shutdown
                             device = None;
                         }
@@ -90,13 +93,15 @@ pub fn collect_joystick_events(tx: Sender<Action>, exit_flag: &AtomicBool) {
 struct StickDevice {
     device: Device,
     epoll: Epoll,
+    config: InputConfig,
     left: StickPosition,
     right: StickPosition,
 }
 
 impl StickDevice {
-    pub fn find() -> Result<Option<Self>, io::Error> {
-        for dev_file in glob::glob("/dev/input/by-id/*-event-joystick").unwrap() {
+    pub fn find(config: &InputConfig) -> Result<Option<Self>, io::Error> {
+        // Checked when the config was loaded
+        for dev_file in glob::glob(&config.joystick).unwrap() {
             let dev_file = match dev_file {
                 Ok(dev_file) => dev_file,
                 Err(_) => {
@@ -105,7 +110,7 @@ impl StickDevice {
             };
             let device = Device::open(dev_file)?;
             // Check that X and Y axes are supported
-            let supported = device.supported_absolute_axes().map_or(false, |axes| {
+            let supported = device.supported_absolute_axes().is_some_and(|axes| {
                 axes.contains(AbsoluteAxisCode::ABS_X) && axes.contains(AbsoluteAxisCode::ABS_Y)
             });
             if !supported {
@@ -120,6 +125,7 @@ impl StickDevice {
             return Ok(Some(StickDevice {
                 device,
                 epoll,
+                config: config.clone(),
                 left: StickPosition {
                     x: 0,
                     y: 0,
@@ -142,15 +148,19 @@ impl StickDevice {
             .to_owned()
     }
 
-    pub fn update_position(&mut self) -> Result<StickValues, io::Error> {
+    /// Returns the stick positions after any new events, along with the
+    /// first new event's timestamp
+    pub fn update_position(&mut self) -> Result<(StickValues, Option<SystemTime>), io::Error> {
         let mut events = [EpollEvent::empty(); 2];
-        let max_wait = EpollTimeout::try_from(POLL_WAIT).unwrap();
+        let max_wait = EpollTimeout::try_from(self.config.poll_wait()).unwrap();
         self.epoll.wait(&mut events, max_wait)?;
 
+        let mut first: Option<SystemTime> = None;
         match self.device.fetch_events() {
             Ok(iterator) => {
                 for ev in iterator {
-                    Self::process_event(&mut self.left, &mut self.right, ev);
+                    first.get_or_insert(ev.timestamp());
+                    Self::process_event(&self.config, &mut self.left, &mut self.right, ev);
                 }
             }
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -161,31 +171,41 @@ impl StickDevice {
             }
         }
 
-        Ok(StickValues(self.left.clone(), self.right.clone()))
+        Ok((StickValues(self.left.clone(), self.right.clone()), first))
     }
 
-    fn process_event(l_pos: &mut StickPosition, r_pos: &mut StickPosition, event: InputEvent) {
+    fn process_event(
+        config: &InputConfig,
+        l_pos: &mut StickPosition,
+        r_pos: &mut StickPosition,
+        event: InputEvent,
+    ) {
+        let dead_zone = i32::from(config.dead_zone);
         match event.destructure() {
             EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_X, value) => {
                 // Use X axis as-is
-                l_pos.x = clamp_with_deadzone(value);
+                l_pos.x = clamp_with_deadzone(value, dead_zone);
             }
             EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_Y, value) => {
                 // Invert Y axis
-                l_pos.y = clamp_with_deadzone(value).saturating_neg();
+                l_pos.y = clamp_with_deadzone(value, dead_zone).saturating_neg();
             }
             EventSummary::Key(_, KeyCode::BTN_THUMBL, value) => {
                 l_pos.button = value != 0;
             }
             EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_RX, value) => {
                 // Use X axis as-is
-                r_pos.x = clamp_with_deadzone(value);
+                r_pos.x = clamp_with_deadzone(value, dead_zone);
             }
             EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_RY, value) => {
-                // Don't invert Y axis, ie let it work "inverted"
-                // TODO: add flag to not "invert" (ie *do* invert) Y axis
-                r_pos.y = clamp_with_deadzone(value);
-                // r_pos.y = clamp_with_deadzone(value).saturating_neg();
+                // Unlike the move stick, leave Y as-is by default, ie let it
+                // work "inverted"
+                let y = clamp_with_deadzone(value, dead_zone);
+                r_pos.y = if config.invert_view_y {
+                    y
+                } else {
+                    y.saturating_neg()
+                };
             }
             EventSummary::Key(_, KeyCode::BTN_THUMBR, value) => {
                 r_pos.button = value != 0;
@@ -195,8 +215,8 @@ impl StickDevice {
     }
 }
 
-fn clamp_with_deadzone(value: i32) -> i16 {
-    if value > -DEAD_ZONE && value < DEAD_ZONE {
+fn clamp_with_deadzone(value: i32, dead_zone: i32) -> i16 {
+    if value > -dead_zone && value < dead_zone {
         return 0;
     }
     if value > i16::MAX as i32 {
@@ -205,5 +225,5 @@ fn clamp_with_deadzone(value: i32) -> i16 {
     if value < i16::MIN as i32 {
         return i16::MIN;
     }
-    return value as i16;
+    value as i16
 }
@@ -0,0 +1,247 @@
+//! Round-trip latency from pings, and input-to-ack latency for stick moves
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+use skelebot_protocol::{Command, Telemetry};
+
+// Ping this often, in slots the scheduler leaves idle
+const PING_INTERVAL: Duration = Duration::from_millis(200);
+// Wait this long for a pong before counting the ping lost; the robot answers
+// in the ack for the next packet it gets, so this allows plenty of losses
+const PONG_TIMEOUT: Duration = Duration::from_millis(200);
+// Samples kept for percentiles, around 20s worth of pings
+const LATENCY_SAMPLES: usize = 100;
+
+/// Latency distribution over the recent samples
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Percentiles {
+    /// How many samples these are over
+    pub samples: usize,
+    /// Median
+    pub p50: Duration,
+    /// 90th percentile
+    pub p90: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    /// Slowest
+    pub max: Duration,
+}
+
+impl Percentiles {
+    fn from_samples(samples: &VecDeque<Duration>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort_unstable();
+        // Nearest-rank, which is plenty with this few samples
+        let rank = |percent: usize| sorted[(sorted.len() * percent).div_ceil(100).max(1) - 1];
+        Some(Self {
+            samples: sorted.len(),
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// Recent latency figures, as reported to the diagnostics view
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    /// From sending a ping to its pong arriving in an ack payload
+    pub rtt: Option<Percentiles>,
+    /// From the joystick event's kernel timestamp to the robot taking the
+    /// command carrying it. The firmware handles a command in the same pass
+    /// of its loop as it reads it, so this is to the ack, or with
+    /// authentication to the receipt a packet later
+    pub input_to_air: Option<Percentiles>,
+    /// Pings sent since the start
+    pub pings_sent: u32,
+    /// Pings never acked, or acked without a pong following in time
+    pub pings_lost: u32,
+}
+
+/// Sends pings and collects round-trip and input-to-air latency samples
+///
+/// A pong can only come back in the ack for a later packet, once the robot
+/// has handled the ping, so the ping is remembered and matched against the
+/// telemetry from whatever goes out next.
+pub(crate) struct LatencyMonitor {
+    next_seq: u8,
+    last_ping: Option<Instant>,
+    // Acked ping still waiting for its pong, and when it was sent
+    outstanding: Option<(u8, Instant)>,
+    rtt: VecDeque<Duration>,
+    input_to_air: VecDeque<Duration>,
+    // Earliest input event not yet acked by the robot
+    pending_input: Option<SystemTime>,
+    pings_sent: u32,
+    pings_lost: u32,
+}
+
+impl Default for LatencyMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self {
+            next_seq: 0,
+            last_ping: None,
+            outstanding: None,
+            rtt: VecDeque::with_capacity(LATENCY_SAMPLES),
+            input_to_air: VecDeque::with_capacity(LATENCY_SAMPLES),
+            pending_input: None,
+            pings_sent: 0,
+            pings_lost: 0,
+        }
+    }
+
+    /// Command for an otherwise idle slot: a no-op to carry an outstanding
+    /// pong back, or a ping if one is due, counting it as sent
+    pub fn next_command(&mut self, now: Instant) -> Option<Command> {
+        if let Some((_, sent_at)) = self.outstanding {
+            if now.saturating_duration_since(sent_at) < PONG_TIMEOUT {
+                return Some(Command::Noop);
+            }
+            self.outstanding = None;
+            self.pings_lost += 1;
+        }
+        if self
+            .last_ping
+            .is_some_and(|last| now.saturating_duration_since(last) < PING_INTERVAL)
+        {
+            return None;
+        }
+        self.last_ping = Some(now);
+        self.pings_sent += 1;
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Some(Command::Ping(seq))
+    }
+
+    /// Records whether a ping was acked; if so its pong is looked for in
+    /// later acks
+    pub fn record_ping(&mut self, seq: u8, sent_at: Instant, acked: bool) {
+        if acked {
+            self.outstanding = Some((seq, sent_at));
+        } else {
+            self.pings_lost += 1;
+        }
+    }
+
+    /// Looks for the outstanding ping's pong in telemetry from an ack
+    pub fn record_telemetry(&mut self, telemetry: &[Telemetry], now: Instant) {
+        if let Some((seq, sent_at)) = self.outstanding {
+            if telemetry.contains(&Telemetry::Pong(seq)) {
+                self.outstanding = None;
+                push_sample(&mut self.rtt, now.saturating_duration_since(sent_at));
+            }
+        }
+    }
+
+    /// Records the kernel timestamp of an input event which changed the control state
+    pub fn record_input(&mut self, time: SystemTime) {
+        // Later events are covered by the same command, so time from the first
+        self.pending_input.get_or_insert(time);
+    }
+
+    /// Records a state update (drive or camera command) being delivered, as
+    /// shown by its ack or receipt
+    pub fn record_state_delivered(&mut self, now: SystemTime) {
+        if let Some(input) = self.pending_input.take() {
+            // Clock steps can put the event in the future, which isn't a sample
+            if let Ok(latency) = now.duration_since(input) {
+                push_sample(&mut self.input_to_air, latency);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        LatencyStats {
+            rtt: Percentiles::from_samples(&self.rtt),
+            input_to_air: Percentiles::from_samples(&self.input_to_air),
+            pings_sent: self.pings_sent,
+            pings_lost: self.pings_lost,
+        }
+    }
+}
+
+fn push_sample(samples: &mut VecDeque<Duration>, sample: Duration) {
+    if samples.len() >= LATENCY_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn pong_from_a_later_ack_is_a_sample() {
+        let start = Instant::now();
+        let mut latency = LatencyMonitor::new();
+        assert_eq!(latency.next_command(start), Some(Command::Ping(0)));
+        latency.record_ping(0, start, true);
+        // Idle slots carry no-ops until the pong turns up
+        assert_eq!(latency.next_command(start + TICK), Some(Command::Noop));
+        latency.record_telemetry(&[Telemetry::Noop], start + TICK);
+        latency.record_telemetry(&[Telemetry::Pong(0)], start + TICK * 2);
+        assert_eq!(latency.next_command(start + TICK * 3), None);
+
+        let stats = latency.stats();
+        assert_eq!(stats.pings_sent, 1);
+        assert_eq!(stats.pings_lost, 0);
+        let rtt = stats.rtt.unwrap();
+        assert_eq!(rtt.samples, 1);
+        assert_eq!(rtt.max, TICK * 2);
+    }
+
+    #[test]
+    fn pong_for_another_ping_is_ignored() {
+        let start = Instant::now();
+        let mut latency = LatencyMonitor::new();
+        latency.next_command(start);
+        latency.record_ping(0, start, true);
+        latency.record_telemetry(&[Telemetry::Pong(7)], start + TICK);
+        assert_eq!(latency.stats().rtt, None);
+        assert_eq!(latency.next_command(start + TICK), Some(Command::Noop));
+    }
+
+    #[test]
+    fn unacked_ping_is_lost() {
+        let start = Instant::now();
+        let mut latency = LatencyMonitor::new();
+        latency.next_command(start);
+        latency.record_ping(0, start, false);
+        assert_eq!(latency.stats().pings_lost, 1);
+        // Nothing to wait for, and the next ping isn't due yet
+        assert_eq!(latency.next_command(start + TICK), None);
+        assert_eq!(
+            latency.next_command(start + PING_INTERVAL),
+            Some(Command::Ping(1))
+        );
+    }
+
+    #[test]
+    fn unanswered_ping_times_out() {
+        let start = Instant::now();
+        let mut latency = LatencyMonitor::new();
+        latency.next_command(start);
+        latency.record_ping(0, start, true);
+        let later = start + PONG_TIMEOUT.max(PING_INTERVAL);
+        assert_eq!(latency.next_command(later), Some(Command::Ping(1)));
+        assert_eq!(latency.stats().pings_lost, 1);
+        // A late pong for the lost ping doesn't count
+        latency.record_ping(1, later, true);
+        latency.record_telemetry(&[Telemetry::Pong(0)], later + TICK);
+        assert_eq!(latency.stats().rtt, None);
+    }
+}
@@ -0,0 +1,36 @@
+//! Controller for the skelebot: joystick and keyboard input, the control state
+//! they drive, and the radio loop sending it to the robot over a Crazyradio or
+//! UDP, with link management, telemetry and a simulator standing in for the
+//! robot
+//!
+//! The `controller` binary is the terminal UI, command line, survey and
+//! discovery on top; the pieces here are for other frontends and tools. A
+//! frontend opens [`radio::radio_channel`], runs [`radio::radio_comms`] (or
+//! [`sim::simulate`]) on a thread with a shared [`actions::ControlState`], and
+//! reads back [`actions::Action`]s for telemetry and link status. Settings
+//! come from [`config::Config`]. The wire protocol is re-exported as
+//! [`protocol`].
+
+#![warn(missing_docs)]
+
+pub mod actions;
+pub mod auth;
+pub mod channel;
+pub mod config;
+pub mod diversity;
+pub mod fleet;
+mod hotplug;
+pub mod joystick;
+pub mod latency;
+pub mod link;
+pub mod params;
+pub mod radio;
+mod receipts;
+pub mod rf;
+mod scheduler;
+pub mod sim;
+pub mod telemetry;
+mod ticker;
+pub mod transport;
+
+pub use skelebot_protocol as protocol;
@@ -0,0 +1,200 @@
+//! Rolling link quality from acks
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::transport::AckInfo;
+
+/// Period over which rolling link statistics are calculated
+pub const LINK_STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Rolling link quality, as reported to the UI
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    /// Packets sent in the window
+    pub packets: u32,
+    /// Percentage of packets in the window with no ack
+    pub loss_percent: f32,
+    /// Retransmissions per second over the window
+    pub retries_per_sec: f32,
+    /// Percentage of received acks over the power detector threshold
+    pub power_detector_percent: f32,
+    /// Failed packets since the last ack, regardless of window
+    pub consecutive_failures: u32,
+}
+
+struct LinkSample {
+    time: Instant,
+    received: bool,
+    retry: usize,
+    power_detector: bool,
+}
+
+/// Collects per-packet ack results into rolling link statistics
+pub(crate) struct LinkMonitor {
+    samples: VecDeque<LinkSample>,
+    consecutive_failures: u32,
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkMonitor {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Records the ack (or lack thereof) for a sent packet
+    pub fn record_ack(&mut self, ack: &AckInfo, time: Instant) {
+        if ack.received {
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        }
+        self.push(LinkSample {
+            time,
+            received: ack.received,
+            retry: ack.retry,
+            power_detector: ack.power_detector,
+        });
+    }
+
+    /// Records a packet which couldn't be sent at all, eg on transport error
+    pub fn record_failure(&mut self, time: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.push(LinkSample {
+            time,
+            received: false,
+            retry: 0,
+            power_detector: false,
+        });
+    }
+
+    /// Clears all samples, eg after changing channel
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.consecutive_failures = 0;
+    }
+
+    pub fn stats(&self, now: Instant) -> LinkStats {
+        let mut packets = 0_u32;
+        let mut received = 0_u32;
+        let mut retries = 0_usize;
+        let mut power_detector = 0_u32;
+        for sample in self.samples.iter() {
+            if now.saturating_duration_since(sample.time) > LINK_STATS_WINDOW {
+                continue;
+            }
+            packets += 1;
+            retries += sample.retry;
+            if sample.received {
+                received += 1;
+                if sample.power_detector {
+                    power_detector += 1;
+                }
+            }
+        }
+
+        let loss_percent = if packets > 0 {
+            100.0 * (packets - received) as f32 / packets as f32
+        } else {
+            0.0
+        };
+        let power_detector_percent = if received > 0 {
+            100.0 * power_detector as f32 / received as f32
+        } else {
+            0.0
+        };
+
+        LinkStats {
+            packets,
+            loss_percent,
+            retries_per_sec: retries as f32 / LINK_STATS_WINDOW.as_secs_f32(),
+            power_detector_percent,
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+
+    fn push(&mut self, sample: LinkSample) {
+        let time = sample.time;
+        self.samples.push_back(sample);
+        while let Some(oldest) = self.samples.front() {
+            if time.saturating_duration_since(oldest.time) <= LINK_STATS_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn ack(received: bool, retry: usize, power_detector: bool) -> AckInfo {
+        AckInfo {
+            received,
+            power_detector,
+            retry,
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn stats_cover_loss_retries_and_power_detector() {
+        let start = Instant::now();
+        let mut monitor = LinkMonitor::new();
+        monitor.record_ack(&ack(true, 1, true), start);
+        monitor.record_ack(&ack(true, 0, false), start + TICK);
+        monitor.record_ack(&ack(false, 3, false), start + TICK * 2);
+        monitor.record_failure(start + TICK * 3);
+
+        let stats = monitor.stats(start + TICK * 3);
+        assert_eq!(stats.packets, 4);
+        assert_eq!(stats.loss_percent, 50.0);
+        assert_eq!(stats.retries_per_sec, 4.0);
+        assert_eq!(stats.power_detector_percent, 50.0);
+        assert_eq!(stats.consecutive_failures, 2);
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let start = Instant::now();
+        let mut monitor = LinkMonitor::new();
+        monitor.record_failure(start);
+        monitor.record_ack(&ack(true, 0, false), start + LINK_STATS_WINDOW);
+        let later = start + LINK_STATS_WINDOW + TICK;
+        let stats = monitor.stats(later);
+        assert_eq!(stats.packets, 1);
+        assert_eq!(stats.loss_percent, 0.0);
+        assert_eq!(
+            monitor.stats(later + LINK_STATS_WINDOW),
+            LinkStats::default()
+        );
+    }
+
+    #[test]
+    fn consecutive_failures_outlast_the_window_until_an_ack() {
+        let start = Instant::now();
+        let mut monitor = LinkMonitor::new();
+        for tick in 0..3 {
+            monitor.record_failure(start + TICK * tick);
+        }
+        let later = start + LINK_STATS_WINDOW * 2;
+        assert_eq!(monitor.stats(later).consecutive_failures, 3);
+        monitor.record_ack(&ack(true, 0, false), later);
+        assert_eq!(monitor.stats(later).consecutive_failures, 0);
+
+        monitor.record_failure(later);
+        monitor.reset();
+        assert_eq!(monitor.stats(later), LinkStats::default());
+    }
+}
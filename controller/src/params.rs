@@ -0,0 +1,230 @@
+//! Reading and editing the robot's stored parameters
+
+use skelebot_protocol::{Command, Param};
+
+// Larger steps move this many small ones
+const LARGE_STEP: i32 = 10;
+
+/// What the controller knows of one parameter on the robot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParamState {
+    /// Value last reported by the robot, if any
+    pub value: Option<u16>,
+    /// Value sent but not yet confirmed
+    pub requested: Option<u16>,
+    // Sets sent without a reply yet, so only the last reply decides rejection
+    outstanding: u32,
+}
+
+/// Parameter editor, as driven from the keyboard and shown by the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParamEditor {
+    /// Whether the editor is showing
+    pub open: bool,
+    /// Index into [`Param::ALL`] of the parameter being edited
+    pub selected: usize,
+    /// What's known of each parameter, in [`Param::ALL`] order
+    pub params: [ParamState; Param::ALL.len()],
+    /// Changes made since the last commit, which would be lost on a reboot
+    pub unsaved: bool,
+}
+
+/// A value the robot didn't take, as it was out of range or out of order
+pub struct Rejected {
+    /// Parameter that was set
+    pub param: Param,
+    /// Value that was sent
+    pub requested: u16,
+    /// Value the robot kept instead
+    pub value: u16,
+}
+
+impl Default for ParamEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParamEditor {
+    /// Closed, with nothing known of the robot's parameters
+    pub fn new() -> Self {
+        Self {
+            open: false,
+            selected: 0,
+            params: [ParamState::default(); Param::ALL.len()],
+            unsaved: false,
+        }
+    }
+
+    /// The parameter being edited
+    pub fn selected_param(&self) -> Param {
+        Param::ALL[self.selected]
+    }
+
+    /// Opens or closes the editor, returning commands to read every parameter
+    /// on opening
+    pub fn toggle(&mut self) -> Vec<Command> {
+        self.open = !self.open;
+        if self.open {
+            self.reload()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Forgets what's known, returning commands to read every parameter again
+    pub fn reload(&mut self) -> Vec<Command> {
+        self.params = [ParamState::default(); Param::ALL.len()];
+        Param::ALL.iter().copied().map(Command::ParamGet).collect()
+    }
+
+    /// Moves to the next or previous parameter, wrapping around
+    pub fn select(&mut self, forward: bool) {
+        let len = Param::ALL.len();
+        self.selected = if forward {
+            (self.selected + 1) % len
+        } else {
+            (self.selected + len - 1) % len
+        };
+    }
+
+    /// Moves the selected parameter by a number of steps, returning the command
+    /// to set it, or None if its value isn't known yet or it's at the limit
+    pub fn nudge(&mut self, steps: i32, large: bool) -> Option<Command> {
+        let param = self.selected_param();
+        let state = &mut self.params[self.selected];
+        let from = state.requested.or(state.value)?;
+        let steps = if large { steps * LARGE_STEP } else { steps };
+        let (min, max) = param.range();
+        let to = (i32::from(from) + steps * i32::from(step(param))).clamp(min.into(), max.into());
+        let to = u16::try_from(to).ok()?;
+        if to == from {
+            return None;
+        }
+        state.requested = Some(to);
+        state.outstanding += 1;
+        self.unsaved = true;
+        Some(Command::ParamSet { param, value: to })
+    }
+
+    /// Returns the command to save the current values on the robot
+    pub fn commit(&mut self) -> Command {
+        self.unsaved = false;
+        Command::ParamCommit
+    }
+
+    /// Records a value reported by the robot, returning the rejected request
+    /// if it was the answer to a set and differs from what was asked for
+    pub fn record(&mut self, param: Param, value: u16) -> Option<Rejected> {
+        let state = &mut self.params[param.to_u8() as usize];
+        state.value = Some(value);
+        state.outstanding = state.outstanding.saturating_sub(1);
+        let requested = state.requested?;
+        if requested == value {
+            state.requested = None;
+            state.outstanding = 0;
+            return None;
+        }
+        if state.outstanding > 0 {
+            // An earlier set's answer, the latest one is still on its way
+            return None;
+        }
+        state.requested = None;
+        Some(Rejected {
+            param,
+            requested,
+            value,
+        })
+    }
+}
+
+// Smallest useful change for a parameter
+fn step(param: Param) -> u16 {
+    match param {
+        Param::ConnLossMs | Param::AckPayloadMs => 10,
+        _ => 1,
+    }
+}
+
+/// Describes a parameter command for messages
+pub(crate) fn describe_param_command(command: Command) -> String {
+    match command {
+        Command::ParamGet(param) => format!("read of {}", param),
+        Command::ParamSet { param, value } => format!("{} = {}", param, value),
+        Command::ParamCommit => String::from("parameter save"),
+        _ => format!("{:?}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Opens the editor with every parameter reported at the given value
+    fn opened(value: u16) -> ParamEditor {
+        let mut editor = ParamEditor::new();
+        assert_eq!(editor.toggle().len(), Param::ALL.len());
+        for param in Param::ALL {
+            assert!(editor.record(param, value).is_none());
+        }
+        editor
+    }
+
+    #[test]
+    fn nudge_waits_for_the_value_and_clamps_to_range() {
+        let mut editor = ParamEditor::new();
+        editor.toggle();
+        assert_eq!(editor.nudge(1, false), None);
+
+        let mut editor = opened(599);
+        assert_eq!(
+            editor.nudge(1, false),
+            Some(Command::ParamSet {
+                param: Param::ServoPanMin,
+                value: 600
+            })
+        );
+        // Already at the top, so nothing to send
+        assert_eq!(editor.nudge(1, true), None);
+        assert!(editor.unsaved);
+        assert_eq!(editor.commit(), Command::ParamCommit);
+        assert!(!editor.unsaved);
+    }
+
+    #[test]
+    fn large_steps_use_the_parameters_step() {
+        let mut editor = opened(500);
+        editor.select(false);
+        assert_eq!(editor.selected_param(), Param::AckPayloadMs);
+        assert_eq!(
+            editor.nudge(-1, true),
+            Some(Command::ParamSet {
+                param: Param::AckPayloadMs,
+                value: 400
+            })
+        );
+    }
+
+    #[test]
+    fn only_the_last_answer_decides_rejection() {
+        let mut editor = opened(300);
+        editor.nudge(1, false);
+        editor.nudge(1, false);
+        // The first set's answer, with the second still on its way
+        assert!(editor.record(Param::ServoPanMin, 301).is_none());
+        assert_eq!(editor.params[0].requested, Some(302));
+        let rejected = editor.record(Param::ServoPanMin, 301).unwrap();
+        assert_eq!(rejected.requested, 302);
+        assert_eq!(rejected.value, 301);
+        assert_eq!(editor.params[0].requested, None);
+    }
+
+    #[test]
+    fn accepted_set_clears_the_request() {
+        let mut editor = opened(300);
+        editor.nudge(-1, false);
+        assert!(editor.record(Param::ServoPanMin, 299).is_none());
+        assert_eq!(editor.params[0].value, Some(299));
+        assert_eq!(editor.params[0].requested, None);
+    }
+}
@@ -1,215 +1,834 @@
+//! The radio thread: sending control state to the robot each tick and handing
+//! telemetry back
+
+use std::error::Error;
+use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use crazyradio::{self, Channel, Crazyradio, Datarate};
+use skelebot_protocol::auth::{self, AuthKey, Authenticator};
+use skelebot_protocol::{
+    Command, DataRate, PaLevel, Telemetry, TelemetryItems, MAX_ITEM_LEN, MAX_TELEMETRY_LEN,
+};
 
 use crate::actions::{
-    record_ticks_for_period, send_error_message, send_message, Action, BatteryCurrent,
-    BatteryVoltage, ControlState, RECORD_TICKS_INTERVAL,
+    send_error_message, send_message, Action, ControlState, RECORD_TICKS_INTERVAL,
 };
+use crate::channel::{AutoMigrate, ChannelStatus, ChannelSwitch, SwitchOutcome};
+use crate::config::DriveConfig;
+use crate::diversity::{RadioSet, SetAck, RADIO_RETRY_INTERVAL};
+use crate::fleet::{FleetStatus, Handover, Robot, StopOutcome};
+use crate::hotplug::UsbHotplug;
+use crate::latency::LatencyMonitor;
+use crate::link::LinkMonitor;
+use crate::params::describe_param_command;
+use crate::receipts::{Confirmations, ReceiptTracker};
+use crate::rf::{AutoRate, Tuning};
+use crate::scheduler::{OneShotQueue, TxScheduler};
+use crate::ticker::{TickStats, Ticker, Waker};
+use crate::transport::{format_address, AckInfo, RadioStatus, TransportKind};
+
+/// Requests from the main thread to the radio thread
+#[derive(Debug)]
+pub enum RadioRequest {
+    /// Move to another channel, keeping the current data rate and PA level
+    ChangeChannel(u8),
+    /// Move to other radio settings altogether
+    Retune(Tuning),
+    /// Turn moving to a quieter channel when the link degrades on or off
+    ToggleAutoMigrate,
+    /// Turn dropping to a slower data rate when the link degrades on or off
+    ToggleAutoRate,
+    /// Drive the robot at this fleet index, stopping the others first
+    SelectRobot(usize),
+    /// Parameter get, set or commit for the active robot
+    Param(Command),
+    /// Switch the active robot's video transmitter on or off
+    SetVtx(bool),
+    /// Control state was updated, so check for anything to send right away;
+    /// includes the input event's timestamp if it came from the joystick
+    ControlChanged(Option<SystemTime>),
+}
 
-enum SendStateType {
-    DRIVE,
-    CAMERA,
+/// The radios and robots the radio thread works with, and how it looks after
+/// the link
+#[derive(Clone, Debug)]
+pub struct RadioOptions {
+    /// Radios to send every packet on, always at least one
+    pub transports: Vec<TransportKind>,
+    /// Addresses of the fleet, always at least one; the first is driven first
+    pub addresses: Vec<[u8; 5]>,
+    /// Move to a quieter channel when the link degrades
+    pub auto_channel: bool,
+    /// Step down to a slower rate or higher power when the link degrades
+    pub auto_rate: bool,
+    /// Data rate the robots are moved to, and auto-rate steps down from
+    pub rate: DataRate,
+    /// PA level the robots are moved to, and auto-rate steps up from
+    pub power: PaLevel,
 }
 
 const RADIO_LOOP_INTERVAL: Duration = Duration::from_millis(10);
-const MAX_ACK_PAYLOAD: u8 = 4;
+const LINK_STATS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sends requests to the radio thread, waking it so they're handled right away
+#[derive(Clone)]
+pub struct RadioSender {
+    tx: Sender<RadioRequest>,
+    waker: Waker,
+}
+
+impl RadioSender {
+    /// Queues a request, returning it if the radio thread has gone
+    pub fn send(&self, request: RadioRequest) -> Result<(), SendError<RadioRequest>> {
+        self.tx.send(request)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+/// Receiving end of `RadioSender`, along with the timer driving the radio loop
+pub struct RadioReceiver {
+    pub(crate) rx: Receiver<RadioRequest>,
+    pub(crate) ticker: Ticker,
+}
+
+/// Creates the channel for requests to the radio thread, ticking every 10ms
+pub fn radio_channel() -> io::Result<(RadioSender, RadioReceiver)> {
+    let (tx, rx) = mpsc::channel::<RadioRequest>();
+    let (ticker, waker) = Ticker::new(RADIO_LOOP_INTERVAL)?;
+    Ok((RadioSender { tx, waker }, RadioReceiver { rx, ticker }))
+}
 
+/// Runs the radio thread until the exit flag is set: sends the control state
+/// to the robots in `options` on each tick, handles requests, and reports
+/// telemetry and link status back as actions
 pub fn radio_comms(
     tx: Sender<Action>,
+    requests: RadioReceiver,
     control_state_mutex: Arc<Mutex<ControlState>>,
     exit_flag: &AtomicBool,
+    options: &RadioOptions,
+    drive: &DriveConfig,
+    auth_key: Option<AuthKey>,
 ) {
-    let mut prev_marker = Instant::now();
-    let mut next_marker = prev_marker + RECORD_TICKS_INTERVAL;
-    let mut ticks = 0_u32;
-
-    let channel: u8 = 76; // Later make this mutable
-    let mut radio: Option<Crazyradio> = None;
-    let mut state_type: SendStateType = SendStateType::DRIVE;
-
-    'outer: loop {
-        // Attempt finding crazyradio device
-        if radio.is_none() {
-            match init_crazyradio(channel) {
-                Ok(cr) => {
-                    if let Ok(serial) = cr.serial() {
-                        let msg = format!("initialized radio, serial {}", serial);
-                        send_message(&tx, "Radio", &msg);
-                    }
-                    radio = Some(cr);
-                }
+    let RadioReceiver { rx, mut ticker } = requests;
+    let mut radio = RadioLoop::new(tx, options, drive, auth_key);
+    let mut tick_due = true;
+
+    loop {
+        // Handle any pending requests from the main thread
+        for request in rx.try_iter() {
+            radio.handle_request(request);
+        }
+
+        radio.send(&control_state_mutex, tick_due);
+
+        // Wait for the next tick, waking early for requests such as control changes
+        tick_due = match ticker.wait() {
+            Ok(tick_due) => tick_due,
+            Err(e) => {
+                let msg = format!("couldn't wait for radio timer: {}", e);
+                send_error_message(&radio.tx, "Radio", &msg);
+                sleep(RADIO_LOOP_INTERVAL);
+                true
+            }
+        };
+
+        let curr_time = Instant::now();
+        if curr_time >= radio.next_link_stats {
+            radio.check_link(curr_time);
+        }
+        if curr_time >= radio.next_marker {
+            radio.record_timing(ticker.take_stats(), curr_time);
+        }
+
+        if exit_flag.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+}
+
+// Everything the radio thread keeps track of between ticks
+struct RadioLoop<'a> {
+    tx: Sender<Action>,
+    options: &'a RadioOptions,
+    robots: Vec<Robot>,
+    active: usize,
+    handover: Option<Handover>,
+    switch: Option<ChannelSwitch>,
+    auto_migrate: AutoMigrate,
+    auto_rate: AutoRate,
+    radios: RadioSet,
+    radio_status: Option<RadioStatus>,
+    hotplug: Option<UsbHotplug>,
+    scheduler: TxScheduler,
+    link_monitor: LinkMonitor,
+    latency: LatencyMonitor,
+    one_shots: OneShotQueue,
+    receipts: Option<ReceiptTracker>,
+    authenticator: Option<Authenticator>,
+    next_link_stats: Instant,
+    // Timing and packet counts reported every RECORD_TICKS_INTERVAL
+    prev_marker: Instant,
+    next_marker: Instant,
+    packets_sent: u32,
+    packets_acked: u32,
+}
+
+impl<'a> RadioLoop<'a> {
+    // Sets up for the robots in `options`, reporting the starting status
+    fn new(
+        tx: Sender<Action>,
+        options: &'a RadioOptions,
+        drive: &DriveConfig,
+        auth_key: Option<AuthKey>,
+    ) -> Self {
+        let robots: Vec<Robot> = options.addresses.iter().copied().map(Robot::new).collect();
+        let now = Instant::now();
+
+        // Only USB radios can be hot-plugged; everything's retried periodically too
+        let usb_radios = options
+            .transports
+            .iter()
+            .any(|kind| matches!(kind, TransportKind::Crazyradio { .. }));
+        let hotplug = if usb_radios {
+            match UsbHotplug::new() {
+                Ok(hotplug) => Some(hotplug),
                 Err(e) => {
-                    let msg = format!("couldn't open radio device: {}", e);
+                    let msg = format!(
+                        "couldn't watch for USB hot-plug: {}, retrying radio every {}s",
+                        e,
+                        RADIO_RETRY_INTERVAL.as_secs()
+                    );
                     send_error_message(&tx, "Radio", &msg);
+                    None
                 }
             }
+        } else {
+            None
+        };
+
+        let radio = Self {
+            radios: RadioSet::new(&options.transports, robots[0].tuning),
+            robots,
+            active: 0,
+            handover: None,
+            switch: None,
+            auto_migrate: AutoMigrate::new(options.auto_channel),
+            auto_rate: AutoRate::new(options.auto_rate, options.rate, options.power),
+            radio_status: None,
+            hotplug,
+            scheduler: TxScheduler::new(*drive),
+            link_monitor: LinkMonitor::new(),
+            latency: LatencyMonitor::new(),
+            one_shots: OneShotQueue::new(),
+            receipts: auth_key.clone().map(ReceiptTracker::new),
+            authenticator: auth_key.map(Authenticator::new),
+            next_link_stats: now + LINK_STATS_INTERVAL,
+            prev_marker: now,
+            next_marker: now + RECORD_TICKS_INTERVAL,
+            packets_sent: 0,
+            packets_acked: 0,
+            tx,
+            options,
+        };
+        radio.send_channel_status();
+        radio.send_fleet_status();
+        if radio.robots.len() > 1 {
+            let msg = format!("driving {}", describe_robot(&radio.robots, radio.active));
+            send_message(&radio.tx, "Radio", &msg);
         }
-        if let Some(ref mut cr) = radio {
-            let control_state = {
-                let control_state = control_state_mutex.lock().unwrap();
-                control_state.clone()
-            };
-            match send_state_update(cr, control_state, &state_type) {
-                Ok(ack_data) => {
-                    receive_ack_data(&tx, ack_data);
-                }
-                Err(e) => {
-                    let msg = format!("couldn't transmit update: {}", e);
-                    send_error_message(&tx, "Radio", &msg);
+        radio
+    }
+
+    fn handle_request(&mut self, request: RadioRequest) {
+        let tx = &self.tx;
+        match request {
+            RadioRequest::ChangeChannel(_) | RadioRequest::Retune(_) => {
+                let from = self.robots[self.active].tuning;
+                let to = match request {
+                    RadioRequest::ChangeChannel(channel) => from.with_channel(channel),
+                    RadioRequest::Retune(to) => to,
+                    _ => unreachable!(),
+                };
+                if let Some(ref ho) = self.handover {
+                    let msg = format!(
+                        "can't change to {}, handing over to robot {}",
+                        describe_tuning(to, from),
+                        ho.to() + 1
+                    );
+                    send_error_message(tx, "Radio", &msg);
+                } else if let Some(ref sw) = self.switch {
+                    let msg = format!(
+                        "can't change to {}, already changing to {}",
+                        describe_tuning(to, from),
+                        describe_tuning(sw.to(), sw.from())
+                    );
+                    send_error_message(tx, "Radio", &msg);
+                } else if to != from {
+                    let msg = format!(
+                        "changing from {} to {}",
+                        describe_tuning(from, to),
+                        describe_tuning(to, from)
+                    );
+                    send_message(tx, "Radio", &msg);
+                    self.switch = Some(ChannelSwitch::new(from, to, Instant::now()));
+                    self.send_channel_status();
                 }
             }
-            // Alternate state updates
-            match state_type {
-                SendStateType::DRIVE => {
-                    state_type = SendStateType::CAMERA;
+            RadioRequest::ToggleAutoMigrate => {
+                self.auto_migrate.enabled = !self.auto_migrate.enabled;
+                let msg = if self.auto_migrate.enabled {
+                    "automatic channel migration enabled"
+                } else {
+                    "automatic channel migration disabled"
+                };
+                send_message(tx, "Radio", msg);
+                self.send_channel_status();
+            }
+            RadioRequest::ToggleAutoRate if !self.auto_rate.has_steps() => {
+                let msg = format!(
+                    "no slower rate or higher power to step to from {} at {} power",
+                    self.options.rate, self.options.power
+                );
+                send_error_message(tx, "Radio", &msg);
+            }
+            RadioRequest::ToggleAutoRate => {
+                self.auto_rate.enabled = !self.auto_rate.enabled;
+                let msg = if self.auto_rate.enabled {
+                    "automatic data rate and power enabled"
+                } else {
+                    "automatic data rate and power disabled"
+                };
+                send_message(tx, "Radio", msg);
+                self.send_channel_status();
+            }
+            RadioRequest::SelectRobot(to) => {
+                if to >= self.robots.len() {
+                    let msg = format!("no robot {}, only {} configured", to + 1, self.robots.len());
+                    send_error_message(tx, "Radio", &msg);
+                } else if let Some(ref sw) = self.switch {
+                    let msg = format!(
+                        "can't select robot {} while changing to {}",
+                        to + 1,
+                        describe_tuning(sw.to(), sw.from())
+                    );
+                    send_error_message(tx, "Radio", &msg);
+                } else if self.handover.is_some() {
+                    let msg = format!("can't select robot {}, already handing over", to + 1);
+                    send_error_message(tx, "Radio", &msg);
+                } else if to != self.active {
+                    let msg = format!("stopping robots before driving robot {}", to + 1);
+                    send_message(tx, "Radio", &msg);
+                    self.handover = Some(Handover::new(self.robots.len(), self.active, to));
+                    self.send_fleet_status();
                 }
-                SendStateType::CAMERA => {
-                    state_type = SendStateType::DRIVE;
+            }
+            RadioRequest::Param(command) => {
+                self.one_shots.push(command);
+            }
+            RadioRequest::SetVtx(on) => {
+                self.one_shots.push(Command::SetVtx(on));
+            }
+            RadioRequest::ControlChanged(input_time) => {
+                // Otherwise nothing to do, the scheduler picks up the change when sending
+                if let Some(input_time) = input_time {
+                    self.latency.record_input(input_time);
                 }
             }
         }
+    }
 
-        // TODO: switch to timerfd
-        sleep(RADIO_LOOP_INTERVAL);
-        ticks += 1;
-
+    // Sends whatever's due to the robot being driven (or stopped, during a
+    // handover), and follows up on what its ack showed
+    fn send(&mut self, control_state_mutex: &Mutex<ControlState>, tick_due: bool) {
         let curr_time = Instant::now();
-        if curr_time >= next_marker {
-            // Send message with loop count for period
-            record_ticks_for_period(&tx, "Radio", ticks, prev_marker, curr_time);
 
-            // Set next marker, ensuring in the future
-            ticks = 0;
-            prev_marker = next_marker;
-            while next_marker < curr_time {
-                next_marker += RECORD_TICKS_INTERVAL;
+        // Attempt opening any radios not open yet (usually finding crazyradio devices)
+        let stopping = self.handover.as_ref().and_then(|ho| ho.stopping());
+        let robot_idx = stopping.unwrap_or(self.active);
+        let wanted_robot = self.robots[robot_idx];
+        let wanted = match self.switch {
+            Some(ref sw) => sw.tuning(),
+            None => wanted_robot.tuning,
+        };
+        let hotplugged = self.hotplug.as_mut().is_some_and(|hp| hp.devices_added());
+        if self
+            .radios
+            .open_missing(&self.tx, wanted, hotplugged, curr_time)
+        {
+            self.scheduler.reset();
+        }
+        self.update_radio_status();
+        if self.radios.is_connected() {
+            self.radios.tune(&self.tx, wanted, &wanted_robot.address);
+
+            let control_state = {
+                let control_state = control_state_mutex.lock().unwrap();
+                *control_state
+            };
+            let command = self.next_command(control_state, stopping.is_some(), tick_due, curr_time);
+            if let Some(command) = command {
+                let confirmations = self.exchange(command, robot_idx, stopping.is_some());
+                if self.handover.is_some() {
+                    self.record_stop(&confirmations);
+                } else if self.switch.is_some() {
+                    self.record_switch(command, &confirmations);
+                }
             }
+        } else if tick_due {
+            // Nothing sent, but from the robot's point of view that's a lost packet
+            self.link_monitor.record_failure(Instant::now());
+            self.radios.record_missed(Instant::now());
         }
+        self.update_radio_status();
+    }
 
-        if exit_flag.load(Ordering::Relaxed) {
-            break 'outer;
+    // Stops during handover and channel change commands take priority over
+    // state updates, and send every tick, as they need to see acks to
+    // progress; otherwise the scheduler decides, including between ticks, and
+    // one-shot commands then pings (or no-ops to bring back their pongs) fill
+    // in the idle ticks
+    fn next_command(
+        &mut self,
+        control_state: ControlState,
+        stopping: bool,
+        tick_due: bool,
+        now: Instant,
+    ) -> Option<Command> {
+        if stopping {
+            return tick_due.then_some(Command::Stop);
         }
+        if let Some(ref sw) = self.switch {
+            return tick_due.then(|| {
+                sw.next_command()
+                    .unwrap_or_else(|| self.scheduler.keepalive_command(control_state))
+            });
+        }
+        // A one-shot waiting on its receipt isn't resent, but no-ops keep
+        // going out until the receipt comes back
+        let awaiting = self.receipts.as_ref().is_some_and(ReceiptTracker::awaiting);
+        let one_shot = self.one_shots.next_command().filter(|&command| {
+            !self
+                .receipts
+                .as_ref()
+                .is_some_and(|receipts| receipts.is_in_flight(command))
+        });
+        self.scheduler
+            .next_command(control_state, now)
+            .or_else(|| tick_due.then_some(one_shot).flatten())
+            .or_else(|| tick_due.then(|| self.latency.next_command(now)).flatten())
+            .or_else(|| (tick_due && awaiting).then_some(Command::Noop))
     }
-}
 
-fn init_crazyradio(channel: u8) -> Result<Crazyradio, crazyradio::Error> {
-    let channel = Channel::from_number(channel)?;
-    let mut cr = Crazyradio::open_first()?;
-    cr.set_datarate(Datarate::Dr250K)?;
-    cr.set_channel(channel)?;
-    cr.set_ard_time(Duration::from_millis(250))?; // This is the smallest per the source
-    cr.set_ard_bytes(MAX_ACK_PAYLOAD)?;
-    cr.set_arc(1)?; // Only retry once, we'd rather just move to the next command
-    Ok(cr)
-}
+    // Sends a command to the robot at `robot_idx`, recording what its ack
+    // showed was delivered; stops during handover go to other robots, so
+    // don't count towards the driven robot's state
+    fn exchange(&mut self, command: Command, robot_idx: usize, stopping: bool) -> Confirmations {
+        let tx = &self.tx;
+        let address = self.robots[robot_idx].address;
+        let sent_at = Instant::now();
+        let auth = self.authenticator.as_mut().map(|auth| (auth, &address));
+        let (acked, telemetry) = match send_command(&mut self.radios, tx, command, auth) {
+            Ok(SetAck { merged, acks }) => {
+                self.link_monitor.record_ack(&merged, Instant::now());
+                let telemetry: Vec<Telemetry> = acks
+                    .into_iter()
+                    .flat_map(|(ack, ack_data)| receive_ack_data(tx, robot_idx, ack, ack_data))
+                    .collect();
+                (merged.received, telemetry)
+            }
+            Err(e) => {
+                self.link_monitor.record_failure(Instant::now());
+                // Each radio's failure is reported as it's dropped
+                if self.radios.is_connected() {
+                    let msg = format!("couldn't send: {}", e);
+                    send_error_message(tx, "Radio", &msg);
+                }
+                (false, Vec::new())
+            }
+        };
+        self.packets_sent += 1;
+        if acked {
+            self.packets_acked += 1;
+        }
+        self.latency.record_telemetry(&telemetry, Instant::now());
+        if let Command::Ping(seq) = command {
+            self.latency.record_ping(seq, sent_at, acked);
+        }
+
+        // With authentication, the robot's radio acks frames the firmware then
+        // rejects, so only its receipts count
+        let confirmations = match (self.authenticator.as_ref(), self.receipts.as_mut()) {
+            (Some(auth), Some(receipts)) => receipts.exchange(
+                robot_idx,
+                &address,
+                (auth.counter(), command),
+                acked,
+                &telemetry,
+                Instant::now(),
+            ),
+            _ => Confirmations::from_ack(command, acked),
+        };
+        // The robot only says so when a frame was genuine but stale, eg after
+        // this controller restarted
+        let stale = telemetry.iter().find_map(|t| match t {
+            Telemetry::AuthStale(floor_high) => Some(*floor_high),
+            _ => None,
+        });
+        if let (Some(auth), Some(floor_high)) = (self.authenticator.as_mut(), stale) {
+            auth.resync(floor_high);
+            if let Some(ref mut receipts) = self.receipts {
+                receipts.clear();
+            }
+        }
+        if self
+            .receipts
+            .as_mut()
+            .is_some_and(|receipts| receipts.check_unconfirmed(Instant::now()))
+        {
+            let msg = "robot is acking but not confirming commands, \
+                is it built with this --auth-key?";
+            send_error_message(tx, "Radio", msg);
+        }
 
-// Maps signed +/- 100 to 0-200 for transmission
-fn map_percent_value(value: i8) -> u8 {
-    if value <= -100_i8 {
-        return 0_u8;
+        if let Some(front) = self.one_shots.next_command() {
+            let outcome = if confirmations.delivered.contains(&front) {
+                Some(true)
+            } else if confirmations.lost.contains(&front) {
+                Some(false)
+            } else {
+                None
+            };
+            let dropped = outcome.and_then(|delivered| self.one_shots.record(delivered));
+            if let Some(dropped) = dropped {
+                let msg = format!(
+                    "robot didn't take {}, giving up",
+                    describe_one_shot(dropped)
+                );
+                send_error_message(tx, "Radio", &msg);
+            }
+        }
+        if !stopping {
+            for &delivered in &confirmations.delivered {
+                self.scheduler.record(delivered, true, Instant::now());
+                if is_state_command(delivered) {
+                    self.latency.record_state_delivered(SystemTime::now());
+                }
+            }
+        }
+        confirmations
     }
-    if value >= 100_i8 {
-        return 200_u8;
+
+    // Moves the handover on with whether the robot being stopped took the
+    // stop, driving the new robot once they all have (or never answered)
+    fn record_stop(&mut self, confirmations: &Confirmations) {
+        let Some(ref mut ho) = self.handover else {
+            return;
+        };
+        match ho.record(confirmations.delivered.contains(&Command::Stop)) {
+            StopOutcome::InProgress => {}
+            StopOutcome::Stopped(_) => {}
+            StopOutcome::Unanswered(idx) => {
+                let msg = format!("{} didn't ack stop", describe_robot(&self.robots, idx));
+                send_error_message(&self.tx, "Radio", &msg);
+            }
+        }
+        if ho.stopping().is_some() {
+            return;
+        }
+        self.active = ho.to();
+        self.handover = None;
+        self.scheduler.reset();
+        self.one_shots.clear();
+        let msg = format!("driving {}", describe_robot(&self.robots, self.active));
+        send_message(&self.tx, "Radio", &msg);
+        self.link_monitor.reset();
+        self.radios.reset_links();
+        self.send_fleet_status();
+        self.send_channel_status();
     }
-    if value < 0_i8 {
-        return (value + 100_i8) as u8;
+
+    // Moves the channel switch on with whether the robot took `command`
+    fn record_switch(&mut self, command: Command, confirmations: &Confirmations) {
+        let Some(ref mut sw) = self.switch else {
+            return;
+        };
+        // The change command itself has to be taken, after which the robot
+        // answering at all on the new settings will do
+        let taken = match sw.next_command() {
+            Some(_) => confirmations.delivered.contains(&command),
+            None => confirmations.answered,
+        };
+        let (from, to) = (sw.from(), sw.to());
+        let tuning = match sw.record(taken, Instant::now()) {
+            SwitchOutcome::InProgress => {
+                return;
+            }
+            SwitchOutcome::Switched(new) => {
+                let msg = format!("changed to {}", describe_tuning(new, from));
+                send_message(&self.tx, "Radio", &msg);
+                new
+            }
+            SwitchOutcome::RolledBack(old) => {
+                let msg = format!(
+                    "robot not found on {}, rolled back to {}",
+                    describe_tuning(to, from),
+                    describe_tuning(old, to)
+                );
+                send_error_message(&self.tx, "Radio", &msg);
+                old
+            }
+            SwitchOutcome::Lost(old) => {
+                let msg = format!(
+                    "robot not found on {} or {}, staying on {}",
+                    describe_tuning(to, from),
+                    describe_tuning(from, to),
+                    describe_tuning(old, to)
+                );
+                send_error_message(&self.tx, "Radio", &msg);
+                old
+            }
+        };
+        self.robots[self.active].tuning = tuning;
+        self.switch = None;
+        self.link_monitor.reset();
+        self.radios.reset_links();
+        self.send_channel_status();
     }
-    return (value as u8) + 100_u8;
-}
 
-// Maps signed +/- 90 to 0-180 for transmission
-fn map_angular_value(value: i8) -> u8 {
-    if value <= -90_i8 {
-        return 0_u8;
+    // Reports the link's stats, and moves to a more robust rate, power or
+    // channel if it's been lossy for a while
+    fn check_link(&mut self, now: Instant) {
+        let stats = self.link_monitor.stats(now);
+        if self.tx.send(Action::LinkStatsUpdate(stats)).is_err() {
+            // Can happen during shutdown
+        }
+        if self
+            .tx
+            .send(Action::LatencyUpdate(self.latency.stats()))
+            .is_err()
+        {
+            // Can happen during shutdown
+        }
+        if self.radios.is_diverse()
+            && self
+                .tx
+                .send(Action::RadioLinksUpdate(self.radios.links(now)))
+                .is_err()
+        {
+            // Can happen during shutdown
+        }
+        // Fall back to a more robust rate or power before giving up on a
+        // persistently noisy channel, unless already moving
+        if self.switch.is_none() && self.handover.is_none() {
+            let from = self.robots[self.active].tuning;
+            let to = match self.auto_rate.check(&stats, from, now) {
+                Some(to) => Some(to),
+                None => self
+                    .auto_migrate
+                    .check(&stats, from.channel, now)
+                    .map(|channel| from.with_channel(channel)),
+            };
+            if let Some(to) = to {
+                let msg = format!(
+                    "{:.0}% loss on {}, moving to {}",
+                    stats.loss_percent,
+                    describe_tuning(from, to),
+                    describe_tuning(to, from)
+                );
+                send_message(&self.tx, "Radio", &msg);
+                self.switch = Some(ChannelSwitch::new(from, to, now));
+                self.send_channel_status();
+            }
+        }
+        while self.next_link_stats <= now {
+            self.next_link_stats += LINK_STATS_INTERVAL;
+        }
     }
-    if value >= 90_i8 {
-        return 180_u8;
+
+    // Reports loop timing and packet counts for the period, and starts the next
+    fn record_timing(&mut self, stats: TickStats, now: Instant) {
+        record_timing_for_period(
+            &self.tx,
+            &stats,
+            self.packets_sent,
+            self.packets_acked,
+            self.prev_marker,
+            now,
+        );
+
+        // Set next marker, ensuring in the future
+        self.packets_sent = 0;
+        self.packets_acked = 0;
+        self.prev_marker = self.next_marker;
+        while self.next_marker < now {
+            self.next_marker += RECORD_TICKS_INTERVAL;
+        }
     }
-    if value < 0_i8 {
-        return (value + 90_i8) as u8;
+
+    fn send_channel_status(&self) {
+        let status = ChannelStatus {
+            tuning: self.robots[self.active].tuning,
+            target: self.switch.as_ref().map(|sw| sw.to()),
+            auto_migrate: self.auto_migrate.enabled,
+            auto_rate: self.auto_rate.enabled,
+        };
+        if self.tx.send(Action::ChannelUpdate(status)).is_err() {
+            // Can happen during shutdown
+        }
     }
-    return (value as u8) + 90_u8;
-}
 
-fn send_state_update(
-    cr: &mut Crazyradio,
-    control_state: ControlState,
-    state_type: &SendStateType,
-) -> Result<[u8; 4], crazyradio::Error> {
-    let mut command: [u8; 3] = [0; 3];
-    let mut command_len: usize = 1;
+    fn send_fleet_status(&self) {
+        let status = FleetStatus {
+            robots: self.robots.len(),
+            active: self.active,
+            target: self.handover.as_ref().map(|ho| ho.to()),
+        };
+        if self.tx.send(Action::FleetUpdate(status)).is_err() {
+            // Can happen during shutdown
+        }
+    }
 
-    match state_type {
-        SendStateType::DRIVE => {
-            let (left_val, right_val) = control_state.as_tank_drive();
-            if left_val == 0 && right_val == 0 {
-                command[0] = 0xF3; // Stop
-            } else {
-                command[0] = 0xF4; // Drive
-                command[1] = map_percent_value(left_val);
-                command[2] = map_percent_value(right_val);
-                command_len = 3;
-            }
+    // Reports the set's overall status to the UI whenever it changes
+    fn update_radio_status(&mut self) {
+        let status = self.radios.status();
+        if status == self.radio_status {
+            return;
         }
-        SendStateType::CAMERA => {
-            let (pan_val, tilt_val) = control_state.as_camera_angles();
-            if pan_val == 0 && tilt_val == 0 {
-                command[0] = 0xF5; // Center camera
-            } else {
-                command[0] = 0xF6; // Look
-                command[1] = map_angular_value(pan_val);
-                command[2] = map_angular_value(tilt_val);
-                command_len = 3;
+        self.radio_status = status;
+        if let Some(status) = status {
+            if self.tx.send(Action::RadioUpdate(status)).is_err() {
+                // Can happen during shutdown
             }
         }
     }
+}
 
-    let cmd_slice = &command[..command_len];
-    let mut ack_data: [u8; MAX_ACK_PAYLOAD as usize] = [0; MAX_ACK_PAYLOAD as usize];
-    let _ack = cr.send_packet(cmd_slice, &mut ack_data)?;
-    // TODO: check ack properties
-    // _ack.received
-    // _ack.power_detector
-    // _ack.retry
+// Reports how closely the loop kept to its interval, and how many packets
+// actually went out, in place of the usual loop count
+fn record_timing_for_period(
+    tx: &Sender<Action>,
+    stats: &TickStats,
+    sent: u32,
+    acked: u32,
+    prev_time: Instant,
+    curr_time: Instant,
+) {
+    let ms_since = curr_time.saturating_duration_since(prev_time).as_millis();
+    let msg = format!(
+        "ticked {} times in {}ms ({} overruns, jitter avg {:.2}ms max {:.2}ms), sent {} packets, {} acked",
+        stats.ticks,
+        ms_since,
+        stats.overruns,
+        stats.mean_jitter().as_secs_f32() * 1_000.0,
+        stats.max_jitter.as_secs_f32() * 1_000.0,
+        sent,
+        acked
+    );
+    send_message(tx, "Radio", &msg);
+}
 
-    Ok(ack_data)
+// Describes just the settings that differ from `other`, to keep messages short
+fn describe_tuning(tuning: Tuning, other: Tuning) -> String {
+    let channel_differs = tuning.channel != other.channel;
+    let rate_differs = (tuning.rate, tuning.power) != (other.rate, other.power);
+    if channel_differs && !rate_differs {
+        format!("channel {}", tuning.channel)
+    } else if rate_differs && !channel_differs {
+        format!("{} {} power", tuning.rate, tuning.power)
+    } else {
+        tuning.to_string()
+    }
 }
 
-fn receive_ack_data(tx: &Sender<Action>, ack_data: [u8; 4]) {
-    match ack_data[0] {
-        // No-op, 0 bytes
-        0xF8 => {}
-        // 0xF9, 0xFA reserved
-        // Battery voltage, 2 bytes
-        0xFB => {
-            let voltage = BatteryVoltage(u16::from_be_bytes([ack_data[1], ack_data[2]]));
-            if let Err(_) = tx.send(Action::BatteryVoltageUpdate(voltage)) {
-                // Can happen during shutdown
-            }
+// Fleet index is shown 1-based, to match the selection keys
+fn describe_robot(robots: &[Robot], idx: usize) -> String {
+    format!(
+        "robot {} ({})",
+        idx + 1,
+        format_address(&robots[idx].address)
+    )
+}
+
+// Sends a command on every radio, in an authenticated frame for the given
+// robot address if authenticating; each radio sends the same frame, so the
+// robot takes whichever copy arrives first
+fn send_command(
+    radios: &mut RadioSet,
+    tx: &Sender<Action>,
+    command: Command,
+    auth: Option<(&mut Authenticator, &[u8; 5])>,
+) -> Result<SetAck, Box<dyn Error>> {
+    let mut packet: [u8; MAX_ITEM_LEN] = [0; MAX_ITEM_LEN];
+    let packet_len = command.encode(&mut packet)?;
+    let mut frame: [u8; auth::MAX_FRAME_LEN] = [0; auth::MAX_FRAME_LEN];
+    let data = match auth {
+        Some((auth, address)) => {
+            let frame_len = auth.seal(address, &packet[..packet_len], &mut frame)?;
+            &frame[..frame_len]
         }
-        // Battery current, 2 bytes
-        0xFC => {
-            let current = BatteryCurrent(u16::from_be_bytes([ack_data[1], ack_data[2]]));
-            if let Err(_) = tx.send(Action::BatteryCurrentUpdate(current)) {
-                // Can happen during shutdown
+        None => &packet[..packet_len],
+    };
+    Ok(radios.send_packet(tx, data)?)
+}
+
+fn describe_one_shot(command: Command) -> String {
+    match command {
+        Command::SetVtx(true) => String::from("VTX on"),
+        Command::SetVtx(false) => String::from("VTX off"),
+        _ => describe_param_command(command),
+    }
+}
+
+// Commands carrying control state, as opposed to radio housekeeping
+fn is_state_command(command: Command) -> bool {
+    matches!(
+        command,
+        Command::Stop | Command::Drive { .. } | Command::CenterCamera | Command::Look { .. }
+    )
+}
+
+// Forwards telemetry to the main thread, also returning it for the radio loop's own use
+fn receive_ack_data(
+    tx: &Sender<Action>,
+    robot_idx: usize,
+    ack: AckInfo,
+    ack_data: [u8; MAX_TELEMETRY_LEN],
+) -> Vec<Telemetry> {
+    // Most acks won't carry a payload, as the robot only stages one periodically
+    if !ack.received || ack.length == 0 {
+        return Vec::new();
+    }
+    let received = Instant::now();
+    let mut items = Vec::new();
+    for item in TelemetryItems::new(&ack_data[..ack.length]) {
+        let telemetry = match item {
+            Ok(telemetry) => telemetry,
+            Err(e) => {
+                let msg = format!("couldn't decode ack payload: {}", e);
+                send_error_message(tx, "Radio", &msg);
+                break;
             }
+        };
+        items.push(telemetry);
+        // Pongs and receipts are only of interest here
+        if matches!(
+            telemetry,
+            Telemetry::Noop | Telemetry::Pong(_) | Telemetry::AuthReceipt { .. }
+        ) {
+            continue;
         }
-        // Left RPM, 2 bytes
-        0xFD => {
-            // TODO
-        }
-        // Right RPM, 2 bytes
-        0xFE => {
-            // TODO
-        }
-        // 0xFF reserved
-        _ => {
-            // Send error message?
+        if tx
+            .send(Action::Telemetry(robot_idx, telemetry, received))
+            .is_err()
+        {
+            // Can happen during shutdown
         }
     }
+    items
 }
@@ -0,0 +1,256 @@
+//! Confirming authenticated commands from the robot's receipts, as its radio
+//! acks every frame before the firmware has checked the MAC and counter
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::auth::{self, AuthKey};
+use skelebot_protocol::{Command, Telemetry};
+
+// A frame with no receipt by then counts as not taken. The robot handles each
+// command as it arrives, so its receipt comes back in the ack for the next
+// packet: a tick later while a command awaits it, or up to a 100ms keepalive
+// for no-ops and pings, which this covers with room for a few losses after
+const RECEIPT_TIMEOUT: Duration = Duration::from_millis(150);
+// Acks without a single receipt for this long suggest the robot has another
+// key; a few receipt timeouts, so a counter resync after a restart (a stale
+// answer, then the skipped frame and its receipt) doesn't set it off
+const UNCONFIRMED_ALERT: Duration = Duration::from_millis(500);
+
+// A frame the robot's radio acked, waiting for the firmware to confirm it
+struct InFlight {
+    robot: usize,
+    counter: u32,
+    command: Command,
+    sent: Instant,
+}
+
+/// What a packet's exchange showed about delivery to the robot it went to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Confirmations {
+    /// Commands the robot is known to have taken, oldest first
+    pub delivered: Vec<Command>,
+    /// Commands which didn't get through, or were never confirmed
+    pub lost: Vec<Command>,
+    /// Whether the robot answered at all, ie its genuine receipt came back
+    /// (or without authentication, the packet was acked)
+    pub answered: bool,
+}
+
+impl Confirmations {
+    /// Without authentication, the radio's ack is all there is to go on
+    pub fn from_ack(command: Command, acked: bool) -> Self {
+        Self {
+            delivered: if acked { vec![command] } else { Vec::new() },
+            lost: if acked { Vec::new() } else { vec![command] },
+            answered: acked,
+        }
+    }
+}
+
+/// Frames sent to each robot, matched up with the receipts it returns
+///
+/// A receipt carries the last counter the robot accepted. Frames are handled
+/// in order, so it also covers every earlier frame to that robot which its
+/// radio acked.
+pub struct ReceiptTracker {
+    key: AuthKey,
+    in_flight: VecDeque<InFlight>,
+    // Since the first ack after the last receipt
+    unconfirmed_since: Option<Instant>,
+    alerted: bool,
+}
+
+impl ReceiptTracker {
+    pub fn new(key: AuthKey) -> Self {
+        Self {
+            key,
+            in_flight: VecDeque::new(),
+            unconfirmed_since: None,
+            alerted: false,
+        }
+    }
+
+    /// Records the frame just sent to `robot` at `address`, as its counter and
+    /// the command sealed in it, and checks its ack's telemetry for receipts
+    pub fn exchange(
+        &mut self,
+        robot: usize,
+        address: &[u8; 5],
+        (counter, command): (u32, Command),
+        acked: bool,
+        telemetry: &[Telemetry],
+        now: Instant,
+    ) -> Confirmations {
+        let mut confirmations = Confirmations::default();
+        if acked {
+            self.in_flight.push_back(InFlight {
+                robot,
+                counter,
+                command,
+                sent: now,
+            });
+            self.unconfirmed_since.get_or_insert(now);
+        } else {
+            confirmations.lost.push(command);
+        }
+
+        for &item in telemetry {
+            if !matches!(item, Telemetry::AuthReceipt { .. }) {
+                continue;
+            }
+            let confirmed = self.in_flight.iter().find(|frame| {
+                frame.robot == robot && auth::check_receipt(&self.key, address, frame.counter, item)
+            });
+            let Some(confirmed) = confirmed.map(|frame| frame.counter) else {
+                continue;
+            };
+            confirmations.answered = true;
+            self.unconfirmed_since = None;
+            self.alerted = false;
+            self.in_flight.retain(|frame| {
+                let covered = frame.robot == robot && frame.counter <= confirmed;
+                if covered {
+                    confirmations.delivered.push(frame.command);
+                }
+                !covered
+            });
+        }
+
+        // Other robots' frames are only of interest while stopping them
+        while let Some(frame) = self.in_flight.front() {
+            if now.saturating_duration_since(frame.sent) < RECEIPT_TIMEOUT {
+                break;
+            }
+            if frame.robot == robot {
+                confirmations.lost.push(frame.command);
+            }
+            self.in_flight.pop_front();
+        }
+        confirmations
+    }
+
+    /// Forgets the frames in flight, as the robot has said they were stale
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+
+    /// Whether anything other than no-ops and pings is waiting for a receipt,
+    /// so more packets are needed to bring it back
+    pub fn awaiting(&self) -> bool {
+        self.in_flight
+            .iter()
+            .any(|frame| !matches!(frame.command, Command::Noop | Command::Ping(_)))
+    }
+
+    /// Whether the command is still waiting for a receipt
+    pub fn is_in_flight(&self, command: Command) -> bool {
+        self.in_flight.iter().any(|frame| frame.command == command)
+    }
+
+    /// Returns true once each time the robot has kept acking without
+    /// confirming anything for a while
+    pub fn check_unconfirmed(&mut self, now: Instant) -> bool {
+        let overdue = self
+            .unconfirmed_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= UNCONFIRMED_ALERT);
+        if overdue && !self.alerted {
+            self.alerted = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: [u8; 5] = [0xE7; 5];
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn key() -> AuthKey {
+        AuthKey::new([7; 16])
+    }
+
+    fn receipt(counter: u32) -> Telemetry {
+        auth::receipt(&key(), &ADDRESS, counter)
+    }
+
+    #[test]
+    fn receipt_confirms_earlier_frames_too() {
+        let start = Instant::now();
+        let mut tracker = ReceiptTracker::new(key());
+        let drive = Command::Drive {
+            left: 120,
+            right: 120,
+        };
+        tracker.exchange(0, &ADDRESS, (1, Command::Stop), true, &[], start);
+        tracker.exchange(0, &ADDRESS, (2, drive), true, &[], start + TICK);
+        assert!(tracker.awaiting());
+        let confirmations = tracker.exchange(
+            0,
+            &ADDRESS,
+            (3, Command::Noop),
+            true,
+            &[receipt(2)],
+            start + TICK * 2,
+        );
+        assert_eq!(confirmations.delivered, vec![Command::Stop, drive]);
+        assert!(confirmations.answered);
+        assert!(!tracker.awaiting());
+    }
+
+    #[test]
+    fn receipt_for_another_robot_or_counter_confirms_nothing() {
+        let start = Instant::now();
+        let mut tracker = ReceiptTracker::new(key());
+        tracker.exchange(0, &ADDRESS, (1, Command::Stop), true, &[], start);
+        let other = [0xC2; 5];
+        let forged = Telemetry::AuthReceipt { counter: 1, tag: 0 };
+        for (address, item) in [
+            (other, receipt(1)),
+            (ADDRESS, receipt(9)),
+            (ADDRESS, forged),
+        ] {
+            let confirmations =
+                tracker.exchange(0, &address, (2, Command::Noop), true, &[item], start);
+            assert_eq!(confirmations.delivered, Vec::new());
+            assert!(!confirmations.answered);
+        }
+        assert!(tracker.is_in_flight(Command::Stop));
+    }
+
+    #[test]
+    fn unacked_and_unconfirmed_frames_are_lost() {
+        let start = Instant::now();
+        let mut tracker = ReceiptTracker::new(key());
+        let confirmations =
+            tracker.exchange(0, &ADDRESS, (1, Command::ParamCommit), false, &[], start);
+        assert_eq!(confirmations.lost, vec![Command::ParamCommit]);
+        tracker.exchange(0, &ADDRESS, (2, Command::ParamCommit), true, &[], start);
+        let later = start + RECEIPT_TIMEOUT;
+        let confirmations = tracker.exchange(0, &ADDRESS, (3, Command::Stop), false, &[], later);
+        assert_eq!(
+            confirmations.lost,
+            vec![Command::Stop, Command::ParamCommit]
+        );
+        assert!(!tracker.is_in_flight(Command::ParamCommit));
+    }
+
+    #[test]
+    fn acks_without_receipts_alert_once() {
+        let start = Instant::now();
+        let mut tracker = ReceiptTracker::new(key());
+        tracker.exchange(0, &ADDRESS, (1, Command::Stop), true, &[], start);
+        assert!(!tracker.check_unconfirmed(start + TICK));
+        let later = start + UNCONFIRMED_ALERT;
+        assert!(tracker.check_unconfirmed(later));
+        assert!(!tracker.check_unconfirmed(later + TICK));
+
+        // Until a receipt turns up and the robot goes quiet again
+        tracker.exchange(0, &ADDRESS, (2, Command::Stop), true, &[receipt(2)], later);
+        tracker.exchange(0, &ADDRESS, (3, Command::Stop), true, &[], later);
+        assert!(tracker.check_unconfirmed(later + UNCONFIRMED_ALERT));
+    }
+}
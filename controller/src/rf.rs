@@ -0,0 +1,340 @@
+//! Data rate and PA level, and switching the robot over to new ones
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::{DataRate, PaLevel};
+
+use crate::channel::ROBOT_BOOT_CHANNEL;
+use crate::link::LinkStats;
+
+/// Data rate the robot firmware uses at boot
+pub const ROBOT_BOOT_RATE: DataRate = DataRate::Kbps250;
+/// PA level the robot firmware uses at boot
+pub const ROBOT_BOOT_POWER: PaLevel = PaLevel::Max;
+
+// Sustained loss over this percentage steps down to a more robust setting
+const STEP_DOWN_LOSS_PERCENT: f32 = 20.0;
+// How long loss must stay over the threshold before stepping down
+const STEP_DOWN_AFTER: Duration = Duration::from_secs(1);
+// Loss at or under this percentage counts as a healthy link
+const STEP_UP_LOSS_PERCENT: f32 = 2.0;
+// How long the link must stay healthy before stepping back up; much longer
+// than stepping down, so a marginal link doesn't flap between settings
+const STEP_UP_AFTER: Duration = Duration::from_secs(10);
+// Longest wait before stepping up, after repeatedly stepping straight back down
+const MAX_STEP_UP_AFTER: Duration = Duration::from_secs(160);
+// Minimum time between steps, so stats can settle on the new setting
+const STEP_HOLDOFF: Duration = Duration::from_secs(3);
+// Too few packets in the window to judge loss, as for channel migration
+const STEP_MIN_PACKETS: u32 = 8;
+
+/// Radio settings the controller and robot must agree on to hear each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tuning {
+    /// 2400 + channel MHz, 0-125
+    pub channel: u8,
+    /// Air data rate
+    pub rate: DataRate,
+    /// Transmit power
+    pub power: PaLevel,
+}
+
+impl Tuning {
+    /// Settings the robot firmware uses at boot
+    pub fn boot() -> Self {
+        Self {
+            channel: ROBOT_BOOT_CHANNEL,
+            rate: ROBOT_BOOT_RATE,
+            power: ROBOT_BOOT_POWER,
+        }
+    }
+
+    /// The same settings on another channel
+    pub fn with_channel(self, channel: u8) -> Self {
+        Self { channel, ..self }
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "channel {} at {} {} power",
+            self.channel, self.rate, self.power
+        )
+    }
+}
+
+/// Data rate by name, as given on the command line or in the settings:
+/// 250k, 1m or 2m, in any case
+pub fn parse_rate(name: &str) -> Option<DataRate> {
+    match name.to_ascii_lowercase().as_str() {
+        "250k" => Some(DataRate::Kbps250),
+        "1m" => Some(DataRate::Mbps1),
+        "2m" => Some(DataRate::Mbps2),
+        _ => None,
+    }
+}
+
+/// PA level by name, as for `parse_rate`: min, low, high or max
+pub fn parse_power(name: &str) -> Option<PaLevel> {
+    match name.to_ascii_lowercase().as_str() {
+        "min" => Some(PaLevel::Min),
+        "low" => Some(PaLevel::Low),
+        "high" => Some(PaLevel::High),
+        "max" => Some(PaLevel::Max),
+        _ => None,
+    }
+}
+
+/// Decides when sustained packet loss warrants a slower data rate or higher
+/// power, and when the link has recovered enough to step back up
+///
+/// Steps run from the configured setting, through raising power a level at a
+/// time, then lowering the data rate at max power. Range improves with each
+/// step, at the cost of airtime (and battery, on the robot).
+pub struct AutoRate {
+    /// Whether it steps at all; otherwise it stays on the first setting
+    pub enabled: bool,
+    steps: Vec<(DataRate, PaLevel)>,
+    degraded_since: Option<Instant>,
+    healthy_since: Option<Instant>,
+    last_step: Option<Instant>,
+    last_step_up: Option<Instant>,
+    // Doubles each time a step up fails soon after, so a setting that only
+    // works while the link is quiet isn't retried every few seconds
+    step_up_after: Duration,
+}
+
+impl AutoRate {
+    /// Steps from `rate` and `power`, to higher power then slower rates
+    pub fn new(enabled: bool, rate: DataRate, power: PaLevel) -> Self {
+        let mut steps = vec![(rate, power)];
+        let mut power = power;
+        while let Some(higher) = PaLevel::from_u8(power.to_u8() + 1) {
+            power = higher;
+            steps.push((rate, power));
+        }
+        let slower = [DataRate::Mbps1, DataRate::Kbps250];
+        steps.extend(
+            slower
+                .iter()
+                .filter(|&&slow| slow < rate)
+                .map(|&slow| (slow, power)),
+        );
+        Self {
+            enabled,
+            steps,
+            degraded_since: None,
+            healthy_since: None,
+            last_step: None,
+            last_step_up: None,
+            step_up_after: STEP_UP_AFTER,
+        }
+    }
+
+    /// Whether there's anywhere to step to from the configured setting, which
+    /// at 250k and max power there isn't
+    pub fn has_steps(&self) -> bool {
+        self.steps.len() > 1
+    }
+
+    /// Checks the latest link stats, returning new settings to switch to if
+    /// the current ones have been bad (or good) for long enough
+    pub fn check(&mut self, stats: &LinkStats, tuning: Tuning, now: Instant) -> Option<Tuning> {
+        // Anything set by hand that's off the ladder is left alone
+        let step = self
+            .steps
+            .iter()
+            .position(|&setting| setting == (tuning.rate, tuning.power))
+            .filter(|_| self.enabled);
+        let Some(step) = step else {
+            self.degraded_since = None;
+            self.healthy_since = None;
+            return None;
+        };
+        let enough_packets = stats.packets >= STEP_MIN_PACKETS;
+
+        // With total loss the robot can't hear the command, and will revert on
+        // its own if it was the last step that lost it
+        let degraded = enough_packets
+            && stats.loss_percent >= STEP_DOWN_LOSS_PERCENT
+            && stats.loss_percent < 100.0;
+        let healthy = enough_packets && stats.loss_percent <= STEP_UP_LOSS_PERCENT;
+        if !degraded {
+            self.degraded_since = None;
+        }
+        if !healthy {
+            self.healthy_since = None;
+        }
+        let held_off = self
+            .last_step
+            .is_some_and(|last| now.saturating_duration_since(last) < STEP_HOLDOFF);
+
+        let target = if degraded {
+            let degraded_since = *self.degraded_since.get_or_insert(now);
+            let due = now.saturating_duration_since(degraded_since) >= STEP_DOWN_AFTER;
+            self.steps.get(step + 1).filter(|_| due)
+        } else if healthy {
+            let healthy_since = *self.healthy_since.get_or_insert(now);
+            let due = now.saturating_duration_since(healthy_since) >= self.step_up_after;
+            step.checked_sub(1)
+                .and_then(|up| self.steps.get(up))
+                .filter(|_| due)
+        } else {
+            None
+        };
+        let &(rate, power) = target.filter(|_| !held_off)?;
+
+        if degraded {
+            let failed_step_up = self
+                .last_step_up
+                .is_some_and(|up| now.saturating_duration_since(up) < self.step_up_after);
+            self.step_up_after = if failed_step_up {
+                (self.step_up_after * 2).min(MAX_STEP_UP_AFTER)
+            } else {
+                STEP_UP_AFTER
+            };
+        } else {
+            self.last_step_up = Some(now);
+        }
+        self.degraded_since = None;
+        self.healthy_since = None;
+        self.last_step = Some(now);
+        Some(Tuning {
+            rate,
+            power,
+            ..tuning
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(loss_percent: f32) -> LinkStats {
+        LinkStats {
+            packets: 100,
+            loss_percent,
+            ..LinkStats::default()
+        }
+    }
+
+    fn tuning(rate: DataRate, power: PaLevel) -> Tuning {
+        Tuning {
+            rate,
+            power,
+            ..Tuning::boot()
+        }
+    }
+
+    #[test]
+    fn ladder_raises_power_before_slowing_down() {
+        let auto_rate = AutoRate::new(true, DataRate::Mbps2, PaLevel::High);
+        assert_eq!(
+            auto_rate.steps,
+            vec![
+                (DataRate::Mbps2, PaLevel::High),
+                (DataRate::Mbps2, PaLevel::Max),
+                (DataRate::Mbps1, PaLevel::Max),
+                (DataRate::Kbps250, PaLevel::Max),
+            ]
+        );
+        assert!(auto_rate.has_steps());
+    }
+
+    #[test]
+    fn boot_setting_has_nowhere_to_step() {
+        assert!(!AutoRate::new(true, ROBOT_BOOT_RATE, ROBOT_BOOT_POWER).has_steps());
+    }
+
+    #[test]
+    fn steps_down_on_sustained_loss() {
+        let mut auto_rate = AutoRate::new(true, DataRate::Mbps1, PaLevel::Max);
+        let from = tuning(DataRate::Mbps1, PaLevel::Max);
+        let start = Instant::now();
+        assert_eq!(auto_rate.check(&stats(50.0), from, start), None);
+        let later = start + STEP_DOWN_AFTER / 2;
+        assert_eq!(auto_rate.check(&stats(50.0), from, later), None);
+        let to = auto_rate.check(&stats(50.0), from, start + STEP_DOWN_AFTER);
+        assert_eq!(to, Some(tuning(DataRate::Kbps250, PaLevel::Max)));
+    }
+
+    #[test]
+    fn loss_must_be_sustained() {
+        let mut auto_rate = AutoRate::new(true, DataRate::Mbps1, PaLevel::Max);
+        let from = tuning(DataRate::Mbps1, PaLevel::Max);
+        let start = Instant::now();
+        auto_rate.check(&stats(50.0), from, start);
+        auto_rate.check(&stats(10.0), from, start + STEP_DOWN_AFTER / 2);
+        assert_eq!(
+            auto_rate.check(&stats(50.0), from, start + STEP_DOWN_AFTER),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_total_loss_and_thin_stats() {
+        let mut auto_rate = AutoRate::new(true, DataRate::Mbps1, PaLevel::Max);
+        let from = tuning(DataRate::Mbps1, PaLevel::Max);
+        let start = Instant::now();
+        let later = start + STEP_DOWN_AFTER * 2;
+        auto_rate.check(&stats(100.0), from, start);
+        assert_eq!(auto_rate.check(&stats(100.0), from, later), None);
+        let thin = LinkStats {
+            packets: STEP_MIN_PACKETS - 1,
+            ..stats(50.0)
+        };
+        auto_rate.check(&thin, from, start);
+        assert_eq!(auto_rate.check(&thin, from, later), None);
+    }
+
+    #[test]
+    fn steps_back_up_once_healthy() {
+        let mut auto_rate = AutoRate::new(true, DataRate::Mbps1, PaLevel::Max);
+        let from = tuning(DataRate::Kbps250, PaLevel::Max);
+        let start = Instant::now();
+        auto_rate.check(&stats(0.0), from, start);
+        let early = start + STEP_UP_AFTER / 2;
+        assert_eq!(auto_rate.check(&stats(0.0), from, early), None);
+        let to = auto_rate.check(&stats(0.0), from, start + STEP_UP_AFTER);
+        assert_eq!(to, Some(tuning(DataRate::Mbps1, PaLevel::Max)));
+    }
+
+    #[test]
+    fn backs_off_after_a_failed_step_up() {
+        let mut auto_rate = AutoRate::new(true, DataRate::Mbps1, PaLevel::Max);
+        let slow = tuning(DataRate::Kbps250, PaLevel::Max);
+        let fast = tuning(DataRate::Mbps1, PaLevel::Max);
+        let mut now = Instant::now();
+        auto_rate.check(&stats(0.0), slow, now);
+        now += STEP_UP_AFTER;
+        assert_eq!(auto_rate.check(&stats(0.0), slow, now), Some(fast));
+        // Straight back down once the hold-off allows
+        now += STEP_HOLDOFF;
+        auto_rate.check(&stats(50.0), fast, now);
+        now += STEP_DOWN_AFTER;
+        assert_eq!(auto_rate.check(&stats(50.0), fast, now), Some(slow));
+        assert_eq!(auto_rate.step_up_after, STEP_UP_AFTER * 2);
+    }
+
+    #[test]
+    fn leaves_settings_alone_when_disabled_or_off_the_ladder() {
+        let mut auto_rate = AutoRate::new(false, DataRate::Mbps1, PaLevel::Max);
+        let from = tuning(DataRate::Mbps1, PaLevel::Max);
+        let start = Instant::now();
+        auto_rate.check(&stats(50.0), from, start);
+        assert_eq!(
+            auto_rate.check(&stats(50.0), from, start + STEP_DOWN_AFTER),
+            None
+        );
+
+        auto_rate.enabled = true;
+        let off_ladder = tuning(DataRate::Mbps2, PaLevel::Min);
+        auto_rate.check(&stats(50.0), off_ladder, start);
+        let later = start + STEP_DOWN_AFTER;
+        assert_eq!(auto_rate.check(&stats(50.0), off_ladder, later), None);
+    }
+}
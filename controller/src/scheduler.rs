@@ -0,0 +1,288 @@
+//! Choosing what to send in each radio slot
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::Command;
+
+use crate::actions::ControlState;
+use crate::config::DriveConfig;
+
+// Send something at least this often, so the robot's 200ms CONN_LOSS_MS stop
+// doesn't kick in; unacked keepalives are retried every tick after this, so
+// there's still room for several losses in a row
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+// After this long without an ack the robot will have stopped (and may have
+// rebooted), so resend the full state when it's back
+const RESYNC_AFTER: Duration = Duration::from_millis(200);
+// Attempts at sending a one-shot command before giving up on it
+const ONE_SHOT_ATTEMPTS: u32 = 10;
+
+/// Decides which command (if any) is worth sending, based on what the robot
+/// has already acked
+///
+/// Stop goes out first, drive changes otherwise alternate with camera changes
+/// if both are pending, and if nothing has changed a drive command is sent as
+/// a keepalive. Commands which aren't acked stay pending, so are resent.
+pub struct TxScheduler {
+    config: DriveConfig,
+    drive: Option<Command>,
+    camera: Option<Command>,
+    last_acked: Option<Instant>,
+    last_was_camera: bool,
+}
+
+impl Default for TxScheduler {
+    fn default() -> Self {
+        Self::new(DriveConfig::default())
+    }
+}
+
+impl TxScheduler {
+    /// Scheduler turning control state into commands within `config`'s limits
+    pub fn new(config: DriveConfig) -> Self {
+        Self {
+            config,
+            drive: None,
+            camera: None,
+            last_acked: None,
+            last_was_camera: false,
+        }
+    }
+
+    /// Forgets what the robot has acked, so the full state is sent again
+    pub fn reset(&mut self) {
+        self.drive = None;
+        self.camera = None;
+    }
+
+    /// Next command to send for the given control state, or None if the robot
+    /// is up to date and a keepalive isn't due yet
+    pub fn next_command(&mut self, control_state: ControlState, now: Instant) -> Option<Command> {
+        let since_ack = self
+            .last_acked
+            .map(|acked| now.saturating_duration_since(acked));
+        if since_ack.is_some_and(|since| since >= RESYNC_AFTER) {
+            self.reset();
+        }
+
+        let drive = drive_command(control_state, &self.config);
+        let camera = camera_command(control_state);
+        let drive_pending = self.drive != Some(drive);
+        let camera_pending = self.camera != Some(camera);
+
+        let command = if drive_pending
+            && (drive == Command::Stop || !camera_pending || self.last_was_camera)
+        {
+            drive
+        } else if camera_pending {
+            camera
+        } else if since_ack.is_none_or(|since| since >= KEEPALIVE_INTERVAL) {
+            drive
+        } else {
+            return None;
+        };
+        self.last_was_camera = command == camera;
+        Some(command)
+    }
+
+    /// Command to send when a packet has to go out regardless, eg to look for
+    /// acks while changing channel
+    pub fn keepalive_command(&self, control_state: ControlState) -> Command {
+        drive_command(control_state, &self.config)
+    }
+
+    /// Records whether a sent command was acked
+    pub fn record(&mut self, command: Command, acked: bool, now: Instant) {
+        if !acked {
+            return;
+        }
+        self.last_acked = Some(now);
+        match command {
+            Command::Stop | Command::Drive { .. } => {
+                self.drive = Some(command);
+            }
+            Command::CenterCamera | Command::Look { .. } => {
+                self.camera = Some(command);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Commands sent once rather than kept up to date, such as parameter
+/// changes, waiting to go out in order; each is resent until acked
+pub struct OneShotQueue {
+    pending: VecDeque<Command>,
+    attempts: u32,
+}
+
+impl Default for OneShotQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OneShotQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            attempts: 0,
+        }
+    }
+
+    pub fn push(&mut self, command: Command) {
+        self.pending.push_back(command);
+    }
+
+    /// Command to send next, if any
+    pub fn next_command(&self) -> Option<Command> {
+        self.pending.front().copied()
+    }
+
+    /// Records whether the command from `next_command` was acked, returning it
+    /// if it has now been given up on
+    pub fn record(&mut self, acked: bool) -> Option<Command> {
+        self.attempts += 1;
+        if !acked && self.attempts < ONE_SHOT_ATTEMPTS {
+            return None;
+        }
+        self.attempts = 0;
+        let command = self.pending.pop_front();
+        command.filter(|_| !acked)
+    }
+
+    /// Drops anything pending, as it was meant for another robot
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.attempts = 0;
+    }
+}
+
+fn drive_command(control_state: ControlState, config: &DriveConfig) -> Command {
+    let (left, right) = control_state.as_tank_drive(config);
+    if left == 0 && right == 0 {
+        Command::Stop
+    } else {
+        Command::Drive { left, right }
+    }
+}
+
+fn camera_command(control_state: ControlState) -> Command {
+    let (pan, tilt) = control_state.as_camera_angles();
+    if pan == 0 && tilt == 0 {
+        Command::CenterCamera
+    } else {
+        Command::Look { pan, tilt }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn driving(throttle: i16, pan: f32) -> ControlState {
+        ControlState {
+            throttle,
+            pan,
+            ..ControlState::new()
+        }
+    }
+
+    // Sends whatever the scheduler picks, acked, returning it
+    fn send(scheduler: &mut TxScheduler, state: ControlState, now: Instant) -> Option<Command> {
+        let command = scheduler.next_command(state, now);
+        if let Some(command) = command {
+            scheduler.record(command, true, now);
+        }
+        command
+    }
+
+    #[test]
+    fn sends_state_once_then_only_keepalives() {
+        let start = Instant::now();
+        let mut scheduler = TxScheduler::default();
+        let state = ControlState::new();
+        assert_eq!(send(&mut scheduler, state, start), Some(Command::Stop));
+        assert_eq!(
+            send(&mut scheduler, state, start + TICK),
+            Some(Command::CenterCamera)
+        );
+        assert_eq!(send(&mut scheduler, state, start + TICK * 2), None);
+        let keepalive = start + TICK + KEEPALIVE_INTERVAL;
+        assert_eq!(send(&mut scheduler, state, keepalive), Some(Command::Stop));
+    }
+
+    #[test]
+    fn drive_and_camera_changes_alternate() {
+        let start = Instant::now();
+        let mut scheduler = TxScheduler::default();
+        send(&mut scheduler, ControlState::new(), start);
+        send(&mut scheduler, ControlState::new(), start);
+
+        let mut state = driving(10_000, 10_000.0);
+        let drive = drive_command(state, &DriveConfig::default());
+        let look = camera_command(state);
+        assert_eq!(scheduler.next_command(state, start), Some(drive));
+        assert_eq!(scheduler.next_command(state, start), Some(look));
+        assert_eq!(scheduler.next_command(state, start), Some(drive));
+
+        // Unless the drive change is a stop, which can't wait its turn
+        scheduler.record(drive, true, start);
+        state.throttle = 0;
+        state.pan = 20_000.0;
+        assert_eq!(scheduler.next_command(state, start), Some(Command::Stop));
+    }
+
+    #[test]
+    fn unacked_commands_stay_pending() {
+        let start = Instant::now();
+        let mut scheduler = TxScheduler::default();
+        let state = ControlState::new();
+        send(&mut scheduler, state, start);
+        send(&mut scheduler, state, start);
+        let state = driving(10_000, 0.0);
+        let drive = scheduler.next_command(state, start);
+        scheduler.record(drive.unwrap(), false, start);
+        assert_eq!(scheduler.next_command(state, start + TICK), drive);
+    }
+
+    #[test]
+    fn resends_everything_after_a_long_silence() {
+        let start = Instant::now();
+        let mut scheduler = TxScheduler::default();
+        let state = ControlState::new();
+        send(&mut scheduler, state, start);
+        send(&mut scheduler, state, start);
+        let later = start + RESYNC_AFTER;
+        scheduler.record(Command::Stop, false, later);
+        assert_eq!(send(&mut scheduler, state, later), Some(Command::Stop));
+        assert_eq!(
+            send(&mut scheduler, state, later),
+            Some(Command::CenterCamera)
+        );
+    }
+
+    #[test]
+    fn one_shots_go_in_order_until_acked_or_given_up() {
+        let mut one_shots = OneShotQueue::new();
+        assert_eq!(one_shots.next_command(), None);
+        one_shots.push(Command::SetVtx(true));
+        one_shots.push(Command::ParamCommit);
+        assert_eq!(one_shots.record(false), None);
+        assert_eq!(one_shots.next_command(), Some(Command::SetVtx(true)));
+        assert_eq!(one_shots.record(true), None);
+
+        for _ in 1..ONE_SHOT_ATTEMPTS {
+            assert_eq!(one_shots.record(false), None);
+        }
+        assert_eq!(one_shots.record(false), Some(Command::ParamCommit));
+        assert_eq!(one_shots.next_command(), None);
+
+        one_shots.push(Command::ParamCommit);
+        one_shots.clear();
+        assert_eq!(one_shots.next_command(), None);
+    }
+}
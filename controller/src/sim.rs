@@ -0,0 +1,259 @@
+//! A simulated robot on a flat arena, driven in place of the radio
+
+use std::f64::consts::{FRAC_PI_2, PI};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::Telemetry;
+
+use crate::actions::{
+    record_ticks_for_period, send_error_message, send_message, Action, ControlState, ThreadMsg,
+    RECORD_TICKS_INTERVAL, WHEEL_RPM_MAX,
+};
+use crate::config::DriveConfig;
+use crate::radio::{RadioReceiver, RadioRequest};
+
+/// Arena width in metres, with the robot starting in the middle facing +y
+pub const ARENA_WIDTH: f64 = 4.0;
+/// Arena height in metres
+pub const ARENA_HEIGHT: f64 = 3.0;
+/// Chassis length in metres, for drawing and keeping it inside the walls
+pub const ROBOT_LENGTH: f64 = 0.3;
+/// Chassis width in metres
+pub const ROBOT_WIDTH: f64 = 0.24;
+
+const WHEEL_DIAMETER: f64 = 0.085;
+// Distance between the left and right wheels' contact patches
+const TRACK_WIDTH: f64 = 0.19;
+// Skid-steer wheels scrub sideways when turning, so the chassis turns slower
+// than the wheel speed difference alone would have it
+const TURN_EFFICIENCY: f64 = 0.6;
+// Time for a wheel to get most of the way to a new speed
+const MOTOR_TIME_CONSTANT: f64 = 0.15;
+// Battery voltage WHEEL_RPM_MAX is reached at, as the motors slow with the battery
+const NOMINAL_VOLTAGE: f64 = 11.1;
+// Winding resistance of each side's pair of motors in parallel
+const MOTOR_RESISTANCE: f64 = 1.5;
+// Current each side draws rolling along at full speed, against friction
+const ROLLING_CURRENT: f64 = 0.6;
+// Board, servos and radio, as the firmware emulator has it
+const IDLE_CURRENT: f64 = 0.25;
+const VTX_CURRENT: f64 = 0.3;
+// 3S LiPo: open-circuit voltage falls roughly linearly from full to empty
+const BATTERY_FULL: f64 = 12.6;
+const BATTERY_EMPTY: f64 = 9.9;
+const BATTERY_CAPACITY_AH: f64 = 2.2;
+const BATTERY_RESISTANCE: f64 = 0.15;
+// The robot sends each kind of telemetry this often
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(100);
+const POSE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Where the simulated robot is, and which way it and its camera face
+///
+/// Positions are in metres from the arena's bottom-left corner; headings are
+/// in radians, anticlockwise from +x.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimPose {
+    /// Centre of the chassis
+    pub x: f64,
+    /// Centre of the chassis
+    pub y: f64,
+    /// Which way the chassis faces
+    pub heading: f64,
+    /// Which way the camera faces, the heading turned by the camera's pan
+    pub camera_heading: f64,
+}
+
+// Skid-steer chassis with a DC motor per side, on a battery which sags under load
+struct SimRobot {
+    x: f64,
+    y: f64,
+    heading: f64,
+    // Signed wheel speeds as a fraction of WHEEL_RPM_MAX
+    left_speed: f64,
+    right_speed: f64,
+    charge: f64,
+    voltage: f64,
+    current: f64,
+    vtx_on: bool,
+}
+
+impl SimRobot {
+    fn new() -> Self {
+        Self {
+            x: ARENA_WIDTH / 2.0,
+            y: ARENA_HEIGHT / 2.0,
+            heading: FRAC_PI_2,
+            left_speed: 0.0,
+            right_speed: 0.0,
+            charge: 1.0,
+            voltage: BATTERY_FULL,
+            current: 0.0,
+            vtx_on: true,
+        }
+    }
+
+    // Advances by dt seconds with the given tank drive, in +/- %
+    fn step(&mut self, (left, right): (i8, i8), dt: f64) {
+        // Motors see the battery's voltage as it was, with the drive as duty cycle
+        let left_current = self.step_wheel(true, f64::from(left) / 100.0, dt);
+        let right_current = self.step_wheel(false, f64::from(right) / 100.0, dt);
+        let vtx_current = if self.vtx_on { VTX_CURRENT } else { 0.0 };
+        self.current = IDLE_CURRENT + vtx_current + left_current + right_current;
+        self.charge = (self.charge - self.current * dt / 3600.0 / BATTERY_CAPACITY_AH).max(0.0);
+        let open_circuit = BATTERY_EMPTY + (BATTERY_FULL - BATTERY_EMPTY) * self.charge;
+        self.voltage = (open_circuit - self.current * BATTERY_RESISTANCE).max(0.0);
+
+        let metres_per_rev = PI * WHEEL_DIAMETER;
+        let max_speed = f64::from(WHEEL_RPM_MAX) / 60.0 * metres_per_rev;
+        let left_v = self.left_speed * max_speed;
+        let right_v = self.right_speed * max_speed;
+        let v = (left_v + right_v) / 2.0;
+        let omega = (right_v - left_v) / TRACK_WIDTH * TURN_EFFICIENCY;
+        self.heading = (self.heading + omega * dt).rem_euclid(2.0 * PI);
+        // Walls stop it, but it can slide along them
+        let margin = ROBOT_LENGTH / 2.0;
+        self.x = (self.x + v * self.heading.cos() * dt).clamp(margin, ARENA_WIDTH - margin);
+        self.y = (self.y + v * self.heading.sin() * dt).clamp(margin, ARENA_HEIGHT - margin);
+    }
+
+    // Moves one side's wheel speed towards what its applied voltage gives,
+    // returning the current it draws
+    fn step_wheel(&mut self, left: bool, duty: f64, dt: f64) -> f64 {
+        let speed = if left {
+            &mut self.left_speed
+        } else {
+            &mut self.right_speed
+        };
+        let applied = duty * self.voltage / NOMINAL_VOLTAGE;
+        let back_emf = *speed;
+        *speed += (applied - back_emf) * (dt / MOTOR_TIME_CONSTANT).min(1.0);
+        // Coasting down draws nothing, but driving against the wheel's motion does
+        let driving = ((applied - back_emf) * applied.signum()).max(0.0);
+        driving * NOMINAL_VOLTAGE / MOTOR_RESISTANCE + ROLLING_CURRENT * speed.abs()
+    }
+
+    fn pose(&self, control_state: &ControlState) -> SimPose {
+        // Positive pan turns the camera to the right
+        let (pan, _) = control_state.as_camera_angles();
+        SimPose {
+            x: self.x,
+            y: self.y,
+            heading: self.heading,
+            camera_heading: self.heading - f64::from(pan).to_radians(),
+        }
+    }
+
+    fn telemetry(&self) -> [Telemetry; 5] {
+        let rpm = |speed: f64| (speed.abs() * f64::from(WHEEL_RPM_MAX)).round() as u16;
+        [
+            Telemetry::BatteryVoltage((self.voltage * 1023.0).round() as u16),
+            Telemetry::BatteryCurrent((self.current * 1023.0).round() as u16),
+            Telemetry::LeftRpm(rpm(self.left_speed)),
+            Telemetry::RightRpm(rpm(self.right_speed)),
+            Telemetry::VtxState(self.vtx_on),
+        ]
+    }
+}
+
+/// Drives a simulated robot in place of the radio thread, from the same
+/// control state and requests, reporting its pose and telemetry as a robot would
+pub fn simulate(
+    tx: Sender<Action>,
+    requests: RadioReceiver,
+    control_state_mutex: Arc<Mutex<ControlState>>,
+    exit_flag: &AtomicBool,
+    drive: &DriveConfig,
+) {
+    let RadioReceiver { rx, mut ticker } = requests;
+    let mut robot = SimRobot::new();
+    let mut prev_marker = Instant::now();
+    let mut next_marker = prev_marker + RECORD_TICKS_INTERVAL;
+    let mut ticks = 0_u32;
+    let mut last_step = Instant::now();
+    let mut next_telemetry = last_step;
+    let mut next_pose = last_step;
+
+    send_message(
+        &tx,
+        "Sim",
+        &format!(
+            "simulating a robot in a {}x{}m arena, no radio in use",
+            ARENA_WIDTH, ARENA_HEIGHT
+        ),
+    );
+
+    loop {
+        match ticker.wait() {
+            Ok(_) => {}
+            Err(e) => {
+                let msg = ThreadMsg {
+                    name: "Sim".to_owned(),
+                    message: format!("couldn't wait for next tick: {}", e),
+                };
+                tx.send(Action::Fatal(msg)).unwrap();
+                return;
+            }
+        }
+
+        for request in rx.try_iter() {
+            match request {
+                RadioRequest::SetVtx(on) => {
+                    robot.vtx_on = on;
+                }
+                RadioRequest::ControlChanged(_) => {}
+                RadioRequest::ChangeChannel(_) | RadioRequest::Retune(_) => {
+                    send_error_message(&tx, "Sim", "no radio to retune in the simulator");
+                }
+                RadioRequest::ToggleAutoMigrate | RadioRequest::ToggleAutoRate => {
+                    send_error_message(&tx, "Sim", "no radio link in the simulator");
+                }
+                RadioRequest::SelectRobot(_) => {
+                    send_error_message(&tx, "Sim", "only one robot in the simulator");
+                }
+                RadioRequest::Param(_) => {
+                    send_error_message(&tx, "Sim", "robot parameters aren't simulated");
+                }
+            }
+        }
+
+        let curr_time = Instant::now();
+        let control_state = {
+            let control_state = control_state_mutex.lock().unwrap();
+            *control_state
+        };
+        let dt = curr_time.duration_since(last_step).as_secs_f64();
+        robot.step(control_state.as_tank_drive(drive), dt);
+        last_step = curr_time;
+
+        if curr_time >= next_telemetry {
+            for telemetry in robot.telemetry() {
+                tx.send(Action::Telemetry(0, telemetry, curr_time)).unwrap();
+            }
+            next_telemetry = curr_time + TELEMETRY_INTERVAL;
+        }
+        if curr_time >= next_pose {
+            tx.send(Action::SimUpdate(robot.pose(&control_state)))
+                .unwrap();
+            next_pose = curr_time + POSE_INTERVAL;
+        }
+
+        ticks += 1;
+        if curr_time >= next_marker {
+            record_ticks_for_period(&tx, "Sim", ticks, prev_marker, curr_time);
+
+            // Set next marker, ensuring in the future
+            ticks = 0;
+            prev_marker = next_marker;
+            while next_marker < curr_time {
+                next_marker += RECORD_TICKS_INTERVAL;
+            }
+        }
+
+        if exit_flag.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Channel survey: how busy each channel is, for `controller survey`
+
+use std::collections::VecDeque;
+use std::io::{self, stdout};
+use std::panic::{set_hook, take_hook};
+use std::time::Duration;
+
+use crossterm::event::{poll, read, Event, KeyCode};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use skelebot_controller::channel::{QUIET_CHANNELS, ROBOT_BOOT_CHANNEL};
+use skelebot_controller::rf::Tuning;
+use skelebot_controller::transport::{open_transport, Transport, TransportKind};
+use skelebot_protocol::{Command, MAX_CHANNEL, MAX_ITEM_LEN};
+
+const NUM_CHANNELS: usize = MAX_CHANNEL as usize + 1;
+// Packets sent per channel per sweep; each reports the power detector state
+const PROBES_PER_CHANNEL: u32 = 5;
+// Sweeps kept for the waterfall display and averages
+const HISTORY_SWEEPS: usize = 64;
+const RECOMMEND_COUNT: usize = 5;
+// Channels in the middle of a sweep are checked for keypresses this often
+const KEY_POLL_CHANNELS: u8 = 8;
+
+/// Per-channel activity for a single sweep, as the fraction of probes with the
+/// power detector set, plus whether anything acked (ie a robot is listening)
+#[derive(Clone, Copy)]
+struct Sweep {
+    activity: [f32; NUM_CHANNELS],
+    acked: [bool; NUM_CHANNELS],
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            activity: [0.0; NUM_CHANNELS],
+            acked: [false; NUM_CHANNELS],
+        }
+    }
+}
+
+struct SurveyState {
+    history: VecDeque<Sweep>,
+    current: Sweep,
+    next_channel: u8,
+    sweeps: u32,
+    status: String,
+}
+
+impl SurveyState {
+    fn new() -> Self {
+        Self {
+            history: VecDeque::new(),
+            current: Sweep::new(),
+            next_channel: 0,
+            sweeps: 0,
+            status: String::from("sweeping..."),
+        }
+    }
+
+    fn finish_sweep(&mut self) {
+        self.history.push_front(self.current);
+        if self.history.len() > HISTORY_SWEEPS {
+            _ = self.history.pop_back();
+        }
+        self.current = Sweep::new();
+        self.next_channel = 0;
+        self.sweeps += 1;
+    }
+
+    // Mean activity per channel over all kept sweeps
+    fn averages(&self) -> [f32; NUM_CHANNELS] {
+        let mut averages = [0.0; NUM_CHANNELS];
+        if self.history.is_empty() {
+            return averages;
+        }
+        for sweep in self.history.iter() {
+            for (avg, activity) in averages.iter_mut().zip(sweep.activity.iter()) {
+                *avg += activity;
+            }
+        }
+        for avg in averages.iter_mut() {
+            *avg /= self.history.len() as f32;
+        }
+        averages
+    }
+
+    fn robot_seen(&self) -> Vec<u8> {
+        (0..=MAX_CHANNEL)
+            .filter(|&ch| self.history.iter().any(|sweep| sweep.acked[ch as usize]))
+            .collect()
+    }
+
+    /// Quietest channels by average activity, optionally only those in the
+    /// radio.md WiFi-free groups
+    fn quietest(&self, wifi_free_only: bool) -> Vec<(u8, f32)> {
+        let averages = self.averages();
+        let mut channels: Vec<(u8, f32)> = (0..=MAX_CHANNEL)
+            .filter(|ch| !wifi_free_only || QUIET_CHANNELS.contains(ch))
+            .map(|ch| (ch, averages[ch as usize]))
+            .collect();
+        // Stable sort, so ties go to the lower channel
+        channels.sort_by(|a, b| a.1.total_cmp(&b.1));
+        channels.truncate(RECOMMEND_COUNT);
+        channels
+    }
+}
+
+pub fn run_survey(transport_kind: &TransportKind) -> io::Result<()> {
+    let mut transport = match open_transport(transport_kind, Tuning::boot().with_channel(0)) {
+        Ok(transport) => transport,
+        Err(e) => {
+            return Err(io::Error::other(format!(
+                "couldn't open radio device: {}",
+                e
+            )));
+        }
+    };
+
+    enable_raw_mode()?;
+    stdout().execute(EnterAlternateScreen)?;
+    let original_hook = take_hook();
+    set_hook(Box::new(move |panic_info| {
+        // intentionally ignore errors here since we're already in a panic
+        let _ = stdout().execute(LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+        original_hook(panic_info);
+    }));
+
+    let result = survey_loop(transport.as_mut());
+
+    let _ = stdout().execute(LeaveAlternateScreen);
+    disable_raw_mode()?;
+    result
+}
+
+fn survey_loop(transport: &mut dyn Transport) -> io::Result<()> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    let mut state = SurveyState::new();
+    let mut paused = false;
+
+    terminal.draw(|frame| render_survey(frame, &state, paused))?;
+
+    'outer: loop {
+        // Check keys between batches of channels, so we stay responsive mid-sweep
+        let wait = if paused {
+            Duration::from_millis(100)
+        } else {
+            Duration::ZERO
+        };
+        while poll(wait)? {
+            if let Event::Key(event) = read()? {
+                match event.code {
+                    KeyCode::Char('q') | KeyCode::Esc => {
+                        break 'outer;
+                    }
+                    KeyCode::Char(' ') => {
+                        paused = !paused;
+                        terminal.draw(|frame| render_survey(frame, &state, paused))?;
+                    }
+                    _ => {}
+                }
+            }
+            if !paused {
+                break;
+            }
+        }
+        if paused {
+            continue;
+        }
+
+        for _ in 0..KEY_POLL_CHANNELS {
+            let channel = state.next_channel;
+            match probe_channel(transport, channel) {
+                Ok((activity, acked)) => {
+                    state.current.activity[channel as usize] = activity;
+                    state.current.acked[channel as usize] = acked;
+                }
+                Err(e) => {
+                    state.status = format!("error probing channel {}: {}", channel, e);
+                }
+            }
+            if channel == MAX_CHANNEL {
+                state.finish_sweep();
+                terminal.draw(|frame| render_survey(frame, &state, paused))?;
+                break;
+            }
+            state.next_channel += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// Sends no-op probes on a channel, returning the fraction which saw the power
+// detector set, and whether any were acked
+fn probe_channel(
+    transport: &mut dyn Transport,
+    channel: u8,
+) -> Result<(f32, bool), Box<dyn std::error::Error>> {
+    transport.set_channel(channel)?;
+    let mut packet = [0_u8; MAX_ITEM_LEN];
+    let packet_len = Command::Noop.encode(&mut packet)?;
+    let mut ack_data = [0_u8; MAX_ITEM_LEN];
+    let mut detected = 0_u32;
+    let mut acked = false;
+    for _ in 0..PROBES_PER_CHANNEL {
+        let ack = transport.send_packet(&packet[..packet_len], &mut ack_data)?;
+        if ack.power_detector {
+            detected += 1;
+        }
+        acked |= ack.received;
+    }
+    Ok((detected as f32 / PROBES_PER_CHANNEL as f32, acked))
+}
+
+fn render_survey(frame: &mut Frame, state: &SurveyState, paused: bool) {
+    let layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![
+            Constraint::Min(6),
+            Constraint::Length(4),
+            Constraint::Length(RECOMMEND_COUNT as u16 + 4),
+        ])
+        .split(frame.area());
+
+    // Waterfall, newest sweep at the top; squash channels into columns if narrow
+    let heatmap_block = Block::bordered().title("Channel activity (newest first)");
+    let heatmap_area = heatmap_block.inner(layout[0]);
+    let per_col = NUM_CHANNELS.div_ceil(heatmap_area.width.max(1) as usize);
+    let heatmap_lines: Vec<Line<'_>> = state
+        .history
+        .iter()
+        .take(heatmap_area.height as usize)
+        .map(|sweep| {
+            let spans: Vec<Span<'_>> = sweep
+                .activity
+                .chunks(per_col)
+                .zip(sweep.acked.chunks(per_col))
+                .map(|(activity, acked)| {
+                    let max = activity.iter().copied().fold(0.0, f32::max);
+                    if acked.iter().any(|&a| a) {
+                        Span::styled("R", Style::default().black().on_magenta())
+                    } else {
+                        activity_span(max)
+                    }
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect();
+    let heatmap = Paragraph::new(heatmap_lines).block(heatmap_block);
+
+    // Channel axis, with the radio.md WiFi-free channels marked underneath
+    let axis_block = Block::bordered();
+    let axis_width = NUM_CHANNELS.div_ceil(per_col);
+    let mut labels = vec![' '; axis_width];
+    for ch in (0..NUM_CHANNELS).step_by(10) {
+        for (i, c) in ch.to_string().chars().enumerate() {
+            if let Some(slot) = labels.get_mut(ch / per_col + i) {
+                *slot = c;
+            }
+        }
+    }
+    let quiet_marks: String = (0..axis_width)
+        .map(|col| {
+            let start = col * per_col;
+            let end = (start + per_col).min(NUM_CHANNELS);
+            if (start..end).all(|ch| QUIET_CHANNELS.contains(&(ch as u8))) {
+                '^'
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let axis = Paragraph::new(vec![
+        Line::from(labels.into_iter().collect::<String>()),
+        Line::styled(quiet_marks, Style::default().green()),
+    ])
+    .block(axis_block);
+
+    // Recommendations
+    let quietest = state.quietest(false);
+    let quietest_wifi_free = state.quietest(true);
+    let robot_seen = state.robot_seen();
+    let mut rec_lines = vec![
+        Line::from(vec![
+            Span::from("Quietest:            "),
+            Span::from(format_channels(&quietest)),
+        ]),
+        Line::from(vec![
+            Span::from("Quietest WiFi-free:  "),
+            Span::styled(
+                format_channels(&quietest_wifi_free),
+                Style::default().green(),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("Default channel:     "),
+            Span::from(format!(
+                "{} ({:.0}%)",
+                ROBOT_BOOT_CHANNEL,
+                100.0 * state.averages()[ROBOT_BOOT_CHANNEL as usize]
+            )),
+        ]),
+        Line::from(vec![
+            Span::from("Robot acked on:      "),
+            Span::from(if robot_seen.is_empty() {
+                String::from("-")
+            } else {
+                robot_seen
+                    .iter()
+                    .map(|ch| ch.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            }),
+        ]),
+    ];
+    let status = if paused {
+        String::from("paused")
+    } else {
+        state.status.clone()
+    };
+    rec_lines.push(Line::from(format!(
+        "{} sweeps, {} | space: pause, q: quit",
+        state.sweeps, status
+    )));
+    let recommendations = Paragraph::new(rec_lines)
+        .block(Block::bordered().title("Recommendations"))
+        .wrap(Wrap { trim: true });
+
+    frame.render_widget(heatmap, layout[0]);
+    frame.render_widget(axis, layout[1]);
+    frame.render_widget(recommendations, layout[2]);
+}
+
+// Channel numbers with frequency and average activity
+fn format_channels(channels: &[(u8, f32)]) -> String {
+    channels
+        .iter()
+        .map(|(ch, activity)| {
+            format!(
+                "{} ({}MHz, {:.0}%)",
+                ch,
+                2400 + *ch as u16,
+                100.0 * activity
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("  ")
+}
+
+fn activity_span(activity: f32) -> Span<'static> {
+    if activity <= 0.0 {
+        Span::styled("·", Style::default().dark_gray())
+    } else if activity < 0.2 {
+        Span::styled("░", Style::default().blue())
+    } else if activity < 0.4 {
+        Span::styled("▒", Style::default().green())
+    } else if activity < 0.7 {
+        Span::styled("▓", Style::default().yellow())
+    } else {
+        Span::styled("█", Style::default().red())
+    }
+}
@@ -0,0 +1,204 @@
+//! Telemetry history for the UI, and spotting robot reboots
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use skelebot_protocol::{ResetCause, Telemetry, TLM_BATTERY_CURRENT, TLM_IDENTITY, UPTIME_TICK};
+
+/// Values older than this are shown as stale; the robot sends battery
+/// telemetry every 50ms, so this is many missed updates in a row
+pub const STALE_AFTER: Duration = Duration::from_secs(1);
+// Values kept per kind, around a minute of battery telemetry
+const HISTORY_LEN: usize = 1_200;
+// Battery current this long before the robot went quiet counts towards a
+// reboot's peak, as brown-outs follow current spikes
+const REBOOT_CURRENT_WINDOW: Duration = Duration::from_secs(2);
+// Uptime is sent as 24 bits, so wraps back to 0 after about 19 days
+const UPTIME_WRAP: u64 = 1 << 24;
+// Leeway when deciding whether uptime wrapped, for telemetry arriving late
+const UPTIME_SLACK_TICKS: u64 = 10;
+
+/// Latest value of one telemetry kind, with when it arrived and what came before
+pub struct TelemetryEntry {
+    /// Most recent value
+    pub latest: Telemetry,
+    /// When the most recent value arrived
+    pub received: Instant,
+    history: VecDeque<(Instant, Telemetry)>,
+}
+
+impl TelemetryEntry {
+    /// Whether it's been too long since the last value to trust it
+    pub fn is_stale(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.received) >= STALE_AFTER
+    }
+
+    /// Values received so far, oldest first, including the latest
+    pub fn history(&self) -> impl Iterator<Item = &(Instant, Telemetry)> {
+        self.history.iter()
+    }
+}
+
+/// A robot reboot, detected from its uptime going backwards
+#[derive(Clone, Copy, Debug)]
+pub struct Reboot {
+    /// When telemetry first showed it
+    pub detected: Instant,
+    /// How long the robot had been up when last heard from before the reboot
+    pub previous_uptime: Duration,
+    /// Why the robot reset, if it's said yet
+    pub reset_cause: Option<ResetCause>,
+    /// Highest raw battery current in the moments before the robot went quiet
+    pub peak_current: Option<u16>,
+}
+
+/// Everything received from the robot, by telemetry type
+#[derive(Default)]
+pub struct TelemetryStore {
+    entries: BTreeMap<u8, TelemetryEntry>,
+}
+
+impl TelemetryStore {
+    /// Empty, as nothing's been received yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a telemetry item, returning a reboot if it reveals one
+    pub fn record(&mut self, telemetry: Telemetry, received: Instant) -> Option<Reboot> {
+        // Nothing to keep, it only pads out an ack
+        if telemetry == Telemetry::Noop {
+            return None;
+        }
+        let reboot = self.check_reboot(telemetry, received);
+        let entry = self
+            .entries
+            .entry(telemetry.type_byte())
+            .or_insert_with(|| TelemetryEntry {
+                latest: telemetry,
+                received,
+                history: VecDeque::new(),
+            });
+        entry.latest = telemetry;
+        entry.received = received;
+        if entry.history.len() >= HISTORY_LEN {
+            entry.history.pop_front();
+        }
+        entry.history.push_back((received, telemetry));
+        reboot
+    }
+
+    /// Entry for the given telemetry type, or None if nothing has been received
+    pub fn get(&self, type_byte: u8) -> Option<&TelemetryEntry> {
+        self.entries.get(&type_byte)
+    }
+
+    fn check_reboot(&self, telemetry: Telemetry, received: Instant) -> Option<Reboot> {
+        let Telemetry::Uptime(uptime) = telemetry else {
+            return None;
+        };
+        let previous = self.entries.get(&telemetry.type_byte())?;
+        let Telemetry::Uptime(previous_uptime) = previous.latest else {
+            return None;
+        };
+        if uptime >= previous_uptime {
+            return None;
+        }
+        // Counting on from the last uptime heard, it would have gone past the
+        // top of the range by now, so this may just be the count wrapping
+        let elapsed = received.saturating_duration_since(previous.received);
+        let elapsed_ticks = (elapsed.as_millis() / UPTIME_TICK.as_millis()) as u64;
+        let counted_on = u64::from(previous_uptime) + elapsed_ticks + UPTIME_SLACK_TICKS;
+        if counted_on >= UPTIME_WRAP && u64::from(uptime) <= counted_on - UPTIME_WRAP {
+            return None;
+        }
+
+        // The robot sends its identity alongside its uptime, so this is already
+        // the one from after the reboot
+        let reset_cause = self.get(TLM_IDENTITY).and_then(|entry| match entry.latest {
+            Telemetry::Identity { reset_cause, .. } => Some(reset_cause),
+            _ => None,
+        });
+        let window_start = previous
+            .received
+            .checked_sub(REBOOT_CURRENT_WINDOW)
+            .unwrap_or(previous.received);
+        let peak_current = self
+            .get(TLM_BATTERY_CURRENT)
+            .into_iter()
+            .flat_map(|entry| entry.history())
+            .filter(|(at, _)| (window_start..=previous.received).contains(at))
+            .filter_map(|(_, t)| t.value())
+            .max();
+        Some(Reboot {
+            detected: received,
+            previous_uptime: UPTIME_TICK * previous_uptime,
+            reset_cause,
+            peak_current,
+        })
+    }
+}
+
+/// Formats an uptime to the most significant two units, e.g. "3m 12s"
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", uptime.as_secs_f32()),
+        60..3_600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3_600, secs % 3_600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uptime(store: &mut TelemetryStore, ticks: u32, at: Instant) -> Option<Reboot> {
+        store.record(Telemetry::Uptime(ticks), at)
+    }
+
+    #[test]
+    fn uptime_going_backwards_is_a_reboot() {
+        let start = Instant::now();
+        let mut store = TelemetryStore::new();
+        assert!(uptime(&mut store, 600, start).is_none());
+        assert!(uptime(&mut store, 605, start + UPTIME_TICK * 5).is_none());
+        let reboot = uptime(&mut store, 3, start + Duration::from_secs(1)).unwrap();
+        assert_eq!(reboot.previous_uptime, UPTIME_TICK * 605);
+        assert_eq!(reboot.reset_cause, None);
+        assert_eq!(reboot.peak_current, None);
+    }
+
+    #[test]
+    fn uptime_wrapping_isnt_a_reboot() {
+        let start = Instant::now();
+        let mut store = TelemetryStore::new();
+        uptime(&mut store, 0xFF_FFFE, start);
+        assert!(uptime(&mut store, 1, start + UPTIME_TICK * 3).is_none());
+        // Near the top of the range, uptime beyond what the time gone by
+        // allows for is still a reboot
+        uptime(&mut store, 0xFF_FFFE, start + Duration::from_secs(10));
+        let later = start + Duration::from_secs(11);
+        assert!(uptime(&mut store, 500, later).is_some());
+    }
+
+    #[test]
+    fn reboot_takes_peak_current_from_before_the_robot_went_quiet() {
+        let start = Instant::now();
+        let mut store = TelemetryStore::new();
+        let current = |store: &mut TelemetryStore, value, secs| {
+            store.record(
+                Telemetry::BatteryCurrent(value),
+                start + Duration::from_secs(secs),
+            );
+        };
+        current(&mut store, 900, 0);
+        current(&mut store, 300, 3);
+        current(&mut store, 400, 4);
+        uptime(&mut store, 50, start + Duration::from_secs(5));
+        // Readings after the last uptime are from the rebooted robot
+        current(&mut store, 700, 6);
+        let reboot = uptime(&mut store, 2, start + Duration::from_secs(7)).unwrap();
+        assert_eq!(reboot.peak_current, Some(400));
+    }
+}
@@ -1,11 +1,15 @@
+//! Reading key presses from the terminal
+
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
 
 use crossterm::event::{poll, read, Event};
+use skelebot_controller::actions::{
+    record_ticks_for_period, send_error_message, Action, RECORD_TICKS_INTERVAL,
+};
 
-use crate::actions::{record_ticks_for_period, send_error_message, Action, RECORD_TICKS_INTERVAL};
-
+/// Sends key presses as actions until the exit flag is set
 pub fn collect_terminal_events(tx: Sender<Action>, exit_flag: &AtomicBool) {
     let mut prev_marker = Instant::now();
     let mut next_marker = prev_marker + RECORD_TICKS_INTERVAL;
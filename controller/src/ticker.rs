@@ -0,0 +1,218 @@
+//! A fixed-rate tick on timerfd, for the radio loop
+
+use std::os::fd::{AsFd, AsRawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+use nix::sys::eventfd::{EfdFlags, EventFd};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use nix::unistd::read;
+
+const TIMER_EVENT: u64 = 0;
+const WAKE_EVENT: u64 = 1;
+
+/// Timing of ticks over a period, from when they were due to when the loop
+/// actually woke
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickStats {
+    pub ticks: u32,
+    /// Ticks missed entirely, because the loop was still busy when they were due
+    pub overruns: u32,
+    pub total_jitter: Duration,
+    pub max_jitter: Duration,
+}
+
+impl TickStats {
+    pub fn mean_jitter(&self) -> Duration {
+        if self.ticks == 0 {
+            Duration::ZERO
+        } else {
+            self.total_jitter / self.ticks
+        }
+    }
+}
+
+/// Wakes a `Ticker` early from another thread
+#[derive(Clone)]
+pub struct Waker(Arc<EventFd>);
+
+impl Waker {
+    pub fn wake(&self) {
+        // Can only fail if the counter would overflow, in which case it's
+        // already been woken plenty
+        _ = self.0.write(1);
+    }
+}
+
+/// Fixed-rate loop timing from a timerfd, so deadlines don't drift with the
+/// time spent in each iteration, and the loop can be woken early via a `Waker`
+pub struct Ticker {
+    timer: TimerFd,
+    wake_fd: Arc<EventFd>,
+    epoll: Epoll,
+    interval: Duration,
+    deadline: Instant,
+    stats: TickStats,
+}
+
+impl Ticker {
+    pub fn new(interval: Duration) -> Result<(Self, Waker), Errno> {
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        let wake_fd = Arc::new(EventFd::from_flags(
+            EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC,
+        )?);
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, TIMER_EVENT))?;
+        epoll.add(
+            wake_fd.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN, WAKE_EVENT),
+        )?;
+
+        timer.set(
+            Expiration::Interval(TimeSpec::from_duration(interval)),
+            TimerSetTimeFlags::empty(),
+        )?;
+        let ticker = Self {
+            timer,
+            wake_fd: Arc::clone(&wake_fd),
+            epoll,
+            interval,
+            deadline: Instant::now() + interval,
+            stats: TickStats::default(),
+        };
+        Ok((ticker, Waker(wake_fd)))
+    }
+
+    /// Blocks until the next tick is due or a `Waker` is used, returning true
+    /// if a tick is due
+    pub fn wait(&mut self) -> Result<bool, Errno> {
+        let mut events = [EpollEvent::empty(); 2];
+        loop {
+            let count = match self.epoll.wait(&mut events, EpollTimeout::NONE) {
+                Ok(count) => count,
+                Err(Errno::EINTR) => {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            let mut ticked = false;
+            let mut woken = false;
+            for event in events[..count].iter() {
+                match event.data() {
+                    TIMER_EVENT => {
+                        ticked |= self.read_timer()?;
+                    }
+                    WAKE_EVENT => {
+                        // Just resets the counter; EAGAIN means another read got there first
+                        _ = self.wake_fd.read();
+                        woken = true;
+                    }
+                    _ => {}
+                }
+            }
+            if ticked || woken {
+                return Ok(ticked);
+            }
+        }
+    }
+
+    /// Returns the stats since last called, and starts a new period
+    pub fn take_stats(&mut self) -> TickStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    // Reads the number of expirations since last read, returning false if none
+    fn read_timer(&mut self) -> Result<bool, Errno> {
+        let mut buf = [0_u8; 8];
+        match read(self.timer.as_fd().as_raw_fd(), &mut buf) {
+            Ok(_) => {}
+            Err(Errno::EAGAIN) => {
+                return Ok(false);
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        let now = Instant::now();
+        let expirations = u32::try_from(u64::from_ne_bytes(buf)).unwrap_or(u32::MAX);
+        if expirations == 0 {
+            return Ok(false);
+        }
+
+        // Jitter is measured against the latest expiry, earlier ones were missed
+        let missed = expirations - 1;
+        let latest = self.deadline + self.interval * missed;
+        let jitter = now.saturating_duration_since(latest);
+        self.deadline = latest + self.interval;
+
+        self.stats.ticks += 1;
+        self.stats.overruns += missed;
+        self.stats.total_jitter += jitter;
+        self.stats.max_jitter = self.stats.max_jitter.max(jitter);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn ticks_at_the_interval() {
+        let (mut ticker, _waker) = Ticker::new(INTERVAL).unwrap();
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(ticker.wait().unwrap());
+        }
+        let took = start.elapsed();
+        // Deadlines are fixed, so only the last tick's lateness adds on
+        assert!(took >= INTERVAL * 5, "took {:?}", took);
+        assert!(
+            took < INTERVAL * 5 + Duration::from_millis(200),
+            "took {:?}",
+            took
+        );
+        let stats = ticker.take_stats();
+        assert_eq!(stats.ticks, 5);
+        assert_eq!(ticker.take_stats().ticks, 0);
+    }
+
+    #[test]
+    fn a_wake_interrupts_the_wait() {
+        let (mut ticker, waker) = Ticker::new(Duration::from_secs(10)).unwrap();
+        let start = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(INTERVAL);
+                waker.wake();
+            });
+            assert!(!ticker.wait().unwrap());
+        });
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(ticker.take_stats().ticks, 0);
+    }
+
+    #[test]
+    fn late_ticks_count_as_overruns() {
+        let (mut ticker, _waker) = Ticker::new(INTERVAL).unwrap();
+        // Busy past three expiries, so two of them are missed entirely
+        thread::sleep(INTERVAL * 3 + INTERVAL / 2);
+        assert!(ticker.wait().unwrap());
+        let stats = ticker.take_stats();
+        assert_eq!(stats.ticks, 1);
+        assert!(stats.overruns >= 2, "{:?}", stats);
+        assert!(stats.max_jitter < INTERVAL, "{:?}", stats);
+    }
+}
@@ -0,0 +1,463 @@
+//! Transports to the robot: a Crazyradio, or UDP to the emulator
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crazyradio::{self, Channel, Crazyradio, Datarate, Power};
+use skelebot_protocol::{DataRate, PaLevel, MAX_TELEMETRY_LEN};
+
+use crate::rf::Tuning;
+
+/// Max packet and ack payload length for the nRF24
+pub(crate) const MAX_PAYLOAD: usize = 32;
+
+/// Crazyradio default address, which the robot firmware mirrors in `control_addr`
+pub const DEFAULT_ADDRESS: [u8; 5] = [0xE7; 5];
+
+/// Formats an nRF address as hex, most significant byte first
+pub fn format_address(address: &[u8; 5]) -> String {
+    address.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+// Roughly equivalent to the radio's ack wait plus a single retry
+const UDP_ACK_TIMEOUT: Duration = Duration::from_millis(5);
+// Sequence number, channel, data rate, then address
+const UDP_HEADER_LEN: usize = 8;
+
+/// Ack status of a sent packet, independent of the transport used
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AckInfo {
+    /// Whether an ack was received at all
+    pub received: bool,
+    /// Whether the received power was over the power detector threshold (-64dBm)
+    pub power_detector: bool,
+    /// Number of retries before the ack was received
+    pub retry: usize,
+    /// Length of the ack payload copied into the ack buffer
+    pub length: usize,
+}
+
+impl From<crazyradio::Ack> for AckInfo {
+    fn from(ack: crazyradio::Ack) -> Self {
+        Self {
+            received: ack.received,
+            power_detector: ack.power_detector,
+            retry: ack.retry,
+            length: ack.length,
+        }
+    }
+}
+
+/// Why a transport couldn't be opened or used
+#[derive(Debug)]
+pub enum TransportError {
+    /// From the Crazyradio driver
+    Radio(crazyradio::Error),
+    /// From the UDP socket
+    Io(io::Error),
+    /// No Crazyradio with the requested serial, listing those that were found
+    SerialNotFound {
+        /// Serial asked for
+        serial: String,
+        /// Serials of the radios plugged in
+        available: Vec<String>,
+    },
+    /// No radio open to send on
+    NotOpen,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Radio(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::SerialNotFound { serial, available } => {
+                if available.is_empty() {
+                    write!(f, "crazyradio {} not found, no others present", serial)
+                } else {
+                    write!(
+                        f,
+                        "crazyradio {} not found, present: {}",
+                        serial,
+                        available.join(", ")
+                    )
+                }
+            }
+            Self::NotOpen => write!(f, "no radio open"),
+        }
+    }
+}
+
+impl Error for TransportError {}
+
+impl From<crazyradio::Error> for TransportError {
+    fn from(e: crazyradio::Error) -> Self {
+        Self::Radio(e)
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A packet link to the robot: sends a single packet and returns the ack
+/// payload (if any) along with the ack metadata
+pub trait Transport: Send {
+    /// Sends a packet of up to `MAX_PAYLOAD` bytes, copying any ack payload
+    /// into `ack_data` (truncated to its length)
+    fn send_packet(&mut self, data: &[u8], ack_data: &mut [u8]) -> Result<AckInfo, TransportError>;
+
+    /// Retunes the transport to the given nRF channel
+    fn set_channel(&mut self, channel: u8) -> Result<(), TransportError>;
+
+    /// Sets the 5-byte nRF address packets are sent to
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), TransportError>;
+
+    /// Sets the air data rate and transmit power
+    fn set_rate(&mut self, rate: DataRate, power: PaLevel) -> Result<(), TransportError>;
+
+    /// Human-readable description, for status messages
+    fn describe(&self) -> String;
+}
+
+/// Whether the radio device is currently usable, as reported to the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RadioStatus {
+    /// Open and sending
+    Connected,
+    /// Unplugged or failing, and being retried
+    Disconnected,
+}
+
+/// Auto-retransmit settings for the Crazyradio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetrySettings {
+    /// Delay before retransmitting (250-4000us in 250us steps), or None to
+    /// wait just long enough for an ack payload of `MAX_TELEMETRY_LEN` bytes
+    pub ard: Option<Duration>,
+    /// Retransmits before giving up on a packet (0-15)
+    pub arc: u8,
+}
+
+impl RetrySettings {
+    /// Most retransmits the Crazyradio can be set to
+    pub const MAX_ARC: u8 = 15;
+
+    /// A retransmit delay in microseconds, if it's one the Crazyradio supports
+    pub fn ard_from_micros(us: u64) -> Option<Duration> {
+        ((250..=4000).contains(&us) && us % 250 == 0).then(|| Duration::from_micros(us))
+    }
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            ard: None,
+            // Only retry once, we'd rather just move to the next command
+            arc: 1,
+        }
+    }
+}
+
+/// Which transport to open, and how
+#[derive(Clone, Debug)]
+pub enum TransportKind {
+    /// Crazyradio with the given serial, or the first found
+    Crazyradio {
+        /// Serial to pick it by
+        serial: Option<String>,
+        /// Auto-retransmit settings
+        retry: RetrySettings,
+    },
+    /// UDP socket, to a software robot such as `hercules`
+    Udp {
+        /// Address to bind
+        local: SocketAddr,
+        /// Robot's address
+        remote: SocketAddr,
+    },
+}
+
+/// Opens a transport on the given radio settings
+pub fn open_transport(
+    kind: &TransportKind,
+    tuning: Tuning,
+) -> Result<Box<dyn Transport>, TransportError> {
+    match kind {
+        TransportKind::Crazyradio { serial, retry } => {
+            let cr = CrazyradioTransport::open(serial.as_deref(), tuning, *retry)?;
+            Ok(Box::new(cr))
+        }
+        TransportKind::Udp { local, remote } => {
+            let udp = UdpTransport::open(*local, *remote, tuning)?;
+            Ok(Box::new(udp))
+        }
+    }
+}
+
+pub(crate) struct CrazyradioTransport {
+    radio: Crazyradio,
+}
+
+impl CrazyradioTransport {
+    pub fn open(
+        serial: Option<&str>,
+        tuning: Tuning,
+        retry: RetrySettings,
+    ) -> Result<Self, TransportError> {
+        let channel = Channel::from_number(tuning.channel)?;
+        let mut cr = match serial {
+            Some(serial) => match Crazyradio::open_by_serial(serial) {
+                Ok(cr) => cr,
+                Err(crazyradio::Error::NotFound) => {
+                    return Err(TransportError::SerialNotFound {
+                        serial: serial.to_owned(),
+                        available: Crazyradio::list_serials().unwrap_or_default(),
+                    });
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            },
+            None => Crazyradio::open_first()?,
+        };
+        cr.set_channel(channel)?;
+        match retry.ard {
+            // The crazyradio lib takes the millis of the given duration as
+            // microseconds, so convert to match
+            Some(ard) => cr.set_ard_time(Duration::from_millis(ard.as_micros() as u64))?,
+            None => cr.set_ard_bytes(MAX_TELEMETRY_LEN as u8)?,
+        }
+        cr.set_arc(retry.arc.into())?;
+        let mut transport = Self { radio: cr };
+        transport.set_rate(tuning.rate, tuning.power)?;
+        Ok(transport)
+    }
+}
+
+impl Transport for CrazyradioTransport {
+    fn send_packet(&mut self, data: &[u8], ack_data: &mut [u8]) -> Result<AckInfo, TransportError> {
+        // The crazyradio lib copies exactly ack_data.len() bytes, so always
+        // receive into a full-size buffer and copy out what was asked for
+        let mut buf = [0_u8; MAX_PAYLOAD];
+        let mut ack = AckInfo::from(self.radio.send_packet(data, &mut buf)?);
+        let len = ack_data.len().min(MAX_PAYLOAD);
+        ack_data[..len].copy_from_slice(&buf[..len]);
+        ack.length = ack.length.min(len);
+        Ok(ack)
+    }
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), TransportError> {
+        let channel = Channel::from_number(channel)?;
+        self.radio.set_channel(channel)?;
+        Ok(())
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), TransportError> {
+        self.radio.set_address(address)?;
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: DataRate, power: PaLevel) -> Result<(), TransportError> {
+        let datarate = match rate {
+            DataRate::Kbps250 => Datarate::Dr250K,
+            DataRate::Mbps1 => Datarate::Dr1M,
+            DataRate::Mbps2 => Datarate::Dr2M,
+        };
+        let power = match power {
+            PaLevel::Min => Power::Pm18dBm,
+            PaLevel::Low => Power::Pm12dBm,
+            PaLevel::High => Power::Pm6dBm,
+            PaLevel::Max => Power::P0dBm,
+        };
+        self.radio.set_datarate(datarate)?;
+        self.radio.set_power(power)?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        match self.radio.serial() {
+            Ok(serial) => format!("crazyradio, serial {}", serial),
+            Err(_) => String::from("crazyradio, serial <unknown>"),
+        }
+    }
+}
+
+/// Datagram transport for driving a software robot, eg on localhost
+///
+/// Each packet goes out as one datagram behind an eight-byte header: a
+/// sequence number, then the channel, data rate (coded as in radio.md) and
+/// five-byte address it was "sent" on. The peer acks by replying with the
+/// same sequence number followed by any ack payload; no reply within the
+/// timeout counts as a lost packet.
+pub(crate) struct UdpTransport {
+    socket: UdpSocket,
+    remote: SocketAddr,
+    channel: u8,
+    rate: DataRate,
+    address: [u8; 5],
+    seq: u8,
+}
+
+impl UdpTransport {
+    pub fn open(
+        local: SocketAddr,
+        remote: SocketAddr,
+        tuning: Tuning,
+    ) -> Result<Self, TransportError> {
+        let socket = UdpSocket::bind(local)?;
+        Ok(Self {
+            socket,
+            remote,
+            channel: tuning.channel,
+            rate: tuning.rate,
+            address: DEFAULT_ADDRESS,
+            seq: 0,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_packet(&mut self, data: &[u8], ack_data: &mut [u8]) -> Result<AckInfo, TransportError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut datagram = [0_u8; MAX_PAYLOAD + UDP_HEADER_LEN];
+        datagram[0] = self.seq;
+        datagram[1] = self.channel;
+        datagram[2] = self.rate.to_u8();
+        datagram[3..UDP_HEADER_LEN].copy_from_slice(&self.address);
+        datagram[UDP_HEADER_LEN..data.len() + UDP_HEADER_LEN].copy_from_slice(data);
+        self.socket
+            .send_to(&datagram[..data.len() + UDP_HEADER_LEN], self.remote)?;
+
+        // Wait for the matching reply, discarding any late ones and anything
+        // from elsewhere, however many of those keep arriving
+        let deadline = Instant::now() + UDP_ACK_TIMEOUT;
+        let mut reply = [0_u8; MAX_PAYLOAD + 1];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(AckInfo::default());
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            let len = match self.socket.recv_from(&mut reply) {
+                Ok((len, from)) if from == self.remote => len,
+                Ok(_) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    return Ok(AckInfo::default());
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            };
+            if len >= 1 && reply[0] == self.seq {
+                let payload = &reply[1..len];
+                let copy_len = payload.len().min(ack_data.len());
+                ack_data[..copy_len].copy_from_slice(&payload[..copy_len]);
+                return Ok(AckInfo {
+                    received: true,
+                    power_detector: false,
+                    retry: 0,
+                    length: copy_len,
+                });
+            }
+        }
+    }
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), TransportError> {
+        self.channel = channel;
+        Ok(())
+    }
+
+    fn set_address(&mut self, address: &[u8; 5]) -> Result<(), TransportError> {
+        self.address = *address;
+        Ok(())
+    }
+
+    fn set_rate(&mut self, rate: DataRate, _power: PaLevel) -> Result<(), TransportError> {
+        self.rate = rate;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("udp, remote {}", self.remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    // Sends junk to `target` from another socket until `stop` is set
+    fn flood(target: SocketAddr, stop: &AtomicBool) {
+        let socket = UdpSocket::bind(localhost()).unwrap();
+        while !stop.load(Ordering::Relaxed) {
+            let _ = socket.send_to(&[0xAA; 4], target);
+            thread::sleep(Duration::from_micros(100));
+        }
+    }
+
+    #[test]
+    fn foreign_datagrams_dont_hold_up_the_timeout() {
+        let robot = UdpSocket::bind(localhost()).unwrap();
+        let mut udp =
+            UdpTransport::open(localhost(), robot.local_addr().unwrap(), Tuning::boot()).unwrap();
+        let local = udp.socket.local_addr().unwrap();
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| flood(local, &stop));
+            let start = Instant::now();
+            let ack = udp.send_packet(&[0xF0], &mut [0; MAX_PAYLOAD]).unwrap();
+            let took = start.elapsed();
+            stop.store(true, Ordering::Relaxed);
+            assert!(!ack.received);
+            assert!(took < UDP_ACK_TIMEOUT * 4, "took {:?}", took);
+        });
+    }
+
+    #[test]
+    fn the_reply_is_picked_out_from_foreign_datagrams() {
+        let robot = UdpSocket::bind(localhost()).unwrap();
+        let mut udp =
+            UdpTransport::open(localhost(), robot.local_addr().unwrap(), Tuning::boot()).unwrap();
+        let local = udp.socket.local_addr().unwrap();
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| flood(local, &stop));
+            s.spawn(|| {
+                let mut datagram = [0; 64];
+                let (_, from) = robot.recv_from(&mut datagram).unwrap();
+                robot.send_to(&[datagram[0], 0xE8, 7], from).unwrap();
+            });
+            let mut ack_data = [0; MAX_PAYLOAD];
+            let ack = udp.send_packet(&[0xF2, 7], &mut ack_data).unwrap();
+            stop.store(true, Ordering::Relaxed);
+            assert!(ack.received);
+            assert_eq!(&ack_data[..ack.length], [0xE8, 7]);
+        });
+    }
+}
@@ -1,3 +1,5 @@
+//! The terminal UI
+
 use std::collections::VecDeque;
 use std::io::stdout;
 use std::panic::{set_hook, take_hook};
@@ -9,49 +11,187 @@ use crossterm::terminal::{disable_raw_mode, EnterAlternateScreen, LeaveAlternate
 use crossterm::ExecutableCommand;
 use ratatui::prelude::*;
 use ratatui::text::Span;
+use ratatui::widgets::canvas::{self, Canvas, Context, Points, Rectangle};
 use ratatui::widgets::{
     Axis, Bar, BarChart, BarGroup, Block, Chart, Dataset, GraphType, Paragraph, Wrap,
 };
-
-use crate::actions::{
+use skelebot_controller::actions::{
     record_ticks_for_period, Action, BatteryCurrent, BatteryVoltage, ControlSpeed, ControlState,
-    ThreadMsg, RECORD_TICKS_INTERVAL,
+    ThreadMsg, WheelRpm, RECORD_TICKS_INTERVAL,
+};
+use skelebot_controller::channel::ChannelStatus;
+use skelebot_controller::config::{DriveConfig, UiConfig};
+use skelebot_controller::diversity::RadioLink;
+use skelebot_controller::fleet::FleetStatus;
+use skelebot_controller::latency::{LatencyStats, Percentiles};
+use skelebot_controller::link::LinkStats;
+use skelebot_controller::params::ParamEditor;
+use skelebot_controller::rf::Tuning;
+use skelebot_controller::sim::{SimPose, ARENA_HEIGHT, ARENA_WIDTH, ROBOT_LENGTH, ROBOT_WIDTH};
+use skelebot_controller::telemetry::{format_uptime, Reboot, TelemetryStore};
+use skelebot_controller::transport::RadioStatus;
+use skelebot_protocol::{
+    Param, Telemetry, TLM_BATTERY_CURRENT, TLM_BATTERY_VOLTAGE, TLM_IDENTITY, TLM_LEFT_RPM,
+    TLM_RIGHT_RPM, TLM_UPTIME, TLM_VTX_STATE, UPTIME_TICK,
 };
 
-const MESSAGE_LINES: u16 = 5;
+// Difference between commanded and actual wheel speed worth highlighting, in %
+const WHEEL_RPM_MISMATCH: f32 = 25.0;
+// Loss thresholds for link quality styling, in %
+const LINK_LOSS_WARN: f32 = 5.0;
+const LINK_LOSS_CRIT: f32 = 25.0;
+// Consecutive failure thresholds, relative to the robot's 200ms connection-loss stop
+const LINK_FAILS_WARN: u32 = 3;
+const LINK_FAILS_CRIT: u32 = 10;
+// How long the battery heading shows a reboot alert
+const REBOOT_ALERT_FOR: Duration = Duration::from_secs(30);
+// Simulated robot's trail, as points this far apart, in metres, up to a limit
+const TRAIL_SPACING: f64 = 0.02;
+const TRAIL_POINTS: usize = 1_000;
+// Space around the arena walls, in metres
+const ARENA_MARGIN: f64 = 0.2;
+// How far the camera heading's drawn out from the robot, in metres
+const CAMERA_RAY: f64 = 0.6;
 
+/// State for the UI thread to show
 pub enum UIUpdate {
     Control(ControlState),
-    BatteryVoltage(BatteryVoltage),
-    BatteryCurrent(BatteryCurrent),
+    /// Telemetry item, with the index of the robot it came from
+    Telemetry(usize, Telemetry, Instant),
+    LinkStats(LinkStats),
+    /// Link quality of each radio, when sending on more than one
+    RadioLinks(Vec<RadioLink>),
+    Latency(LatencyStats),
+    /// Switches between the stick chart and latency diagnostics
+    ToggleDiagnostics,
+    /// Parameter editor state, shown in place of the stick chart while open
+    Params(ParamEditor),
+    Channel(ChannelStatus),
+    ChannelSelection(Option<u8>),
+    Fleet(FleetStatus),
+    Radio(RadioStatus),
+    /// Simulated robot's pose, which puts the arena in place of the stick chart
+    Sim(SimPose),
     Message(ThreadMsg),
     Error(ThreadMsg),
 }
 
+// What's been heard from one robot in the fleet
+#[derive(Default)]
+struct RobotTelemetry {
+    store: TelemetryStore,
+    last_reboot: Option<Reboot>,
+    reboots: u32,
+}
+
 struct UIState {
     control_state: ControlState,
-    battery_voltage: BatteryVoltage,
-    battery_current: BatteryCurrent,
+    // Speed limits, to show the wheel speeds the control state asks for
+    drive: DriveConfig,
+    // By robot index, so switching robot doesn't look like a reboot
+    robots: Vec<RobotTelemetry>,
+    link_stats: LinkStats,
+    radio_links: Vec<RadioLink>,
+    latency: LatencyStats,
+    diagnostics: bool,
+    params: ParamEditor,
+    channel: ChannelStatus,
+    channel_selection: Option<u8>,
+    fleet: FleetStatus,
+    radio: Option<RadioStatus>,
+    sim: Option<SimPose>,
+    sim_trail: VecDeque<(f64, f64)>,
     messages: VecDeque<String>,
+    message_lines: u16,
 }
 
 impl UIState {
-    fn new() -> Self {
+    fn new(message_lines: u16, drive: DriveConfig) -> Self {
         Self {
             control_state: ControlState::new(),
-            battery_voltage: BatteryVoltage(0),
-            battery_current: BatteryCurrent(0),
+            drive,
+            robots: vec![RobotTelemetry::default()],
+            link_stats: LinkStats::default(),
+            radio_links: Vec::new(),
+            latency: LatencyStats::default(),
+            diagnostics: false,
+            params: ParamEditor::new(),
+            channel: ChannelStatus {
+                tuning: Tuning::boot(),
+                target: None,
+                auto_migrate: false,
+                auto_rate: false,
+            },
+            channel_selection: None,
+            fleet: FleetStatus {
+                robots: 1,
+                active: 0,
+                target: None,
+            },
+            radio: None,
+            sim: None,
+            sim_trail: VecDeque::new(),
             messages: vec![].into(),
+            message_lines,
+        }
+    }
+
+    // Extends the trail once the robot's moved far enough from its end
+    fn record_sim_pose(&mut self, pose: SimPose) {
+        let moved = self
+            .sim_trail
+            .back()
+            .is_none_or(|&(x, y)| (pose.x - x).hypot(pose.y - y) >= TRAIL_SPACING);
+        if moved {
+            self.sim_trail.push_back((pose.x, pose.y));
+            if self.sim_trail.len() > TRAIL_POINTS {
+                _ = self.sim_trail.pop_front();
+            }
+        }
+        self.sim = Some(pose);
+    }
+
+    fn record_telemetry(&mut self, robot: usize, telemetry: Telemetry, received: Instant) {
+        if robot >= self.robots.len() {
+            self.robots.resize_with(robot + 1, RobotTelemetry::default);
+        }
+        let Some(reboot) = self.robots[robot].store.record(telemetry, received) else {
+            return;
+        };
+        // Only worth saying which robot when there's a choice
+        let which = (self.fleet.robots > 1).then_some(robot);
+        self.push_message(describe_reboot(which, &reboot));
+        let robot = &mut self.robots[robot];
+        robot.last_reboot = Some(reboot);
+        robot.reboots += 1;
+    }
+
+    // Telemetry for the robot being driven
+    fn active_robot(&self) -> &RobotTelemetry {
+        &self.robots[self.fleet.active]
+    }
+
+    fn push_message(&mut self, message: String) {
+        self.messages.push_back(message);
+        if self.messages.len() > self.message_lines.into() {
+            _ = self.messages.pop_front();
         }
     }
 }
 
-pub fn draw_ui(rx: Receiver<UIUpdate>, tx: Sender<Action>, exit_flag: &AtomicBool) {
+/// Draws the UI until the exit flag is set, restoring the terminal on panic
+pub fn draw_ui(
+    rx: Receiver<UIUpdate>,
+    tx: Sender<Action>,
+    config: &UiConfig,
+    drive: &DriveConfig,
+    exit_flag: &AtomicBool,
+) {
     let mut prev_marker = Instant::now();
     let mut next_marker = prev_marker + RECORD_TICKS_INTERVAL;
     let mut ticks = 0_u32;
 
-    let mut ui_state = UIState::new();
+    let mut ui_state = UIState::new(config.message_lines, *drive);
 
     let backend = CrosstermBackend::new(stdout());
     let mut terminal = match Terminal::new(backend) {
@@ -90,28 +230,49 @@ pub fn draw_ui(rx: Receiver<UIUpdate>, tx: Sender<Action>, exit_flag: &AtomicBoo
                     UIUpdate::Control(new_state) => {
                         ui_state.control_state = new_state;
                     }
-                    UIUpdate::BatteryVoltage(new_voltage) => {
-                        ui_state.battery_voltage = new_voltage;
+                    UIUpdate::Telemetry(robot, telemetry, received) => {
+                        ui_state.record_telemetry(robot, telemetry, received);
+                    }
+                    UIUpdate::LinkStats(new_stats) => {
+                        ui_state.link_stats = new_stats;
+                    }
+                    UIUpdate::RadioLinks(new_links) => {
+                        ui_state.radio_links = new_links;
+                    }
+                    UIUpdate::Latency(new_stats) => {
+                        ui_state.latency = new_stats;
+                    }
+                    UIUpdate::ToggleDiagnostics => {
+                        ui_state.diagnostics = !ui_state.diagnostics;
+                    }
+                    UIUpdate::Params(editor) => {
+                        ui_state.params = editor;
                     }
-                    UIUpdate::BatteryCurrent(new_current) => {
-                        ui_state.battery_current = new_current;
+                    UIUpdate::Channel(new_status) => {
+                        ui_state.channel = new_status;
+                    }
+                    UIUpdate::ChannelSelection(new_selection) => {
+                        ui_state.channel_selection = new_selection;
+                    }
+                    UIUpdate::Fleet(new_status) => {
+                        ui_state.fleet = new_status;
+                        let robots = new_status.robots.max(ui_state.robots.len());
+                        ui_state.robots.resize_with(robots, RobotTelemetry::default);
+                    }
+                    UIUpdate::Radio(new_status) => {
+                        ui_state.radio = Some(new_status);
+                    }
+                    UIUpdate::Sim(pose) => {
+                        ui_state.record_sim_pose(pose);
                     }
                     UIUpdate::Message(msg) => {
-                        ui_state
-                            .messages
-                            .push_back(format!("{0}: {1}\r\n", msg.name, msg.message));
-                        if ui_state.messages.len() > MESSAGE_LINES.into() {
-                            _ = ui_state.messages.pop_front();
-                        }
+                        ui_state.push_message(format!("{0}: {1}\r\n", msg.name, msg.message));
                     }
                     UIUpdate::Error(err_msg) => {
-                        ui_state.messages.push_back(format!(
+                        ui_state.push_message(format!(
                             "Error from {0}: {1}\r\n",
                             err_msg.name, err_msg.message
                         ));
-                        if ui_state.messages.len() > MESSAGE_LINES.into() {
-                            _ = ui_state.messages.pop_front();
-                        }
                     }
                 }
                 if let Err(e) = terminal.draw(|frame| render_ui(frame, &ui_state)) {
@@ -153,17 +314,26 @@ pub fn draw_ui(rx: Receiver<UIUpdate>, tx: Sender<Action>, exit_flag: &AtomicBoo
 
 fn render_ui(frame: &mut Frame, ui_state: &UIState) {
     // Extracted from joystick position
-    let (left_val, right_val) = ui_state.control_state.as_tank_drive();
+    let (left_val, right_val) = ui_state.control_state.as_tank_drive(&ui_state.drive);
     let move_speed = ui_state.control_state.move_speed;
     let (pan_val, tilt_val) = ui_state.control_state.as_camera_angles();
-    let voltage = ui_state.battery_voltage.as_float();
-    let current = ui_state.battery_current.as_float();
+    let now = Instant::now();
+    let robot = ui_state.active_robot();
+    let voltage = reading(&robot.store, TLM_BATTERY_VOLTAGE, now);
+    let current = reading(&robot.store, TLM_BATTERY_CURRENT, now);
+    let left_rpm = reading(&robot.store, TLM_LEFT_RPM, now);
+    let right_rpm = reading(&robot.store, TLM_RIGHT_RPM, now);
+    let vtx = reading(&robot.store, TLM_VTX_STATE, now);
+    let left_rpm_pct = WheelRpm(left_rpm.value).as_percent();
+    let right_rpm_pct = WheelRpm(right_rpm.value).as_percent();
+    let link = ui_state.link_stats;
+    let channel = ui_state.channel;
 
     let outer_layout = Layout::default()
         .direction(Direction::Vertical)
         .constraints(vec![
-            Constraint::Min(23),
-            Constraint::Length(MESSAGE_LINES + 2),
+            Constraint::Min(33),
+            Constraint::Length(ui_state.message_lines + 2),
         ])
         .split(frame.area());
     let upper_layout = Layout::default()
@@ -172,12 +342,14 @@ fn render_ui(frame: &mut Frame, ui_state: &UIState) {
             Constraint::Length(14),
             Constraint::Min(23),
             Constraint::Length(14),
+            Constraint::Length(20),
         ])
         .split(outer_layout[0]);
     let lower_layout = outer_layout[1];
     let upper_left = upper_layout[0];
     let upper_mid = upper_layout[1];
     let upper_right = upper_layout[2];
+    let upper_far_right = upper_layout[3];
 
     // Summary values
     let sum_data = vec![
@@ -209,13 +381,91 @@ fn render_ui(frame: &mut Frame, ui_state: &UIState) {
             Span::styled(format!("{}°", tilt_val), camera_angle_style(tilt_val)),
         ]),
         Line::from(""),
-        Line::from("Battery"),
-        // TODO: style
+        battery_heading_line(robot.last_reboot, now),
         // TODO: figure out how to stop Ratatui from eating the leading spaces
-        Line::raw(format!("{:>10}", format!("  {:.2}V", voltage))),
-        Line::raw(format!("{:>10}", format!("  {:.2}A", current))),
-        // TODO: left RPM
-        // TODO: right RPM
+        Line::styled(
+            format!(
+                "{:>10}",
+                voltage.format(|v| format!("  {:.2}V", BatteryVoltage(v).as_float()))
+            ),
+            voltage.style(Style::default().white()),
+        ),
+        Line::styled(
+            format!(
+                "{:>10}",
+                current.format(|v| format!("  {:.2}A", BatteryCurrent(v).as_float()))
+            ),
+            current.style(Style::default().white()),
+        ),
+        Line::from(vec![
+            Span::from("L RPM: "),
+            Span::styled(
+                left_rpm.format(|v| v.to_string()),
+                left_rpm.style(wheel_rpm_style(left_val, left_rpm_pct)),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("R RPM: "),
+            Span::styled(
+                right_rpm.format(|v| v.to_string()),
+                right_rpm.style(wheel_rpm_style(right_val, right_rpm_pct)),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("VTX:   "),
+            Span::styled(
+                vtx.format(|v| String::from(if v != 0 { "on" } else { "off" })),
+                vtx.style(vtx_style(vtx.value != 0)),
+            ),
+        ]),
+        Line::from(""),
+        Line::from("Link"),
+        radio_line(
+            ui_state.radio,
+            &ui_state.radio_links,
+            ui_state.sim.is_some(),
+        ),
+        fleet_line(ui_state.fleet),
+        Line::from(vec![
+            Span::from("Chan:  "),
+            Span::styled(channel.tuning.channel.to_string(), channel_style(channel)),
+            Span::from(if channel.auto_migrate { " A" } else { "" }),
+        ]),
+        channel_next_line(channel, ui_state.channel_selection),
+        Line::from(vec![
+            Span::from("Rate:  "),
+            Span::styled(channel.tuning.rate.to_string(), rate_style(channel)),
+        ]),
+        Line::from(vec![
+            Span::from("Power: "),
+            Span::styled(channel.tuning.power.to_string(), rate_style(channel)),
+            Span::from(if channel.auto_rate { " A" } else { "" }),
+        ]),
+        Line::from(vec![
+            Span::from("Loss:  "),
+            Span::styled(
+                format!("{:.0}%", link.loss_percent),
+                link_loss_style(link.loss_percent),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("Retry: "),
+            Span::styled(
+                format!("{:.0}/s", link.retries_per_sec),
+                link_loss_style(link.loss_percent),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("Fails: "),
+            Span::styled(
+                link.consecutive_failures.to_string(),
+                link_failures_style(link.consecutive_failures),
+            ),
+        ]),
+        Line::from(vec![
+            Span::from("PD:    "),
+            Span::from(format!("{:.0}%", link.power_detector_percent)),
+        ]),
     ];
     let sum_para = Paragraph::new(sum_data)
         .block(Block::bordered())
@@ -286,6 +536,42 @@ fn render_ui(frame: &mut Frame, ui_state: &UIState) {
         .data(BarGroup::default().bars(ur_data))
         .max(200);
 
+    // Commanded vs actual wheel speed
+    // RPM telemetry is unsigned, so compare against commanded magnitude
+    let left_rpm_data = &[
+        Bar::default()
+            .value(left_val.unsigned_abs().into())
+            .label("C".into())
+            .style(tank_drive_style(left_val))
+            .value_style(tank_drive_value_style(left_val)),
+        Bar::default()
+            .value(left_rpm_pct.round() as u64)
+            .label("A".into())
+            .style(wheel_rpm_style(left_val, left_rpm_pct))
+            .value_style(wheel_rpm_value_style(left_val, left_rpm_pct)),
+    ];
+    let right_rpm_data = &[
+        Bar::default()
+            .value(right_val.unsigned_abs().into())
+            .label("C".into())
+            .style(tank_drive_style(right_val))
+            .value_style(tank_drive_value_style(right_val)),
+        Bar::default()
+            .value(right_rpm_pct.round() as u64)
+            .label("A".into())
+            .style(wheel_rpm_style(right_val, right_rpm_pct))
+            .value_style(wheel_rpm_value_style(right_val, right_rpm_pct)),
+    ];
+    let rpm_chart = BarChart::default()
+        .block(Block::bordered().title("RPM"))
+        .bar_width(3)
+        .bar_gap(1)
+        .group_gap(2)
+        .value_style(Style::new().black().on_black())
+        .data(BarGroup::default().label("L".into()).bars(left_rpm_data))
+        .data(BarGroup::default().label("R".into()).bars(right_rpm_data))
+        .max(100);
+
     // Message list
     let msg_data: Vec<Line<'_>> = ui_state
         .messages
@@ -299,11 +585,35 @@ fn render_ui(frame: &mut Frame, ui_state: &UIState) {
         .wrap(Wrap { trim: true });
 
     frame.render_widget(sum_para, upper_left);
-    frame.render_widget(um_chart, upper_mid);
+    if ui_state.params.open {
+        frame.render_widget(param_editor_para(&ui_state.params), upper_mid);
+    } else if ui_state.diagnostics {
+        let para = diagnostics_para(
+            &ui_state.latency,
+            &ui_state.radio_links,
+            &robot.store,
+            robot.reboots,
+        );
+        frame.render_widget(para, upper_mid);
+    } else if let Some(pose) = ui_state.sim {
+        let trail: Vec<(f64, f64)> = ui_state.sim_trail.iter().copied().collect();
+        frame.render_widget(arena_canvas(pose, trail, upper_mid), upper_mid);
+    } else {
+        frame.render_widget(um_chart, upper_mid);
+    }
     frame.render_widget(ur_chart, upper_right);
+    frame.render_widget(rpm_chart, upper_far_right);
     frame.render_widget(msg_para, lower_layout);
 }
 
+fn vtx_style(on: bool) -> Style {
+    if on {
+        Style::default().green()
+    } else {
+        Style::default().white()
+    }
+}
+
 fn tank_drive_style(val: i8) -> Style {
     if val > 0 {
         Style::default().green()
@@ -341,6 +651,482 @@ fn camera_angle_style(val: i8) -> Style {
     }
 }
 
+// Highlight wheels lagging well behind (or running well ahead of) the drive command
+fn wheel_rpm_style(drive_val: i8, rpm_percent: f32) -> Style {
+    let diff = (f32::from(drive_val).abs() - rpm_percent).abs();
+    if diff >= WHEEL_RPM_MISMATCH {
+        Style::default().light_red()
+    } else {
+        Style::default().magenta()
+    }
+}
+
+fn wheel_rpm_value_style(drive_val: i8, rpm_percent: f32) -> Style {
+    let diff = (f32::from(drive_val).abs() - rpm_percent).abs();
+    if diff >= WHEEL_RPM_MISMATCH {
+        Style::default().light_red().on_light_red()
+    } else {
+        Style::default().magenta().on_magenta()
+    }
+}
+
+fn channel_style(status: ChannelStatus) -> Style {
+    if status
+        .target
+        .is_some_and(|target| target.channel != status.tuning.channel)
+    {
+        Style::default().light_yellow()
+    } else {
+        Style::default().white()
+    }
+}
+
+// Data rate and power share a style, as they're changed together
+fn rate_style(status: ChannelStatus) -> Style {
+    let tuning = status.tuning;
+    if status
+        .target
+        .is_some_and(|target| (target.rate, target.power) != (tuning.rate, tuning.power))
+    {
+        Style::default().light_yellow()
+    } else {
+        Style::default().white()
+    }
+}
+
+// With several radios, shows how many are connected, as one is enough to drive
+fn radio_line(status: Option<RadioStatus>, links: &[RadioLink], sim: bool) -> Line<'static> {
+    if sim {
+        return Line::from(vec![
+            Span::from("Radio: "),
+            Span::styled("sim", Style::default().cyan()),
+        ]);
+    }
+    let connected = links
+        .iter()
+        .filter(|link| link.status == Some(RadioStatus::Connected))
+        .count();
+    let value = match status {
+        Some(RadioStatus::Connected) if !links.is_empty() => {
+            let style = if connected < links.len() {
+                Style::default().light_yellow()
+            } else {
+                Style::default().green()
+            };
+            Span::styled(format!("{}/{}", connected, links.len()), style)
+        }
+        Some(RadioStatus::Connected) => Span::styled("ok", Style::default().green()),
+        Some(RadioStatus::Disconnected) => Span::styled("none", Style::default().red()),
+        None => Span::styled("...", Style::default().dark_gray()),
+    };
+    Line::from(vec![Span::from("Radio: "), value])
+}
+
+// Top-down view of the simulated robot in its arena, scaled to fit the area
+// with metres the same size across and up, as terminal cells are about twice
+// as tall as they are wide
+fn arena_canvas(
+    pose: SimPose,
+    trail: Vec<(f64, f64)>,
+    area: Rect,
+) -> Canvas<'static, impl Fn(&mut Context)> {
+    let cols = f64::from(area.width.saturating_sub(2).max(1));
+    let rows = f64::from(area.height.saturating_sub(2).max(1));
+    let metres_per_col = f64::max(
+        (ARENA_WIDTH + 2.0 * ARENA_MARGIN) / cols,
+        (ARENA_HEIGHT + 2.0 * ARENA_MARGIN) / (2.0 * rows),
+    );
+    let half_width = metres_per_col * cols / 2.0;
+    let half_height = metres_per_col * rows;
+    let x_bounds = [
+        ARENA_WIDTH / 2.0 - half_width,
+        ARENA_WIDTH / 2.0 + half_width,
+    ];
+    let y_bounds = [
+        ARENA_HEIGHT / 2.0 - half_height,
+        ARENA_HEIGHT / 2.0 + half_height,
+    ];
+
+    Canvas::default()
+        .block(Block::bordered().title(format!("Arena ({:.2}m, {:.2}m)", pose.x, pose.y)))
+        .marker(symbols::Marker::Braille)
+        .x_bounds(x_bounds)
+        .y_bounds(y_bounds)
+        .paint(move |ctx| {
+            ctx.draw(&Rectangle {
+                x: 0.0,
+                y: 0.0,
+                width: ARENA_WIDTH,
+                height: ARENA_HEIGHT,
+                color: Color::White,
+            });
+            ctx.draw(&Points {
+                coords: &trail,
+                color: Color::DarkGray,
+            });
+            ctx.layer();
+
+            // Chassis outline, with the front edge picked out
+            let (sin, cos) = pose.heading.sin_cos();
+            let corner = |forward: f64, left: f64| {
+                (
+                    pose.x + forward * cos - left * sin,
+                    pose.y + forward * sin + left * cos,
+                )
+            };
+            let (half_length, half_width) = (ROBOT_LENGTH / 2.0, ROBOT_WIDTH / 2.0);
+            let corners = [
+                corner(half_length, half_width),
+                corner(-half_length, half_width),
+                corner(-half_length, -half_width),
+                corner(half_length, -half_width),
+            ];
+            for (i, &(x1, y1)) in corners.iter().enumerate() {
+                let (x2, y2) = corners[(i + 1) % corners.len()];
+                let color = if i == corners.len() - 1 {
+                    Color::Green
+                } else {
+                    Color::Cyan
+                };
+                ctx.draw(&canvas::Line::new(x1, y1, x2, y2, color));
+            }
+
+            let (sin, cos) = pose.camera_heading.sin_cos();
+            ctx.draw(&canvas::Line::new(
+                pose.x,
+                pose.y,
+                pose.x + CAMERA_RAY * cos,
+                pose.y + CAMERA_RAY * sin,
+                Color::Yellow,
+            ));
+        })
+}
+
+// Each radio's own link quality, to tell which is pulling its weight
+fn radio_link_lines(links: &[RadioLink]) -> Vec<Line<'static>> {
+    links
+        .iter()
+        .enumerate()
+        .map(|(idx, link)| {
+            let label = Span::from(format!("{}: ", idx + 1));
+            if link.status != Some(RadioStatus::Connected) {
+                return Line::from(vec![label, Span::styled("none", Style::default().red())]);
+            }
+            let stats = link.stats;
+            Line::from(vec![
+                label,
+                Span::styled(
+                    format!("{:.0}% loss", stats.loss_percent),
+                    link_loss_style(stats.loss_percent),
+                ),
+                Span::from(format!(
+                    ", {:.0} retries/s, PD {:.0}%",
+                    stats.retries_per_sec, stats.power_detector_percent
+                )),
+            ])
+        })
+        .collect()
+}
+
+fn fleet_line(status: FleetStatus) -> Line<'static> {
+    let mut spans = vec![
+        Span::from("Bot:   "),
+        Span::from(format!("{}/{}", status.active + 1, status.robots)),
+    ];
+    if let Some(target) = status.target {
+        spans.push(Span::styled(
+            format!(">{}", target + 1),
+            Style::default().light_yellow(),
+        ));
+    }
+    Line::from(spans)
+}
+
+// Shows an in-progress change, or else the operator's pending channel selection
+fn channel_next_line(status: ChannelStatus, selection: Option<u8>) -> Line<'static> {
+    match (status.target, selection) {
+        (Some(target), _) => {
+            // Only room for one setting, and switches rarely change more than one
+            let next = if target.channel != status.tuning.channel {
+                target.channel.to_string()
+            } else if target.rate != status.tuning.rate {
+                target.rate.to_string()
+            } else {
+                target.power.to_string()
+            };
+            Line::from(vec![
+                Span::from("Next:  "),
+                Span::styled(next, Style::default().light_yellow()),
+            ])
+        }
+        (None, Some(selected)) => Line::from(vec![
+            Span::from("Sel:   "),
+            Span::styled(format!("{}?", selected), Style::default().cyan()),
+        ]),
+        (None, None) => Line::from(""),
+    }
+}
+
+// Latest raw value of a telemetry kind, zero if nothing has been received
+struct Reading {
+    value: u16,
+    received: bool,
+    stale: bool,
+}
+
+impl Reading {
+    fn format(&self, format_value: impl Fn(u16) -> String) -> String {
+        if self.received {
+            format_value(self.value)
+        } else {
+            String::from("--")
+        }
+    }
+
+    // Greys out values which haven't been updated for a while
+    fn style(&self, fresh_style: Style) -> Style {
+        if self.stale {
+            Style::default().dark_gray()
+        } else {
+            fresh_style
+        }
+    }
+}
+
+fn reading(store: &TelemetryStore, type_byte: u8, now: Instant) -> Reading {
+    match store.get(type_byte) {
+        Some(entry) => Reading {
+            value: entry.latest.value().unwrap_or(0),
+            received: true,
+            stale: entry.is_stale(now),
+        },
+        None => Reading {
+            value: 0,
+            received: false,
+            stale: true,
+        },
+    }
+}
+
+fn diagnostics_para(
+    latency: &LatencyStats,
+    radio_links: &[RadioLink],
+    telemetry: &TelemetryStore,
+    reboots: u32,
+) -> Paragraph<'static> {
+    let mut lines = vec![Line::from("Round trip (ping to pong)")];
+    lines.push(percentiles_line(latency.rtt));
+    lines.push(Line::from(format!(
+        "{} of {} pings lost",
+        latency.pings_lost, latency.pings_sent
+    )));
+    lines.push(Line::from(""));
+    lines.push(Line::from(
+        "Input to air (stick event to robot handling it)",
+    ));
+    lines.push(percentiles_line(latency.input_to_air));
+    lines.push(Line::from(""));
+    if !radio_links.is_empty() {
+        lines.push(Line::from("Radios"));
+        lines.extend(radio_link_lines(radio_links));
+        lines.push(Line::from(""));
+    }
+    lines.push(Line::from("Battery voltage (recent)"));
+    lines.push(battery_range_line(telemetry));
+    lines.push(Line::from(""));
+    lines.push(Line::from("Robot"));
+    lines.extend(robot_health_lines(telemetry, reboots));
+    Paragraph::new(lines)
+        .block(Block::bordered().title("Diagnostics"))
+        .style(Style::new().white().on_black())
+        .left_aligned()
+        .wrap(Wrap { trim: true })
+}
+
+fn robot_health_lines(telemetry: &TelemetryStore, reboots: u32) -> Vec<Line<'static>> {
+    let identity = telemetry.get(TLM_IDENTITY).map(|entry| entry.latest);
+    let uptime = telemetry.get(TLM_UPTIME).map(|entry| entry.latest);
+    let (
+        Some(Telemetry::Identity {
+            major,
+            minor,
+            reset_cause,
+        }),
+        Some(Telemetry::Uptime(ticks)),
+    ) = (identity, uptime)
+    else {
+        return vec![Line::styled(
+            "not heard from yet",
+            Style::default().dark_gray(),
+        )];
+    };
+    let reboots_style = if reboots > 0 {
+        Style::default().red()
+    } else {
+        Style::default()
+    };
+    vec![
+        Line::from(format!(
+            "firmware {}.{}, up {}",
+            major,
+            minor,
+            format_uptime(UPTIME_TICK * ticks)
+        )),
+        Line::from(vec![
+            Span::from(format!("last reset: {}  ", reset_cause)),
+            Span::styled(format!("reboots seen: {}", reboots), reboots_style),
+        ]),
+    ]
+}
+
+fn describe_reboot(robot: Option<usize>, reboot: &Reboot) -> String {
+    let robot = match robot {
+        Some(idx) => format!("Robot {}", idx + 1),
+        None => String::from("Robot"),
+    };
+    let cause = match reboot.reset_cause {
+        Some(cause) => cause.to_string(),
+        None => String::from("unknown cause"),
+    };
+    let current = match reboot.peak_current {
+        Some(current) => format!("{:.2}A", BatteryCurrent(current).as_float()),
+        None => String::from("unknown"),
+    };
+    format!(
+        "{} rebooted ({}) after {} up, peak current before: {}\r\n",
+        robot,
+        cause,
+        format_uptime(reboot.previous_uptime),
+        current
+    )
+}
+
+// Battery heading, replaced by an alert for a while after the robot reboots
+fn battery_heading_line(last_reboot: Option<Reboot>, now: Instant) -> Line<'static> {
+    let recent = last_reboot
+        .filter(|reboot| now.saturating_duration_since(reboot.detected) < REBOOT_ALERT_FOR);
+    let Some(reboot) = recent else {
+        return Line::from("Battery");
+    };
+    let alert = match reboot.peak_current {
+        Some(current) => format!("Reboot {:.2}A", BatteryCurrent(current).as_float()),
+        None => String::from("Rebooted!"),
+    };
+    Line::styled(alert, Style::default().white().on_red().bold())
+}
+
+fn param_editor_para(editor: &ParamEditor) -> Paragraph<'static> {
+    let mut lines: Vec<Line> = Param::ALL
+        .iter()
+        .zip(editor.params.iter())
+        .enumerate()
+        .map(|(idx, (param, state))| {
+            let (min, max) = param.range();
+            let mut spans = vec![Span::from(format!("{:<16}", param.to_string()))];
+            match (state.value, state.requested) {
+                (_, Some(requested)) => {
+                    spans.push(Span::styled(
+                        format!("{:>5} (setting)", requested),
+                        Style::default().yellow(),
+                    ));
+                }
+                (Some(value), None) => {
+                    spans.push(Span::from(format!("{:>5}", value)));
+                }
+                (None, None) => {
+                    spans.push(Span::styled(
+                        format!("{:>5} (reading)", "--"),
+                        Style::default().dark_gray(),
+                    ));
+                }
+            }
+            spans.push(Span::styled(
+                format!("  {}-{}", min, max),
+                Style::default().dark_gray(),
+            ));
+            let line = Line::from(spans);
+            if idx == editor.selected {
+                line.reversed()
+            } else {
+                line
+            }
+        })
+        .collect();
+    lines.push(Line::from(""));
+    if editor.unsaved {
+        lines.push(Line::styled(
+            "Unsaved: 'w' keeps these over a reboot",
+            Style::default().yellow(),
+        ));
+    } else {
+        lines.push(Line::from(""));
+    }
+    lines.push(Line::styled(
+        "'k'/'j' select, '-'/'=' adjust, PgDn/PgUp x10",
+        Style::default().dark_gray(),
+    ));
+    lines.push(Line::styled(
+        "'g' reload, 'w' save, 'p' close",
+        Style::default().dark_gray(),
+    ));
+    Paragraph::new(lines)
+        .block(Block::bordered().title("Robot parameters"))
+        .style(Style::new().white().on_black())
+        .left_aligned()
+        .wrap(Wrap { trim: true })
+}
+
+fn battery_range_line(telemetry: &TelemetryStore) -> Line<'static> {
+    let volts = telemetry
+        .get(TLM_BATTERY_VOLTAGE)
+        .into_iter()
+        .flat_map(|entry| entry.history())
+        .filter_map(|(_, t)| t.value())
+        .map(|value| BatteryVoltage(value).as_float());
+    let (count, min, max) = volts.fold((0, f32::MAX, f32::MIN), |(count, min, max), v| {
+        (count + 1, min.min(v), max.max(v))
+    });
+    if count == 0 {
+        return Line::styled("no samples yet", Style::default().dark_gray());
+    }
+    Line::from(vec![
+        Span::from(format!("min {:.2}V  max {:.2}V  ", min, max)),
+        Span::styled(format!("(n={})", count), Style::default().dark_gray()),
+    ])
+}
+
+fn percentiles_line(percentiles: Option<Percentiles>) -> Line<'static> {
+    let Some(p) = percentiles else {
+        return Line::styled("no samples yet", Style::default().dark_gray());
+    };
+    let ms = |d: Duration| format!("{:.1}ms", d.as_secs_f32() * 1_000.0);
+    Line::from(vec![
+        Span::from(format!("p50 {}  p90 {}  ", ms(p.p50), ms(p.p90))),
+        Span::from(format!("p99 {}  max {}  ", ms(p.p99), ms(p.max))),
+        Span::styled(format!("(n={})", p.samples), Style::default().dark_gray()),
+    ])
+}
+
+fn link_loss_style(loss_percent: f32) -> Style {
+    if loss_percent >= LINK_LOSS_CRIT {
+        Style::default().red()
+    } else if loss_percent >= LINK_LOSS_WARN {
+        Style::default().light_yellow()
+    } else {
+        Style::default().green()
+    }
+}
+
+fn link_failures_style(failures: u32) -> Style {
+    if failures >= LINK_FAILS_CRIT {
+        Style::default().red()
+    } else if failures >= LINK_FAILS_WARN {
+        Style::default().light_yellow()
+    } else {
+        Style::default().white()
+    }
+}
+
 fn send_io_error(tx: Sender<Action>, err: std::io::Error, err_desc: &str) {
     let msg = ThreadMsg {
         name: "UI".to_owned(),
@@ -0,0 +1,278 @@
+//! The radio thread driving the firmware emulator over UDP, end to end
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use skelebot_controller::actions::{Action, ControlState};
+use skelebot_controller::channel::ROBOT_BOOT_CHANNEL;
+use skelebot_controller::config::DriveConfig;
+use skelebot_controller::radio::{self, RadioOptions, RadioRequest, RadioSender};
+use skelebot_controller::rf::{ROBOT_BOOT_POWER, ROBOT_BOOT_RATE};
+use skelebot_controller::transport::{TransportKind, DEFAULT_ADDRESS};
+use skelebot_protocol::auth::{parse_key, AuthKey};
+use skelebot_protocol::{Command as RobotCommand, Telemetry};
+
+const KEY: &str = "000102030405060708090A0B0C0D0E0F";
+const OTHER_KEY: &str = "F0E0D0C0B0A090807060504030201000";
+const WAIT: Duration = Duration::from_secs(5);
+
+/// The emulator on a free port, with its log lines; killed on drop
+struct Hercules {
+    child: Child,
+    addr: SocketAddr,
+    log: Receiver<String>,
+    key_file: Option<PathBuf>,
+}
+
+impl Hercules {
+    fn start(key: Option<&str>) -> Self {
+        let key_file = key.map(|key| {
+            let path = std::env::temp_dir().join(format!(
+                "skelebot-hercules-test-{}-{}.key",
+                std::process::id(),
+                key
+            ));
+            fs::write(&path, format!("{}\n", key)).unwrap();
+            path
+        });
+        let mut command = Command::new(env!("CARGO_BIN_EXE_hercules"));
+        command.args(["--listen", "127.0.0.1:0", "--fast-boot"]);
+        if let Some(ref path) = key_file {
+            command.arg("--auth-key").arg(path);
+        }
+        let mut child = command.stdout(Stdio::piped()).spawn().unwrap();
+
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let first = lines.next().unwrap().unwrap();
+        let addr = first
+            .split_once("listening on ")
+            .and_then(|(_, rest)| rest.split(',').next())
+            .and_then(|addr| addr.parse().ok())
+            .unwrap_or_else(|| panic!("no address in \"{}\"", first));
+        let (tx, log) = mpsc::channel();
+        thread::spawn(move || {
+            for line in lines.map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            child,
+            addr,
+            log,
+            key_file,
+        }
+    }
+
+    /// Waits for a log line containing `text`
+    fn expect_log(&self, text: &str) -> String {
+        let deadline = Instant::now() + WAIT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.log.recv_timeout(left) {
+                Ok(line) if line.contains(text) => return line,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        panic!("hercules never logged \"{}\"", text);
+    }
+
+    /// Sends `command` on its own, as the controller's UDP transport would,
+    /// and waits for the ack
+    fn send(&self, socket: &UdpSocket, command: RobotCommand) {
+        let mut datagram = vec![0, ROBOT_BOOT_CHANNEL, ROBOT_BOOT_RATE.to_u8()];
+        datagram.extend_from_slice(&DEFAULT_ADDRESS);
+        let mut payload = [0; 32];
+        let len = command.encode(&mut payload).unwrap();
+        datagram.extend_from_slice(&payload[..len]);
+        socket.send_to(&datagram, self.addr).unwrap();
+        socket.set_read_timeout(Some(WAIT)).unwrap();
+        let mut reply = [0; 64];
+        socket.recv(&mut reply).expect("no ack from hercules");
+    }
+}
+
+impl Drop for Hercules {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(ref path) = self.key_file {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Runs the radio thread against `hercules` until `test` returns
+fn with_radio<F>(hercules: &Hercules, key: Option<&str>, test: F)
+where
+    F: FnOnce(&RadioSender, &Mutex<ControlState>, &Receiver<Action>),
+{
+    let options = RadioOptions {
+        transports: vec![TransportKind::Udp {
+            local: SocketAddr::from(([127, 0, 0, 1], 0)),
+            remote: hercules.addr,
+        }],
+        addresses: vec![DEFAULT_ADDRESS],
+        auto_channel: false,
+        auto_rate: false,
+        rate: ROBOT_BOOT_RATE,
+        power: ROBOT_BOOT_POWER,
+    };
+    let auth_key = key.map(|key| AuthKey::new(parse_key(key).unwrap()));
+    let (radio_tx, radio_rx) = radio::radio_channel().unwrap();
+    let control_state = Arc::new(Mutex::new(ControlState::new()));
+    let exit_flag = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            radio::radio_comms(
+                tx,
+                radio_rx,
+                Arc::clone(&control_state),
+                &exit_flag,
+                &options,
+                &DriveConfig::default(),
+                auth_key,
+            );
+        });
+        // Stop the radio thread even if the test fails
+        struct Exit<'a>(&'a AtomicBool);
+        impl Drop for Exit<'_> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+        let _exit = Exit(&exit_flag);
+        test(&radio_tx, &control_state, &rx);
+    });
+}
+
+/// Waits for an action `pick` returns something for
+fn expect_action<T>(rx: &Receiver<Action>, mut pick: impl FnMut(Action) -> Option<T>) -> T {
+    let deadline = Instant::now() + WAIT;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match rx.recv_timeout(left) {
+            Ok(action) => {
+                if let Some(found) = pick(action) {
+                    return found;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    panic!("radio thread never sent the expected action");
+}
+
+fn drive_forward(radio_tx: &RadioSender, control_state: &Mutex<ControlState>) {
+    control_state.lock().unwrap().throttle = i16::MAX;
+    radio_tx.send(RadioRequest::ControlChanged(None)).unwrap();
+}
+
+#[test]
+fn drives_the_robot_and_reads_its_telemetry() {
+    let hercules = Hercules::start(None);
+    hercules.expect_log("booted");
+    with_radio(&hercules, None, |radio_tx, control_state, rx| {
+        let volts = expect_action(rx, |action| match action {
+            Action::Telemetry(0, Telemetry::BatteryVoltage(raw), _) => Some(raw),
+            _ => None,
+        });
+        // 11.1V, as hercules reports by default
+        assert!((11_000..=11_600).contains(&volts), "battery {}", volts);
+
+        drive_forward(radio_tx, control_state);
+        hercules.expect_log("left +50% right +50%");
+
+        control_state.lock().unwrap().throttle = 0;
+        radio_tx.send(RadioRequest::ControlChanged(None)).unwrap();
+        hercules.expect_log("left +0% right +0%");
+    });
+}
+
+#[test]
+fn moves_the_robot_to_another_channel() {
+    let hercules = Hercules::start(None);
+    with_radio(&hercules, None, |radio_tx, _, rx| {
+        radio_tx.send(RadioRequest::ChangeChannel(77)).unwrap();
+        hercules.expect_log("controller followed to channel 77");
+        expect_action(rx, |action| match action {
+            Action::ChannelUpdate(status)
+                if status.tuning.channel == 77 && status.target.is_none() =>
+            {
+                Some(())
+            }
+            _ => None,
+        });
+    });
+}
+
+#[test]
+fn authenticated_commands_are_confirmed() {
+    let hercules = Hercules::start(Some(KEY));
+    with_radio(&hercules, Some(KEY), |radio_tx, control_state, rx| {
+        drive_forward(radio_tx, control_state);
+        hercules.expect_log("left +50% right +50%");
+
+        radio_tx.send(RadioRequest::SetVtx(false)).unwrap();
+        expect_action(rx, |action| match action {
+            Action::Telemetry(0, Telemetry::VtxState(false), _) => Some(()),
+            _ => None,
+        });
+        hercules.expect_log("VTX off");
+    });
+}
+
+#[test]
+fn commands_under_the_wrong_key_are_dropped_and_reported() {
+    let hercules = Hercules::start(Some(KEY));
+    with_radio(&hercules, Some(OTHER_KEY), |radio_tx, control_state, rx| {
+        drive_forward(radio_tx, control_state);
+        hercules.expect_log("dropped command");
+        expect_action(rx, |action| match action {
+            Action::Error(msg) if msg.message.contains("not confirming commands") => Some(()),
+            _ => None,
+        });
+    });
+}
+
+#[test]
+fn a_single_command_is_handled_without_waiting_for_the_next() {
+    let hercules = Hercules::start(None);
+    hercules.expect_log("booted");
+    // Outputs are only logged every 250ms, and the drive would be undone by
+    // the connection timing out before the next chance after boot
+    hercules.expect_log("left +0% right +0%");
+    thread::sleep(Duration::from_millis(300));
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    hercules.send(
+        &socket,
+        RobotCommand::Drive {
+            left: 50,
+            right: 50,
+        },
+    );
+    hercules.expect_log("left +50% right +50%");
+    // Nothing follows the stop, so it has to take effect before the robot
+    // gives up on the connection and stops by itself
+    hercules.send(&socket, RobotCommand::Stop);
+    let deadline = Instant::now() + WAIT;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        let line = hercules.log.recv_timeout(left).unwrap();
+        assert!(!line.contains("no command"), "stop not handled: {}", line);
+        if line.contains("left +0% right +0%") {
+            return;
+        }
+    }
+    panic!("hercules never stopped");
+}
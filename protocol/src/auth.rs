@@ -0,0 +1,263 @@
+//! Authenticated frames: a command wrapped with a rolling counter and a
+//! truncated Chaskey-12 MAC, so only controllers with the shared key can
+//! drive the robot, and recorded frames can't be replayed
+
+use core::error::Error;
+use core::fmt;
+
+use crate::{ProtocolError, Telemetry, CMD_AUTHENTICATED, MAX_ITEM_LEN, TLM_AUTH_RECEIPT};
+
+/// Shared key length, as for Chaskey
+pub const KEY_LEN: usize = 16;
+/// MAC bytes kept in each frame; forging one takes ~2^31 attempts on air,
+/// far more than the link can carry before anyone notices
+pub const TAG_LEN: usize = 4;
+/// MAC bytes kept in each receipt; a forged one only makes the controller
+/// think a command got through, so this is traded for fitting in an item
+pub const RECEIPT_TAG_LEN: usize = 2;
+const COUNTER_LEN: usize = 4;
+/// Longest authenticated frame: wrapper type, counter, command and tag
+pub const MAX_FRAME_LEN: usize = 1 + COUNTER_LEN + MAX_ITEM_LEN + TAG_LEN;
+// Longest message covered by the MAC: address, counter and command
+const MAX_MAC_INPUT_LEN: usize = 5 + COUNTER_LEN + MAX_ITEM_LEN;
+// Rounds of the Chaskey permutation, as for Chaskey-12
+const CHASKEY_ROUNDS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// Not wrapped in an authenticated frame at all
+    NotAuthenticated,
+    /// Too short to hold a counter, command and tag
+    Truncated,
+    /// MAC doesn't match, so wrong key, wrong robot or tampered with
+    BadTag,
+    /// Counter not above the last one accepted, so possibly a replay
+    Stale { counter: u32 },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAuthenticated => write!(f, "not an authenticated frame"),
+            Self::Truncated => write!(f, "authenticated frame too short"),
+            Self::BadTag => write!(f, "MAC doesn't match"),
+            Self::Stale { counter } => write!(f, "stale counter {}", counter),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// Chaskey key, along with the subkeys used for the final block
+#[derive(Clone)]
+pub struct AuthKey {
+    k: [u32; 4],
+    k1: [u32; 4],
+    k2: [u32; 4],
+}
+
+impl AuthKey {
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        let k = words(&bytes);
+        let k1 = times_two(k);
+        let k2 = times_two(k1);
+        Self { k, k1, k2 }
+    }
+
+    /// Full 16-byte Chaskey-12 MAC of a message, its words little-endian as
+    /// in the reference code
+    pub fn mac(&self, message: &[u8]) -> [u8; 16] {
+        let mut v = self.k;
+        let mut blocks = message.chunks(16).peekable();
+        let mut last = [0_u8; 16];
+        let mut last_key = self.k2;
+        while let Some(block) = blocks.next() {
+            if blocks.peek().is_some() {
+                xor_words(&mut v, &words(block.try_into().unwrap()));
+                permute(&mut v);
+            } else if block.len() == 16 {
+                last.copy_from_slice(block);
+                last_key = self.k1;
+            } else {
+                last[..block.len()].copy_from_slice(block);
+                last[block.len()] = 0x01;
+            }
+        }
+        if message.is_empty() {
+            last[0] = 0x01;
+        }
+        xor_words(&mut v, &words(&last));
+        xor_words(&mut v, &last_key);
+        permute(&mut v);
+        xor_words(&mut v, &last_key);
+
+        let mut tag = [0_u8; 16];
+        for (bytes, word) in tag.chunks_mut(4).zip(v) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        tag
+    }
+
+    // Truncated MAC over the robot's address, the counter and the command
+    fn tag(&self, address: &[u8; 5], counter: u32, packet: &[u8]) -> [u8; TAG_LEN] {
+        let mut message = [0_u8; MAX_MAC_INPUT_LEN];
+        message[..5].copy_from_slice(address);
+        message[5..9].copy_from_slice(&counter.to_be_bytes());
+        let len = 9 + packet.len();
+        message[9..len].copy_from_slice(packet);
+        let mac = self.mac(&message[..len]);
+        mac[..TAG_LEN].try_into().unwrap()
+    }
+
+    // Truncated MAC over the robot's address, the counter and the receipt's
+    // type byte, which no command shares, so it can't pass for a frame's tag
+    fn receipt_tag(&self, address: &[u8; 5], counter: u32) -> u16 {
+        let mut message = [0_u8; 5 + COUNTER_LEN + 1];
+        message[..5].copy_from_slice(address);
+        message[5..9].copy_from_slice(&counter.to_be_bytes());
+        message[9] = TLM_AUTH_RECEIPT;
+        let mac = self.mac(&message);
+        u16::from_be_bytes(mac[..RECEIPT_TAG_LEN].try_into().unwrap())
+    }
+}
+
+/// Wraps commands in authenticated frames, keeping the rolling counter
+///
+/// The counter covers every robot, as each only needs it to keep rising.
+pub struct Authenticator {
+    key: AuthKey,
+    counter: u32,
+}
+
+impl Authenticator {
+    pub fn new(key: AuthKey) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    /// Wraps an encoded command for the robot at `address`, returning the frame length
+    pub fn seal(
+        &mut self,
+        address: &[u8; 5],
+        packet: &[u8],
+        frame: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        let len = 1 + COUNTER_LEN + packet.len() + TAG_LEN;
+        if frame.len() < len || packet.len() > MAX_ITEM_LEN {
+            return Err(ProtocolError::BufferTooSmall {
+                needed: len,
+                actual: frame.len(),
+            });
+        }
+        self.counter = self.counter.saturating_add(1);
+        let tag = self.key.tag(address, self.counter, packet);
+        frame[0] = CMD_AUTHENTICATED;
+        frame[1..5].copy_from_slice(&self.counter.to_be_bytes());
+        frame[5..5 + packet.len()].copy_from_slice(packet);
+        frame[5 + packet.len()..len].copy_from_slice(&tag);
+        Ok(len)
+    }
+
+    /// Counter of the frame last sealed, 0 before the first
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Moves the counter past the robot's, as reported when it rejects a stale
+    /// frame; `floor_high` is the top 24 bits of the last counter it accepted
+    pub fn resync(&mut self, floor_high: u32) {
+        let floor = floor_high.saturating_add(1).saturating_mul(1 << 8);
+        self.counter = self.counter.max(floor);
+    }
+}
+
+/// Checks an authenticated frame for the robot at `address`, as the robot
+/// does, returning the counter and the command inside
+pub fn open<'a>(
+    key: &AuthKey,
+    address: &[u8; 5],
+    frame: &'a [u8],
+    last_counter: u32,
+) -> Result<(u32, &'a [u8]), AuthError> {
+    if frame.first() != Some(&CMD_AUTHENTICATED) {
+        return Err(AuthError::NotAuthenticated);
+    }
+    if frame.len() < 1 + COUNTER_LEN + 1 + TAG_LEN || frame.len() > MAX_FRAME_LEN {
+        return Err(AuthError::Truncated);
+    }
+    let counter = u32::from_be_bytes(frame[1..5].try_into().unwrap());
+    let (packet, tag) = frame[5..].split_at(frame.len() - 5 - TAG_LEN);
+    if key.tag(address, counter, packet) != tag {
+        return Err(AuthError::BadTag);
+    }
+    if counter <= last_counter {
+        return Err(AuthError::Stale { counter });
+    }
+    Ok((counter, packet))
+}
+
+/// Receipt for the last frame the robot at `address` accepted, as it puts in
+/// its ack payloads
+pub fn receipt(key: &AuthKey, address: &[u8; 5], counter: u32) -> Telemetry {
+    Telemetry::AuthReceipt {
+        counter: counter as u8,
+        tag: key.receipt_tag(address, counter),
+    }
+}
+
+/// Checks whether a receipt from the robot at `address` is for the frame
+/// sealed with `counter`
+pub fn check_receipt(key: &AuthKey, address: &[u8; 5], counter: u32, receipt: Telemetry) -> bool {
+    receipt == self::receipt(key, address, counter)
+}
+
+/// Parses a key written as 32 hex digits
+pub fn parse_key(text: &str) -> Option<[u8; KEY_LEN]> {
+    if text.len() != KEY_LEN * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut key = [0_u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn words(bytes: &[u8; 16]) -> [u32; 4] {
+    let mut words = [0_u32; 4];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+fn xor_words(v: &mut [u32; 4], other: &[u32; 4]) {
+    for (word, other) in v.iter_mut().zip(other) {
+        *word ^= other;
+    }
+}
+
+// Multiplication by x in GF(2^128), for deriving the subkeys
+fn times_two(k: [u32; 4]) -> [u32; 4] {
+    let carry = if k[3] & 0x8000_0000 != 0 { 0x87 } else { 0 };
+    [
+        (k[0] << 1) ^ carry,
+        (k[1] << 1) | (k[0] >> 31),
+        (k[2] << 1) | (k[1] >> 31),
+        (k[3] << 1) | (k[2] >> 31),
+    ]
+}
+
+fn permute(v: &mut [u32; 4]) {
+    for _ in 0..CHASKEY_ROUNDS {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(5) ^ v[0];
+        v[0] = v[0].rotate_left(16);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(8) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(13) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(7) ^ v[2];
+        v[2] = v[2].rotate_left(16);
+    }
+}
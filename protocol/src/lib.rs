@@ -0,0 +1,756 @@
+//! The controller and robot's radio protocol, as per radio.md
+//!
+//! Commands and telemetry encode into and decode from caller-provided
+//! buffers of fixed maximum size, without allocating, so the same code
+//! serves the controller, the host firmware emulator, and firmware on the
+//! robot's microcontroller.
+
+#![no_std]
+
+use core::error::Error;
+use core::fmt;
+use core::time::Duration;
+
+pub mod auth;
+
+// Command types, as per radio.md
+pub const CMD_PARAM_GET: u8 = 0xE0;
+pub const CMD_PARAM_SET: u8 = 0xE1;
+pub const CMD_PARAM_COMMIT: u8 = 0xE2;
+pub const CMD_SET_VTX: u8 = 0xE3;
+/// Wraps another command in an authenticated frame, see `auth`
+pub const CMD_AUTHENTICATED: u8 = 0xE4;
+pub const CMD_NOOP: u8 = 0xF0;
+pub const CMD_CHANGE_CHANNEL: u8 = 0xF1;
+pub const CMD_PING: u8 = 0xF2;
+pub const CMD_STOP: u8 = 0xF3;
+pub const CMD_DRIVE: u8 = 0xF4;
+pub const CMD_CENTER_CAMERA: u8 = 0xF5;
+pub const CMD_LOOK: u8 = 0xF6;
+pub const CMD_SET_RADIO: u8 = 0xF7;
+
+// Telemetry types, as per radio.md; 0xF8-0xFF is full, so later additions
+// sit in 0xE8-0xEF, keeping telemetry in the upper half of each block of 16
+pub const TLM_PONG: u8 = 0xE8;
+pub const TLM_PARAM_VALUE: u8 = 0xE9;
+pub const TLM_VTX_STATE: u8 = 0xEA;
+pub const TLM_AUTH_STALE: u8 = 0xEB;
+pub const TLM_AUTH_RECEIPT: u8 = 0xEC;
+pub const TLM_NOOP: u8 = 0xF8;
+pub const TLM_IDENTITY: u8 = 0xF9;
+pub const TLM_UPTIME: u8 = 0xFA;
+pub const TLM_BATTERY_VOLTAGE: u8 = 0xFB;
+pub const TLM_BATTERY_CURRENT: u8 = 0xFC;
+pub const TLM_LEFT_RPM: u8 = 0xFD;
+pub const TLM_RIGHT_RPM: u8 = 0xFE;
+
+/// Largest single command or telemetry item: one type byte plus up to 3 payload bytes
+pub const MAX_ITEM_LEN: usize = 4;
+/// Longest ack payload the robot sends, as several telemetry items back to
+/// back (up to 8 bytes, plus an auth receipt when authenticating); the
+/// controller's radio waits just long enough for this many bytes
+pub const MAX_TELEMETRY_LEN: usize = 12;
+/// Resolution of the robot's uptime telemetry
+pub const UPTIME_TICK: Duration = Duration::from_millis(100);
+/// Highest channel number usable by both the Crazyradio and nRF24
+pub const MAX_CHANNEL: u8 = 125;
+
+/// Highest data rate value, as per `rf24_datarate_e` (which differs from the Crazyradio's order)
+pub const MAX_DATA_RATE: u8 = 2;
+/// Highest PA level value, as per `rf24_pa_dbm_e`
+pub const MAX_PA_LEVEL: u8 = 3;
+
+const PERCENT_MAX: u8 = 200;
+const ANGLE_MAX: u8 = 180;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Zero-length packet or buffer
+    Empty,
+    /// Type byte not in the command or telemetry table
+    UnknownType(u8),
+    /// Payload length doesn't match the table for this type
+    WrongLength {
+        type_byte: u8,
+        expected: usize,
+        actual: usize,
+    },
+    /// Payload value outside the range given in the table
+    OutOfRange { type_byte: u8, value: u8 },
+    /// Output buffer too short for the encoded item
+    BufferTooSmall { needed: usize, actual: usize },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty packet"),
+            Self::UnknownType(t) => write!(f, "unknown type 0x{:02X}", t),
+            Self::WrongLength {
+                type_byte,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "wrong length for type 0x{:02X}: expected {} bytes, got {}",
+                type_byte, expected, actual
+            ),
+            Self::OutOfRange { type_byte, value } => {
+                write!(
+                    f,
+                    "value {} out of range for type 0x{:02X}",
+                    value, type_byte
+                )
+            }
+            Self::BufferTooSmall { needed, actual } => {
+                write!(
+                    f,
+                    "buffer too small: need {} bytes, have {}",
+                    needed, actual
+                )
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+/// nRF24 air data rate, ordered slowest (and longest range) first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+impl DataRate {
+    /// Value as transmitted, which is also the RF24 library's enum value
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Mbps1 => 0,
+            Self::Mbps2 => 1,
+            Self::Kbps250 => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Mbps1),
+            1 => Some(Self::Mbps2),
+            2 => Some(Self::Kbps250),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kbps250 => write!(f, "250k"),
+            Self::Mbps1 => write!(f, "1M"),
+            Self::Mbps2 => write!(f, "2M"),
+        }
+    }
+}
+
+/// nRF24 transmit power, from -18dBm (min) to 0dBm (max)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PaLevel {
+    Min,
+    Low,
+    High,
+    Max,
+}
+
+impl PaLevel {
+    /// Value as transmitted, which is also the RF24 library's enum value
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Min => 0,
+            Self::Low => 1,
+            Self::High => 2,
+            Self::Max => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Min),
+            1 => Some(Self::Low),
+            2 => Some(Self::High),
+            3 => Some(Self::Max),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PaLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Min => write!(f, "min"),
+            Self::Low => write!(f, "low"),
+            Self::High => write!(f, "high"),
+            Self::Max => write!(f, "max"),
+        }
+    }
+}
+
+/// Why the robot's microcontroller last reset, as the AVR's MCUSR flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetCause(pub u8);
+
+impl ResetCause {
+    pub const POWER_ON: u8 = 1 << 0;
+    pub const EXTERNAL: u8 = 1 << 1;
+    pub const BROWN_OUT: u8 = 1 << 2;
+    pub const WATCHDOG: u8 = 1 << 3;
+
+    pub fn is_brown_out(self) -> bool {
+        self.0 & Self::BROWN_OUT != 0
+    }
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::POWER_ON, "power-on"),
+            (Self::EXTERNAL, "reset pin"),
+            (Self::BROWN_OUT, "brown-out"),
+            (Self::WATCHDOG, "watchdog"),
+        ];
+        let mut causes = names
+            .iter()
+            .filter(|&&(flag, _)| self.0 & flag != 0)
+            .map(|&(_, name)| name);
+        // The bootloader may have cleared the flags before the firmware saw them
+        let Some(first) = causes.next() else {
+            return write!(f, "unknown");
+        };
+        write!(f, "{}", first)?;
+        causes.try_for_each(|name| write!(f, "+{}", name))
+    }
+}
+
+/// Robot settings which can be read and changed at runtime, rather than
+/// being fixed when the firmware is built
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Param {
+    ServoPanMin,
+    ServoPanMid,
+    ServoPanMax,
+    ServoTiltMin,
+    ServoTiltMid,
+    ServoTiltMax,
+    ConnLossMs,
+    AckPayloadMs,
+}
+
+impl Param {
+    pub const ALL: [Self; 8] = [
+        Self::ServoPanMin,
+        Self::ServoPanMid,
+        Self::ServoPanMax,
+        Self::ServoTiltMin,
+        Self::ServoTiltMid,
+        Self::ServoTiltMax,
+        Self::ConnLossMs,
+        Self::AckPayloadMs,
+    ];
+
+    /// Id as transmitted
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(value)).copied()
+    }
+
+    /// Values the robot accepts; it also keeps each servo's min < mid < max
+    pub fn range(self) -> (u16, u16) {
+        match self {
+            // PWM counts at 50Hz, a little beyond any servo's travel
+            Self::ServoPanMin
+            | Self::ServoPanMid
+            | Self::ServoPanMax
+            | Self::ServoTiltMin
+            | Self::ServoTiltMid
+            | Self::ServoTiltMax => (40, 600),
+            // Over the controller's 100ms keepalive, or the robot would keep stopping
+            Self::ConnLossMs => (120, 2_000),
+            Self::AckPayloadMs => (10, 1_000),
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ServoPanMin => write!(f, "pan min"),
+            Self::ServoPanMid => write!(f, "pan mid"),
+            Self::ServoPanMax => write!(f, "pan max"),
+            Self::ServoTiltMin => write!(f, "tilt min"),
+            Self::ServoTiltMid => write!(f, "tilt mid"),
+            Self::ServoTiltMax => write!(f, "tilt max"),
+            Self::ConnLossMs => write!(f, "conn loss ms"),
+            Self::AckPayloadMs => write!(f, "ack payload ms"),
+        }
+    }
+}
+
+/// Controller-to-robot commands
+///
+/// Drive and look values are signed percentages and degrees respectively, and
+/// are clamped to +/- 100% and +/- 90° on encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Noop,
+    ChangeChannel(u8),
+    /// Echoed back as a `Telemetry::Pong` in a later ack payload
+    Ping(u8),
+    Stop,
+    Drive {
+        left: i8,
+        right: i8,
+    },
+    CenterCamera,
+    Look {
+        pan: i8,
+        tilt: i8,
+    },
+    SetRadio {
+        channel: u8,
+        rate: DataRate,
+        power: PaLevel,
+    },
+    /// Answered with a `Telemetry::ParamValue` in a later ack payload
+    ParamGet(Param),
+    /// Answered as for `ParamGet`, with the old value if the robot rejected the new one
+    ParamSet {
+        param: Param,
+        value: u16,
+    },
+    /// Saves the current parameters to the robot's EEPROM
+    ParamCommit,
+    /// Switches the video transmitter on (true) or off
+    SetVtx(bool),
+}
+
+impl Command {
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            Self::Noop => CMD_NOOP,
+            Self::ChangeChannel(_) => CMD_CHANGE_CHANNEL,
+            Self::Ping(_) => CMD_PING,
+            Self::Stop => CMD_STOP,
+            Self::Drive { .. } => CMD_DRIVE,
+            Self::CenterCamera => CMD_CENTER_CAMERA,
+            Self::Look { .. } => CMD_LOOK,
+            Self::SetRadio { .. } => CMD_SET_RADIO,
+            Self::ParamGet(_) => CMD_PARAM_GET,
+            Self::ParamSet { .. } => CMD_PARAM_SET,
+            Self::ParamCommit => CMD_PARAM_COMMIT,
+            Self::SetVtx(_) => CMD_SET_VTX,
+        }
+    }
+
+    /// Total encoded length, including the type byte
+    pub fn encoded_len(&self) -> usize {
+        1 + command_payload_len(self.type_byte()).unwrap_or(0)
+    }
+
+    /// Encodes into the start of `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(ProtocolError::BufferTooSmall {
+                needed: len,
+                actual: buf.len(),
+            });
+        }
+        buf[0] = self.type_byte();
+        match *self {
+            Self::Noop | Self::Stop | Self::CenterCamera | Self::ParamCommit => {}
+            Self::ChangeChannel(channel) => {
+                if channel > MAX_CHANNEL {
+                    return Err(ProtocolError::OutOfRange {
+                        type_byte: CMD_CHANGE_CHANNEL,
+                        value: channel,
+                    });
+                }
+                buf[1] = channel;
+            }
+            Self::Ping(seq) => {
+                buf[1] = seq;
+            }
+            Self::Drive { left, right } => {
+                buf[1] = map_percent_value(left);
+                buf[2] = map_percent_value(right);
+            }
+            Self::Look { pan, tilt } => {
+                buf[1] = map_angular_value(pan);
+                buf[2] = map_angular_value(tilt);
+            }
+            Self::SetRadio {
+                channel,
+                rate,
+                power,
+            } => {
+                if channel > MAX_CHANNEL {
+                    return Err(ProtocolError::OutOfRange {
+                        type_byte: CMD_SET_RADIO,
+                        value: channel,
+                    });
+                }
+                buf[1] = channel;
+                buf[2] = rate.to_u8();
+                buf[3] = power.to_u8();
+            }
+            Self::ParamGet(param) => {
+                buf[1] = param.to_u8();
+            }
+            Self::ParamSet { param, value } => {
+                buf[1] = param.to_u8();
+                buf[2..4].copy_from_slice(&value.to_be_bytes());
+            }
+            Self::SetVtx(on) => {
+                buf[1] = on.into();
+            }
+        }
+        Ok(len)
+    }
+
+    /// Decodes a complete command packet, which must be exactly the right length
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let (&type_byte, payload) = bytes.split_first().ok_or(ProtocolError::Empty)?;
+        let expected =
+            command_payload_len(type_byte).ok_or(ProtocolError::UnknownType(type_byte))?;
+        check_length(type_byte, expected, payload)?;
+
+        let command = match type_byte {
+            CMD_NOOP => Self::Noop,
+            CMD_CHANGE_CHANNEL => {
+                let channel = check_range(type_byte, payload[0], MAX_CHANNEL)?;
+                Self::ChangeChannel(channel)
+            }
+            CMD_PING => Self::Ping(payload[0]),
+            CMD_STOP => Self::Stop,
+            CMD_DRIVE => Self::Drive {
+                left: unmap_percent_value(check_range(type_byte, payload[0], PERCENT_MAX)?),
+                right: unmap_percent_value(check_range(type_byte, payload[1], PERCENT_MAX)?),
+            },
+            CMD_CENTER_CAMERA => Self::CenterCamera,
+            CMD_LOOK => Self::Look {
+                pan: unmap_angular_value(check_range(type_byte, payload[0], ANGLE_MAX)?),
+                tilt: unmap_angular_value(check_range(type_byte, payload[1], ANGLE_MAX)?),
+            },
+            CMD_SET_RADIO => Self::SetRadio {
+                channel: check_range(type_byte, payload[0], MAX_CHANNEL)?,
+                rate: DataRate::from_u8(check_range(type_byte, payload[1], MAX_DATA_RATE)?)
+                    .unwrap(),
+                power: PaLevel::from_u8(check_range(type_byte, payload[2], MAX_PA_LEVEL)?).unwrap(),
+            },
+            CMD_PARAM_GET => Self::ParamGet(read_param(type_byte, payload[0])?),
+            CMD_PARAM_SET => Self::ParamSet {
+                param: read_param(type_byte, payload[0])?,
+                value: read_u16(&payload[1..]),
+            },
+            CMD_PARAM_COMMIT => Self::ParamCommit,
+            CMD_SET_VTX => Self::SetVtx(check_range(type_byte, payload[0], 1)? != 0),
+            _ => unreachable!(),
+        };
+        Ok(command)
+    }
+}
+
+/// Robot-to-controller telemetry, sent as ack payloads
+///
+/// Values are raw as transmitted; see radio.md for units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Telemetry {
+    /// Sequence number of the ping being answered
+    Pong(u8),
+    /// Value in use after a `Command::ParamGet` or `Command::ParamSet`
+    ParamValue {
+        param: Param,
+        value: u16,
+    },
+    Noop,
+    /// Whether the video transmitter is on
+    VtxState(bool),
+    /// Top 24 bits of the last authenticated frame counter the robot accepted,
+    /// sent when it rejects one as stale; the counter shifted right by 8, so
+    /// anything over 24 bits is out of range to encode
+    AuthStale(u32),
+    /// Low byte of the last authenticated frame counter the robot accepted,
+    /// with a truncated MAC over the whole counter, see `auth::check_receipt`
+    AuthReceipt {
+        counter: u8,
+        tag: u16,
+    },
+    /// Firmware version, and why the robot last reset
+    Identity {
+        major: u8,
+        minor: u8,
+        reset_cause: ResetCause,
+    },
+    /// Time since the robot booted, in 100ms units (24 bits, so wraps after
+    /// ~19 days); anything over 24 bits is out of range to encode
+    Uptime(u32),
+    BatteryVoltage(u16),
+    BatteryCurrent(u16),
+    LeftRpm(u16),
+    RightRpm(u16),
+}
+
+impl Telemetry {
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            Self::Pong(_) => TLM_PONG,
+            Self::ParamValue { .. } => TLM_PARAM_VALUE,
+            Self::VtxState(_) => TLM_VTX_STATE,
+            Self::AuthStale(_) => TLM_AUTH_STALE,
+            Self::AuthReceipt { .. } => TLM_AUTH_RECEIPT,
+            Self::Noop => TLM_NOOP,
+            Self::Identity { .. } => TLM_IDENTITY,
+            Self::Uptime(_) => TLM_UPTIME,
+            Self::BatteryVoltage(_) => TLM_BATTERY_VOLTAGE,
+            Self::BatteryCurrent(_) => TLM_BATTERY_CURRENT,
+            Self::LeftRpm(_) => TLM_LEFT_RPM,
+            Self::RightRpm(_) => TLM_RIGHT_RPM,
+        }
+    }
+
+    /// Total encoded length, including the type byte
+    pub fn encoded_len(&self) -> usize {
+        1 + telemetry_payload_len(self.type_byte()).unwrap_or(0)
+    }
+
+    /// Raw value as transmitted, for kinds which carry one
+    pub fn value(&self) -> Option<u16> {
+        match *self {
+            Self::Noop
+            | Self::Identity { .. }
+            | Self::Uptime(_)
+            | Self::AuthStale(_)
+            | Self::AuthReceipt { .. } => None,
+            Self::ParamValue { value, .. } => Some(value),
+            Self::VtxState(on) => Some(on.into()),
+            Self::Pong(seq) => Some(seq.into()),
+            Self::BatteryVoltage(value)
+            | Self::BatteryCurrent(value)
+            | Self::LeftRpm(value)
+            | Self::RightRpm(value) => Some(value),
+        }
+    }
+
+    /// Encodes into the start of `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(ProtocolError::BufferTooSmall {
+                needed: len,
+                actual: buf.len(),
+            });
+        }
+        buf[0] = self.type_byte();
+        match *self {
+            Self::Pong(seq) => {
+                buf[1] = seq;
+            }
+            Self::ParamValue { param, value } => {
+                buf[1] = param.to_u8();
+                buf[2..4].copy_from_slice(&value.to_be_bytes());
+            }
+            Self::VtxState(on) => {
+                buf[1] = on.into();
+            }
+            Self::Noop => {}
+            Self::Identity {
+                major,
+                minor,
+                reset_cause,
+            } => {
+                buf[1..4].copy_from_slice(&[major, minor, reset_cause.0]);
+            }
+            Self::Uptime(ticks) => {
+                write_u24(TLM_UPTIME, ticks, &mut buf[1..4])?;
+            }
+            Self::AuthStale(floor_high) => {
+                write_u24(TLM_AUTH_STALE, floor_high, &mut buf[1..4])?;
+            }
+            Self::AuthReceipt { counter, tag } => {
+                buf[1] = counter;
+                buf[2..4].copy_from_slice(&tag.to_be_bytes());
+            }
+            Self::BatteryVoltage(value)
+            | Self::BatteryCurrent(value)
+            | Self::LeftRpm(value)
+            | Self::RightRpm(value) => {
+                buf[1..3].copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        Ok(len)
+    }
+
+    /// Decodes a complete telemetry item, which must be exactly the right length
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let (&type_byte, payload) = bytes.split_first().ok_or(ProtocolError::Empty)?;
+        let expected =
+            telemetry_payload_len(type_byte).ok_or(ProtocolError::UnknownType(type_byte))?;
+        check_length(type_byte, expected, payload)?;
+
+        let telemetry = match type_byte {
+            TLM_PONG => Self::Pong(payload[0]),
+            TLM_VTX_STATE => Self::VtxState(check_range(type_byte, payload[0], 1)? != 0),
+            TLM_PARAM_VALUE => Self::ParamValue {
+                param: read_param(type_byte, payload[0])?,
+                value: read_u16(&payload[1..]),
+            },
+            TLM_NOOP => Self::Noop,
+            TLM_IDENTITY => Self::Identity {
+                major: payload[0],
+                minor: payload[1],
+                reset_cause: ResetCause(payload[2]),
+            },
+            TLM_UPTIME => Self::Uptime(read_u24(payload)),
+            TLM_AUTH_STALE => Self::AuthStale(read_u24(payload)),
+            TLM_AUTH_RECEIPT => Self::AuthReceipt {
+                counter: payload[0],
+                tag: read_u16(&payload[1..]),
+            },
+            TLM_BATTERY_VOLTAGE => Self::BatteryVoltage(read_u16(payload)),
+            TLM_BATTERY_CURRENT => Self::BatteryCurrent(read_u16(payload)),
+            TLM_LEFT_RPM => Self::LeftRpm(read_u16(payload)),
+            TLM_RIGHT_RPM => Self::RightRpm(read_u16(payload)),
+            _ => unreachable!(),
+        };
+        Ok(telemetry)
+    }
+}
+
+/// Iterator over telemetry items packed back to back in an ack payload
+///
+/// Each item's length follows from its type, so decoding stops at the first
+/// unknown type or truncated item, after yielding the error.
+pub struct TelemetryItems<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TelemetryItems<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl Iterator for TelemetryItems<'_> {
+    type Item = Result<Telemetry, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &type_byte = self.bytes.first()?;
+        let item_len = match telemetry_payload_len(type_byte) {
+            Some(payload_len) if self.bytes.len() > payload_len => 1 + payload_len,
+            Some(payload_len) => {
+                let actual = self.bytes.len() - 1;
+                self.bytes = &[];
+                return Some(Err(ProtocolError::WrongLength {
+                    type_byte,
+                    expected: payload_len,
+                    actual,
+                }));
+            }
+            None => {
+                self.bytes = &[];
+                return Some(Err(ProtocolError::UnknownType(type_byte)));
+            }
+        };
+        let (item, rest) = self.bytes.split_at(item_len);
+        self.bytes = rest;
+        Some(Telemetry::decode(item))
+    }
+}
+
+/// Payload length for a command type, or None if not a (usable) command type
+pub fn command_payload_len(type_byte: u8) -> Option<usize> {
+    match type_byte {
+        CMD_NOOP | CMD_STOP | CMD_CENTER_CAMERA | CMD_PARAM_COMMIT => Some(0),
+        CMD_CHANGE_CHANNEL | CMD_PING | CMD_PARAM_GET | CMD_SET_VTX => Some(1),
+        CMD_DRIVE | CMD_LOOK => Some(2),
+        CMD_SET_RADIO | CMD_PARAM_SET => Some(3),
+        _ => None,
+    }
+}
+
+/// Payload length for a telemetry type, or None if not a (usable) telemetry type
+pub fn telemetry_payload_len(type_byte: u8) -> Option<usize> {
+    match type_byte {
+        TLM_NOOP => Some(0),
+        TLM_PONG | TLM_VTX_STATE => Some(1),
+        TLM_BATTERY_VOLTAGE | TLM_BATTERY_CURRENT | TLM_LEFT_RPM | TLM_RIGHT_RPM => Some(2),
+        TLM_IDENTITY | TLM_UPTIME | TLM_PARAM_VALUE | TLM_AUTH_STALE | TLM_AUTH_RECEIPT => Some(3),
+        _ => None,
+    }
+}
+
+fn check_length(type_byte: u8, expected: usize, payload: &[u8]) -> Result<(), ProtocolError> {
+    if payload.len() != expected {
+        return Err(ProtocolError::WrongLength {
+            type_byte,
+            expected,
+            actual: payload.len(),
+        });
+    }
+    Ok(())
+}
+
+fn check_range(type_byte: u8, value: u8, max: u8) -> Result<u8, ProtocolError> {
+    if value > max {
+        return Err(ProtocolError::OutOfRange { type_byte, value });
+    }
+    Ok(value)
+}
+
+fn read_param(type_byte: u8, value: u8) -> Result<Param, ProtocolError> {
+    Param::from_u8(value).ok_or(ProtocolError::OutOfRange { type_byte, value })
+}
+
+fn read_u16(payload: &[u8]) -> u16 {
+    u16::from_be_bytes([payload[0], payload[1]])
+}
+
+fn read_u24(payload: &[u8]) -> u32 {
+    u32::from_be_bytes([0, payload[0], payload[1], payload[2]])
+}
+
+// Values over 24 bits don't fit, and are reported by the byte that's left over
+fn write_u24(type_byte: u8, value: u32, buf: &mut [u8]) -> Result<(), ProtocolError> {
+    let [top, bytes @ ..] = value.to_be_bytes();
+    if top != 0 {
+        return Err(ProtocolError::OutOfRange {
+            type_byte,
+            value: top,
+        });
+    }
+    buf.copy_from_slice(&bytes);
+    Ok(())
+}
+
+// Maps signed +/- 100 to 0-200 for transmission
+fn map_percent_value(value: i8) -> u8 {
+    (value.clamp(-100, 100) as i16 + 100) as u8
+}
+
+// Maps 0-200 back to signed +/- 100
+fn unmap_percent_value(value: u8) -> i8 {
+    (value as i16 - 100) as i8
+}
+
+// Maps signed +/- 90 to 0-180 for transmission
+fn map_angular_value(value: i8) -> u8 {
+    (value.clamp(-90, 90) as i16 + 90) as u8
+}
+
+// Maps 0-180 back to signed +/- 90
+fn unmap_angular_value(value: u8) -> i8 {
+    (value as i16 - 90) as i8
+}
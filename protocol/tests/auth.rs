@@ -0,0 +1,171 @@
+use skelebot_protocol::auth::{self, parse_key, AuthError, AuthKey, Authenticator};
+use skelebot_protocol::{Command, Param, Telemetry, CMD_AUTHENTICATED, MAX_ITEM_LEN};
+
+const ADDRESS: [u8; 5] = [0xE7; 5];
+
+fn key() -> AuthKey {
+    AuthKey::new(parse_key("000102030405060708090A0B0C0D0E0F").unwrap())
+}
+
+fn seal(authenticator: &mut Authenticator, address: &[u8; 5], command: Command) -> Vec<u8> {
+    let mut packet = [0_u8; MAX_ITEM_LEN];
+    let packet_len = command.encode(&mut packet).unwrap();
+    let mut frame = [0_u8; auth::MAX_FRAME_LEN];
+    let len = authenticator
+        .seal(address, &packet[..packet_len], &mut frame)
+        .unwrap();
+    frame[..len].to_vec()
+}
+
+// Chaskey-12 MACs in the reference code's test layout: key bytes 0x00-0x0F,
+// and a message of bytes 0, 1, 2, ... of each length, as 32-bit words.
+// Lengths cover the empty message, padded and full final blocks, and more
+// than one block
+const CHASKEY12_VECTORS: [(usize, [u32; 4]); 7] = [
+    (0, [0xF1CC3BD7, 0x83764897, 0x8F301CBD, 0x176A6538]),
+    (1, [0xA8628061, 0x72821FB1, 0x03A9CAB1, 0x2BA431B5]),
+    (15, [0x329BEBB1, 0x64F2721E, 0x39BC528A, 0x790FF5E8]),
+    (16, [0xB1954870, 0xEA064C28, 0xAF891455, 0x4FA7967C]),
+    (17, [0xBDBEB10E, 0xCCB382C7, 0x9365A13F, 0x677F6563]),
+    (32, [0x252CFACA, 0x20CA26A3, 0x06515977, 0xCDCB8A5F]),
+    (33, [0xBB5F4BD1, 0xCACC8629, 0xA0256F9E, 0x8801020F]),
+];
+
+#[test]
+fn mac_matches_chaskey12_vectors() {
+    let message: Vec<u8> = (0..=u8::MAX).collect();
+    for (len, expected) in CHASKEY12_VECTORS {
+        let mac = key().mac(&message[..len]);
+        let words: Vec<u32> = mac
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, expected, "{}-byte message", len);
+    }
+}
+
+#[test]
+fn frames_match_the_firmware() {
+    // As herakles.ino checks it: counter 1 and Param set servo pan mid to 306,
+    // for the default address
+    let frame = [
+        0xE4, 0x00, 0x00, 0x00, 0x01, 0xE1, 0x01, 0x01, 0x32, 0xBD, 0x78, 0x16, 0x2E,
+    ];
+    let command = Command::ParamSet {
+        param: Param::ServoPanMid,
+        value: 306,
+    };
+    let mut authenticator = Authenticator::new(key());
+    assert_eq!(seal(&mut authenticator, &ADDRESS, command), frame);
+    let (counter, packet) = auth::open(&key(), &ADDRESS, &frame, 0).unwrap();
+    assert_eq!(counter, 1);
+    assert_eq!(Command::decode(packet), Ok(command));
+
+    // And its receipt, as the firmware stages it
+    let receipt = Telemetry::AuthReceipt {
+        counter: 0x01,
+        tag: 0xAD57,
+    };
+    assert_eq!(auth::receipt(&key(), &ADDRESS, 1), receipt);
+}
+
+#[test]
+fn sealed_frames_open_with_the_same_key_and_address() {
+    let mut authenticator = Authenticator::new(key());
+    let command = Command::Drive {
+        left: 50,
+        right: -50,
+    };
+    let frame = seal(&mut authenticator, &ADDRESS, command);
+    assert_eq!(frame[0], CMD_AUTHENTICATED);
+    assert_eq!(frame.len(), 1 + 4 + command.encoded_len() + auth::TAG_LEN);
+
+    let (counter, packet) = auth::open(&key(), &ADDRESS, &frame, 0).unwrap();
+    assert_eq!(counter, 1);
+    assert_eq!(Command::decode(packet), Ok(command));
+}
+
+#[test]
+fn tampered_or_misaddressed_frames_are_rejected() {
+    let mut authenticator = Authenticator::new(key());
+    let mut frame = seal(&mut authenticator, &ADDRESS, Command::Stop);
+    assert_eq!(
+        auth::open(&key(), &[0xC2; 5], &frame, 0),
+        Err(AuthError::BadTag)
+    );
+    let other_key = AuthKey::new([0xFF; auth::KEY_LEN]);
+    assert_eq!(
+        auth::open(&other_key, &ADDRESS, &frame, 0),
+        Err(AuthError::BadTag)
+    );
+    frame[5] ^= 0x01;
+    assert_eq!(
+        auth::open(&key(), &ADDRESS, &frame, 0),
+        Err(AuthError::BadTag)
+    );
+}
+
+#[test]
+fn replayed_frames_are_stale() {
+    let mut authenticator = Authenticator::new(key());
+    let frame = seal(&mut authenticator, &ADDRESS, Command::Noop);
+    let (counter, _) = auth::open(&key(), &ADDRESS, &frame, 0).unwrap();
+    assert_eq!(
+        auth::open(&key(), &ADDRESS, &frame, counter),
+        Err(AuthError::Stale { counter })
+    );
+}
+
+#[test]
+fn resync_skips_past_the_robots_counter() {
+    let mut authenticator = Authenticator::new(key());
+    // Robot last accepted 0x0123_4567, reporting its top 24 bits
+    authenticator.resync(0x0123_4567 >> 8);
+    let frame = seal(&mut authenticator, &ADDRESS, Command::Noop);
+    let (counter, _) = auth::open(&key(), &ADDRESS, &frame, 0x0123_4567).unwrap();
+    assert_eq!(counter, 0x0123_4601);
+}
+
+#[test]
+fn plain_and_short_frames_are_told_apart() {
+    assert_eq!(
+        auth::open(&key(), &ADDRESS, &[0xF0], 0),
+        Err(AuthError::NotAuthenticated)
+    );
+    assert_eq!(
+        auth::open(&key(), &ADDRESS, &[CMD_AUTHENTICATED, 0, 0, 0, 1], 0),
+        Err(AuthError::Truncated)
+    );
+}
+
+#[test]
+fn receipts_check_against_the_counter_address_and_key() {
+    let receipt = auth::receipt(&key(), &ADDRESS, 0x0123_4567);
+    assert!(matches!(
+        receipt,
+        Telemetry::AuthReceipt { counter: 0x67, .. }
+    ));
+    assert!(auth::check_receipt(&key(), &ADDRESS, 0x0123_4567, receipt));
+    // Same low byte, different counter
+    assert!(!auth::check_receipt(&key(), &ADDRESS, 0x0123_4667, receipt));
+    assert!(!auth::check_receipt(
+        &key(),
+        &[0xC2; 5],
+        0x0123_4567,
+        receipt
+    ));
+    let other_key = AuthKey::new([0xFF; auth::KEY_LEN]);
+    assert!(!auth::check_receipt(
+        &other_key,
+        &ADDRESS,
+        0x0123_4567,
+        receipt
+    ));
+}
+
+#[test]
+fn keys_parse_from_32_hex_digits() {
+    assert!(parse_key("000102030405060708090a0b0c0d0e0f").is_some());
+    assert!(parse_key("000102030405060708090A0B0C0D0E").is_none());
+    assert!(parse_key("000102030405060708090A0B0C0D0E0G").is_none());
+}
@@ -0,0 +1,355 @@
+use skelebot_protocol::{
+    command_payload_len, telemetry_payload_len, Command, DataRate, PaLevel, Param, ProtocolError,
+    ResetCause, Telemetry, TelemetryItems, CMD_AUTHENTICATED, CMD_DRIVE, CMD_LOOK, CMD_SET_RADIO,
+    MAX_ITEM_LEN, MAX_TELEMETRY_LEN, TLM_BATTERY_VOLTAGE,
+};
+
+// Byte values worth trying in a third payload byte, where trying all of them
+// along with the first two would take too long
+const EDGE_BYTES: [u8; 10] = [0, 1, 2, 3, 4, 0x7F, 0x80, 0xC8, 0xFE, 0xFF];
+
+fn encode_command(command: Command) -> Vec<u8> {
+    let mut buf = [0_u8; MAX_ITEM_LEN];
+    let len = command.encode(&mut buf).unwrap();
+    assert_eq!(len, command.encoded_len());
+    buf[..len].to_vec()
+}
+
+fn encode_telemetry(telemetry: Telemetry) -> Vec<u8> {
+    let mut buf = [0_u8; MAX_ITEM_LEN];
+    let len = telemetry.encode(&mut buf).unwrap();
+    assert_eq!(len, telemetry.encoded_len());
+    buf[..len].to_vec()
+}
+
+#[test]
+fn commands_round_trip() {
+    let commands = [
+        Command::Noop,
+        Command::ChangeChannel(125),
+        Command::Ping(7),
+        Command::Stop,
+        Command::Drive {
+            left: -100,
+            right: 42,
+        },
+        Command::CenterCamera,
+        Command::Look { pan: 90, tilt: -3 },
+        Command::SetRadio {
+            channel: 40,
+            rate: DataRate::Mbps2,
+            power: PaLevel::Low,
+        },
+        Command::ParamGet(Param::ServoTiltMax),
+        Command::ParamSet {
+            param: Param::ConnLossMs,
+            value: 1_500,
+        },
+        Command::ParamCommit,
+        Command::SetVtx(true),
+    ];
+    for command in commands {
+        let bytes = encode_command(command);
+        assert_eq!(Command::decode(&bytes), Ok(command), "{:02X?}", bytes);
+    }
+}
+
+#[test]
+fn telemetry_round_trips() {
+    let items = [
+        Telemetry::Pong(200),
+        Telemetry::ParamValue {
+            param: Param::ServoPanMid,
+            value: 306,
+        },
+        Telemetry::Noop,
+        Telemetry::VtxState(false),
+        Telemetry::AuthStale(0x00AB_CDEF),
+        Telemetry::AuthReceipt {
+            counter: 0x45,
+            tag: 0xBEEF,
+        },
+        Telemetry::Identity {
+            major: 1,
+            minor: 1,
+            reset_cause: ResetCause(ResetCause::BROWN_OUT),
+        },
+        Telemetry::Uptime(0x00FF_FFFF),
+        Telemetry::BatteryVoltage(11_355),
+        Telemetry::BatteryCurrent(512),
+        Telemetry::LeftRpm(180),
+        Telemetry::RightRpm(0),
+    ];
+    for telemetry in items {
+        let bytes = encode_telemetry(telemetry);
+        assert_eq!(Telemetry::decode(&bytes), Ok(telemetry), "{:02X?}", bytes);
+    }
+}
+
+// Every payload of the given length: all of them up to two bytes, and with
+// the third byte limited to edge cases beyond that
+fn payloads(len: usize) -> impl Iterator<Item = Vec<u8>> {
+    let firsts = if len == 0 { 0..=0 } else { 0..=u8::MAX };
+    let seconds = if len < 2 { 0..=0 } else { 0..=u8::MAX };
+    let thirds: &[u8] = if len < 3 { &[0] } else { &EDGE_BYTES };
+    firsts.flat_map(move |first| {
+        seconds.clone().flat_map(move |second| {
+            thirds
+                .iter()
+                .map(move |&third| [first, second, third][..len].to_vec())
+        })
+    })
+}
+
+#[test]
+fn every_command_that_decodes_encodes_back_to_the_same_bytes() {
+    for type_byte in 0..=u8::MAX {
+        let Some(len) = command_payload_len(type_byte) else {
+            continue;
+        };
+        let mut decoded = 0;
+        for payload in payloads(len) {
+            let bytes = [&[type_byte], payload.as_slice()].concat();
+            match Command::decode(&bytes) {
+                Ok(command) => {
+                    assert_eq!(encode_command(command), bytes, "{:?}", command);
+                    decoded += 1;
+                }
+                Err(ProtocolError::OutOfRange {
+                    type_byte: t,
+                    value,
+                }) => {
+                    assert_eq!(t, type_byte);
+                    assert!(payload.contains(&value), "{:02X?}", bytes);
+                }
+                Err(e) => panic!("{:02X?}: {}", bytes, e),
+            }
+        }
+        assert!(decoded > 0, "no valid {:02X} command", type_byte);
+    }
+}
+
+#[test]
+fn every_telemetry_item_that_decodes_encodes_back_to_the_same_bytes() {
+    for type_byte in 0..=u8::MAX {
+        let Some(len) = telemetry_payload_len(type_byte) else {
+            continue;
+        };
+        let mut decoded = 0;
+        for payload in payloads(len) {
+            let bytes = [&[type_byte], payload.as_slice()].concat();
+            match Telemetry::decode(&bytes) {
+                Ok(telemetry) => {
+                    assert_eq!(encode_telemetry(telemetry), bytes, "{:?}", telemetry);
+                    decoded += 1;
+                }
+                Err(ProtocolError::OutOfRange {
+                    type_byte: t,
+                    value,
+                }) => {
+                    assert_eq!(t, type_byte);
+                    assert!(payload.contains(&value), "{:02X?}", bytes);
+                }
+                Err(e) => panic!("{:02X?}: {}", bytes, e),
+            }
+        }
+        assert!(decoded > 0, "no valid {:02X} telemetry", type_byte);
+    }
+}
+
+#[test]
+fn every_type_byte_rejects_the_wrong_length() {
+    for type_byte in 0..=u8::MAX {
+        for actual in 0..MAX_ITEM_LEN {
+            let bytes = [&[type_byte], &[0_u8; MAX_ITEM_LEN][..actual]].concat();
+            let expected_error = |payload_len: Option<usize>| match payload_len {
+                None => Some(ProtocolError::UnknownType(type_byte)),
+                Some(expected) if expected != actual => Some(ProtocolError::WrongLength {
+                    type_byte,
+                    expected,
+                    actual,
+                }),
+                Some(_) => None,
+            };
+            if let Some(error) = expected_error(command_payload_len(type_byte)) {
+                assert_eq!(Command::decode(&bytes), Err(error));
+            }
+            if let Some(error) = expected_error(telemetry_payload_len(type_byte)) {
+                assert_eq!(Telemetry::decode(&bytes), Err(error));
+            }
+        }
+    }
+}
+
+#[test]
+fn commands_and_telemetry_keep_to_their_bands() {
+    for type_byte in 0..=u8::MAX {
+        let is_telemetry_band = type_byte & 0x08 != 0;
+        if command_payload_len(type_byte).is_some() || type_byte == CMD_AUTHENTICATED {
+            assert!(!is_telemetry_band, "command {:02X}", type_byte);
+        }
+        if telemetry_payload_len(type_byte).is_some() {
+            assert!(is_telemetry_band, "telemetry {:02X}", type_byte);
+        }
+    }
+}
+
+#[test]
+fn drive_and_look_are_offset_and_clamped() {
+    let bytes = encode_command(Command::Drive {
+        left: -128,
+        right: 127,
+    });
+    assert_eq!(bytes, [CMD_DRIVE, 0, 200]);
+    let bytes = encode_command(Command::Look { pan: -128, tilt: 0 });
+    assert_eq!(bytes, [CMD_LOOK, 0, 90]);
+}
+
+#[test]
+fn set_radio_uses_rf24_enum_values() {
+    let bytes = encode_command(Command::SetRadio {
+        channel: 76,
+        rate: DataRate::Kbps250,
+        power: PaLevel::Max,
+    });
+    assert_eq!(bytes, [CMD_SET_RADIO, 76, 2, 3]);
+}
+
+#[test]
+fn decode_rejects_malformed_packets() {
+    assert_eq!(Command::decode(&[]), Err(ProtocolError::Empty));
+    assert_eq!(
+        Command::decode(&[0x00]),
+        Err(ProtocolError::UnknownType(0x00))
+    );
+    assert_eq!(
+        Command::decode(&[CMD_DRIVE, 100]),
+        Err(ProtocolError::WrongLength {
+            type_byte: CMD_DRIVE,
+            expected: 2,
+            actual: 1,
+        })
+    );
+    assert_eq!(
+        Command::decode(&[CMD_DRIVE, 100, 201]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: CMD_DRIVE,
+            value: 201,
+        })
+    );
+    assert_eq!(
+        Command::decode(&[CMD_SET_RADIO, 126, 0, 0]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: CMD_SET_RADIO,
+            value: 126,
+        })
+    );
+}
+
+#[test]
+fn encode_needs_room() {
+    let mut buf = [0_u8; 2];
+    assert_eq!(
+        Command::Look { pan: 0, tilt: 0 }.encode(&mut buf[..2]),
+        Err(ProtocolError::BufferTooSmall {
+            needed: 3,
+            actual: 2,
+        })
+    );
+    assert_eq!(
+        Command::ChangeChannel(126).encode(&mut [0; MAX_ITEM_LEN]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: 0xF1,
+            value: 126,
+        })
+    );
+}
+
+#[test]
+fn auth_stale_has_to_fit_in_24_bits() {
+    // The top 24 bits of the highest counter
+    assert_eq!(
+        encode_telemetry(Telemetry::AuthStale(u32::MAX >> 8)),
+        [0xEB, 0xFF, 0xFF, 0xFF]
+    );
+    assert_eq!(
+        Telemetry::AuthStale(u32::MAX).encode(&mut [0; MAX_ITEM_LEN]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: 0xEB,
+            value: 0xFF,
+        })
+    );
+    assert_eq!(
+        Telemetry::AuthStale(0x0100_0000).encode(&mut [0; MAX_ITEM_LEN]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: 0xEB,
+            value: 1,
+        })
+    );
+}
+
+#[test]
+fn uptime_has_to_fit_in_24_bits() {
+    assert_eq!(
+        encode_telemetry(Telemetry::Uptime(0x00FF_FFFF)),
+        [0xFA, 0xFF, 0xFF, 0xFF]
+    );
+    assert_eq!(
+        Telemetry::Uptime(0x0100_0000).encode(&mut [0; MAX_ITEM_LEN]),
+        Err(ProtocolError::OutOfRange {
+            type_byte: 0xFA,
+            value: 1,
+        })
+    );
+}
+
+#[test]
+fn telemetry_items_split_an_ack_payload() {
+    let mut payload = [0_u8; MAX_TELEMETRY_LEN];
+    let first = Telemetry::BatteryVoltage(11_000)
+        .encode(&mut payload)
+        .unwrap();
+    let second = Telemetry::Pong(3).encode(&mut payload[first..]).unwrap();
+    let len = first + second;
+    let items: Vec<_> = TelemetryItems::new(&payload[..len]).collect();
+    assert_eq!(
+        items,
+        [
+            Ok(Telemetry::BatteryVoltage(11_000)),
+            Ok(Telemetry::Pong(3))
+        ]
+    );
+}
+
+#[test]
+fn telemetry_items_stop_after_an_error() {
+    let payload = [TLM_BATTERY_VOLTAGE, 0x2A, 0x00, 0x01, TLM_BATTERY_VOLTAGE];
+    let items: Vec<_> = TelemetryItems::new(&payload).collect();
+    assert_eq!(
+        items,
+        [
+            Ok(Telemetry::BatteryVoltage(0x2A00)),
+            Err(ProtocolError::UnknownType(0x01)),
+        ]
+    );
+
+    let truncated = [TLM_BATTERY_VOLTAGE, 0x2A];
+    let items: Vec<_> = TelemetryItems::new(&truncated).collect();
+    assert_eq!(
+        items,
+        [Err(ProtocolError::WrongLength {
+            type_byte: TLM_BATTERY_VOLTAGE,
+            expected: 2,
+            actual: 1,
+        })]
+    );
+}
+
+#[test]
+fn params_map_to_and_from_ids() {
+    for (id, param) in Param::ALL.iter().enumerate() {
+        assert_eq!(param.to_u8() as usize, id);
+        assert_eq!(Param::from_u8(id as u8), Some(*param));
+    }
+    assert_eq!(Param::from_u8(Param::ALL.len() as u8), None);
+}
@@ -4,42 +4,89 @@
 #include <RF24.h>
 #include <Wire.h>
 #include <Adafruit_PWMServoDriver.h>
+#include <EEPROM.h>
 #include <Arduino.h>
 
+#define FIRMWARE_VERSION_MAJOR 1
+#define FIRMWARE_VERSION_MINOR 1
+
 #define CE_PIN A0
 #define CSN_PIN A1
 
 RF24 radio(CE_PIN, CSN_PIN);
 
-// Command buffer
-uint8_t commands[4][4];
+// Command buffer, big enough for authenticated frames if those are required
+#ifdef AUTH_KEY
+#define COMMAND_BUF_LEN 13
+#else
+#define COMMAND_BUF_LEN 4
+#endif
+uint8_t commands[4][COMMAND_BUF_LEN];
+uint8_t command_lens[4];
 uint8_t command_head = 0;
 uint8_t command_tail = 0;
 
+// Authenticated commands, see radio.md. Build with the key `controller pair`
+// prints, e.g. -DAUTH_KEY=0x01,0x02,... and every command must then be wrapped
+// with a valid MAC and a counter above the last one accepted
+#ifdef AUTH_KEY
+const uint8_t auth_key_bytes[16] = {AUTH_KEY};
+uint32_t auth_key[4];
+uint32_t auth_key2[4];   // Chaskey subkey for a padded final block
+uint32_t auth_last_counter = 0;
+// Counter value saved to EEPROM ahead of use, so a reboot can't reopen old
+// counters for replay without writing EEPROM on every command
+uint32_t auth_counter_reserve = 0;
+// Whether a command has been accepted since boot, so there's a receipt to give
+bool auth_accepted = false;
+#define AUTH_RESERVE_STEP 65536UL
+#define AUTH_EEPROM_ADDR 32
+#define AUTH_EEPROM_MAGIC 0xA7E4
+#endif
+
 // Radio params
 uint8_t channel = 76;   // Default for RF24 lib, Crazyradio needs changing
-uint8_t control_addr[6] = {0xE7, 0xE7, 0xE7, 0xE7, 0xE7, 0x00}; // Default for Crazyradio
+// Data rate and PA level values match the RF24 enums, as does the protocol
+uint8_t data_rate = RF24_250KBPS;
+uint8_t pa_level = RF24_PA_MAX;
+uint8_t prev_channel = 76;
+uint8_t prev_data_rate = RF24_250KBPS;
+uint8_t prev_pa_level = RF24_PA_MAX;
+bool radio_pending = false;   // Changed radio settings but no command received since
+// Pipe address, change per robot so a fleet can share a channel; bytes in the
+// same order as the controller's --address option
+#ifndef ROBOT_ADDRESS
+#define ROBOT_ADDRESS 0xE7, 0xE7, 0xE7, 0xE7, 0xE7  // Default for Crazyradio
+#endif
+uint8_t control_addr[6] = {ROBOT_ADDRESS, 0x00};
 
 // Timer values
-#define CONN_LOSS_MS 200
+#define CONN_LOSS_MS 200     // Default, see params below
 unsigned long last_cmd = 0;
-#define ACK_PAYLOAD_MS 50
+#define ACK_PAYLOAD_MS 50    // Default, see params below
 unsigned long last_ack = 0;
-
-typedef enum: uint8_t {
-    BATT_VOLTAGE,
-    BATT_CURRENT,
-} AckType;
-AckType next_ack_type = AckType::BATT_VOLTAGE;
+#define RADIO_REVERT_MS 1000
+unsigned long radio_changed = 0;
+// Every this many ack payloads, identity and uptime go out instead of battery
+#define HEALTH_ACK_EVERY 10
+uint8_t acks_since_health = 0;
+// Telemetry staged for the next ack, behind any receipt
+uint8_t ack_items[8];
+uint8_t ack_items_len = 0;
+
+// MCUSR as found at boot: bit 0 power-on, 1 external, 2 brown-out, 3 watchdog
+uint8_t reset_cause = 0;
 
 #define BATT_VOLTAGE_PIN A6
 #define BATT_CURRENT_PIN A2
 #define VTX_EN_PIN 2
+bool vtx_on = false;
 
 // Servo values
 #define SERVO_I2C_ADDR 0x40
 #define SERVO_PWM_FREQ_VAL 50   // 50 Hz
 #define SERVO_PAN_PIN 0
+// Servo limits are defaults in PWM counts, see params below
 #define SERVO_PAN_MIN 65   // 0°
 #define SERVO_PAN_MID 306   // 90°
 #define SERVO_PAN_MAX 548   // 180°
@@ -51,8 +98,38 @@ AckType next_ack_type = AckType::BATT_VOLTAGE;
 // #define SERVO_TILT_MAX 499   // 180°
 
 Adafruit_PWMServoDriver pwm = Adafruit_PWMServoDriver(SERVO_I2C_ADDR);
+// Last angles set, so servo limit changes can be applied straight away
+uint8_t pan_angle = 90;
+uint8_t tilt_angle = 90;
+
+// Parameters the controller can read and change at runtime, by id as per radio.md
+#define PARAM_SERVO_PAN_MIN 0
+#define PARAM_SERVO_PAN_MID 1
+#define PARAM_SERVO_PAN_MAX 2
+#define PARAM_SERVO_TILT_MIN 3
+#define PARAM_SERVO_TILT_MID 4
+#define PARAM_SERVO_TILT_MAX 5
+#define PARAM_CONN_LOSS_MS 6
+#define PARAM_ACK_PAYLOAD_MS 7
+#define PARAM_COUNT 8
+uint16_t params[PARAM_COUNT] = {
+    SERVO_PAN_MIN, SERVO_PAN_MID, SERVO_PAN_MAX,
+    SERVO_TILT_MIN, SERVO_TILT_MID, SERVO_TILT_MAX,
+    CONN_LOSS_MS, ACK_PAYLOAD_MS,
+};
+const uint16_t param_min[PARAM_COUNT] = {40, 40, 40, 40, 40, 40, 120, 10};
+const uint16_t param_max[PARAM_COUNT] = {600, 600, 600, 600, 600, 600, 2000, 1000};
+
+// Committed params live in EEPROM after a marker, which changes if the layout does
+#define PARAMS_EEPROM_ADDR 0
+#define PARAMS_EEPROM_MAGIC 0x5B01
 
 void setup() {
+    // Capture why we reset before anything else, then clear it for next time.
+    // Optiboot may already have cleared it, in which case this reads 0 (unknown)
+    reset_cause = MCUSR;
+    MCUSR = 0;
+
     #ifdef ENABLE_DEBUG_OUTPUT
     Serial.begin(9600);
     Serial.println("Beginning startup");
@@ -67,7 +144,7 @@ void setup() {
 
     // Set VTX pin low to start
     pinMode(VTX_EN_PIN, OUTPUT);
-    digitalWrite(VTX_EN_PIN, LOW);
+    setVtx(false);
 
     // Setup I2C for servo control
     Wire.begin();
@@ -87,6 +164,13 @@ void setup() {
     pwm.setPWMFreq(SERVO_PWM_FREQ_VAL);
     delay(10);
 
+    // Committed params replace the defaults, if there are any
+    loadParams();
+
+    #ifdef AUTH_KEY
+    loadAuth();
+    #endif
+
     // Initial servo wakeup stretching
     if (!setCameraPanAngle(90) || !setCameraTiltAngle(90)) {
         #ifdef ENABLE_DEBUG_OUTPUT
@@ -123,7 +207,7 @@ void setup() {
     // Under 6V and we can assume we're on external power (at least the kind
     // which doesn't necessarily want the extra draw)
     if (getBatteryVoltage() > (6 * 1023)) {
-        digitalWrite(VTX_EN_PIN, HIGH);
+        setVtx(true);
     }
 }
 
@@ -135,33 +219,58 @@ void loop() {
     if (radio.available()) {
         uint8_t command_len = radio.getDynamicPayloadSize();
         // Really should have an error flag somewhere around here
-        // We shouldn't ever get more than 4 bytes as per protocol
-        if (command_len > 4) {
-            command_len = 4;
+        // We shouldn't ever get more than this as per protocol
+        if (command_len > COMMAND_BUF_LEN) {
+            command_len = COMMAND_BUF_LEN;
         }
         uint8_t next_head = (command_head + 1) % 4;
         // We have to drop payloads if the ring buffer's full
         if (next_head != command_tail) {
-            radio.read(commands[next_head], command_len);
+            // Written at head and read from tail, so it's handled below
+            // straight away rather than once the next payload arrives
+            radio.read(commands[command_head], command_len);
+            command_lens[command_head] = command_len;
             command_head = next_head;
+            // Its ack took whatever was staged
+            ack_items_len = 0;
         }
-        received_cmd = true;
     }
 
     // Potentially process multiple payloads, however
     while (command_tail != command_head) {
         // TODO: move this all to a function
-        uint8_t (&command)[4] = commands[command_tail];
+        uint8_t *command = commands[command_tail];
+        #ifdef AUTH_KEY
+        // Anything not genuine and fresh is dropped, and doesn't keep the
+        // connection alive either
+        command = authenticateCommand(command, command_lens[command_tail]);
+        if (command == NULL) {
+            command_tail = (command_tail + 1) % 4;
+            continue;
+        }
+        #endif
+        received_cmd = true;
+        // Any other command after a radio change confirms the controller followed
+        if (command[0] != 0xF1 && command[0] != 0xF7) {
+            radio_pending = false;
+        }
         switch (command[0]) {
             case 0xF0:
                 // No-op
                 break;
             case 0xF1:
-                // Change channel
-                radio.setChannel(command[1]);
+                // Change channel, reverting later if the controller doesn't follow
+                if (command[1] <= 125 && command[1] != channel) {
+                    changeRadio(command[1], data_rate, pa_level);
+                }
                 break;
             case 0xF2:
-                // (Reserved)
+                // Ping (seq), answered in the very next ack by dropping any
+                // staged telemetry, which is restaged soon enough anyway
+                ack_items[0] = 0xE8;
+                ack_items[1] = command[1];
+                ack_items_len = 2;
+                stageAck();
                 break;
             case 0xF3:
                 // Stop
@@ -184,28 +293,59 @@ void loop() {
                 setCameraPanAngle(command[1]);
                 setCameraTiltAngle(command[2]);
                 break;
+            case 0xE0:
+                // Param get (id), answered in the next ack as for Ping
+                if (command[1] < PARAM_COUNT) {
+                    stageParamValue(command[1]);
+                }
+                break;
+            case 0xE1:
+                // Param set (id, value), answered with the value now in use,
+                // which is the old one if the new one was out of range
+                if (command[1] < PARAM_COUNT) {
+                    setParam(command[1], ((uint16_t)command[2] << 8) | command[3]);
+                    stageParamValue(command[1]);
+                }
+                break;
+            case 0xE2:
+                // Param commit, keeping the current values over a reboot
+                EEPROM.put(PARAMS_EEPROM_ADDR, (uint16_t)PARAMS_EEPROM_MAGIC);
+                EEPROM.put(PARAMS_EEPROM_ADDR + sizeof(uint16_t), params);
+                break;
+            case 0xE3:
+                // Set VTX (on), reported back with the battery telemetry
+                setVtx(command[1] != 0);
+                break;
             case 0xF7:
-                // (Reserved)
+                // Set radio (channel, data rate, PA level), reverting later as above
+                if (command[1] <= 125 && command[2] <= RF24_250KBPS && command[3] <= RF24_PA_MAX) {
+                    changeRadio(command[1], command[2], command[3]);
+                }
                 break;
             default:
                 break;
         }
+        #ifdef AUTH_KEY
+        // Restaged so the next ack carries this command's receipt
+        stageAck();
+        #endif
         command_tail = (command_tail + 1) % 4;
     }
 
     unsigned long current_tick = millis();
 
-    // If no command received in CONN_LOSS_MS ms, assume connection lost and stop
+    // If no command received in the conn loss period, assume connection lost and stop
     if (received_cmd) {
         last_cmd = current_tick;
     }
-    if (current_tick - last_cmd >= CONN_LOSS_MS) {
+    if (current_tick - last_cmd >= params[PARAM_CONN_LOSS_MS]) {
         MOTOR.setStop1();
         MOTOR.setStop2();
         // This will reset our conn loss check period
         last_cmd = current_tick;
         // Flush pending acks because they may no longer refer to valid packets
         radio.flush_tx();
+        ack_items_len = 0;
         // If the radio has failed in some way, it may require restart
         if (radio.failureDetected) {
             if (!restartRadio()) {
@@ -215,69 +355,115 @@ void loop() {
         }
     }
 
-    if (current_tick - last_ack >= ACK_PAYLOAD_MS) {
+    // If the controller never showed up on the new settings, go back to the old ones
+    if (radio_pending && current_tick - radio_changed >= RADIO_REVERT_MS) {
+        channel = prev_channel;
+        data_rate = prev_data_rate;
+        pa_level = prev_pa_level;
+        applyRadio();
+        radio_pending = false;
+    }
+
+    uint16_t ack_payload_ms = params[PARAM_ACK_PAYLOAD_MS];
+    // Replies still waiting to go out take priority
+    if (current_tick - last_ack >= ack_payload_ms && ack_items_len == 0) {
         // Update next ack and stage this one for sending
         do {
-            last_ack += ACK_PAYLOAD_MS;
-        } while (current_tick - last_ack >= ACK_PAYLOAD_MS);
-
-        uint16_t ack_value;
-        uint8_t ack[3];
-
-        if (next_ack_type == AckType::BATT_VOLTAGE) {
+            last_ack += ack_payload_ms;
+        } while (current_tick - last_ack >= ack_payload_ms);
+
+        uint8_t *ack = ack_items;
+
+        if (acks_since_health + 1 >= HEALTH_ACK_EVERY) {
+            // Identity and uptime go out together, so a reboot's cause
+            // arrives along with the uptime that reveals it
+            ack[0] = 0xF9;  // Identity
+            ack[1] = FIRMWARE_VERSION_MAJOR;
+            ack[2] = FIRMWARE_VERSION_MINOR;
+            ack[3] = reset_cause;
+
+            // Uptime in 100ms units, 24 bits (wraps after ~19 days)
+            uint32_t uptime = current_tick / 100;
+            ack[4] = 0xFA;  // Uptime
+            ack[5] = (uptime >> 16) & 0xFF;
+            writeBigEndian(&ack[6], uptime & 0xFFFF);
+            ack_items_len = 8;
+            acks_since_health = 0;
+        } else {
+            // Battery voltage and current go out together, back to back,
+            // followed by VTX state
             // Get divided battery voltage from ADC6
-            // R1 = 430k, R2 = 100k, Vo = Vi * (430000 + 100000) / 100000
-            // We have clearance here in terms of int sizes because the
-            // analog value is 10 bits
-            ack_value = getBatteryVoltage();
             ack[0] = 0xFB;  // Battery voltage
-        } else if (next_ack_type == AckType::BATT_CURRENT) {
+            writeBigEndian(&ack[1], getBatteryVoltage());
+
             // Get divided battery-sourced current from ADC
-            ack_value = getBatteryCurrent();
-            ack[0] = 0xFC;  // Battery current
-        }
+            ack[3] = 0xFC;  // Battery current
+            writeBigEndian(&ack[4], getBatteryCurrent());
 
-        // Convert little-endian to big-endian
-        uint8_t *ack_bytes = (uint8_t*)&ack_value;
-        ack[1] = ack_bytes[1];
-        ack[2] = ack_bytes[0];
-        // TODO: queue up ack payloads
-        if (!radio.writeAckPayload(0, ack, 3)) {
-            // Flush previous ack payloads and retry
-            radio.flush_tx();
-            radio.writeAckPayload(0, ack, 3);
+            ack[6] = 0xEA;  // VTX state
+            ack[7] = vtx_on;
+            ack_items_len = 8;
+            acks_since_health++;
         }
 
-        // Set next ack payload type
-        switch (next_ack_type) {
-            case AckType::BATT_VOLTAGE:
-                next_ack_type = AckType::BATT_CURRENT;
-                break;
-            case AckType::BATT_CURRENT:
-                next_ack_type = AckType::BATT_VOLTAGE;
-                break;
-            default:
-                break;
-        }
+        stageAck();
     }
+}
 
-    // TODO: write ack payload(s) and drain queue if successful
+// Replaces whatever's staged with the receipt for the last command accepted,
+// if authenticating, followed by the staged telemetry
+void stageAck() {
+    uint8_t ack[12];
+    uint8_t ack_len = 0;
+    #ifdef AUTH_KEY
+    if (auth_accepted) {
+        ack[0] = 0xEC;  // Auth receipt
+        ack[1] = auth_last_counter & 0xFF;
+        writeBigEndian(&ack[2], receiptTag(auth_last_counter));
+        ack_len = 4;
+    }
+    #endif
+    memcpy(&ack[ack_len], ack_items, ack_items_len);
+    ack_len += ack_items_len;
+    radio.flush_tx();
+    radio.writeAckPayload(0, ack, ack_len);
 }
 
 void startRadio() {
-    // TODO: figure out if/when to set to less than max
-    radio.setPALevel(RF24_PA_MAX);
-    radio.setDataRate(RF24_250KBPS);
     // Enable dynamic payloads and payload acks
     radio.enableDynamicPayloads();
     radio.enableAckPayload();
-    // Set channel and address, reading from crazyradio tx
+    // Set address, reading from crazyradio tx
     radio.openReadingPipe(0, control_addr);
-    radio.setChannel(channel);
+    // Boots at the slowest rate and max power, until the controller says otherwise
+    applyRadio();
     // Put radio in RX mode
     radio.startListening();
 }
 
+// Sets channel, data rate and PA level from the current radio params
+void applyRadio() {
+    radio.setChannel(channel);
+    radio.setDataRate((rf24_datarate_e)data_rate);
+    radio.setPALevel((rf24_pa_dbm_e)pa_level);
+}
+
+// Switches to new radio settings, pending confirmation by the controller
+void changeRadio(uint8_t new_channel, uint8_t new_data_rate, uint8_t new_pa_level) {
+    // Keep the last confirmed settings if several changes arrive unconfirmed
+    if (!radio_pending) {
+        prev_channel = channel;
+        prev_data_rate = data_rate;
+        prev_pa_level = pa_level;
+    }
+    channel = new_channel;
+    data_rate = new_data_rate;
+    pa_level = new_pa_level;
+    applyRadio();
+    radio_pending = true;
+    radio_changed = millis();
+}
+
 bool restartRadio() {
     // As the prophets teach, off and on again
     radio.powerDown();
@@ -323,11 +509,186 @@ bool setCameraAngle(uint8_t pin, uint8_t angle, uint16_t min, uint16_t mid, uint
 }
 
 bool setCameraPanAngle(uint8_t angle) {
-    return setCameraAngle(SERVO_PAN_PIN, angle, SERVO_PAN_MIN, SERVO_PAN_MID, SERVO_PAN_MAX);
+    pan_angle = angle;
+    return setCameraAngle(SERVO_PAN_PIN, angle, params[PARAM_SERVO_PAN_MIN],
+                          params[PARAM_SERVO_PAN_MID], params[PARAM_SERVO_PAN_MAX]);
 }
 
 bool setCameraTiltAngle(uint8_t angle) {
-    return setCameraAngle(SERVO_TILT_PIN, angle, SERVO_TILT_MIN, SERVO_TILT_MID, SERVO_TILT_MAX);
+    tilt_angle = angle;
+    return setCameraAngle(SERVO_TILT_PIN, angle, params[PARAM_SERVO_TILT_MIN],
+                          params[PARAM_SERVO_TILT_MID], params[PARAM_SERVO_TILT_MAX]);
+}
+
+void setVtx(bool on) {
+    digitalWrite(VTX_EN_PIN, on ? HIGH : LOW);
+    vtx_on = on;
+}
+
+// Checks a full set of params, including that servo limits stay in order
+bool paramsValid(const uint16_t *values) {
+    for (uint8_t id = 0; id < PARAM_COUNT; id++) {
+        if (values[id] < param_min[id] || values[id] > param_max[id]) {
+            return false;
+        }
+    }
+    return values[PARAM_SERVO_PAN_MIN] < values[PARAM_SERVO_PAN_MID]
+        && values[PARAM_SERVO_PAN_MID] < values[PARAM_SERVO_PAN_MAX]
+        && values[PARAM_SERVO_TILT_MIN] < values[PARAM_SERVO_TILT_MID]
+        && values[PARAM_SERVO_TILT_MID] < values[PARAM_SERVO_TILT_MAX];
+}
+
+void loadParams() {
+    uint16_t magic;
+    EEPROM.get(PARAMS_EEPROM_ADDR, magic);
+    if (magic != PARAMS_EEPROM_MAGIC) {
+        return;
+    }
+    uint16_t stored[PARAM_COUNT];
+    EEPROM.get(PARAMS_EEPROM_ADDR + sizeof(uint16_t), stored);
+    // Anything odd, such as limits from an older build, and the defaults stay
+    if (paramsValid(stored)) {
+        memcpy(params, stored, sizeof(params));
+    }
+}
+
+// Changes one param if the result is valid, reapplying servo limits straight away
+bool setParam(uint8_t id, uint16_t value) {
+    uint16_t updated[PARAM_COUNT];
+    memcpy(updated, params, sizeof(params));
+    updated[id] = value;
+    if (!paramsValid(updated)) {
+        return false;
+    }
+    params[id] = value;
+    if (id <= PARAM_SERVO_PAN_MAX) {
+        setCameraPanAngle(pan_angle);
+    } else if (id <= PARAM_SERVO_TILT_MAX) {
+        setCameraTiltAngle(tilt_angle);
+    }
+    return true;
+}
+
+#ifdef AUTH_KEY
+#define ROTL(x, b) (uint32_t)(((x) << (b)) | ((x) >> (32 - (b))))
+
+void chaskeyPermute(uint32_t *v) {
+    for (uint8_t i = 0; i < 12; i++) {
+        v[0] += v[1]; v[1] = ROTL(v[1], 5); v[1] ^= v[0]; v[0] = ROTL(v[0], 16);
+        v[2] += v[3]; v[3] = ROTL(v[3], 8); v[3] ^= v[2];
+        v[0] += v[3]; v[3] = ROTL(v[3], 13); v[3] ^= v[0];
+        v[2] += v[1]; v[1] = ROTL(v[1], 7); v[1] ^= v[2]; v[2] = ROTL(v[2], 16);
+    }
+}
+
+// Multiplication by x in GF(2^128), for deriving the subkeys
+void chaskeyTimesTwo(uint32_t *out, const uint32_t *in) {
+    uint32_t carry = (in[3] & 0x80000000UL) ? 0x87 : 0;
+    out[0] = (in[0] << 1) ^ carry;
+    out[1] = (in[1] << 1) | (in[0] >> 31);
+    out[2] = (in[2] << 1) | (in[1] >> 31);
+    out[3] = (in[3] << 1) | (in[2] >> 31);
+}
+
+void loadAuth() {
+    // AVR is little-endian, as Chaskey's words are
+    uint32_t key1[4];
+    memcpy(auth_key, auth_key_bytes, sizeof(auth_key));
+    chaskeyTimesTwo(key1, auth_key);
+    chaskeyTimesTwo(auth_key2, key1);
+
+    uint16_t magic;
+    EEPROM.get(AUTH_EEPROM_ADDR, magic);
+    if (magic == AUTH_EEPROM_MAGIC) {
+        EEPROM.get(AUTH_EEPROM_ADDR + sizeof(uint16_t), auth_last_counter);
+    }
+    // Forces a fresh reservation on the first command accepted
+    auth_counter_reserve = auth_last_counter;
+}
+
+// Chaskey-12 MAC of our address followed by `len` bytes of message, leaving
+// the first word of it in `v`. Messages are always shorter than a block, so
+// only the padded final block applies
+void authMac(uint32_t *v, const uint8_t *message, uint8_t len) {
+    uint8_t block[16] = {0};
+    memcpy(block, control_addr, 5);
+    memcpy(&block[5], message, len);
+    block[5 + len] = 0x01;
+
+    uint32_t m[4];
+    memcpy(v, auth_key, 4 * sizeof(uint32_t));
+    memcpy(m, block, sizeof(m));
+    for (uint8_t i = 0; i < 4; i++) {
+        v[i] ^= m[i] ^ auth_key2[i];
+    }
+    chaskeyPermute(v);
+    v[0] ^= auth_key2[0];
+}
+
+// Checks the first 4 bytes of the MAC of the counter and the command
+bool authTagValid(const uint8_t *frame, uint8_t command_len) {
+    uint32_t v[4];
+    authMac(v, &frame[1], 4 + command_len);
+    return memcmp(v, &frame[5 + command_len], 4) == 0;
+}
+
+// First 2 bytes of the MAC of the counter and the receipt's type byte, which
+// no command shares, as the receipt's tag (big-endian, as it's sent)
+uint16_t receiptTag(uint32_t counter) {
+    uint8_t message[5] = {
+        (uint8_t)(counter >> 24), (uint8_t)(counter >> 16),
+        (uint8_t)(counter >> 8), (uint8_t)counter, 0xEC,
+    };
+    uint32_t v[4];
+    authMac(v, message, sizeof(message));
+    uint8_t *mac = (uint8_t *)v;
+    return ((uint16_t)mac[0] << 8) | mac[1];
+}
+
+// Unwraps an authenticated frame (0xE4, counter, command, tag), returning the
+// command inside, or NULL if it's not genuine or not fresh
+uint8_t *authenticateCommand(uint8_t *frame, uint8_t len) {
+    if (len < 10 || frame[0] != 0xE4) {
+        return NULL;
+    }
+    uint8_t command_len = len - 9;
+    if (!authTagValid(frame, command_len)) {
+        return NULL;
+    }
+    uint32_t counter = ((uint32_t)frame[1] << 24) | ((uint32_t)frame[2] << 16)
+        | ((uint32_t)frame[3] << 8) | frame[4];
+    if (counter == auth_last_counter) {
+        // The same frame again, as sent on each of the controller's radios
+        return NULL;
+    }
+    if (counter < auth_last_counter) {
+        // Genuine but stale, eg the controller restarted, so tell it where we
+        // are (top 24 bits) and it'll skip ahead
+        ack_items[0] = 0xEB;  // Auth stale
+        ack_items[1] = (uint8_t)(auth_last_counter >> 24);
+        writeBigEndian(&ack_items[2], (auth_last_counter >> 8) & 0xFFFF);
+        ack_items_len = 4;
+        stageAck();
+        return NULL;
+    }
+    auth_last_counter = counter;
+    auth_accepted = true;
+    if (counter >= auth_counter_reserve) {
+        auth_counter_reserve = counter + AUTH_RESERVE_STEP;
+        EEPROM.put(AUTH_EEPROM_ADDR, (uint16_t)AUTH_EEPROM_MAGIC);
+        EEPROM.put(AUTH_EEPROM_ADDR + sizeof(uint16_t), auth_counter_reserve);
+    }
+    return &frame[5];
+}
+#endif
+
+// Stages a param value as the next ack payload, dropping any staged telemetry
+void stageParamValue(uint8_t id) {
+    ack_items[0] = 0xE9;
+    ack_items[1] = id;
+    writeBigEndian(&ack_items[2], params[id]);
+    ack_items_len = 4;
+    stageAck();
 }
 
 uint8_t motorSpeedCeiling(uint8_t value) {
@@ -404,3 +765,9 @@ uint16_t getBatteryCurrent() {
 
     return (uint16_t)current;
 }
+
+// Writes a value in the protocol's big-endian byte order
+void writeBigEndian(uint8_t *dest, uint16_t value) {
+    dest[0] = value >> 8;
+    dest[1] = value & 0xFF;
+}
@@ -2,25 +2,83 @@
 #include <RF24.h>
 #include <printf.h>
 
+// Stand-in for the robot on the bench: speaks the same radio protocol as
+// herakles.ino (see radio.md), but prints what it would do over serial
+
+#define FIRMWARE_VERSION_MAJOR 1
+#define FIRMWARE_VERSION_MINOR 1
+
 #define CE_PIN 7
 #define CSN_PIN 8
 
 RF24 radio(CE_PIN, CSN_PIN);
 
-// Command buffer
-uint8_t commands[4][4];
+// Command buffer, big enough for authenticated frames if those are required
+#ifdef AUTH_KEY
+#define COMMAND_BUF_LEN 13
+#else
+#define COMMAND_BUF_LEN 4
+#endif
+uint8_t commands[4][COMMAND_BUF_LEN];
+uint8_t command_lens[4];
 uint8_t command_head = 0;
 uint8_t command_tail = 0;
 
+// Authenticated commands, as in herakles.ino. Build with the key `controller
+// pair` prints, e.g. -DAUTH_KEY=0x01,0x02,... The last counter isn't kept over
+// a reset here, so old frames can be replayed to a freshly reset placeholder
+#ifdef AUTH_KEY
+const uint8_t auth_key_bytes[16] = {AUTH_KEY};
+uint32_t auth_key[4];
+uint32_t auth_key2[4];   // Chaskey subkey for a padded final block
+uint32_t auth_last_counter = 0;
+// Whether a command has been accepted since boot, so there's a receipt to give
+bool auth_accepted = false;
+#endif
+
 // Radio params
 uint8_t channel = 76;   // Default for RF24 lib, Crazyradio needs changing
-uint8_t control_addr[6] = {0xE7, 0xE7, 0xE7, 0xE7, 0xE7, 0x00}; // Default for Crazyradio
+// Data rate and PA level values match the RF24 enums, as does the protocol;
+// PA level low for debug
+uint8_t data_rate = RF24_250KBPS;
+uint8_t pa_level = RF24_PA_LOW;
+uint8_t prev_channel = 76;
+uint8_t prev_data_rate = RF24_250KBPS;
+uint8_t prev_pa_level = RF24_PA_LOW;
+bool radio_pending = false;   // Changed radio settings but no command received since
+#ifndef ROBOT_ADDRESS
+#define ROBOT_ADDRESS 0xE7, 0xE7, 0xE7, 0xE7, 0xE7  // Default for Crazyradio
+#endif
+uint8_t control_addr[6] = {ROBOT_ADDRESS, 0x00};
 
 // Timer values
 #define TICK_MS 20
-#define ACK_MS 50
+#define RADIO_REVERT_MS 1000
 unsigned long last_tick = 0;
+unsigned long last_cmd = 0;
 unsigned long last_ack = 0;
+unsigned long radio_changed = 0;
+// Every this many ack payloads, identity and uptime go out instead of battery
+#define HEALTH_ACK_EVERY 10
+uint8_t acks_since_health = 0;
+// Telemetry staged for the next ack, behind any receipt
+uint8_t ack_items[8];
+uint8_t ack_items_len = 0;
+
+// Parameters, by id as per radio.md. Nothing here uses the servo limits, and
+// they're only kept until reset, but they're checked and answered the same
+#define PARAM_SERVO_PAN_MIN 0
+#define PARAM_SERVO_PAN_MID 1
+#define PARAM_SERVO_PAN_MAX 2
+#define PARAM_SERVO_TILT_MIN 3
+#define PARAM_SERVO_TILT_MID 4
+#define PARAM_SERVO_TILT_MAX 5
+#define PARAM_CONN_LOSS_MS 6
+#define PARAM_ACK_PAYLOAD_MS 7
+#define PARAM_COUNT 8
+uint16_t params[PARAM_COUNT] = {65, 306, 548, 190, 300, 394, 200, 50};
+const uint16_t param_min[PARAM_COUNT] = {40, 40, 40, 40, 40, 40, 120, 10};
+const uint16_t param_max[PARAM_COUNT] = {600, 600, 600, 600, 600, 600, 2000, 1000};
 
 // Motor values
 char motor_dir = 'S';
@@ -31,6 +89,9 @@ int8_t motor_right = 0;
 int8_t camera_pan = 0;
 int8_t camera_tilt = 0;
 
+// Video transmitter, which there isn't one of on the bench
+bool vtx_on = true;
+
 void setup() {
     // Serial setup first
     Serial.begin(115200);
@@ -38,20 +99,21 @@ void setup() {
         // Wait for USB serial to init
     }
 
+    #ifdef AUTH_KEY
+    loadAuth();
+    #endif
+
     // Radio init
     if (!radio.begin()) {
         Serial.println(F("Radio not responding, aborting setup..."));
         while (1) {}
     }
-    // Set PA level low for debug
-    radio.setPALevel(RF24_PA_LOW);
-    radio.setDataRate(RF24_250KBPS);
     // Enable dynamic payloads and payload acks
     radio.enableDynamicPayloads();
     radio.enableAckPayload();
-    // Set channel and address, reading from crazyradio tx
+    // Set address, reading from crazyradio tx
     radio.openReadingPipe(0, control_addr);
-    radio.setChannel(channel);
+    applyRadio();
     // Put radio in RX mode
     radio.startListening();
 
@@ -65,40 +127,69 @@ void setup() {
         Serial.print(b & 0x0F, HEX);
     }
     Serial.print("\n");
+    #ifdef AUTH_KEY
+    Serial.println(F("Authenticated commands only"));
+    #endif
 }
 
 void loop() {
+    bool received_cmd = false;
+
     // First, handle any payload we've received
     // Only one payload received per loop for now, may refactor later
     if (radio.available()) {
         uint8_t command_len = radio.getDynamicPayloadSize();
         // Really should have an error flag somewhere around here
-        // We shouldn't ever get more than 4 bytes as per protocol
-        if (command_len > 4) {
-            command_len = 4;
+        // We shouldn't ever get more than this as per protocol
+        if (command_len > COMMAND_BUF_LEN) {
+            command_len = COMMAND_BUF_LEN;
         }
         uint8_t next_head = (command_head + 1) % 4;
         // We have to drop payloads if the ring buffer's full
         if (next_head != command_tail) {
-            radio.read(commands[next_head], command_len);
+            // Written at head and read from tail, so it's handled below
+            // straight away rather than once the next payload arrives
+            radio.read(commands[command_head], command_len);
+            command_lens[command_head] = command_len;
             command_head = next_head;
+            // Its ack took whatever was staged
+            ack_items_len = 0;
         }
     }
 
     // Potentially process multiple payloads, however
     while (command_tail != command_head) {
-        // TODO: move this all to a function
-        uint8_t (&command)[4] = commands[command_tail];
+        uint8_t *command = commands[command_tail];
+        #ifdef AUTH_KEY
+        // Anything not genuine and fresh is dropped, and doesn't keep the
+        // connection alive either
+        command = authenticateCommand(command, command_lens[command_tail]);
+        if (command == NULL) {
+            command_tail = (command_tail + 1) % 4;
+            continue;
+        }
+        #endif
+        received_cmd = true;
+        // Any other command after a radio change confirms the controller followed
+        if (command[0] != 0xF1 && command[0] != 0xF7) {
+            radio_pending = false;
+        }
         switch (command[0]) {
             case 0xF0:
                 // No-op
                 break;
             case 0xF1:
-                // Change channel
-                radio.setChannel(command[1]);
+                // Change channel, reverting later if the controller doesn't follow
+                if (command[1] <= 125 && command[1] != channel) {
+                    changeRadio(command[1], data_rate, pa_level);
+                }
                 break;
             case 0xF2:
-                // (Reserved)
+                // Ping (seq), answered in the very next ack
+                ack_items[0] = 0xE8;
+                ack_items[1] = command[1];
+                ack_items_len = 2;
+                stageAck();
                 break;
             case 0xF3:
                 // Stop
@@ -123,16 +214,67 @@ void loop() {
                 camera_tilt = convertCameraAngle(command[2]);
                 break;
             case 0xF7:
-                // (Reserved)
+                // Set radio (channel, data rate, PA level), reverting later as above
+                if (command[1] <= 125 && command[2] <= RF24_250KBPS && command[3] <= RF24_PA_MAX) {
+                    changeRadio(command[1], command[2], command[3]);
+                }
+                break;
+            case 0xE0:
+                // Param get (id), answered in the next ack as for Ping
+                if (command[1] < PARAM_COUNT) {
+                    stageParamValue(command[1]);
+                }
+                break;
+            case 0xE1:
+                // Param set (id, value), answered with the value now in use
+                if (command[1] < PARAM_COUNT) {
+                    setParam(command[1], ((uint16_t)command[2] << 8) | command[3]);
+                    stageParamValue(command[1]);
+                }
+                break;
+            case 0xE2:
+                // Param commit, with no EEPROM use here to keep them in
+                Serial.println(F("\nParams committed"));
+                break;
+            case 0xE3:
+                // Set VTX (on), reported back with the battery telemetry
+                vtx_on = command[1] != 0;
                 break;
             default:
                 break;
         }
+        #ifdef AUTH_KEY
+        // Restaged so the next ack carries this command's receipt
+        stageAck();
+        #endif
         command_tail = (command_tail + 1) % 4;
     }
 
     unsigned long current_tick = millis();
 
+    // If no command received in the conn loss period, assume connection lost and stop
+    if (received_cmd) {
+        last_cmd = current_tick;
+    }
+    if (current_tick - last_cmd >= params[PARAM_CONN_LOSS_MS]) {
+        motor_dir = 'S';
+        motor_left = 0;
+        motor_right = 0;
+        last_cmd = current_tick;
+        // Flush pending acks because they may no longer refer to valid packets
+        radio.flush_tx();
+        ack_items_len = 0;
+    }
+
+    // If the controller never showed up on the new settings, go back to the old ones
+    if (radio_pending && current_tick - radio_changed >= RADIO_REVERT_MS) {
+        channel = prev_channel;
+        data_rate = prev_data_rate;
+        pa_level = prev_pa_level;
+        applyRadio();
+        radio_pending = false;
+    }
+
     if (current_tick - last_tick >= TICK_MS) {
         // Update next tick and execute this one
         do {
@@ -140,32 +282,219 @@ void loop() {
         } while (current_tick - last_tick >= TICK_MS);
 
         // Output current drive values to serial
-        char output_str[32];
-        sprintf(output_str, "\r%c L %+.3i R %+.3i P %+.3i T %+.3i", motor_dir,
-                motor_left, motor_right, camera_pan, camera_tilt);
+        char output_str[48];
+        sprintf(output_str, "\r%c L %+.3i R %+.3i P %+.3i T %+.3i C %.3u V %c", motor_dir,
+                motor_left, motor_right, camera_pan, camera_tilt, channel, vtx_on ? '1' : '0');
         Serial.print(output_str);
     }
 
-    if (current_tick - last_ack >= ACK_MS) {
+    uint16_t ack_payload_ms = params[PARAM_ACK_PAYLOAD_MS];
+    // Replies still waiting to go out take priority
+    if (current_tick - last_ack >= ack_payload_ms && ack_items_len == 0) {
         // Update next ack and stage this one for sending
         do {
-            last_ack += ACK_MS;
-        } while (current_tick - last_ack >= ACK_MS);
+            last_ack += ack_payload_ms;
+        } while (current_tick - last_ack >= ack_payload_ms);
+
+        uint8_t *ack = ack_items;
 
-        // Fake a battery voltage using the current tick
-        uint16_t voltage = (uint16_t)65535 - (uint16_t)(current_tick & 0xFFFF);
-        uint8_t *voltage_bytes = (uint8_t*)&voltage;
+        if (acks_since_health + 1 >= HEALTH_ACK_EVERY) {
+            ack[0] = 0xF9;  // Identity, with an unknown reset cause
+            ack[1] = FIRMWARE_VERSION_MAJOR;
+            ack[2] = FIRMWARE_VERSION_MINOR;
+            ack[3] = 0;
 
-        uint8_t ack[3];
-        ack[0] = 0xFB;  // Battery voltage
-        // Convert little-endian to big-endian
-        ack[1] = voltage_bytes[1];
-        ack[2] = voltage_bytes[0];
+            // Uptime in 100ms units, 24 bits (wraps after ~19 days)
+            uint32_t uptime = current_tick / 100;
+            ack[4] = 0xFA;  // Uptime
+            ack[5] = (uptime >> 16) & 0xFF;
+            writeBigEndian(&ack[6], uptime & 0xFFFF);
+            ack_items_len = 8;
+            acks_since_health = 0;
+        } else {
+            // Fake a battery voltage using the current tick
+            uint16_t voltage = (uint16_t)65535 - (uint16_t)(current_tick & 0xFFFF);
+
+            ack[0] = 0xFB;  // Battery voltage
+            writeBigEndian(&ack[1], voltage);
+            ack[3] = 0xEA;  // VTX state
+            ack[4] = vtx_on;
+            ack_items_len = 5;
+            acks_since_health++;
+        }
+
+        stageAck();
+    }
+}
+
+// Replaces whatever's staged with the receipt for the last command accepted,
+// if authenticating, followed by the staged telemetry
+void stageAck() {
+    uint8_t ack[12];
+    uint8_t ack_len = 0;
+    #ifdef AUTH_KEY
+    if (auth_accepted) {
+        ack[0] = 0xEC;  // Auth receipt
+        ack[1] = auth_last_counter & 0xFF;
+        writeBigEndian(&ack[2], receiptTag(auth_last_counter));
+        ack_len = 4;
+    }
+    #endif
+    memcpy(&ack[ack_len], ack_items, ack_items_len);
+    ack_len += ack_items_len;
+    radio.flush_tx();
+    radio.writeAckPayload(0, ack, ack_len);
+}
+
+// Sets channel, data rate and PA level from the current radio params
+void applyRadio() {
+    radio.setChannel(channel);
+    radio.setDataRate((rf24_datarate_e)data_rate);
+    radio.setPALevel((rf24_pa_dbm_e)pa_level);
+}
 
-        radio.writeAckPayload(0, ack, 3);
+// Switches to new radio settings, pending confirmation by the controller
+void changeRadio(uint8_t new_channel, uint8_t new_data_rate, uint8_t new_pa_level) {
+    // Keep the last confirmed settings if several changes arrive unconfirmed
+    if (!radio_pending) {
+        prev_channel = channel;
+        prev_data_rate = data_rate;
+        prev_pa_level = pa_level;
     }
+    channel = new_channel;
+    data_rate = new_data_rate;
+    pa_level = new_pa_level;
+    applyRadio();
+    radio_pending = true;
+    radio_changed = millis();
 }
 
+// Changes one param if it's in range and servo limits stay in order
+bool setParam(uint8_t id, uint16_t value) {
+    if (value < param_min[id] || value > param_max[id]) {
+        return false;
+    }
+    uint16_t updated[PARAM_COUNT];
+    memcpy(updated, params, sizeof(params));
+    updated[id] = value;
+    if (!(updated[PARAM_SERVO_PAN_MIN] < updated[PARAM_SERVO_PAN_MID]
+          && updated[PARAM_SERVO_PAN_MID] < updated[PARAM_SERVO_PAN_MAX]
+          && updated[PARAM_SERVO_TILT_MIN] < updated[PARAM_SERVO_TILT_MID]
+          && updated[PARAM_SERVO_TILT_MID] < updated[PARAM_SERVO_TILT_MAX])) {
+        return false;
+    }
+    params[id] = value;
+    return true;
+}
+
+// Stages a param value as the next ack payload, dropping any staged telemetry
+void stageParamValue(uint8_t id) {
+    ack_items[0] = 0xE9;
+    ack_items[1] = id;
+    writeBigEndian(&ack_items[2], params[id]);
+    ack_items_len = 4;
+    stageAck();
+}
+
+#ifdef AUTH_KEY
+#define ROTL(x, b) (uint32_t)(((x) << (b)) | ((x) >> (32 - (b))))
+
+void chaskeyPermute(uint32_t *v) {
+    for (uint8_t i = 0; i < 12; i++) {
+        v[0] += v[1]; v[1] = ROTL(v[1], 5); v[1] ^= v[0]; v[0] = ROTL(v[0], 16);
+        v[2] += v[3]; v[3] = ROTL(v[3], 8); v[3] ^= v[2];
+        v[0] += v[3]; v[3] = ROTL(v[3], 13); v[3] ^= v[0];
+        v[2] += v[1]; v[1] = ROTL(v[1], 7); v[1] ^= v[2]; v[2] = ROTL(v[2], 16);
+    }
+}
+
+// Multiplication by x in GF(2^128), for deriving the subkeys
+void chaskeyTimesTwo(uint32_t *out, const uint32_t *in) {
+    uint32_t carry = (in[3] & 0x80000000UL) ? 0x87 : 0;
+    out[0] = (in[0] << 1) ^ carry;
+    out[1] = (in[1] << 1) | (in[0] >> 31);
+    out[2] = (in[2] << 1) | (in[1] >> 31);
+    out[3] = (in[3] << 1) | (in[2] >> 31);
+}
+
+void loadAuth() {
+    // AVR is little-endian, as Chaskey's words are
+    uint32_t key1[4];
+    memcpy(auth_key, auth_key_bytes, sizeof(auth_key));
+    chaskeyTimesTwo(key1, auth_key);
+    chaskeyTimesTwo(auth_key2, key1);
+}
+
+// Chaskey-12 MAC of our address followed by `len` bytes of message, leaving
+// the first word of it in `v`. Messages are always shorter than a block, so
+// only the padded final block applies
+void authMac(uint32_t *v, const uint8_t *message, uint8_t len) {
+    uint8_t block[16] = {0};
+    memcpy(block, control_addr, 5);
+    memcpy(&block[5], message, len);
+    block[5 + len] = 0x01;
+
+    uint32_t m[4];
+    memcpy(v, auth_key, 4 * sizeof(uint32_t));
+    memcpy(m, block, sizeof(m));
+    for (uint8_t i = 0; i < 4; i++) {
+        v[i] ^= m[i] ^ auth_key2[i];
+    }
+    chaskeyPermute(v);
+    v[0] ^= auth_key2[0];
+}
+
+// Checks the first 4 bytes of the MAC of the counter and the command
+bool authTagValid(const uint8_t *frame, uint8_t command_len) {
+    uint32_t v[4];
+    authMac(v, &frame[1], 4 + command_len);
+    return memcmp(v, &frame[5 + command_len], 4) == 0;
+}
+
+// First 2 bytes of the MAC of the counter and the receipt's type byte, which
+// no command shares, as the receipt's tag (big-endian, as it's sent)
+uint16_t receiptTag(uint32_t counter) {
+    uint8_t message[5] = {
+        (uint8_t)(counter >> 24), (uint8_t)(counter >> 16),
+        (uint8_t)(counter >> 8), (uint8_t)counter, 0xEC,
+    };
+    uint32_t v[4];
+    authMac(v, message, sizeof(message));
+    uint8_t *mac = (uint8_t *)v;
+    return ((uint16_t)mac[0] << 8) | mac[1];
+}
+
+// Unwraps an authenticated frame (0xE4, counter, command, tag), returning the
+// command inside, or NULL if it's not genuine or not fresh
+uint8_t *authenticateCommand(uint8_t *frame, uint8_t len) {
+    if (len < 10 || frame[0] != 0xE4) {
+        return NULL;
+    }
+    uint8_t command_len = len - 9;
+    if (!authTagValid(frame, command_len)) {
+        return NULL;
+    }
+    uint32_t counter = ((uint32_t)frame[1] << 24) | ((uint32_t)frame[2] << 16)
+        | ((uint32_t)frame[3] << 8) | frame[4];
+    if (counter == auth_last_counter) {
+        // The same frame again, as sent on each of the controller's radios
+        return NULL;
+    }
+    if (counter < auth_last_counter) {
+        // Genuine but stale, so tell the controller where we are (top 24 bits)
+        ack_items[0] = 0xEB;  // Auth stale
+        ack_items[1] = (uint8_t)(auth_last_counter >> 24);
+        writeBigEndian(&ack_items[2], (auth_last_counter >> 8) & 0xFFFF);
+        ack_items_len = 4;
+        stageAck();
+        return NULL;
+    }
+    auth_last_counter = counter;
+    auth_accepted = true;
+    return &frame[5];
+}
+#endif
+
 int8_t convertMotorSpeed(uint8_t value) {
     if (value < 0) return -100;
     if (value > 200) return 100;
@@ -204,3 +533,9 @@ int8_t convertCameraAngle(uint8_t value) {
   }
   return (int8_t)(value - 90);
 }
+
+// Writes a value in the protocol's big-endian byte order
+void writeBigEndian(uint8_t *dest, uint16_t value) {
+    dest[0] = value >> 8;
+    dest[1] = value & 0xFF;
+}